@@ -8,23 +8,23 @@ use std::{
   time::Duration as StdDuration,
 };
 
-use bincode::Options;
 use parking_lot::{Mutex, RwLock};
 use serde::{Deserialize, Serialize};
 use tokio::{net::UdpSocket, task::JoinHandle, time::timeout};
 
 use chatroom_core::{
+  codec::Codec,
   connection::Connection,
   data::{
-    Command, ErrorCode, Message, Notification, Response, ResponseData, UserInfo, UserOnlineInfo,
+    Capabilities, Command, ErrorCode, Message, Notification, Response, ResponseData, SessionInfo,
+    SessionToken, UserInfo, PROTOCOL_VERSION,
   },
+  srp::{self, ClientHandshake, ClientSession, Verifier},
   utils::Error,
 };
 
 use time::OffsetDateTime;
 
-use sha2::{Digest, Sha256};
-
 use crypto_box::PublicKey;
 
 use tauri::{AppHandle, Manager};
@@ -32,11 +32,16 @@ use tauri::{AppHandle, Manager};
 type RwHashMap<K, V> = RwLock<HashMap<K, V>>;
 type RwBTreeMap<K, V> = RwLock<BTreeMap<K, V>>;
 
+/// optional protocol features this client is able to provide
+const SUPPORTED_CAPABILITIES: Capabilities =
+  Capabilities::COMPRESSION.union(Capabilities::BATCHING);
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ChatEntry {
   Online,
   Offline,
   Message(String),
+  Announcement(String),
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -65,10 +70,12 @@ pub struct ClientState {
   pub group_history: RwBTreeMap<OffsetDateTime, OwnedChatEntry>,
   pub ono2one_history: RwHashMap<String, BTreeMap<OffsetDateTime, OwnedChatEntry>>,
   pub personal_info: Arc<Mutex<Option<PersonalInfo>>>,
+  /// from the last login, to come back with after losing the connection
+  pub session_token: Mutex<Option<SessionToken>>,
 }
 
 impl ClientState {
-  fn new(heartbeat_interval: StdDuration) -> Self {
+  fn new() -> Self {
     ClientState {
       addr2user: Default::default(),
       users: Default::default(),
@@ -76,16 +83,19 @@ impl ClientState {
       group_history: Default::default(),
       ono2one_history: Default::default(),
       personal_info: Default::default(),
+      session_token: Default::default(),
     }
   }
 }
 
 pub struct Client<Coder>
 where
-  Coder: 'static + Options + Copy + Sync + Send,
+  Coder: Codec,
 {
   pub client_addr: SocketAddr,
   pub server_addr: SocketAddr,
+  pub protocol_version: u16,
+  pub capabilities: Capabilities,
   state: Arc<ClientState>,
   connection: Arc<Connection<Coder>>,
   app_handle: AppHandle,
@@ -96,7 +106,7 @@ where
 
 impl<Coder> Client<Coder>
 where
-  Coder: 'static + Options + Copy + Sync + Send,
+  Coder: Codec,
 {
   pub async fn new(
     client_addr: SocketAddr,
@@ -109,7 +119,7 @@ where
   ) -> Result<Self, Error> {
     let sock = UdpSocket::bind(client_addr).await?;
 
-    let state = Arc::new(ClientState::new(heartbeat_interval));
+    let state = Arc::new(ClientState::new());
 
     let (connection, receiver, _) = Connection::new(
       sock,
@@ -126,8 +136,34 @@ where
     )
     .await??;
 
+    let (protocol_version, capabilities) = match connection
+      .request::<_, Response>(
+        &Command::Hello {
+          version: PROTOCOL_VERSION,
+          capabilities: SUPPORTED_CAPABILITIES,
+        },
+        server_addr,
+      )
+      .await?
+    {
+      Ok(ResponseData::Hello {
+        version,
+        capabilities,
+      }) => (version, capabilities),
+      // servers predating the handshake know nothing about optional features
+      Err(ErrorCode::Unsupported) => (0, Capabilities::empty()),
+      Err(err) => return Err(err.into()),
+      _ => return Err(Error::UnsupportedResponse),
+    };
+    if capabilities.contains(Capabilities::COMPRESSION) {
+      connection.as_inner().enable_compression(server_addr);
+    }
+
+    let heartbeat_timer: Arc<Mutex<Option<JoinHandle<()>>>> = Default::default();
+
     let net_receiver = tokio::spawn({
       let state = state.clone();
+      let heartbeat_timer = heartbeat_timer.clone();
       let coder = coder.clone();
       let connection = connection.clone();
       let app_handle = app_handle.clone();
@@ -153,15 +189,27 @@ where
                       .update_pub_keys(iter::once((info.pub_key.clone().into(), info.ip_address)));
                     // TODO: well, this won't handle new registered user really well,
                     // if future online unrelated info are included in user info
-                    state
-                      .users
-                      .write()
-                      .entry(name.clone())
-                      .or_insert_with(|| UserInfo {
+                    let was_online = {
+                      let mut users = state.users.write();
+                      let user = users.entry(name.clone()).or_insert_with(|| UserInfo {
                         name: name.clone(),
-                        online_info: None,
-                      })
-                      .online_info = Some(info);
+                        devices: vec![],
+                      });
+                      let was_online = user.is_online();
+                      match user
+                        .devices
+                        .iter_mut()
+                        .find(|device| device.ip_address == info.ip_address)
+                      {
+                        Some(device) => *device = info,
+                        None => user.devices.push(info),
+                      }
+                      was_online
+                    };
+                    // one more device of someone already online changes nothing in the chat
+                    if was_online {
+                      continue;
+                    }
                     state
                       .group_history
                       .write()
@@ -179,20 +227,30 @@ where
                   Ok(Notification::Offline {
                     timestamp: time,
                     name,
+                    ip_address,
                   }) => {
-                    let online_info = match state.users.write().get_mut(&name) {
-                      Some(user) => user.online_info.take(),
+                    let still_online = match state.users.write().get_mut(&name) {
+                      Some(user) => {
+                        let before = user.devices.len();
+                        user
+                          .devices
+                          .retain(|device| device.ip_address != ip_address);
+                        if user.devices.len() == before {
+                          continue;
+                        }
+                        user.is_online()
+                      }
                       _ => continue,
                     };
 
-                    let online_info = match online_info {
-                      Some(s) => s,
-                      None => continue,
-                    };
+                    connection.as_inner().release(ip_address);
 
-                    connection.as_inner().release(online_info.ip_address);
+                    if let None = state.addr2user.write().remove(&ip_address) {
+                      continue;
+                    }
 
-                    if let None = state.addr2user.write().remove(&online_info.ip_address) {
+                    // the user is still around on another device
+                    if still_online {
                       continue;
                     }
 
@@ -211,6 +269,34 @@ where
                     let _ = app_handle.emit_all("offline", name);
                     let _ = app_handle.emit_all("new-msg", None::<String>);
                   }
+                  Ok(Notification::Announcement {
+                    timestamp: time,
+                    msg,
+                  }) => {
+                    // announcements come from nobody in particular
+                    state.group_history.write().insert(
+                      time,
+                      OwnedChatEntry::new("".into(), ChatEntry::Announcement(msg)),
+                    );
+                    let _ = app_handle.emit_all("new-msg", None::<String>);
+                  }
+                  Ok(
+                    notification @ (Notification::SessionRevoked { .. }
+                    | Notification::Kicked { .. }),
+                  ) => {
+                    // signed out from another device or by the operator, the token is of no use
+                    // anymore
+                    *state.personal_info.lock() = None;
+                    *state.session_token.lock() = None;
+                    if let Some(timer) = { heartbeat_timer.lock().take() } {
+                      timer.abort();
+                    }
+                    let event = match notification {
+                      Notification::Kicked { .. } => "kicked",
+                      _ => "session-revoked",
+                    };
+                    let _ = app_handle.emit_all(event, ());
+                  }
                   _ => {
                     // log error
                   }
@@ -264,11 +350,13 @@ where
     Ok(Self {
       client_addr,
       server_addr,
+      protocol_version,
+      capabilities,
       state,
       connection,
       app_handle,
       net_receiver,
-      heartbeat_timer: Default::default(),
+      heartbeat_timer,
       heartbeat_interval,
     })
   }
@@ -278,15 +366,14 @@ where
   }
 
   pub async fn register(&self, name: String, pass: &str) -> Result<(), Error> {
-    let mut hasher = Sha256::new();
-    hasher.update(pass.trim_start());
-    let password = hasher.finalize().into();
+    let Verifier { salt, verifier } = Verifier::new(&name, pass);
     match self
       .connection
       .request::<_, Response>(
         &Command::Register {
           username: name,
-          password,
+          salt,
+          verifier,
         },
         self.server_addr,
       )
@@ -298,115 +385,255 @@ where
     }
   }
 
-  pub async fn login(&self, name: String, pass: &str) -> Result<(), Error> {
-    let mut hasher = Sha256::new();
-    hasher.update(pass.trim_start());
-    let password = hasher.finalize().into();
+  /// ask the server for a challenge and answer it as `name`
+  async fn authenticate(&self, name: &str, pass: &str) -> Result<ClientSession, Error> {
+    let handshake = ClientHandshake::new(name, pass);
     match self
       .connection
       .request::<_, Response>(
-        &Command::Login {
-          username: name.clone(),
-          password,
+        &Command::Authenticate {
+          username: name.to_string(),
+          public_key: handshake.public_key(),
         },
         self.server_addr,
       )
       .await?
     {
-      Ok(ResponseData::ChatroomStatus { users }) => {
-        let timer = tokio::spawn({
-          let connection = self.connection.clone();
-          let server_addr = self.server_addr;
-          let mut interval = tokio::time::interval(self.heartbeat_interval);
-          async move {
-            loop {
-              interval.tick().await;
-              if let Err(_) = connection
-                .as_inner()
-                .send_to_with_empty_meta(&Command::Heartbeat, server_addr)
-                .await
-              {
-                // TODO: log error
-              }
-            }
-          }
-        });
-        let old_timer = self.heartbeat_timer.lock().replace(timer);
-        if let Some(old_timer) = old_timer {
-          old_timer.abort();
-        }
+      Ok(ResponseData::Challenge { salt, public_key }) => {
+        Ok(handshake.respond(&salt, &public_key)?)
+      }
+      Err(ErrorCode::InvalidUserOrPass) => Err(ErrorCode::InvalidUserOrPass.into()),
+      Err(code @ ErrorCode::Throttled { .. }) | Err(code @ ErrorCode::PasswordRequired) => {
+        Err(code.into())
+      }
+      _ => Err(Error::UnsupportedResponse),
+    }
+  }
 
-        *self.state.addr2user.write() = users
-          .iter()
-          .filter_map(|u| {
-            if let UserInfo {
-              online_info: Some(UserOnlineInfo { ip_address, .. }),
-              name,
-              ..
-            } = u
-            {
-              Some((ip_address.clone(), name.clone()))
-            } else {
-              None
-            }
-          })
-          .collect();
-        self
-          .connection
-          .as_inner()
-          .update_pub_keys(users.iter().filter_map(|u| {
-            if let UserInfo {
-              online_info:
-                Some(UserOnlineInfo {
-                  ip_address,
-                  pub_key,
-                  ..
-                }),
-              ..
-            } = u
-            {
-              Some((pub_key.clone().into(), ip_address.clone()))
-            } else {
-              None
-            }
-          }));
-        *self.state.users.write() = users.into_iter().map(|u| (u.name.clone(), u)).collect();
+  /// prove the password the way clients did before srp once, so that the server replaces the
+  /// hash it kept back then with a verifier, logging in fails like for a wrong password until
+  /// then and works as usual afterwards
+  pub async fn migrate_password(&self, name: String, pass: &str) -> Result<(), Error> {
+    let Verifier { salt, verifier } = Verifier::new(&name, pass);
+    match self
+      .connection
+      .request::<_, Response>(
+        &Command::MigratePassword {
+          digest: srp::legacy_digest(pass),
+          username: name,
+          salt,
+          verifier,
+        },
+        self.server_addr,
+      )
+      .await?
+    {
+      Ok(ResponseData::Success) => Ok(()),
+      Err(ErrorCode::InvalidUserOrPass) => Err(ErrorCode::InvalidUserOrPass.into()),
+      Err(code @ ErrorCode::Throttled { .. }) | Err(code @ ErrorCode::Banned) => Err(code.into()),
+      _ => Err(Error::UnsupportedResponse),
+    }
+  }
 
-        let my_addr = {
-          let users = self.state.users.read();
-          // TODO: log error
-          users
-            .get(&name)
-            .unwrap()
-            .online_info
-            .as_ref()
-            .unwrap()
-            .ip_address
-        };
+  /// `device` names this device in the session list of the user, `code` is a one-time code or a
+  /// recovery code, which is required once the user has enrolled in two-factor authentication,
+  /// the password itself is only sent to servers without srp if `allow_plaintext` is set
+  pub async fn login(
+    &self,
+    name: String,
+    pass: &str,
+    device: String,
+    code: Option<String>,
+    allow_plaintext: bool,
+  ) -> Result<(), Error> {
+    let session = match self.authenticate(&name, pass).await {
+      Ok(session) => session,
+      Err(Error::Server(ErrorCode::PasswordRequired)) if allow_plaintext => {
+        return self.password_login(name, pass, device, code).await;
+      }
+      Err(err) => return Err(err),
+    };
+    match self
+      .connection
+      .request::<_, Response>(
+        &Command::Login {
+          proof: session.proof().to_vec(),
+          device,
+          code,
+        },
+        self.server_addr,
+      )
+      .await?
+    {
+      Ok(ResponseData::LoggedIn {
+        proof,
+        users,
+        token,
+      }) => {
+        // a server which does not know the verifier must not get to see anything
+        session.verify_server(&proof)?;
+
+        self.enter(name, users)?;
+        *self.state.session_token.lock() = Some(token);
+        Ok(())
+      }
+      Err(ErrorCode::InvalidUserOrPass) => Err(ErrorCode::InvalidUserOrPass.into()),
+      Err(code @ ErrorCode::Throttled { .. })
+      | Err(code @ ErrorCode::SecondFactorRequired)
+      | Err(code @ ErrorCode::InvalidOneTimeCode) => Err(code.into()),
+      _ => Err(Error::UnsupportedResponse),
+    }
+  }
 
-        *self.state.personal_info.lock() = Some(PersonalInfo {
-          name: name.into(),
-          ip_address: my_addr,
-        });
+  /// for servers checking passwords with a provider other than their own store, the password is
+  /// only ever sent over the secure connection
+  async fn password_login(
+    &self,
+    name: String,
+    pass: &str,
+    device: String,
+    code: Option<String>,
+  ) -> Result<(), Error> {
+    match self
+      .connection
+      .request::<_, Response>(
+        &Command::PasswordLogin {
+          username: name.clone(),
+          password: pass.to_string(),
+          device,
+          code,
+        },
+        self.server_addr,
+      )
+      .await?
+    {
+      Ok(ResponseData::LoggedIn { users, token, .. }) => {
+        self.enter(name, users)?;
+        *self.state.session_token.lock() = Some(token);
         Ok(())
       }
       Err(ErrorCode::InvalidUserOrPass) => Err(ErrorCode::InvalidUserOrPass.into()),
+      Err(code @ ErrorCode::Throttled { .. })
+      | Err(code @ ErrorCode::Banned)
+      | Err(code @ ErrorCode::SecondFactorRequired)
+      | Err(code @ ErrorCode::InvalidOneTimeCode) => Err(code.into()),
       _ => Err(Error::UnsupportedResponse),
     }
   }
 
-  pub async fn change_password(&self, old: &str, new: &str) -> Result<(), Error> {
-    let mut hasher = Sha256::new();
-    hasher.update(old.trim_start());
-    let old = hasher.finalize().into();
+  /// log in as the owner of `token` without the password, e.g. on a new connection
+  pub async fn resume(&self, token: SessionToken, device: String) -> Result<(), Error> {
+    match self
+      .connection
+      .request::<_, Response>(
+        &Command::Resume {
+          token: token.clone(),
+          device,
+        },
+        self.server_addr,
+      )
+      .await?
+    {
+      Ok(ResponseData::ChatroomStatus { users }) => {
+        self.enter(token.name.clone(), users)?;
+        *self.state.session_token.lock() = Some(token);
+        Ok(())
+      }
+      Err(code @ ErrorCode::SessionExpired) | Err(code @ ErrorCode::Banned) => Err(code.into()),
+      _ => Err(Error::UnsupportedResponse),
+    }
+  }
+
+  /// start sending heartbeats and take in everyone else after logging in as `name`, the server
+  /// has to list this device among them
+  fn enter(&self, name: String, users: Vec<UserInfo>) -> Result<(), Error> {
+    let my_addr = self
+      .find_my_addr(&name, &users)
+      .ok_or(Error::UnsupportedResponse)?;
+    let timer = tokio::spawn({
+      let connection = self.connection.clone();
+      let server_addr = self.server_addr;
+      let mut interval = tokio::time::interval(self.heartbeat_interval);
+      async move {
+        loop {
+          interval.tick().await;
+          if let Err(_) = connection
+            .as_inner()
+            .send_to_with_empty_meta(&Command::Heartbeat, server_addr)
+            .await
+          {
+            // TODO: log error
+          }
+        }
+      }
+    });
+    let old_timer = self.heartbeat_timer.lock().replace(timer);
+    if let Some(old_timer) = old_timer {
+      old_timer.abort();
+    }
+
+    self.take_in(&users);
+    *self.state.users.write() = users.into_iter().map(|u| (u.name.clone(), u)).collect();
+
+    *self.state.personal_info.lock() = Some(PersonalInfo {
+      name,
+      ip_address: my_addr,
+    });
+    Ok(())
+  }
 
-    let mut hasher = Sha256::new();
-    hasher.update(new.trim_start());
-    let new = hasher.finalize().into();
+  /// map the address of every online device to its user and learn their keys
+  fn take_in(&self, users: &[UserInfo]) {
+    *self.state.addr2user.write() = users
+      .iter()
+      .flat_map(|u| {
+        u.devices
+          .iter()
+          .map(move |device| (device.ip_address, u.name.clone()))
+      })
+      .collect();
+    self.connection.as_inner().update_pub_keys(
+      users
+        .iter()
+        .flat_map(|u| u.devices.iter())
+        .map(|device| (device.pub_key.clone().into(), device.ip_address)),
+    );
+  }
+
+  /// the address the server sees this device at, told apart from other devices of the same user
+  /// by its public key
+  fn find_my_addr(&self, name: &str, users: &[UserInfo]) -> Option<SocketAddr> {
+    let my_key = self.connection.as_inner().get_public_key();
+    users
+      .iter()
+      .find(|u| u.name == name)?
+      .devices
+      .iter()
+      .find(|device| &device.pub_key == my_key.as_bytes())
+      .map(|device| device.ip_address)
+  }
+
+  pub async fn change_password(&self, old: &str, new: &str) -> Result<(), Error> {
+    let name = match self.state.personal_info.lock().as_ref() {
+      Some(info) => info.name.clone(),
+      None => {
+        let _ = self.app_handle.emit_all("not-login", ());
+        return Err(ErrorCode::LoginRequired.into());
+      }
+    };
+    let session = self.authenticate(&name, old).await?;
+    let Verifier { salt, verifier } = Verifier::new(&name, new);
 
     match self
       .connection
-      .request::<_, Response>(&Command::ChangePassword { old, new }, self.server_addr)
+      .request::<_, Response>(
+        &Command::ChangePassword {
+          proof: session.proof().to_vec(),
+          salt,
+          verifier,
+        },
+        self.server_addr,
+      )
       .await?
     {
       Ok(ResponseData::Success) => Ok(()),
@@ -436,19 +663,20 @@ where
     if let Some(username) = username {
       // personal chat
       let user_info = self.state.users.read().get(&username).cloned();
-      if let Some(UserInfo { name, online_info }) = user_info {
-        if let Some(UserOnlineInfo { ip_address, .. }) = online_info {
+      if let Some(UserInfo { name, devices }) = user_info {
+        if !devices.is_empty() {
+          // every device of the user gets a copy
           let timestamp = OffsetDateTime::now_utc();
           self
             .connection
             .as_inner()
-            .send_to_with_empty_meta(
+            .send_to_multiple_with_empty_meta(
               &Message {
                 to_all: false,
                 timestamp,
                 msg: msg.clone(),
               },
-              ip_address,
+              devices.iter().map(|device| device.ip_address),
             )
             .await?;
           self
@@ -479,17 +707,8 @@ where
 
       let addrs = (self.state.users.read())
         .values()
-        .filter_map(|u| {
-          if let Some(UserOnlineInfo { ip_address, .. }) = u.online_info {
-            if my_addr != ip_address {
-              Some(ip_address)
-            } else {
-              None
-            }
-          } else {
-            None
-          }
-        })
+        .flat_map(|u| u.devices.iter().map(|device| device.ip_address))
+        .filter(|&ip_address| ip_address != my_addr)
         .collect::<Vec<_>>();
       if let Err(_) = self
         .connection
@@ -517,40 +736,20 @@ where
       .await?
     {
       Ok(ResponseData::ChatroomStatus { users }) => {
-        *self.state.addr2user.write() = users
-          .iter()
-          .filter_map(|u| {
-            if let Some(UserOnlineInfo { ip_address, .. }) = u.online_info {
-              Some((ip_address, u.name.clone()))
-            } else {
-              None
-            }
-          })
-          .collect();
-        self
-          .connection
-          .as_inner()
-          .update_pub_keys(users.iter().filter_map(|u| {
-            if let UserInfo {
-              online_info:
-                Some(UserOnlineInfo {
-                  ip_address,
-                  pub_key,
-                  ..
-                }),
-              ..
-            } = u
-            {
-              Some((pub_key.clone().into(), ip_address.clone()))
-            } else {
-              None
-            }
-          }));
-        let my_addr = (self.state.users.read())
-          .get(&self.state.personal_info.lock().as_ref().unwrap().name)
-          .map(|u| u.online_info.as_ref().unwrap().ip_address) // TODO: log error
-          .unwrap(); // TODO: log error
-        self.state.personal_info.lock().as_mut().unwrap().ip_address = my_addr;
+        let my_name = match self.state.personal_info.lock().as_ref() {
+          Some(info) => info.name.clone(),
+          None => {
+            let _ = self.app_handle.emit_all("not-login", ());
+            return Err(ErrorCode::LoginRequired.into());
+          }
+        };
+        let my_addr = self
+          .find_my_addr(&my_name, &users)
+          .ok_or(Error::UnsupportedResponse)?;
+        self.take_in(&users);
+        if let Some(info) = self.state.personal_info.lock().as_mut() {
+          info.ip_address = my_addr;
+        }
         *self.state.users.write() = users.into_iter().map(|u| (u.name.clone(), u)).collect();
         Ok(())
       }
@@ -562,12 +761,99 @@ where
     }
   }
 
+  /// where this user is logged in, this device included
+  pub async fn list_sessions(&self) -> Result<Vec<SessionInfo>, Error> {
+    match self
+      .connection
+      .request::<_, Response>(&Command::ListSessions, self.server_addr)
+      .await?
+    {
+      Ok(ResponseData::Sessions { sessions }) => Ok(sessions),
+      Err(ErrorCode::LoginRequired) => {
+        let _ = self.app_handle.emit_all("not-login", ());
+        Err(ErrorCode::LoginRequired.into())
+      }
+      _ => Err(Error::UnsupportedResponse),
+    }
+  }
+
+  /// sign a session out, the device using it is logged out
+  pub async fn revoke_session(&self, id: [u8; 16]) -> Result<(), Error> {
+    match self
+      .connection
+      .request::<_, Response>(&Command::RevokeSession { id }, self.server_addr)
+      .await?
+    {
+      Ok(ResponseData::Success) => Ok(()),
+      Err(ErrorCode::SessionNotExisted) => Err(ErrorCode::SessionNotExisted.into()),
+      Err(ErrorCode::LoginRequired) => {
+        let _ = self.app_handle.emit_all("not-login", ());
+        Err(ErrorCode::LoginRequired.into())
+      }
+      _ => Err(Error::UnsupportedResponse),
+    }
+  }
+
+  /// start enrolling in two-factor authentication, returns the secret in base32 along with the
+  /// `otpauth://` uri of it
+  pub async fn enroll_totp(&self) -> Result<(String, String), Error> {
+    match self
+      .connection
+      .request::<_, Response>(&Command::EnrollTotp, self.server_addr)
+      .await?
+    {
+      Ok(ResponseData::TotpProvisioning { secret, uri }) => Ok((secret, uri)),
+      Err(ErrorCode::TotpEnrolled) => Err(ErrorCode::TotpEnrolled.into()),
+      Err(ErrorCode::LoginRequired) => {
+        let _ = self.app_handle.emit_all("not-login", ());
+        Err(ErrorCode::LoginRequired.into())
+      }
+      _ => Err(Error::UnsupportedResponse),
+    }
+  }
+
+  /// finish enrolling with a code from the authenticator, returns the recovery codes
+  pub async fn confirm_totp(&self, code: String) -> Result<Vec<String>, Error> {
+    match self
+      .connection
+      .request::<_, Response>(&Command::ConfirmTotp { code }, self.server_addr)
+      .await?
+    {
+      Ok(ResponseData::RecoveryCodes { codes }) => Ok(codes),
+      Err(ErrorCode::InvalidOneTimeCode) => Err(ErrorCode::InvalidOneTimeCode.into()),
+      Err(ErrorCode::LoginRequired) => {
+        let _ = self.app_handle.emit_all("not-login", ());
+        Err(ErrorCode::LoginRequired.into())
+      }
+      _ => Err(Error::UnsupportedResponse),
+    }
+  }
+
+  pub async fn disable_totp(&self, code: String) -> Result<(), Error> {
+    match self
+      .connection
+      .request::<_, Response>(&Command::DisableTotp { code }, self.server_addr)
+      .await?
+    {
+      Ok(ResponseData::Success) => Ok(()),
+      Err(code @ ErrorCode::InvalidOneTimeCode)
+      | Err(code @ ErrorCode::TotpNotEnrolled)
+      | Err(code @ ErrorCode::Throttled { .. }) => Err(code.into()),
+      Err(ErrorCode::LoginRequired) => {
+        let _ = self.app_handle.emit_all("not-login", ());
+        Err(ErrorCode::LoginRequired.into())
+      }
+      _ => Err(Error::UnsupportedResponse),
+    }
+  }
+
   pub async fn logout(self) -> Result<Option<Self>, Error> {
     let _ = self
       .connection
       .request::<_, Response>(&Command::Logout, self.server_addr)
       .await;
     *self.state.personal_info.lock() = None;
+    *self.state.session_token.lock() = None;
     if let Some(timer) = { self.heartbeat_timer.lock().take() } {
       timer.abort();
     };
@@ -577,7 +863,7 @@ where
 
 impl<Coder> Drop for Client<Coder>
 where
-  Coder: 'static + Options + Copy + Sync + Send,
+  Coder: Codec,
 {
   fn drop(&mut self) {
     self.net_receiver.abort();
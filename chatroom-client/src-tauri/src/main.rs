@@ -5,15 +5,18 @@
 
 mod client;
 
-use std::{net::SocketAddr, sync::Arc};
+use std::{collections::BTreeSet, net::SocketAddr, path::PathBuf, sync::Arc};
 
 use client::{ChatEntry, Client, OwnedChatEntry, PersonalInfo};
 
 use chatroom_core::{
-  data::{default_coder, DefaultCoder, ErrorCode, UserInfo},
+  data::{default_coder, DefaultCoder, ErrorCode, SessionInfo, UserInfo},
+  settings,
   utils::ErrorMsg,
 };
 
+use tauri::{api::path::app_dir, AppHandle, Manager};
+
 use parking_lot::RwLock;
 
 use time::{OffsetDateTime, UtcOffset};
@@ -23,13 +26,20 @@ use std::time::Duration as StdDuration;
 
 use serde::{Deserialize, Serialize};
 
+use tracing::warn;
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
 struct Settings {
   heartbeat_interval: StdDuration,
   server_addr: String,
   client_addr: String,
   request_timeout: StdDuration,
   retry_limits: u32,
+  /// shown to the user in the list of their sessions
+  device_name: String,
+  /// servers without srp the user agreed to send the password itself to
+  plaintext_servers: BTreeSet<SocketAddr>,
 }
 
 impl Default for Settings {
@@ -40,7 +50,34 @@ impl Default for Settings {
       client_addr: "0.0.0.0:0".into(),
       request_timeout: StdDuration::from_secs(5),
       retry_limits: 5,
+      device_name: std::env::var("COMPUTERNAME")
+        .or_else(|_| std::env::var("HOSTNAME"))
+        .unwrap_or_else(|_| "chatroom-client".into()),
+      plaintext_servers: BTreeSet::new(),
+    }
+  }
+}
+
+impl settings::Settings for Settings {
+  const VERSION: u32 = 1;
+
+  fn validate(&self) -> Result<(), String> {
+    if self.heartbeat_interval.is_zero() {
+      return Err("heartbeat interval must not be zero".into());
+    }
+    if self.request_timeout.is_zero() {
+      return Err("request timeout must not be zero".into());
     }
+    if let Err(err) = self.server_addr.parse::<SocketAddr>() {
+      return Err(format!("server address \"{}\": {}", self.server_addr, err));
+    }
+    if let Err(err) = self.client_addr.parse::<SocketAddr>() {
+      return Err(format!("client address \"{}\": {}", self.client_addr, err));
+    }
+    if self.device_name.trim().is_empty() {
+      return Err("device name must not be empty".into());
+    }
+    Ok(())
   }
 }
 
@@ -52,9 +89,46 @@ struct State {
 
 type MyState = Arc<State>;
 
+fn settings_path(app: &AppHandle) -> Result<PathBuf, &'static str> {
+  let config_dir = app_dir(&app.config()).ok_or("failed to locate app data directory")?;
+  Ok(config_dir.join("settings.json"))
+}
+
+#[tauri::command]
+async fn get_settings(state: tauri::State<'_, MyState>) -> Result<Settings, ErrorMsg> {
+  Ok(state.settings.read().clone())
+}
+
+#[tauri::command]
+async fn set_settings(
+  app: AppHandle,
+  state: tauri::State<'_, MyState>,
+  heartbeat_interval: Option<u64>,
+  client_addr: Option<String>,
+  request_timeout: Option<u64>,
+  retry_limits: Option<u32>,
+) -> Result<(), ErrorMsg> {
+  let mut settings = state.settings.read().clone();
+  if let Some(heartbeat_interval) = heartbeat_interval {
+    settings.heartbeat_interval = StdDuration::from_millis(heartbeat_interval);
+  };
+  if let Some(client_addr) = client_addr {
+    settings.client_addr = client_addr;
+  };
+  if let Some(request_timeout) = request_timeout {
+    settings.request_timeout = StdDuration::from_millis(request_timeout);
+  };
+  if let Some(retry_limits) = retry_limits {
+    settings.retry_limits = retry_limits;
+  };
+  settings::save(&settings_path(&app)?, &settings)?;
+  *state.settings.write() = settings;
+  Ok(())
+}
+
 #[tauri::command]
 async fn connect_server(
-  app: tauri::AppHandle,
+  app: AppHandle,
   state: tauri::State<'_, MyState>,
   server_addr: String,
 ) -> Result<(), ErrorMsg> {
@@ -73,6 +147,12 @@ async fn connect_server(
     settings.server_addr = server_addr_str;
     settings.clone()
   };
+  if let Ok(path) = settings_path(&app) {
+    // remember the address for the next start
+    if let Err(err) = settings::save(&path, &*state.settings.read()) {
+      warn!(source = "internal", "failed to save settings: {}", err);
+    }
+  }
 
   let client_addr = client_addr.parse::<SocketAddr>()?;
   let client = Client::new(
@@ -89,6 +169,50 @@ async fn connect_server(
   Ok(())
 }
 
+/// swap the connection for a fresh one and resume the session on it, without the password
+#[tauri::command]
+async fn reconnect_server(
+  app: AppHandle,
+  state: tauri::State<'_, MyState>,
+) -> Result<(), ErrorMsg> {
+  let mut client = state.client.write().await;
+  let server_addr = match client.as_ref() {
+    Some(old) => old.server_addr,
+    None => return Err("server not connected".into()),
+  };
+  let Settings {
+    heartbeat_interval,
+    client_addr,
+    request_timeout,
+    retry_limits,
+    device_name,
+    ..
+  } = state.settings.read().clone();
+
+  let new = Client::new(
+    client_addr.parse::<SocketAddr>()?,
+    server_addr,
+    app,
+    default_coder(),
+    heartbeat_interval,
+    request_timeout,
+    retry_limits,
+  )
+  .await?;
+  // the old client is only given up once the new one is there, its session is not logged out but
+  // picked up again below
+  let token = client
+    .take()
+    .and_then(|old| old.get_state().session_token.lock().clone());
+  let resumed = match token {
+    Some(token) => new.resume(token, device_name).await,
+    None => Err(ErrorCode::LoginRequired.into()),
+  };
+  // stay connected either way, logging in with the password is still possible
+  *client = Some(new);
+  Ok(resumed?)
+}
+
 #[tauri::command]
 async fn disconnect_server(state: tauri::State<'_, MyState>) -> Result<(), ErrorMsg> {
   let mut client = state.client.write().await;
@@ -130,10 +254,77 @@ async fn login(
   state: tauri::State<'_, MyState>,
   username: String,
   password: String,
+  code: Option<String>,
 ) -> Result<(), ErrorMsg> {
+  let (device_name, plaintext_servers) = {
+    let settings = state.settings.read();
+    (
+      settings.device_name.clone(),
+      settings.plaintext_servers.clone(),
+    )
+  };
   let client = state.client.read().await;
   if let Some(client) = client.as_ref() {
-    Ok(client.login(username, password.as_str()).await?)
+    let allow_plaintext = plaintext_servers.contains(&client.server_addr);
+    Ok(
+      client
+        .login(
+          username,
+          password.as_str(),
+          device_name,
+          code,
+          allow_plaintext,
+        )
+        .await?,
+    )
+  } else {
+    Err("server not connected".into())
+  }
+}
+
+/// let the password itself be sent to the connected server from now on, once the user agreed to
+#[tauri::command]
+async fn allow_plaintext_login(
+  app: AppHandle,
+  state: tauri::State<'_, MyState>,
+) -> Result<(), ErrorMsg> {
+  let server_addr = match state.client.read().await.as_ref() {
+    Some(client) => client.server_addr,
+    None => return Err("server not connected".into()),
+  };
+  let mut settings = state.settings.read().clone();
+  settings.plaintext_servers.insert(server_addr);
+  settings::save(&settings_path(&app)?, &settings)?;
+  *state.settings.write() = settings;
+  Ok(())
+}
+
+/// replace a password hash from before srp with a verifier, after the user agreed to
+#[tauri::command]
+async fn migrate_password(
+  state: tauri::State<'_, MyState>,
+  username: String,
+  password: String,
+) -> Result<(), ErrorMsg> {
+  let client = state.client.read().await;
+  if let Some(client) = client.as_ref() {
+    Ok(client.migrate_password(username, password.as_str()).await?)
+  } else {
+    Err("server not connected".into())
+  }
+}
+
+/// log in again on the same connection, e.g. after the heartbeat was lost
+#[tauri::command]
+async fn resume_session(state: tauri::State<'_, MyState>) -> Result<(), ErrorMsg> {
+  let device_name = state.settings.read().device_name.clone();
+  let client = state.client.read().await;
+  if let Some(client) = client.as_ref() {
+    let token = client.get_state().session_token.lock().clone();
+    match token {
+      Some(token) => Ok(client.resume(token, device_name).await?),
+      None => Err(ErrorCode::LoginRequired.into()),
+    }
   } else {
     Err("server not connected".into())
   }
@@ -153,6 +344,60 @@ async fn change_password(
   }
 }
 
+#[tauri::command]
+async fn list_sessions(state: tauri::State<'_, MyState>) -> Result<Vec<SessionInfo>, ErrorMsg> {
+  let client = state.client.read().await;
+  if let Some(client) = client.as_ref() {
+    Ok(client.list_sessions().await?)
+  } else {
+    Err("server not connected".into())
+  }
+}
+
+#[tauri::command]
+async fn revoke_session(state: tauri::State<'_, MyState>, id: [u8; 16]) -> Result<(), ErrorMsg> {
+  let client = state.client.read().await;
+  if let Some(client) = client.as_ref() {
+    Ok(client.revoke_session(id).await?)
+  } else {
+    Err("server not connected".into())
+  }
+}
+
+/// returns the secret in base32 and the `otpauth://` uri of it
+#[tauri::command]
+async fn enroll_totp(state: tauri::State<'_, MyState>) -> Result<(String, String), ErrorMsg> {
+  let client = state.client.read().await;
+  if let Some(client) = client.as_ref() {
+    Ok(client.enroll_totp().await?)
+  } else {
+    Err("server not connected".into())
+  }
+}
+
+#[tauri::command]
+async fn confirm_totp(
+  state: tauri::State<'_, MyState>,
+  code: String,
+) -> Result<Vec<String>, ErrorMsg> {
+  let client = state.client.read().await;
+  if let Some(client) = client.as_ref() {
+    Ok(client.confirm_totp(code).await?)
+  } else {
+    Err("server not connected".into())
+  }
+}
+
+#[tauri::command]
+async fn disable_totp(state: tauri::State<'_, MyState>, code: String) -> Result<(), ErrorMsg> {
+  let client = state.client.read().await;
+  if let Some(client) = client.as_ref() {
+    Ok(client.disable_totp(code).await?)
+  } else {
+    Err("server not connected".into())
+  }
+}
+
 #[tauri::command]
 async fn say(
   state: tauri::State<'_, MyState>,
@@ -258,15 +503,30 @@ async fn get_chats(
 }
 
 fn main() {
+  tracing_subscriber::fmt()
+    .with_writer(std::io::stderr)
+    .with_target(false)
+    .init();
   tauri::Builder::default()
     .manage(MyState::default())
     .invoke_handler(tauri::generate_handler![
+      get_settings,
+      set_settings,
       get_server_info,
       connect_server,
+      reconnect_server,
       disconnect_server,
       register,
       login,
+      allow_plaintext_login,
+      migrate_password,
+      resume_session,
       change_password,
+      list_sessions,
+      revoke_session,
+      enroll_totp,
+      confirm_totp,
+      disable_totp,
       say,
       fetch_chatroom_status,
       logout,
@@ -274,6 +534,16 @@ fn main() {
       get_user_info,
       get_chats,
     ])
+    .setup(|app| {
+      let app = app.handle();
+      if let Ok(path) = settings_path(&app) {
+        match settings::load::<Settings>(&path) {
+          Ok(settings) => *app.state::<MyState>().settings.write() = settings,
+          Err(err) => warn!(source = "internal", "failed to load settings: {}", err),
+        }
+      }
+      Ok(())
+    })
     .run(tauri::generate_context!())
     .expect("error while running tauri application");
 }
@@ -15,6 +15,7 @@ import { useContainer } from "unstated-next";
 import PathState from "states/PathState";
 
 import { listen } from "@tauri-apps/api/event";
+import { invoke } from "@tauri-apps/api/tauri";
 
 const theme = createTheme();
 
@@ -49,8 +50,14 @@ const AppDisplay: FC = () => {
   useEffect(() => {
     const unsubscribe = listen("connection-lost", () => {
       (async () => {
-        enqueueSnackbar("服务器链接已断开", { variant: "error" });
-        set_path(["connection"]);
+        try {
+          // the session is picked up on a new connection without the password
+          await invoke("reconnect_server");
+          enqueueSnackbar("已重新连接服务器", { variant: "info" });
+        } catch (e) {
+          enqueueSnackbar("服务器链接已断开", { variant: "error" });
+          set_path(["connection"]);
+        }
       })();
     });
     return () => {
@@ -61,8 +68,13 @@ const AppDisplay: FC = () => {
   useEffect(() => {
     const unsubscribe = listen("not-login", () => {
       (async () => {
-        enqueueSnackbar("客户端未登录，请重新登录", { variant: "error" });
-        set_path((path) => (path[0] !== "connection" ? ["login"] : path));
+        try {
+          await invoke("resume_session");
+          enqueueSnackbar("已恢复登录", { variant: "info" });
+        } catch (e) {
+          enqueueSnackbar("客户端未登录，请重新登录", { variant: "error" });
+          set_path((path) => (path[0] !== "connection" ? ["login"] : path));
+        }
       })();
     });
     return () => {
@@ -70,6 +82,30 @@ const AppDisplay: FC = () => {
     };
   });
 
+  useEffect(() => {
+    const unsubscribe = listen("session-revoked", () => {
+      enqueueSnackbar("登录已在其他设备上被注销，请重新登录", {
+        variant: "warning",
+      });
+      set_path((path) => (path[0] !== "connection" ? ["login"] : path));
+    });
+    return () => {
+      unsubscribe.then((f) => f());
+    };
+  });
+
+  useEffect(() => {
+    const unsubscribe = listen("kicked", () => {
+      enqueueSnackbar("你已被管理员强制下线，请重新登录", {
+        variant: "warning",
+      });
+      set_path((path) => (path[0] !== "connection" ? ["login"] : path));
+    });
+    return () => {
+      unsubscribe.then((f) => f());
+    };
+  });
+
   if (path[0] === "connection") {
     return <ConnectionPage />;
   } else if (path[0] === "login") {
@@ -0,0 +1,131 @@
+import React, { FC, useCallback, useEffect, useState } from "react";
+
+import {
+  Box,
+  Button,
+  List,
+  ListItem,
+  ListItemText,
+  Typography,
+} from "@mui/material";
+import { invoke } from "@tauri-apps/api/tauri";
+
+import { useSnackbar } from "notistack";
+
+interface Session {
+  id: number[];
+  device: string;
+  ip_address: string;
+  logged_in: string;
+  last_heartbeat: string;
+  expires: string;
+  online: boolean;
+  current: boolean;
+}
+
+interface SessionsModalProps {
+  onClose: () => void;
+}
+
+const SessionsModal: FC<SessionsModalProps> = ({ onClose }) => {
+  const { enqueueSnackbar } = useSnackbar();
+  const [sessions, set_sessions] = useState<Session[]>([]);
+  const [busy, set_busy] = useState<boolean>(false);
+
+  const refresh = useCallback(async () => {
+    try {
+      set_sessions(await invoke("list_sessions"));
+    } catch (err) {
+      console.error(err);
+    }
+  }, []);
+
+  useEffect(() => {
+    refresh();
+  }, [refresh]);
+
+  const revoke = useCallback(
+    async (session: Session) => {
+      set_busy(true);
+      try {
+        await invoke("revoke_session", { id: session.id });
+        enqueueSnackbar(`已注销 ${session.device} 上的登录`, {
+          variant: "success",
+        });
+      } catch (err) {
+        const msg = (err as any).msg;
+        if (msg === "session is not existed") {
+          enqueueSnackbar("该登录已失效", { variant: "warning" });
+        } else {
+          console.error(err);
+        }
+      }
+      set_busy(false);
+      await refresh();
+    },
+    [refresh, enqueueSnackbar]
+  );
+
+  return (
+    <Box
+      sx={{
+        position: "absolute" as "absolute",
+        top: "50%",
+        left: "50%",
+        transform: "translate(-50%, -50%)",
+        width: 480,
+        bgcolor: "background.paper",
+        border: "1px solid #ccc",
+        boxShadow: 24,
+        borderRadius: 1,
+        p: 4,
+
+        display: "flex",
+        flexDirection: "column",
+      }}
+    >
+      <Typography component="h1" variant="h6" sx={{ ml: 1 }}>
+        登录设备
+      </Typography>
+      <List sx={{ maxHeight: 360, overflow: "auto" }}>
+        {sessions.map((session) => (
+          <ListItem
+            key={session.id.join(",")}
+            secondaryAction={
+              session.current ? (
+                <Typography variant="body2" sx={{ color: "text.secondary" }}>
+                  本设备
+                </Typography>
+              ) : (
+                <Button
+                  disabled={busy}
+                  onClick={() => revoke(session)}
+                  sx={{ color: "error.main" }}
+                >
+                  注销
+                </Button>
+              )
+            }
+          >
+            <ListItemText
+              primary={`${session.device} (${session.ip_address})${
+                session.online ? "" : " - 离线"
+              }`}
+              secondary={`登录于 ${session.logged_in}，最后活动于 ${session.last_heartbeat}`}
+            />
+          </ListItem>
+        ))}
+      </List>
+      <Box sx={{ display: "flex", flexDirection: "row", mt: 2 }}>
+        <Button disabled={busy} onClick={refresh} sx={{ ml: 2 }}>
+          刷新
+        </Button>
+        <Button onClick={onClose} sx={{ mr: 2, ml: "auto" }}>
+          关闭
+        </Button>
+      </Box>
+    </Box>
+  );
+};
+
+export default SessionsModal;
@@ -0,0 +1,202 @@
+import React, { FC, useState } from "react";
+
+import { Box, Button, TextField, Typography } from "@mui/material";
+import { invoke } from "@tauri-apps/api/tauri";
+
+import { useSnackbar } from "notistack";
+
+interface TwoFactorModalProps {
+  onClose: () => void;
+}
+
+type Stage =
+  | { kind: "idle" }
+  | { kind: "enrolling"; secret: string; uri: string }
+  | { kind: "enrolled"; codes: string[] }
+  | { kind: "disabling" };
+
+const TwoFactorModal: FC<TwoFactorModalProps> = ({ onClose }) => {
+  const { enqueueSnackbar } = useSnackbar();
+  const [stage, set_stage] = useState<Stage>({ kind: "idle" });
+  const [code, set_code] = useState<string>("");
+  const [error, set_error] = useState<string | null>(null);
+  const [busy, set_busy] = useState<boolean>(false);
+
+  const handle_error = (err: unknown) => {
+    const msg = (err as any).msg;
+    if (msg === "one-time code is invalid") {
+      set_error("验证码不正确");
+    } else if (msg === "two-factor authentication is already enabled") {
+      set_error("两步验证已开启，请先关闭");
+    } else if (msg === "two-factor authentication is not enabled") {
+      set_error("两步验证未开启");
+    } else if (typeof msg === "string" && msg.startsWith("too many failed")) {
+      set_error("尝试次数过多，请稍后再试");
+    } else {
+      console.error(err);
+    }
+  };
+
+  const enroll = async () => {
+    set_busy(true);
+    set_error(null);
+    try {
+      const [secret, uri] = (await invoke("enroll_totp")) as [string, string];
+      set_code("");
+      set_stage({ kind: "enrolling", secret, uri });
+    } catch (err) {
+      handle_error(err);
+    }
+    set_busy(false);
+  };
+
+  const confirm = async () => {
+    set_busy(true);
+    set_error(null);
+    try {
+      const codes = (await invoke("confirm_totp", { code })) as string[];
+      set_stage({ kind: "enrolled", codes });
+      enqueueSnackbar("两步验证已开启", { variant: "success" });
+    } catch (err) {
+      handle_error(err);
+    }
+    set_busy(false);
+  };
+
+  const disable = async () => {
+    set_busy(true);
+    set_error(null);
+    try {
+      await invoke("disable_totp", { code });
+      enqueueSnackbar("两步验证已关闭", { variant: "success" });
+      onClose();
+    } catch (err) {
+      handle_error(err);
+    }
+    set_busy(false);
+  };
+
+  const code_field = (
+    <TextField
+      margin="normal"
+      fullWidth
+      label={stage.kind === "disabling" ? "动态验证码或恢复码" : "动态验证码"}
+      autoComplete="one-time-code"
+      error={!!error}
+      helperText={error}
+      disabled={busy}
+      value={code}
+      onChange={(event) => set_code(event.target.value)}
+    />
+  );
+
+  return (
+    <Box
+      sx={{
+        position: "absolute" as "absolute",
+        top: "50%",
+        left: "50%",
+        transform: "translate(-50%, -50%)",
+        width: 480,
+        bgcolor: "background.paper",
+        border: "1px solid #ccc",
+        boxShadow: 24,
+        borderRadius: 1,
+        p: 4,
+
+        display: "flex",
+        flexDirection: "column",
+      }}
+    >
+      <Typography component="h1" variant="h6" sx={{ ml: 1 }}>
+        两步验证
+      </Typography>
+      {stage.kind === "idle" && (
+        <>
+          <Typography variant="body2" sx={{ m: 1, color: "text.secondary" }}>
+            开启后，登录时除密码外还需输入验证器应用生成的动态验证码
+          </Typography>
+          {error && (
+            <Typography variant="body2" sx={{ m: 1, color: "error.main" }}>
+              {error}
+            </Typography>
+          )}
+        </>
+      )}
+      {stage.kind === "enrolling" && (
+        <>
+          <Typography variant="body2" sx={{ m: 1 }}>
+            请在验证器应用中添加以下密钥，然后输入其生成的验证码
+          </Typography>
+          <Typography
+            variant="body1"
+            sx={{ m: 1, fontFamily: "monospace", wordBreak: "break-all" }}
+          >
+            {stage.secret}
+          </Typography>
+          <Typography
+            variant="caption"
+            sx={{ m: 1, color: "text.secondary", wordBreak: "break-all" }}
+          >
+            {stage.uri}
+          </Typography>
+          {code_field}
+        </>
+      )}
+      {stage.kind === "enrolled" && (
+        <>
+          <Typography variant="body2" sx={{ m: 1 }}>
+            请妥善保存以下恢复码，每个只能代替验证码使用一次，关闭后将不再显示
+          </Typography>
+          <Box sx={{ m: 1, fontFamily: "monospace" }}>
+            {stage.codes.map((code) => (
+              <Typography key={code} variant="body1" sx={{ fontFamily: "monospace" }}>
+                {code}
+              </Typography>
+            ))}
+          </Box>
+        </>
+      )}
+      {stage.kind === "disabling" && code_field}
+      <Box sx={{ display: "flex", flexDirection: "row", mt: 2 }}>
+        {stage.kind === "idle" && (
+          <>
+            <Button disabled={busy} onClick={enroll} sx={{ ml: 2 }}>
+              开启
+            </Button>
+            <Button
+              disabled={busy}
+              onClick={() => {
+                set_code("");
+                set_error(null);
+                set_stage({ kind: "disabling" });
+              }}
+              sx={{ color: "error.main" }}
+            >
+              关闭两步验证
+            </Button>
+          </>
+        )}
+        {stage.kind === "enrolling" && (
+          <Button disabled={busy || code === ""} onClick={confirm} sx={{ ml: 2 }}>
+            确认
+          </Button>
+        )}
+        {stage.kind === "disabling" && (
+          <Button
+            disabled={busy || code === ""}
+            onClick={disable}
+            sx={{ ml: 2, color: "error.main" }}
+          >
+            确认关闭
+          </Button>
+        )}
+        <Button onClick={onClose} sx={{ mr: 2, ml: "auto" }}>
+          {stage.kind === "enrolled" ? "完成" : "取消"}
+        </Button>
+      </Box>
+    </Box>
+  );
+};
+
+export default TwoFactorModal;
@@ -24,9 +24,13 @@ import {
   Logout,
   Edit,
   Refresh,
+  Devices,
+  Security,
 } from "@mui/icons-material";
 
 import ChangePassModal from "components/ChangePassModal";
+import SessionsModal from "components/SessionsModal";
+import TwoFactorModal from "components/TwoFactorModal";
 
 import { invoke } from "@tauri-apps/api/tauri";
 import { listen, Event as TauriEvent } from "@tauri-apps/api/event";
@@ -38,10 +42,10 @@ import PathState from "states/PathState";
 
 interface BackendUser {
   name: string;
-  online_info: null | {
+  devices: {
     ip_address: string;
     pub_key: number[];
-  };
+  }[];
 }
 
 interface User {
@@ -52,7 +56,14 @@ interface User {
 
 type ChatData = [
   string,
-  { user: string; entry: "Online" | "Offline" | { Message: string } }
+  {
+    user: string;
+    entry:
+      | "Online"
+      | "Offline"
+      | { Message: string; Announcement?: undefined }
+      | { Announcement: string; Message?: undefined };
+  }
 ];
 
 const compare_users = (a: User, b: User) => {
@@ -92,9 +103,9 @@ const get_user_info = async (name: string | null): Promise<User[]> => {
   const raw_users = (await invoke("get_user_info")) as BackendUser[];
   const raw_users_without_me = raw_users.filter((u) => u.name !== name);
   const users_without_me = raw_users_without_me.map(
-    ({ name, online_info }) => ({
+    ({ name, devices }) => ({
       name,
-      is_online: online_info != null,
+      is_online: devices.length > 0,
       new_msg_count: 0,
     })
   );
@@ -118,6 +129,8 @@ const ChatPage: FC = () => {
   const input_textarea = useRef<HTMLTextAreaElement>(null);
   const [expand, set_expand] = useState<boolean>(false);
   const [modal_open, set_modal_open] = useState<boolean>(false);
+  const [sessions_open, set_sessions_open] = useState<boolean>(false);
+  const [two_factor_open, set_two_factor_open] = useState<boolean>(false);
   const [current_chat, set_current_chat] = useState<string | null>(null);
   const [message, set_message] = useState("");
 
@@ -414,6 +427,18 @@ const ChatPage: FC = () => {
                 </ListItemIcon>
                 <ListItemText primary="修改密码" />
               </ListItemButton>
+              <ListItemButton onClick={() => set_sessions_open(true)}>
+                <ListItemIcon>
+                  <Devices />
+                </ListItemIcon>
+                <ListItemText primary="登录设备" />
+              </ListItemButton>
+              <ListItemButton onClick={() => set_two_factor_open(true)}>
+                <ListItemIcon>
+                  <Security />
+                </ListItemIcon>
+                <ListItemText primary="两步验证" />
+              </ListItemButton>
               <ListItemButton
                 onClick={async () => {
                   try {
@@ -538,6 +563,25 @@ const ChatPage: FC = () => {
                   了聊天室
                 </Box>
               );
+            } else if (chat.entry.Announcement !== undefined) {
+              return (
+                <Box
+                  sx={{
+                    whiteSpace: "pre-wrap",
+                    wordBreak: "break-all",
+                    maxWidth: "calc(100vw - 400px)",
+                    marginTop: "10px",
+                    alignSelf: "center",
+                    fontSize: "0.9em",
+                    backgroundColor: "warning.light",
+                    color: "#fff",
+                    px: 1,
+                    borderRadius: 3,
+                  }}
+                >
+                  [{time}] 服务器公告：{chat.entry.Announcement}
+                </Box>
+              );
             } else if (chat.user === username) {
               return (
                 <Box
@@ -638,6 +682,12 @@ const ChatPage: FC = () => {
       <Modal open={modal_open} onClose={() => set_modal_open(false)}>
         <ChangePassModal onClose={() => set_modal_open(false)} />
       </Modal>
+      <Modal open={sessions_open} onClose={() => set_sessions_open(false)}>
+        <SessionsModal onClose={() => set_sessions_open(false)} />
+      </Modal>
+      <Modal open={two_factor_open} onClose={() => set_two_factor_open(false)}>
+        <TwoFactorModal onClose={() => set_two_factor_open(false)} />
+      </Modal>
     </Box>
   );
 };
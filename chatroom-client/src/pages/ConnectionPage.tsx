@@ -1,4 +1,4 @@
-import React, { FC } from "react";
+import React, { FC, useEffect } from "react";
 
 import { AUTHOR } from "about";
 
@@ -33,10 +33,20 @@ const ConnectionPage: FC = () => {
   const {
     handleSubmit,
     setError,
+    setValue,
     control,
     formState: { isSubmitting },
   } = useForm<LoginData>();
 
+  useEffect(() => {
+    (async () => {
+      const settings: { server_addr: string } = await invoke("get_settings");
+      if (settings.server_addr !== "0.0.0.0:0") {
+        setValue("addr", settings.server_addr);
+      }
+    })();
+  }, [setValue]);
+
   return (
     <Container
       component="main"
@@ -1,4 +1,4 @@
-import React, { FC } from "react";
+import React, { FC, useState } from "react";
 
 import { AUTHOR } from "about";
 
@@ -25,10 +25,13 @@ type LoginData = {
   addr: string;
   username: string;
   password: string;
+  code: string;
 };
 
 const LoginPage: FC = () => {
   let { set_path } = useContainer(PathState);
+  const [need_code, set_need_code] = useState<boolean>(false);
+  const [offer_migration, set_offer_migration] = useState<boolean>(false);
 
   const {
     handleSubmit,
@@ -37,6 +40,72 @@ const LoginPage: FC = () => {
     formState: { isSubmitting },
   } = useForm<LoginData>();
 
+  const submit = async (data: LoginData): Promise<void> => {
+    try {
+      await invoke("login", {
+        username: data.username,
+        password: data.password,
+        code: need_code ? data.code : null,
+      });
+      set_path(["chatroom"]);
+    } catch (err) {
+      const msg = (err as any).msg;
+      if (typeof msg === "string") {
+        const throttled = msg.match(
+          /^too many failed login attempts, please retry after (\d+) seconds$/
+        );
+        if (msg === "username or password are invalid") {
+          setError("username", { message: "用户名或密码不正确" });
+          setError("password", { message: "用户名或密码不正确" });
+          // the server does not tell which accounts predate secure login
+          set_offer_migration(true);
+        } else if (msg === "one-time code is required") {
+          set_need_code(true);
+          setError("code", { message: "请输入动态验证码或恢复码" });
+        } else if (msg === "one-time code is invalid") {
+          setError("code", { message: "验证码不正确" });
+        } else if (msg === "server needs the password itself to check it") {
+          if (
+            window.confirm(
+              "该服务器不支持安全登录，需要将密码本身通过加密连接发送给服务器，仅在信任该服务器时继续。是否继续？（此选择将被记住）"
+            )
+          ) {
+            try {
+              await invoke("allow_plaintext_login");
+            } catch (err) {
+              console.error(err);
+              return;
+            }
+            return submit(data);
+          }
+          setError("password", { message: "该服务器需要发送密码才能登录" });
+        } else if (throttled != null) {
+          setError("password", {
+            message: `尝试次数过多，请 ${throttled[1]} 秒后再试`,
+          });
+        } else {
+          console.error(err);
+        }
+      } else {
+        console.error(err);
+      }
+    }
+  };
+
+  const migrate = async (data: LoginData): Promise<void> => {
+    try {
+      await invoke("migrate_password", {
+        username: data.username,
+        password: data.password,
+      });
+    } catch (err) {
+      setError("password", { message: "用户名或密码不正确" });
+      return;
+    }
+    set_offer_migration(false);
+    return submit(data);
+  };
+
   return (
     <Container
       component="main"
@@ -65,27 +134,7 @@ const LoginPage: FC = () => {
         </Typography>
         <Box
           component="form"
-          onSubmit={handleSubmit(async (data) => {
-            try {
-              await invoke("login", {
-                username: data.username,
-                password: data.password,
-              });
-              set_path(["chatroom"]);
-            } catch (err) {
-              const msg = (err as any).msg;
-              if (typeof msg === "string") {
-                if (msg === "username or password are invalid") {
-                  setError("username", { message: "用户名或密码不正确" });
-                  setError("password", { message: "用户名或密码不正确" });
-                } else {
-                  console.error(err);
-                }
-              } else {
-                console.error(err);
-              }
-            }
-          })}
+          onSubmit={handleSubmit(submit)}
           noValidate
           sx={{ mt: 1 }}
         >
@@ -146,6 +195,36 @@ const LoginPage: FC = () => {
             )}
           />
 
+          {need_code && (
+            <Controller
+              name="code"
+              control={control}
+              defaultValue=""
+              rules={{ required: "请输入动态验证码或恢复码" }}
+              render={({
+                field: { onChange, onBlur, value, ref },
+                fieldState: { error },
+                formState: { isSubmitting },
+              }) => (
+                <TextField
+                  id="code"
+                  margin="normal"
+                  required
+                  fullWidth
+                  label="动态验证码"
+                  autoComplete="one-time-code"
+                  error={!!error}
+                  helperText={error && error.message}
+                  disabled={isSubmitting}
+                  value={value}
+                  onChange={onChange}
+                  onBlur={onBlur}
+                  inputRef={ref}
+                />
+              )}
+            />
+          )}
+
           <Box
             sx={{
               display: "flex",
@@ -167,6 +246,22 @@ const LoginPage: FC = () => {
             >
               还没有账号？点击注册
             </Link>
+            {offer_migration && (
+              <Link
+                href="#"
+                variant="body2"
+                onClick={(event) => {
+                  event.preventDefault();
+                  handleSubmit(migrate)();
+                }}
+                sx={{
+                  m: 1,
+                  textDecoration: "none",
+                }}
+              >
+                旧版本的账号？点击升级密码
+              </Link>
+            )}
             <Link
               href="#"
               variant="body2"
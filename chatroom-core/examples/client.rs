@@ -8,25 +8,23 @@ use std::{
   time::Duration as StdDuration,
 };
 
-use bincode::Options;
 use parking_lot::{Mutex, RwLock};
 use tokio::{net::UdpSocket, task::JoinHandle};
 
 use clap::Parser;
 
 use chatroom_core::{
+  codec::{Codec, Format},
   connection::Connection,
   data::{
-    default_coder, Command, ErrorCode, Message, Notification, Response, ResponseData, UserInfo,
-    UserOnlineInfo,
+    Command, ErrorCode, Message, Notification, Response, ResponseData, SessionToken, UserInfo,
   },
+  srp::{self, ClientHandshake, Verifier},
   utils::Error,
 };
 
 use time::OffsetDateTime;
 
-use sha2::{Digest, Sha256};
-
 use crypto_box::PublicKey;
 /// Chatroom client
 #[derive(Parser, Debug)]
@@ -35,6 +33,12 @@ struct Args {
   /// specify socket address
   #[clap(short, long, default_value = "0.0.0.0:0")]
   addr: String,
+  /// specify wire format, one of "bincode", "msgpack" and "json"
+  #[clap(short, long, default_value = "bincode")]
+  codec: Format,
+  /// name of this device in the session list
+  #[clap(short, long, default_value = "example-client")]
+  device: String,
 }
 
 type RwHashMap<K, V> = RwLock<HashMap<K, V>>;
@@ -73,12 +77,15 @@ struct State {
   group_history: RwBTreeMap<OffsetDateTime, OwnedChatEntry>,
   ono2one_history: RwHashMap<String, BTreeMap<OffsetDateTime, ChatEntry>>,
   personal_info: Arc<Mutex<Option<PersonalInfo>>>,
+  /// from the last login, to resume with
+  session_token: Mutex<Option<SessionToken>>,
   heartbeat_timer: Arc<Mutex<Option<JoinHandle<()>>>>,
   heartbeat_interval: StdDuration,
+  device: String,
 }
 
 impl State {
-  fn new(heartbeat_interval: StdDuration) -> Self {
+  fn new(heartbeat_interval: StdDuration, device: String) -> Self {
     State {
       addr2user: Default::default(),
       users: Default::default(),
@@ -86,8 +93,10 @@ impl State {
       group_history: Default::default(),
       ono2one_history: Default::default(),
       personal_info: Default::default(),
+      session_token: Default::default(),
       heartbeat_timer: Default::default(),
       heartbeat_interval,
+      device,
     }
   }
 }
@@ -120,9 +129,9 @@ async fn main() -> Result<(), Error> {
     }
   };
 
-  let state = Arc::new(State::new(StdDuration::from_secs(30)));
+  let state = Arc::new(State::new(StdDuration::from_secs(30), args.device));
 
-  let coder = default_coder();
+  let coder = args.codec;
 
   let (connection, receiver, _) = Connection::new(
     sock,
@@ -152,7 +161,6 @@ async fn main() -> Result<(), Error> {
                   name,
                   info,
                 }) => {
-                  println!("[{}: is online]", &name);
                   state
                     .addr2user
                     .write()
@@ -162,15 +170,22 @@ async fn main() -> Result<(), Error> {
                     .update_pub_keys(iter::once((info.pub_key.clone().into(), info.ip_address)));
                   // TODO: well, this won't handle new registered user really well,
                   // if future online unrelated info are included in user info
-                  state
-                    .users
-                    .write()
-                    .entry(name.clone())
-                    .or_insert_with(|| UserInfo {
+                  let was_online = {
+                    let mut users = state.users.write();
+                    let user = users.entry(name.clone()).or_insert_with(|| UserInfo {
                       name: name.clone(),
-                      online_info: None,
-                    })
-                    .online_info = Some(info);
+                      devices: vec![],
+                    });
+                    let was_online = user.is_online();
+                    user.devices.retain(|d| d.ip_address != info.ip_address);
+                    user.devices.push(info);
+                    was_online
+                  };
+                  if was_online {
+                    println!("[{}: is online on one more device]", &name);
+                    continue;
+                  }
+                  println!("[{}: is online]", &name);
                   state
                     .group_history
                     .write()
@@ -185,20 +200,28 @@ async fn main() -> Result<(), Error> {
                 Ok(Notification::Offline {
                   timestamp: time,
                   name,
+                  ip_address,
                 }) => {
-                  let online_info = match state.users.write().get_mut(&name) {
-                    Some(user) => user.online_info.take(),
+                  let still_online = match state.users.write().get_mut(&name) {
+                    Some(user) => {
+                      let before = user.devices.len();
+                      user.devices.retain(|d| d.ip_address != ip_address);
+                      if user.devices.len() == before {
+                        continue;
+                      }
+                      user.is_online()
+                    }
                     _ => continue,
                   };
 
-                  let online_info = match online_info {
-                    Some(s) => s,
-                    None => continue,
-                  };
+                  connection.as_inner().release(ip_address);
 
-                  connection.as_inner().release(online_info.ip_address);
+                  if let None = state.addr2user.write().remove(&ip_address) {
+                    continue;
+                  }
 
-                  if let None = state.addr2user.write().remove(&online_info.ip_address) {
+                  if still_online {
+                    println!("[{}: is offline on {}]", name, ip_address);
                     continue;
                   }
 
@@ -216,6 +239,24 @@ async fn main() -> Result<(), Error> {
                     .or_default()
                     .insert(time, ChatEntry::Offline);
                 }
+                Ok(Notification::Announcement { msg, .. }) => {
+                  println!("[announcement: {}]", msg);
+                }
+                Ok(
+                  notification
+                  @ (Notification::SessionRevoked { .. } | Notification::Kicked { .. }),
+                ) => {
+                  let by = match notification {
+                    Notification::Kicked { .. } => "the operator",
+                    _ => "another device",
+                  };
+                  println!("[[server]] You are logged out by {}", by);
+                  *state.personal_info.lock() = None;
+                  *state.session_token.lock() = None;
+                  if let Some(timer) = state.heartbeat_timer.lock().take() {
+                    timer.abort();
+                  }
+                }
                 Ok(_) => {
                   // log error
                 }
@@ -267,6 +308,8 @@ async fn main() -> Result<(), Error> {
     });
   }
 
+  // whether the user agreed to send passwords themselves to this server
+  let mut plaintext_allowed = false;
   let mut input = String::new();
   loop {
     input.clear();
@@ -279,14 +322,13 @@ async fn main() -> Result<(), Error> {
           if let (Some(name), Some(pass), None) =
             (args_iter.next(), args_iter.next(), args_iter.next())
           {
-            let mut hasher = Sha256::new();
-            hasher.update(pass.trim_start());
-            let password = hasher.finalize().into();
+            let Verifier { salt, verifier } = Verifier::new(name, pass);
             match connection
               .request::<_, Response>(
                 &Command::Register {
                   username: name.into(),
-                  password,
+                  salt,
+                  verifier,
                 },
                 server_addr,
               )
@@ -303,98 +345,137 @@ async fn main() -> Result<(), Error> {
             eprintln!("[[client]] Invalid command");
           }
         }
-        "LOGIN" => {
+        // upgrade a password from before srp, logging in fails until then
+        "MIGRATE" => {
           if let (Some(name), Some(pass), None) =
             (args_iter.next(), args_iter.next(), args_iter.next())
           {
-            let mut hasher = Sha256::new();
-            hasher.update(pass.trim_start());
-            let password = hasher.finalize().into();
+            let Verifier { salt, verifier } = Verifier::new(name, pass);
             match connection
               .request::<_, Response>(
-                &Command::Login {
+                &Command::MigratePassword {
                   username: name.into(),
-                  password,
+                  digest: srp::legacy_digest(pass),
+                  salt,
+                  verifier,
                 },
                 server_addr,
               )
               .await?
             {
-              Ok(ResponseData::ChatroomStatus { users }) => {
-                let timer = tokio::spawn({
-                  let connection = connection.clone();
-                  let mut interval = tokio::time::interval(state.heartbeat_interval);
-                  async move {
-                    loop {
-                      interval.tick().await;
-                      if let Err(_) = connection
-                        .as_inner()
-                        .send_to_with_empty_meta(&Command::Heartbeat, server_addr)
-                        .await
-                      {
-                        // TODO: log error
-                      }
-                    }
+              Ok(ResponseData::Success) => {
+                println!("[[server]] Succeeded, now you can login as \"{}\"", name);
+              }
+              Ok(response) => eprintln!("[[client]] unexpected response {:?}", response),
+              Err(ErrorCode::InvalidUserOrPass) => {
+                eprintln!("[[server]] username or password is incorrect")
+              }
+              Err(error) => eprintln!("[[server]] operation failed: {:?}", error),
+            }
+          } else {
+            eprintln!("[[client]] Invalid command");
+          }
+        }
+        "LOGIN" => {
+          if let (Some(name), Some(pass), code, None) = (
+            args_iter.next(),
+            args_iter.next(),
+            args_iter.next(),
+            args_iter.next(),
+          ) {
+            let handshake = ClientHandshake::new(name, pass);
+            let mut session = None;
+            let response = match connection
+              .request::<_, Response>(
+                &Command::Authenticate {
+                  username: name.into(),
+                  public_key: handshake.public_key(),
+                },
+                server_addr,
+              )
+              .await?
+            {
+              Ok(ResponseData::Challenge { salt, public_key }) => {
+                match handshake.respond(&salt, &public_key) {
+                  Ok(answer) => {
+                    let proof = answer.proof().to_vec();
+                    session = Some(answer);
+                    connection
+                      .request::<_, Response>(
+                        &Command::Login {
+                          proof,
+                          device: state.device.clone(),
+                          code: code.map(|code| code.to_string()),
+                        },
+                        server_addr,
+                      )
+                      .await?
                   }
-                });
-
-                *state.addr2user.write() = users
-                  .iter()
-                  .filter_map(|u| {
-                    if let UserInfo {
-                      online_info: Some(UserOnlineInfo { ip_address, .. }),
-                      name,
-                      ..
-                    } = u
-                    {
-                      Some((ip_address.clone(), name.clone()))
-                    } else {
-                      None
-                    }
-                  })
-                  .collect();
+                  Err(err) => {
+                    eprintln!("[[client]] {}", err);
+                    continue;
+                  }
+                }
+              }
+              // the server checks passwords with a directory or a file, it needs the password, which
+              // is never sent without asking
+              Err(ErrorCode::PasswordRequired) => {
+                if !plaintext_allowed {
+                  print!(
+                    "[[client]] {} has no secure login and needs the password itself, send it over the encrypted connection? [y/N] ",
+                    server_addr
+                  );
+                  io::stdout().flush().map_err(Error::StdIO)?;
+                  let mut answer = String::new();
+                  io::stdin().read_line(&mut answer).map_err(Error::StdIO)?;
+                  plaintext_allowed = answer.trim().eq_ignore_ascii_case("y");
+                }
+                if !plaintext_allowed {
+                  eprintln!("[[client]] login is cancelled");
+                  continue;
+                }
                 connection
-                  .as_inner()
-                  .update_pub_keys(users.iter().filter_map(|u| {
-                    if let UserInfo {
-                      online_info:
-                        Some(UserOnlineInfo {
-                          ip_address,
-                          pub_key,
-                          ..
-                        }),
-                      ..
-                    } = u
-                    {
-                      Some((pub_key.clone().into(), ip_address.clone()))
-                    } else {
-                      None
-                    }
-                  }));
-                *state.users.write() = users.into_iter().map(|u| (u.name.clone(), u)).collect();
-
-                let my_addr = {
-                  let users = state.users.read();
-                  // TODO: log error
-                  users
-                    .get(name)
-                    .unwrap()
-                    .online_info
-                    .as_ref()
-                    .unwrap()
-                    .ip_address
-                };
-
-                *state.personal_info.lock() = Some(PersonalInfo {
-                  name: name.into(),
-                  ip_address: my_addr,
-                });
-                *state.heartbeat_timer.lock() = Some(timer);
+                  .request::<_, Response>(
+                    &Command::PasswordLogin {
+                      username: name.into(),
+                      password: pass.into(),
+                      device: state.device.clone(),
+                      code: code.map(|code| code.to_string()),
+                    },
+                    server_addr,
+                  )
+                  .await?
+              }
+              response => response,
+            };
+            match response {
+              Ok(ResponseData::LoggedIn { proof, .. })
+                if session
+                  .as_ref()
+                  .map_or(false, |session| session.verify_server(&proof).is_err()) =>
+              {
+                eprintln!("[[client]] server failed to prove that it knows the password")
+              }
+              Ok(ResponseData::LoggedIn { users, token, .. }) => {
+                enter(&state, &connection, server_addr, name, users);
+                *state.session_token.lock() = Some(token);
                 println!("[[server]] You have logged in as \"{}\"", name);
               }
               Ok(response) => eprintln!("[[client]] unexpected response {:?}", response),
-              Err(ErrorCode::InvalidUserOrPass) => {
-                eprintln!("[[server]] username or password is incorrect")
+              Err(ErrorCode::InvalidUserOrPass) => eprintln!(
+                "[[server]] username or password is incorrect, passwords from before secure \
+                 login have to be upgraded with \"MIGRATE {} <pass>\" first",
+                name
+              ),
+              Err(ErrorCode::Throttled { retry_after }) => eprintln!(
+                "[[server]] too many failed attempts, retry after {} seconds",
+                retry_after
+              ),
+              Err(ErrorCode::SecondFactorRequired) => {
+                eprintln!("[[server]] one-time code is required, append it to the command")
+              }
+              Err(ErrorCode::InvalidOneTimeCode) => {
+                eprintln!("[[server]] one-time code is incorrect")
               }
               Err(error) => eprintln!("[[server]] operation failed: {:?}", error),
             }
@@ -406,18 +487,49 @@ async fn main() -> Result<(), Error> {
           if let (Some(old), Some(new), None) =
             (args_iter.next(), args_iter.next(), args_iter.next())
           {
-            let mut hasher = Sha256::new();
-            hasher.update(old.trim_start());
-            let old = hasher.finalize().into();
-
-            let mut hasher = Sha256::new();
-            hasher.update(new.trim_start());
-            let new = hasher.finalize().into();
-
-            match connection
-              .request::<_, Response>(&Command::ChangePassword { old, new }, server_addr)
+            let name = match state.personal_info.lock().as_ref() {
+              Some(info) => info.name.clone(),
+              None => {
+                eprintln!("[[client]] login is required");
+                continue;
+              }
+            };
+            let handshake = ClientHandshake::new(&name, old);
+            let Verifier { salt, verifier } = Verifier::new(&name, new);
+            let response = match connection
+              .request::<_, Response>(
+                &Command::Authenticate {
+                  username: name,
+                  public_key: handshake.public_key(),
+                },
+                server_addr,
+              )
               .await?
             {
+              Ok(ResponseData::Challenge {
+                salt: old_salt,
+                public_key,
+              }) => match handshake.respond(&old_salt, &public_key) {
+                Ok(session) => {
+                  connection
+                    .request::<_, Response>(
+                      &Command::ChangePassword {
+                        proof: session.proof().to_vec(),
+                        salt,
+                        verifier,
+                      },
+                      server_addr,
+                    )
+                    .await?
+                }
+                Err(err) => {
+                  eprintln!("[[client]] {}", err);
+                  continue;
+                }
+              },
+              response => response,
+            };
+            match response {
               Ok(ResponseData::Success) => println!("[[server]] Succeeded"),
               Ok(response) => eprintln!("[[client]] unexpected response {:?}", response),
               Err(ErrorCode::InvalidUserOrPass) => {
@@ -432,19 +544,19 @@ async fn main() -> Result<(), Error> {
         "SAY_TO" => {
           if let Some((username, msg)) = args.split_once(' ') {
             // TODO: eliminate the clone here
-            if let Some(UserInfo { name, online_info }) = state.users.read().get(username).cloned()
-            {
-              if let Some(UserOnlineInfo { ip_address, .. }) = online_info {
+            if let Some(UserInfo { name, devices }) = state.users.read().get(username).cloned() {
+              if !devices.is_empty() {
+                // every device of the user gets a copy
                 let timestamp = OffsetDateTime::now_utc();
                 connection
                   .as_inner()
-                  .send_to_with_empty_meta(
+                  .send_to_multiple_with_empty_meta(
                     &Message {
                       to_all: false,
                       timestamp,
                       msg: msg.into(),
                     },
-                    ip_address,
+                    devices.iter().map(|d| d.ip_address),
                   )
                   .await?;
                 state
@@ -486,17 +598,8 @@ async fn main() -> Result<(), Error> {
 
           let addrs = (state.users.read())
             .values()
-            .filter_map(|u| {
-              if let Some(UserOnlineInfo { ip_address, .. }) = u.online_info {
-                if my_addr != ip_address {
-                  Some(ip_address)
-                } else {
-                  None
-                }
-              } else {
-                None
-              }
-            })
+            .flat_map(|u| u.devices.iter().map(|d| d.ip_address))
+            .filter(|&ip_address| ip_address != my_addr)
             .collect::<Vec<_>>();
           if let Err(_) = connection
             .as_inner()
@@ -513,6 +616,75 @@ async fn main() -> Result<(), Error> {
             // TODO: log error
           }
         }
+        "REVOKE" => {
+          let id = match parse_session_id(args.trim()) {
+            Some(id) => id,
+            None => {
+              eprintln!("[[client]] Invalid session id");
+              continue;
+            }
+          };
+          match connection
+            .request::<_, Response>(&Command::RevokeSession { id }, server_addr)
+            .await?
+          {
+            Ok(ResponseData::Success) => println!("[[server]] Succeeded"),
+            Ok(response) => eprintln!("[[client]] unexpected response {:?}", response),
+            Err(ErrorCode::SessionNotExisted) => eprintln!("[[server]] no such session"),
+            Err(error) => eprintln!("[[server]] operation failed: {:?}", error),
+          }
+        }
+        "TOTP_CONFIRM" => {
+          match connection
+            .request::<_, Response>(
+              &Command::ConfirmTotp {
+                code: args.trim().to_string(),
+              },
+              server_addr,
+            )
+            .await?
+          {
+            Ok(ResponseData::RecoveryCodes { codes }) => {
+              println!("[[server]] two-factor authentication is enabled, recovery codes:");
+              for code in codes.iter() {
+                println!("[[server]]   {}", code);
+              }
+            }
+            Ok(response) => eprintln!("[[client]] unexpected response {:?}", response),
+            Err(ErrorCode::InvalidOneTimeCode) => {
+              eprintln!("[[server]] one-time code is incorrect")
+            }
+            Err(error) => eprintln!("[[server]] operation failed: {:?}", error),
+          }
+        }
+        "TOTP_DISABLE" => {
+          match connection
+            .request::<_, Response>(
+              &Command::DisableTotp {
+                code: args.trim().to_string(),
+              },
+              server_addr,
+            )
+            .await?
+          {
+            Ok(ResponseData::Success) => println!("[[server]] Succeeded"),
+            Ok(response) => eprintln!("[[client]] unexpected response {:?}", response),
+            Err(ErrorCode::InvalidOneTimeCode) => {
+              eprintln!("[[server]] one-time code is incorrect")
+            }
+            Err(error) => eprintln!("[[server]] operation failed: {:?}", error),
+          }
+        }
+        "RESUME" => {
+          let token = match serde_json::from_str::<SessionToken>(args.trim()) {
+            Ok(token) => token,
+            Err(err) => {
+              eprintln!("[[client]] Invalid token: {}", err);
+              continue;
+            }
+          };
+          resume(&state, &connection, server_addr, token).await?;
+        }
         _ => {
           eprintln!("[[client]] Invalid command");
         }
@@ -527,49 +699,22 @@ async fn main() -> Result<(), Error> {
           {
             Ok(ResponseData::ChatroomStatus { users }) => {
               for user in users.iter() {
-                if user.online_info.is_some() {
-                  println!("[[server]] \"{}\" is online", &user.name);
+                if user.is_online() {
+                  println!(
+                    "[[server]] \"{}\" is online on {} devices",
+                    &user.name,
+                    user.devices.len()
+                  );
                 }
               }
               for user in users.iter() {
-                if user.online_info.is_none() {
+                if !user.is_online() {
                   println!("[[server]] \"{}\" is offline", &user.name);
                 }
               }
-              *state.addr2user.write() = users
-                .iter()
-                .filter_map(|u| {
-                  if let Some(UserOnlineInfo { ip_address, .. }) = u.online_info {
-                    Some((ip_address, u.name.clone()))
-                  } else {
-                    None
-                  }
-                })
-                .collect();
-              connection
-                .as_inner()
-                .update_pub_keys(users.iter().filter_map(|u| {
-                  if let UserInfo {
-                    online_info:
-                      Some(UserOnlineInfo {
-                        ip_address,
-                        pub_key,
-                        ..
-                      }),
-                    ..
-                  } = u
-                  {
-                    Some((pub_key.clone().into(), ip_address.clone()))
-                  } else {
-                    None
-                  }
-                }));
-              let my_addr = state
-                .users
-                .read()
-                .get(&state.personal_info.lock().as_ref().unwrap().name)
-                .map(|u| u.online_info.as_ref().unwrap().ip_address) // TODO: log error
-                .unwrap(); // TODO: log error
+              take_in(&state, &connection, &users);
+              let my_name = state.personal_info.lock().as_ref().unwrap().name.clone(); // TODO: log error
+              let my_addr = find_my_addr(&connection, &my_name, &users).unwrap(); // TODO: log error
               state.personal_info.lock().as_mut().unwrap().ip_address = my_addr;
               *state.users.write() = users.into_iter().map(|u| (u.name.clone(), u)).collect();
             }
@@ -580,6 +725,54 @@ async fn main() -> Result<(), Error> {
             Err(error) => eprintln!("[[server]] operation failed: {:?}", error),
           }
         }
+        "SESSIONS" => {
+          match connection
+            .request::<_, Response>(&Command::ListSessions, server_addr)
+            .await?
+          {
+            Ok(ResponseData::Sessions { sessions }) => {
+              for session in sessions.iter() {
+                println!(
+                  "[[server]] {} \"{}\" at {}, logged in {}, last heartbeat {}{}{}",
+                  format_session_id(&session.id),
+                  session.device,
+                  session.ip_address,
+                  session.logged_in,
+                  session.last_heartbeat,
+                  if session.online { "" } else { ", offline" },
+                  if session.current { ", this device" } else { "" },
+                );
+              }
+            }
+            Ok(response) => eprintln!("[[client]] unexpected response {:?}", response),
+            Err(error) => eprintln!("[[server]] operation failed: {:?}", error),
+          }
+        }
+        "TOTP_ENROLL" => {
+          match connection
+            .request::<_, Response>(&Command::EnrollTotp, server_addr)
+            .await?
+          {
+            Ok(ResponseData::TotpProvisioning { secret, uri }) => {
+              println!("[[server]] secret: {}", secret);
+              println!("[[server]] {}", uri);
+              println!("[[client]] confirm with \"TOTP_CONFIRM <code>\"");
+            }
+            Ok(response) => eprintln!("[[client]] unexpected response {:?}", response),
+            Err(error) => eprintln!("[[server]] operation failed: {:?}", error),
+          }
+        }
+        "TOKEN" => match state.session_token.lock().as_ref() {
+          Some(token) => println!("[[client]] {}", serde_json::to_string(token).unwrap()),
+          None => eprintln!("[[client]] login first"),
+        },
+        "RESUME" => {
+          let token = state.session_token.lock().clone();
+          match token {
+            Some(token) => resume(&state, &connection, server_addr, token).await?,
+            None => eprintln!("[[client]] login first"),
+          }
+        }
         "LOGOUT" => {
           // we don't care errors arise during logout
           let _ = connection
@@ -599,3 +792,121 @@ async fn main() -> Result<(), Error> {
   }
   Ok(())
 }
+
+/// pick the session up again without the password, e.g. after the heartbeat was lost
+async fn resume<C: Codec>(
+  state: &Arc<State>,
+  connection: &Arc<Connection<C>>,
+  server_addr: SocketAddr,
+  token: SessionToken,
+) -> Result<(), Error> {
+  match connection
+    .request::<_, Response>(
+      &Command::Resume {
+        token: token.clone(),
+        device: state.device.clone(),
+      },
+      server_addr,
+    )
+    .await?
+  {
+    Ok(ResponseData::ChatroomStatus { users }) => {
+      enter(state, connection, server_addr, &token.name, users);
+      println!("[[server]] You are back as \"{}\"", &token.name);
+      *state.session_token.lock() = Some(token);
+    }
+    Ok(response) => eprintln!("[[client]] unexpected response {:?}", response),
+    Err(ErrorCode::SessionExpired) => {
+      eprintln!("[[server]] session is expired or revoked, please login again")
+    }
+    Err(error) => eprintln!("[[server]] operation failed: {:?}", error),
+  }
+  Ok(())
+}
+
+/// start sending heartbeats and take in everyone else after logging in as `name`
+fn enter<C: Codec>(
+  state: &Arc<State>,
+  connection: &Arc<Connection<C>>,
+  server_addr: SocketAddr,
+  name: &str,
+  users: Vec<UserInfo>,
+) {
+  let timer = tokio::spawn({
+    let connection = connection.clone();
+    let mut interval = tokio::time::interval(state.heartbeat_interval);
+    async move {
+      loop {
+        interval.tick().await;
+        if let Err(_) = connection
+          .as_inner()
+          .send_to_with_empty_meta(&Command::Heartbeat, server_addr)
+          .await
+        {
+          // TODO: log error
+        }
+      }
+    }
+  });
+
+  take_in(state, connection, &users);
+  let my_addr = find_my_addr(connection, name, &users).unwrap(); // TODO: log error
+  *state.users.write() = users.into_iter().map(|u| (u.name.clone(), u)).collect();
+
+  *state.personal_info.lock() = Some(PersonalInfo {
+    name: name.into(),
+    ip_address: my_addr,
+  });
+  if let Some(old_timer) = state.heartbeat_timer.lock().replace(timer) {
+    old_timer.abort();
+  }
+}
+
+/// map the address of every online device to its user and learn their keys
+fn take_in<C: Codec>(state: &Arc<State>, connection: &Arc<Connection<C>>, users: &[UserInfo]) {
+  *state.addr2user.write() = users
+    .iter()
+    .flat_map(|u| {
+      u.devices
+        .iter()
+        .map(move |d| (d.ip_address, u.name.clone()))
+    })
+    .collect();
+  connection.as_inner().update_pub_keys(
+    users
+      .iter()
+      .flat_map(|u| u.devices.iter())
+      .map(|d| (d.pub_key.clone().into(), d.ip_address)),
+  );
+}
+
+/// the address the server sees us at, told apart from other devices of the same user by the key
+fn find_my_addr<C: Codec>(
+  connection: &Arc<Connection<C>>,
+  name: &str,
+  users: &[UserInfo],
+) -> Option<SocketAddr> {
+  let my_key = connection.as_inner().get_public_key();
+  users
+    .iter()
+    .find(|u| u.name == name)?
+    .devices
+    .iter()
+    .find(|d| &d.pub_key == my_key.as_bytes())
+    .map(|d| d.ip_address)
+}
+
+fn format_session_id(id: &[u8; 16]) -> String {
+  id.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn parse_session_id(s: &str) -> Option<[u8; 16]> {
+  if s.len() != 32 {
+    return None;
+  }
+  let mut id = [0u8; 16];
+  for (i, b) in id.iter_mut().enumerate() {
+    *b = u8::from_str_radix(s.get(i * 2..i * 2 + 2)?, 16).ok()?;
+  }
+  Some(id)
+}
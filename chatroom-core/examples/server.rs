@@ -4,11 +4,14 @@ use time::OffsetDateTime;
 use tokio::{self, net::UdpSocket, task::JoinHandle};
 
 use chatroom_core::{
+  buffer::Frame,
+  codec::{Codec, Format},
   connection::SecureConnection,
   data::{
-    default_coder, Command, ErrorCode, Notification, Response, ResponseData, User, UserInfo,
+    Command, ErrorCode, Notification, Response, ResponseData, SessionToken, User, UserInfo,
     UserOnlineInfo,
   },
+  srp::{ServerHandshake, Verifier},
   utils::Error,
 };
 
@@ -16,8 +19,6 @@ use rand::Rng;
 
 use parking_lot::{RwLock, RwLockUpgradableReadGuard, RwLockWriteGuard};
 
-use bincode::Options;
-
 use clap::Parser;
 
 use byteorder::{ByteOrder, NetworkEndian};
@@ -31,6 +32,9 @@ struct Args {
   /// specify socket address of server
   #[clap(short, long, default_value = "0.0.0.0:0")]
   addr: String,
+  /// specify wire format, one of "bincode", "msgpack" and "json"
+  #[clap(short, long, default_value = "bincode")]
+  codec: Format,
 }
 
 type RwHashMap<K, V> = RwLock<HashMap<K, V>>;
@@ -39,8 +43,10 @@ type RwHashMap<K, V> = RwLock<HashMap<K, V>>;
 struct State {
   addr2user: RwHashMap<SocketAddr, String>,
   users: RwHashMap<String, User>,
-  user_active_timers: RwHashMap<String, JoinHandle<()>>,
+  /// one for every logged in device
+  user_active_timers: RwHashMap<SocketAddr, JoinHandle<()>>,
   pub_keys: Arc<RwHashMap<SocketAddr, PublicKey>>,
+  handshakes: RwHashMap<SocketAddr, ServerHandshake>,
   heartbeat_interval: Duration,
 }
 
@@ -51,6 +57,7 @@ impl State {
       users: Default::default(),
       user_active_timers: Default::default(),
       pub_keys: Default::default(),
+      handshakes: Default::default(),
       heartbeat_interval,
     }
   }
@@ -65,8 +72,7 @@ async fn main() -> Result<(), Error> {
   let sock = UdpSocket::bind(&args.addr).await?;
   println!("server running at {}", sock.local_addr()?);
 
-  let (connection, key_receiver) =
-    SecureConnection::new(sock, state.pub_keys.clone(), default_coder());
+  let (connection, key_receiver) = SecureConnection::new(sock, state.pub_keys.clone(), args.codec);
   let connection = Arc::new(connection);
 
   tokio::spawn({
@@ -77,7 +83,8 @@ async fn main() -> Result<(), Error> {
         if let Some((key, addr)) = key_receiver.recv().await {
           if let Some(name) = state.addr2user.read().get(&addr) {
             if let Some(user) = state.users.write().get_mut(name) {
-              if let Some(info) = user.online_info.as_mut() {
+              let device = user.devices.iter_mut().find(|d| d.ip_address == addr);
+              if let Some(info) = device {
                 info.pub_key = *key.as_bytes();
               }
             }
@@ -87,10 +94,8 @@ async fn main() -> Result<(), Error> {
     }
   });
 
-  let mut buf = vec![0u8; 65535];
-
   loop {
-    let (buf, addr) = match connection.recv_from_raw(&mut buf).await {
+    let (buf, addr) = match connection.recv_from_raw().await {
       Ok(req) => req,
       Err(err) => {
         eprintln!("{}", Error::from(err));
@@ -109,10 +114,10 @@ async fn main() -> Result<(), Error> {
   }
 }
 
-async fn process<Coder: 'static + Options + Copy + Send + Sync>(
+async fn process<Coder: Codec>(
   state: Arc<State>,
   connection: Arc<SecureConnection<Coder>>,
-  buf: Vec<u8>,
+  buf: Frame,
   addr: SocketAddr,
 ) -> Result<(), Error> {
   let id = NetworkEndian::read_u16(&buf[..]);
@@ -120,57 +125,75 @@ async fn process<Coder: 'static + Options + Copy + Send + Sync>(
   println!("{:?}", command);
 
   let response: Option<Response> = match command {
-    Command::Register { username, password } => {
-      Some(loop {
-        let users = state.users.upgradable_read();
-        if users.contains_key(&username) {
-          break Err(ErrorCode::UserExisted);
-        }
-
-        let mut salt = [0u8; 32];
-        rand::thread_rng().fill(&mut salt);
-
-        let password_hash =
-          argon2::hash_encoded(&password, &salt, &argon2::Config::default()).unwrap(); // TODO: log error
+    Command::Register {
+      username,
+      salt,
+      verifier,
+    } => Some(loop {
+      let users = state.users.upgradable_read();
+      if users.contains_key(&username) {
+        break Err(ErrorCode::UserExisted);
+      }
 
-        let mut users = RwLockUpgradableReadGuard::<_>::upgrade(users);
-        users.insert(
-          username.clone(),
-          User {
-            name: username,
-            password_hash,
-            online_info: None,
-          },
-        );
-        break Ok(ResponseData::Success);
-      })
-    }
-    Command::Login { username, password } => {
+      let password_hash = Verifier { salt, verifier }.encode();
+
+      let mut users = RwLockUpgradableReadGuard::<_>::upgrade(users);
+      users.insert(
+        username.clone(),
+        User {
+          name: username,
+          password_hash,
+          devices: vec![],
+        },
+      );
+      break Ok(ResponseData::Success);
+    }),
+    Command::Authenticate {
+      username,
+      public_key,
+    } => Some(loop {
+      let verifier = match state.users.read().get(&username) {
+        Some(user) => Verifier::decode(&user.password_hash).unwrap(), // TODO: log error
+        None => break Err(ErrorCode::InvalidUserOrPass),
+      };
+      let handshake = match ServerHandshake::new(&username, &verifier, &public_key) {
+        Ok(handshake) => handshake,
+        Err(_) => break Err(ErrorCode::InvalidUserOrPass),
+      };
+      let challenge = ResponseData::Challenge {
+        salt: handshake.salt().to_vec(),
+        public_key: handshake.public_key(),
+      };
+      state.handshakes.write().insert(addr, handshake);
+      break Ok(challenge);
+    }),
+    Command::Login { proof, .. } => {
       let response: Response = loop {
-        // check username and password
-        let users = state.users.upgradable_read();
-        let user = match users.get(&username) {
-          Some(s) => s,
+        // check the answer to the challenge
+        let handshake = match state.handshakes.write().remove(&addr) {
+          Some(handshake) => handshake,
           None => break Err(ErrorCode::InvalidUserOrPass),
         };
-        if !argon2::verify_encoded(&user.password_hash, &password).unwrap() {
-          // TODO: log error
-          break Err(ErrorCode::InvalidUserOrPass);
-        }
+        let server_proof = match handshake.verify(&proof) {
+          Ok(server_proof) => server_proof,
+          Err(_) => break Err(ErrorCode::InvalidUserOrPass),
+        };
+        let username = handshake.username().to_string();
+        let users = state.users.upgradable_read();
 
         let pub_key = match state.pub_keys.read().get(&addr) {
           Some(pub_key) => *pub_key.as_bytes(),
           _ => break Err(ErrorCode::ConnectionNotSecure),
         };
 
-        // update activity timer
-        let old_timer = state.user_active_timers.write().insert(username.clone(), {
+        // update activity timer of the device
+        let old_timer = state.user_active_timers.write().insert(addr, {
           let state = state.clone();
           let sock = connection.clone();
           let username = username.clone();
           tokio::spawn(async move {
             tokio::time::sleep(state.heartbeat_interval).await;
-            announce_offline(state, username, sock).await;
+            announce_offline(state, username, addr, sock).await;
           })
         });
 
@@ -182,28 +205,28 @@ async fn process<Coder: 'static + Options + Copy + Send + Sync>(
         let mut users = RwLockUpgradableReadGuard::<_>::upgrade(users);
         let user_info = {
           let user = users.get_mut(&username).unwrap();
-          let old_online_info = user.online_info.take();
-          if let Some(UserOnlineInfo {
-            ip_address: old_addr,
-            ..
-          }) = old_online_info
-          {
-            if old_addr == addr {
-              state.addr2user.write().remove(&old_addr);
-            }
-          }
+          // other devices of the user stay online
+          user.devices.retain(|d| d.ip_address != addr);
 
           let info = UserOnlineInfo {
             ip_address: addr,
             pub_key,
           };
-          user.online_info = Some(info.clone());
+          user.devices.push(info.clone());
           info
         };
         let users = RwLockWriteGuard::<_>::downgrade_to_upgradable(users);
 
         state.addr2user.write().insert(addr, username.clone());
 
+        // resuming is not supported here, so the token is never checked
+        let token = SessionToken {
+          id: rand::thread_rng().gen(),
+          name: username.clone(),
+          expires: OffsetDateTime::now_utc(),
+          signature: [0; 32],
+        };
+
         // broadcast online message
         {
           let state = state.clone();
@@ -219,50 +242,53 @@ async fn process<Coder: 'static + Options + Copy + Send + Sync>(
           .map(|(_, user)| UserInfo::new(user))
           .collect::<Vec<_>>();
 
-        break Ok(ResponseData::ChatroomStatus { users: users_info });
+        break Ok(ResponseData::LoggedIn {
+          proof: server_proof,
+          users: users_info,
+          token,
+        });
       };
       Some(response)
     }
-    Command::ChangePassword { old, new } => {
-      Some(loop {
-        let addr2user = state.addr2user.read();
-        let username = match addr2user.get(&addr) {
-          Some(s) => s,
-          None => break Err(ErrorCode::LoginRequired),
-        };
-
-        if !state.user_active_timers.read().contains_key(username) {
-          break Err(ErrorCode::LoginRequired);
-        }
-
-        let users = state.users.upgradable_read();
-        let user = users.get(username).unwrap(); // TODO: log error
+    Command::ChangePassword {
+      proof,
+      salt,
+      verifier,
+    } => Some(loop {
+      let addr2user = state.addr2user.read();
+      let username = match addr2user.get(&addr) {
+        Some(s) => s,
+        None => break Err(ErrorCode::LoginRequired),
+      };
 
-        if !argon2::verify_encoded(&user.password_hash, &old).expect("failed to verify password") {
-          break Err(ErrorCode::InvalidUserOrPass);
-        }
+      if !state.user_active_timers.read().contains_key(&addr) {
+        break Err(ErrorCode::LoginRequired);
+      }
 
-        let mut salt = [0u8; 32];
-        rand::thread_rng().fill(&mut salt);
+      let handshake = match state.handshakes.write().remove(&addr) {
+        Some(handshake) if handshake.username() == username => handshake,
+        _ => break Err(ErrorCode::InvalidUserOrPass),
+      };
+      if let Err(_) = handshake.verify(&proof) {
+        break Err(ErrorCode::InvalidUserOrPass);
+      }
 
-        let password_hash = argon2::hash_encoded(&new, &salt, &argon2::Config::default()).unwrap(); // TODO: log error
+      let password_hash = Verifier { salt, verifier }.encode();
 
-        let mut users = RwLockUpgradableReadGuard::<_>::upgrade(users);
-        users.get_mut(username).unwrap().password_hash = password_hash;
+      let users = state.users.upgradable_read();
+      let mut users = RwLockUpgradableReadGuard::<_>::upgrade(users);
+      users.get_mut(username).unwrap().password_hash = password_hash;
 
-        break Ok(ResponseData::Success);
-      })
-    }
+      break Ok(ResponseData::Success);
+    }),
     Command::GetChatroomStatus => Some(loop {
-      let addr2user = state.addr2user.read();
-      let username = match addr2user.get(&addr) {
-        Some(s) => s,
-        None => break Err(ErrorCode::LoginRequired),
-      };
+      if !state.addr2user.read().contains_key(&addr) {
+        break Err(ErrorCode::LoginRequired);
+      }
 
       let user_active_timers = state.user_active_timers.read();
 
-      if !user_active_timers.contains_key(username) {
+      if !user_active_timers.contains_key(&addr) {
         break Err(ErrorCode::LoginRequired);
       }
 
@@ -277,13 +303,13 @@ async fn process<Coder: 'static + Options + Copy + Send + Sync>(
     }),
     Command::Heartbeat => {
       if let Some(username) = state.addr2user.read().get(&addr).cloned() {
-        if let Some(timer) = state.user_active_timers.write().get_mut(&username) {
+        if let Some(timer) = state.user_active_timers.write().get_mut(&addr) {
           timer.abort();
           let state = state.clone();
           let sock = connection.clone();
           *timer = tokio::spawn(async move {
             tokio::time::sleep(state.heartbeat_interval).await;
-            announce_offline(state, username, sock).await;
+            announce_offline(state, username, addr, sock).await;
           });
         }
       };
@@ -293,23 +319,27 @@ async fn process<Coder: 'static + Options + Copy + Send + Sync>(
       Some(match state.addr2user.write().remove(&addr) {
         Some(username) => {
           loop {
-            let timer = state.user_active_timers.write().remove(&username);
+            let timer = state.user_active_timers.write().remove(&addr);
             if let Some(timer) = timer {
               timer.abort();
             } else {
               break Err(ErrorCode::LoginRequired);
             }
 
-            let user = match state.users.write().get_mut(&username) {
-              Some(s) => s.online_info.take(),
+            let removed = match state.users.write().get_mut(&username) {
+              Some(s) => {
+                let before = s.devices.len();
+                s.devices.retain(|d| d.ip_address != addr);
+                s.devices.len() != before
+              }
               None => break Err(ErrorCode::LoginRequired),
             };
 
-            if user.is_some() {
+            if removed {
               let state = state.clone();
               let sock = connection.clone();
               tokio::spawn(async move {
-                announce_offline(state, username, sock).await // TODO: log error
+                announce_offline(state, username, addr, sock).await // TODO: log error
               });
               break Ok(ResponseData::Success);
             } else {
@@ -330,7 +360,7 @@ async fn process<Coder: 'static + Options + Copy + Send + Sync>(
   Ok(())
 }
 
-async fn announce_online<Coder: 'static + Options + Copy + Send + Sync>(
+async fn announce_online<Coder: Codec>(
   state: Arc<State>,
   name: String,
   info: UserOnlineInfo,
@@ -339,8 +369,9 @@ async fn announce_online<Coder: 'static + Options + Copy + Send + Sync>(
   let addrs = state
     .addr2user
     .read()
-    .iter()
-    .filter_map(|(&addr, n)| if n != &name { Some(addr) } else { None })
+    .keys()
+    .filter(|&&addr| addr != info.ip_address)
+    .cloned()
     .collect::<Vec<_>>();
 
   let notification = Notification::Online {
@@ -356,21 +387,24 @@ async fn announce_online<Coder: 'static + Options + Copy + Send + Sync>(
   }
 }
 
-async fn announce_offline<Coder: 'static + Options + Copy + Send + Sync>(
+async fn announce_offline<Coder: Codec>(
   state: Arc<State>,
   name: String,
+  addr: SocketAddr,
   connection: Arc<SecureConnection<Coder>>,
 ) {
   let addrs = state
     .addr2user
     .read()
-    .iter()
-    .filter_map(|(&addr, n)| if n != &name { Some(addr) } else { None })
+    .keys()
+    .filter(|&&a| a != addr)
+    .cloned()
     .collect::<Vec<_>>();
 
   let notification = Notification::Offline {
     timestamp: OffsetDateTime::now_utc(),
     name,
+    ip_address: addr,
   };
 
   if let Err(_) = connection
@@ -0,0 +1,149 @@
+use std::{
+  collections::HashMap,
+  result::Result,
+  sync::Arc,
+  time::{Duration, Instant},
+};
+
+use parking_lot::RwLock;
+use tokio::net::UdpSocket;
+
+use clap::Parser;
+
+use byteorder::{ByteOrder, NetworkEndian};
+
+use chatroom_core::{
+  codec::{Codec, Format},
+  connection::{Connection, SecureConnection},
+  sim::{NetworkConditions, SimulatedTransport},
+  transport::Transport,
+  utils::Error,
+};
+
+/// Fire requests between two local peers over a simulated bad network
+#[derive(Parser, Debug)]
+#[clap(about, version, author)]
+struct Args {
+  /// seed of the simulated network, the same seed replays the same misbehaviors
+  #[clap(short, long, default_value = "0")]
+  seed: u64,
+  /// number of requests to send
+  #[clap(short, long, default_value = "1000")]
+  requests: u32,
+  /// probability of a datagram being dropped
+  #[clap(long, default_value = "0.05")]
+  loss: f64,
+  /// delay of every datagram in milliseconds
+  #[clap(long, default_value = "20")]
+  latency: u64,
+  /// upper bound of the random extra delay in milliseconds
+  #[clap(long, default_value = "10")]
+  jitter: u64,
+  /// probability of a datagram being delivered twice
+  #[clap(long, default_value = "0.01")]
+  duplication: f64,
+  /// probability of a datagram being overtaken by later ones
+  #[clap(long, default_value = "0.01")]
+  reordering: f64,
+  /// specify wire format, one of "bincode", "msgpack" and "json"
+  #[clap(short, long, default_value = "bincode")]
+  codec: Format,
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+  let args = Args::parse();
+
+  let conditions = NetworkConditions {
+    loss: args.loss,
+    latency: Duration::from_millis(args.latency),
+    jitter: Duration::from_millis(args.jitter),
+    duplication: args.duplication,
+    reordering: args.reordering,
+    reorder_delay: Duration::from_millis(args.latency + args.jitter + 1),
+  };
+
+  // keys are exchanged over a perfect network, the handshake has no retry of its own
+  let server_sock = Arc::new(SimulatedTransport::new(
+    UdpSocket::bind("127.0.0.1:0").await?,
+    Default::default(),
+    args.seed,
+  ));
+  let client_sock = Arc::new(SimulatedTransport::new(
+    UdpSocket::bind("127.0.0.1:0").await?,
+    Default::default(),
+    args.seed.wrapping_add(1),
+  ));
+  let server_addr = server_sock.local_addr()?;
+
+  let (server, _) = SecureConnection::new(server_sock.clone(), Default::default(), args.codec);
+  let server = Arc::new(server);
+  tokio::spawn({
+    let server = server.clone();
+    async move {
+      loop {
+        let (frame, addr) = match server.recv_from_raw().await {
+          Ok(r) => r,
+          Err(err) => {
+            eprintln!("[server] {}", err);
+            continue;
+          }
+        };
+        if frame.len() < 2 {
+          continue;
+        }
+        let id = NetworkEndian::read_u16(&frame[..]);
+        let value = match server.get_coder().deserialize::<u32>(&frame[2..]) {
+          Ok(value) => value,
+          Err(err) => {
+            eprintln!("[server] {}", err);
+            continue;
+          }
+        };
+        if let Err(err) = server.send_to_with_meta(&value, addr, id).await {
+          eprintln!("[server] {}", err);
+        }
+      }
+    }
+  });
+
+  let pub_keys: Arc<RwLock<HashMap<_, _>>> = Default::default();
+  let (client, _, _) = Connection::new(
+    client_sock.clone(),
+    args.codec,
+    pub_keys,
+    Duration::from_millis(4 * (args.latency + args.jitter) + 50),
+    5,
+  );
+  client.as_inner().exchange_key_with(server_addr).await?;
+
+  server_sock.set_conditions(conditions);
+  client_sock.set_conditions(conditions);
+
+  let start = Instant::now();
+  let (mut succeeded, mut mismatched, mut failed) = (0u32, 0u32, 0u32);
+  for i in 0..args.requests {
+    match client.request::<u32, u32>(&i, server_addr).await {
+      Ok(value) if value == i => succeeded += 1,
+      Ok(_) => mismatched += 1,
+      Err(err) => {
+        eprintln!("[client] request {} failed: {}", i, err);
+        failed += 1;
+      }
+    }
+  }
+
+  println!(
+    "finished {} requests in {:?}",
+    args.requests,
+    start.elapsed()
+  );
+  println!(
+    "succeeded: {}, mismatched: {}, failed: {}",
+    succeeded, mismatched, failed
+  );
+  println!("client -> server: {:?}", client_sock.get_stats());
+  println!("server -> client: {:?}", server_sock.get_stats());
+
+  Ok(())
+}
@@ -12,15 +12,15 @@ use tokio::net::UdpSocket;
 use clap::Parser;
 
 use chatroom_core::{
+  codec::Codec,
   connection::Connection,
-  data::{default_coder, Command, Response},
+  data::{default_coder, Command, Response, ResponseData},
+  srp::{ClientHandshake, Verifier},
   utils::Error,
 };
 
 use parking_lot::RwLock;
 
-use sha2::{Digest, Sha256};
-
 use crypto_box::PublicKey;
 
 /// Chatroom client
@@ -71,8 +71,11 @@ async fn main() -> Result<(), Error> {
     5,
   );
 
+  // name of the last login, needed to prove the old password when changing it
+  let mut username: Option<String> = None;
+
   let mut input = String::new();
-  loop {
+  'input: loop {
     input.clear();
     io::stdin().read_line(&mut input).map_err(Error::StdIO)?;
 
@@ -84,25 +87,30 @@ async fn main() -> Result<(), Error> {
             if let (Some(name), Some(pass), None) =
               (args_iter.next(), args_iter.next(), args_iter.next())
             {
-              let mut hasher = Sha256::new();
-              hasher.update(pass.trim_start());
-              let password = hasher.finalize().into();
+              let Verifier { salt, verifier } = Verifier::new(name, pass);
               break Some(Command::Register {
                 username: name.to_string(),
-                password,
+                salt,
+                verifier,
               });
             }
           }
           "LOGIN" => {
-            if let (Some(name), Some(pass), None) =
-              (args_iter.next(), args_iter.next(), args_iter.next())
-            {
-              let mut hasher = Sha256::new();
-              hasher.update(pass.trim_start());
-              let password = hasher.finalize().into();
+            if let (Some(name), Some(pass), code, None) = (
+              args_iter.next(),
+              args_iter.next(),
+              args_iter.next(),
+              args_iter.next(),
+            ) {
+              let proof = match authenticate(&connection, server_addr, name, pass).await? {
+                Some(proof) => proof,
+                None => continue 'input,
+              };
+              username = Some(name.to_string());
               break Some(Command::Login {
-                username: name.to_string(),
-                password,
+                proof,
+                device: "test-client".into(),
+                code: code.map(|code| code.to_string()),
               });
             }
           }
@@ -110,15 +118,23 @@ async fn main() -> Result<(), Error> {
             if let (Some(old), Some(new), None) =
               (args_iter.next(), args_iter.next(), args_iter.next())
             {
-              let mut hasher = Sha256::new();
-              hasher.update(old.trim_start());
-              let old = hasher.finalize().into();
-
-              let mut hasher = Sha256::new();
-              hasher.update(new.trim_start());
-              let new = hasher.finalize().into();
-
-              break Some(Command::ChangePassword { old, new });
+              let name = match &username {
+                Some(name) => name.clone(),
+                None => {
+                  eprintln!("[client] login first");
+                  continue 'input;
+                }
+              };
+              let proof = match authenticate(&connection, server_addr, &name, old).await? {
+                Some(proof) => proof,
+                None => continue 'input,
+              };
+              let Verifier { salt, verifier } = Verifier::new(&name, new);
+              break Some(Command::ChangePassword {
+                proof,
+                salt,
+                verifier,
+              });
             }
           }
           _ => {}
@@ -163,3 +179,29 @@ async fn main() -> Result<(), Error> {
     }
   }
 }
+
+/// ask the server for a challenge and answer it, `None` if there was none to answer
+async fn authenticate<C: Codec>(
+  connection: &Connection<C>,
+  server_addr: net::SocketAddr,
+  name: &str,
+  pass: &str,
+) -> Result<Option<Vec<u8>>, Error> {
+  let handshake = ClientHandshake::new(name, pass);
+  let challenge = connection
+    .request::<Command, Response>(
+      &Command::Authenticate {
+        username: name.to_string(),
+        public_key: handshake.public_key(),
+      },
+      server_addr,
+    )
+    .await?;
+  println!("{:?}", challenge);
+  match challenge {
+    Ok(ResponseData::Challenge { salt, public_key }) => Ok(Some(
+      handshake.respond(&salt, &public_key)?.proof().to_vec(),
+    )),
+    _ => Ok(None),
+  }
+}
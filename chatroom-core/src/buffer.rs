@@ -0,0 +1,136 @@
+use std::{
+  ops::{Deref, DerefMut, Range},
+  sync::Arc,
+};
+
+use parking_lot::Mutex;
+
+/// pool of byte buffers recycled between datagrams, so that the receive and send paths stop
+/// hitting the allocator once warmed up
+#[derive(Debug)]
+pub struct BufferPool {
+  buffers: Mutex<Vec<Vec<u8>>>,
+  buffer_capacity: usize,
+  max_pooled: usize,
+  /// hand buffers out at their full capacity rather than empty
+  full: bool,
+}
+
+impl BufferPool {
+  pub fn new(buffer_capacity: usize, max_pooled: usize) -> Arc<Self> {
+    Arc::new(Self {
+      buffers: Default::default(),
+      buffer_capacity,
+      max_pooled,
+      full: false,
+    })
+  }
+
+  /// a pool handing buffers out at their full capacity, e.g. to receive datagrams into, they get
+  /// zeroed once when allocated and keep what was written to them from then on
+  pub fn new_full(buffer_capacity: usize, max_pooled: usize) -> Arc<Self> {
+    Arc::new(Self {
+      buffers: Default::default(),
+      buffer_capacity,
+      max_pooled,
+      full: true,
+    })
+  }
+
+  /// take a buffer out of the pool, empty unless the pool is a full one, it goes back once dropped
+  pub fn get(self: &Arc<Self>) -> PooledBuf {
+    let mut buf = match self.buffers.lock().pop() {
+      Some(buf) => buf,
+      None => Vec::with_capacity(self.buffer_capacity),
+    };
+    if self.full {
+      buf.resize(self.buffer_capacity, 0);
+    }
+    PooledBuf {
+      buf,
+      pool: self.clone(),
+    }
+  }
+
+  pub fn pooled(&self) -> usize {
+    self.buffers.lock().len()
+  }
+}
+
+#[derive(Debug)]
+pub struct PooledBuf {
+  buf: Vec<u8>,
+  pool: Arc<BufferPool>,
+}
+
+impl Deref for PooledBuf {
+  type Target = Vec<u8>;
+
+  fn deref(&self) -> &Self::Target {
+    &self.buf
+  }
+}
+
+impl DerefMut for PooledBuf {
+  fn deref_mut(&mut self) -> &mut Self::Target {
+    &mut self.buf
+  }
+}
+
+impl Drop for PooledBuf {
+  fn drop(&mut self) {
+    let mut buffers = self.pool.buffers.lock();
+    if buffers.len() < self.pool.max_pooled {
+      let mut buf = std::mem::take(&mut self.buf);
+      if !self.pool.full {
+        buf.clear();
+      }
+      buffers.push(buf);
+    }
+  }
+}
+
+/// a received payload, borrowed from a pooled buffer which the other frames of a batch may share
+#[derive(Debug, Clone)]
+pub struct Frame {
+  buf: Arc<PooledBuf>,
+  start: usize,
+  end: usize,
+}
+
+impl Frame {
+  pub fn new(buf: PooledBuf, start: usize) -> Self {
+    let end = buf.len();
+    Self::with_range(Arc::new(buf), start..end)
+  }
+
+  /// the bytes of `buf` within `range`, which gets clamped to them
+  pub fn with_range(buf: Arc<PooledBuf>, range: Range<usize>) -> Self {
+    let end = range.end.min(buf.len());
+    let start = range.start.min(end);
+    Self { buf, start, end }
+  }
+
+  /// a part of the payload sharing the same buffer, `range` is relative to the payload
+  pub fn slice(&self, range: Range<usize>) -> Self {
+    let end = (self.start + range.end).min(self.end);
+    Self::with_range(self.buf.clone(), self.start + range.start..end)
+  }
+
+  /// skip the first `n` bytes of the payload, e.g. a header that has already been read
+  pub fn advance(&mut self, n: usize) {
+    self.start = (self.start + n).min(self.end);
+  }
+
+  pub fn into_vec(self) -> Vec<u8> {
+    self[..].to_vec()
+  }
+}
+
+impl Deref for Frame {
+  type Target = [u8];
+
+  fn deref(&self) -> &Self::Target {
+    &self.buf[self.start..self.end]
+  }
+}
@@ -0,0 +1,240 @@
+use std::{fmt::Display, io::Write, str::FromStr};
+
+use thiserror::Error as ThisError;
+
+use serde::{Deserialize, Serialize};
+
+use bincode::Options;
+
+/// serialization format used for everything sent over the wire
+pub trait Codec: Copy + Send + Sync + 'static {
+  fn serialize_into<W, T>(self, writer: W, value: &T) -> Result<(), Error>
+  where
+    W: Write,
+    T: ?Sized + Serialize;
+
+  fn deserialize<'a, T>(self, bytes: &'a [u8]) -> Result<T, Error>
+  where
+    T: Deserialize<'a>;
+
+  fn serialize<T>(self, value: &T) -> Result<Vec<u8>, Error>
+  where
+    T: ?Sized + Serialize,
+  {
+    let mut buf = Vec::new();
+    self.serialize_into(&mut buf, value)?;
+    Ok(buf)
+  }
+}
+
+/// bincode with the given options
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Bincode<O>(pub O);
+
+impl<O> Codec for Bincode<O>
+where
+  O: Options + Copy + Send + Sync + 'static,
+{
+  fn serialize_into<W, T>(self, writer: W, value: &T) -> Result<(), Error>
+  where
+    W: Write,
+    T: ?Sized + Serialize,
+  {
+    Ok(self.0.serialize_into(writer, value)?)
+  }
+
+  fn deserialize<'a, T>(self, bytes: &'a [u8]) -> Result<T, Error>
+  where
+    T: Deserialize<'a>,
+  {
+    Ok(self.0.deserialize(bytes)?)
+  }
+}
+
+/// MessagePack, with structs encoded as maps so that peers need not know field order
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MessagePack;
+
+impl Codec for MessagePack {
+  fn serialize_into<W, T>(self, mut writer: W, value: &T) -> Result<(), Error>
+  where
+    W: Write,
+    T: ?Sized + Serialize,
+  {
+    Ok(rmp_serde::encode::write_named(&mut writer, value)?)
+  }
+
+  fn deserialize<'a, T>(self, bytes: &'a [u8]) -> Result<T, Error>
+  where
+    T: Deserialize<'a>,
+  {
+    Ok(rmp_serde::from_slice(bytes)?)
+  }
+}
+
+/// plain JSON, mainly useful for debugging and for tooling outside of rust
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Json;
+
+impl Codec for Json {
+  fn serialize_into<W, T>(self, writer: W, value: &T) -> Result<(), Error>
+  where
+    W: Write,
+    T: ?Sized + Serialize,
+  {
+    Ok(serde_json::to_writer(writer, value)?)
+  }
+
+  fn deserialize<'a, T>(self, bytes: &'a [u8]) -> Result<T, Error>
+  where
+    T: Deserialize<'a>,
+  {
+    Ok(serde_json::from_slice(bytes)?)
+  }
+}
+
+/// codec picked at runtime, e.g. from a command line flag or a settings file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+  #[default]
+  Bincode,
+  #[serde(rename = "msgpack")]
+  MessagePack,
+  Json,
+}
+
+impl Codec for Format {
+  fn serialize_into<W, T>(self, writer: W, value: &T) -> Result<(), Error>
+  where
+    W: Write,
+    T: ?Sized + Serialize,
+  {
+    match self {
+      Self::Bincode => crate::data::default_coder().serialize_into(writer, value),
+      Self::MessagePack => MessagePack.serialize_into(writer, value),
+      Self::Json => Json.serialize_into(writer, value),
+    }
+  }
+
+  fn deserialize<'a, T>(self, bytes: &'a [u8]) -> Result<T, Error>
+  where
+    T: Deserialize<'a>,
+  {
+    match self {
+      Self::Bincode => crate::data::default_coder().deserialize(bytes),
+      Self::MessagePack => MessagePack.deserialize(bytes),
+      Self::Json => Json.deserialize(bytes),
+    }
+  }
+}
+
+impl Display for Format {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.write_str(match self {
+      Self::Bincode => "bincode",
+      Self::MessagePack => "msgpack",
+      Self::Json => "json",
+    })
+  }
+}
+
+impl FromStr for Format {
+  type Err = Error;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s.to_ascii_lowercase().as_str() {
+      "bincode" => Ok(Self::Bincode),
+      "msgpack" | "messagepack" => Ok(Self::MessagePack),
+      "json" => Ok(Self::Json),
+      _ => Err(Error::UnknownFormat(s.to_string())),
+    }
+  }
+}
+
+#[derive(ThisError, Debug)]
+pub enum Error {
+  #[error(transparent)]
+  Bincode(#[from] bincode::Error),
+  #[error(transparent)]
+  MessagePackEncode(#[from] rmp_serde::encode::Error),
+  #[error(transparent)]
+  MessagePackDecode(#[from] rmp_serde::decode::Error),
+  #[error(transparent)]
+  Json(#[from] serde_json::Error),
+  #[error("unknown codec \"{0}\"")]
+  UnknownFormat(String),
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use crate::data::{Capabilities, Command, ErrorCode, Response, ResponseData};
+
+  const FORMATS: [Format; 3] = [Format::Bincode, Format::MessagePack, Format::Json];
+
+  #[test]
+  fn round_trips_commands() {
+    let commands = [
+      Command::Hello {
+        version: 3,
+        capabilities: Capabilities::COMPRESSION,
+      },
+      Command::GetChatroomStatus,
+      Command::Heartbeat,
+    ];
+    for format in FORMATS {
+      for command in &commands {
+        let bytes = format.serialize(command).unwrap();
+        let decoded: Command = format.deserialize(&bytes).unwrap();
+        assert_eq!(&decoded, command, "{}", format);
+      }
+    }
+  }
+
+  #[test]
+  fn round_trips_responses() {
+    let responses: [Response; 2] = [
+      Ok(ResponseData::ChatroomStatus { users: vec![] }),
+      Err(ErrorCode::IncompatibleVersion {
+        version: 0,
+        min: 1,
+        max: u16::MAX,
+      }),
+    ];
+    for format in FORMATS {
+      for response in &responses {
+        let bytes = format.serialize(response).unwrap();
+        let decoded: Response = format.deserialize(&bytes).unwrap();
+        assert_eq!(&decoded, response, "{}", format);
+      }
+    }
+  }
+
+  #[test]
+  fn rejects_garbage() {
+    for format in FORMATS {
+      assert!(
+        format.deserialize::<Command>(&[0xff; 3]).is_err(),
+        "{}",
+        format
+      );
+    }
+  }
+
+  #[test]
+  fn parses_format_names() {
+    for format in FORMATS {
+      assert_eq!(format.to_string().parse::<Format>().unwrap(), format);
+    }
+    assert_eq!(
+      "MessagePack".parse::<Format>().unwrap(),
+      Format::MessagePack
+    );
+    assert!(matches!(
+      "xml".parse::<Format>(),
+      Err(Error::UnknownFormat(name)) if name == "xml"
+    ));
+  }
+}
@@ -0,0 +1,225 @@
+use thiserror::Error as ThisError;
+
+use lz4_flex::block::{self, DecompressError};
+
+/// payload is sent as is
+const STORED: u8 = 0;
+/// payload is a size prepended lz4 block
+const LZ4: u8 = 1;
+
+/// compression applied to payloads before encryption, for peers that negotiated
+/// `Capabilities::COMPRESSION`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompressionConfig {
+  /// payloads shorter than this are never compressed
+  pub threshold: usize,
+  /// decompressed payloads larger than this are rejected without being inflated
+  pub max_decompressed_size: usize,
+}
+
+impl Default for CompressionConfig {
+  fn default() -> Self {
+    Self {
+      threshold: 256,
+      max_decompressed_size: 1 << 20,
+    }
+  }
+}
+
+impl CompressionConfig {
+  /// append the payload to `out`, compressed if that is worth it
+  pub fn compress_into(&self, data: &[u8], out: &mut Vec<u8>) {
+    if data.len() >= self.threshold {
+      let start = out.len();
+      out.push(LZ4);
+      out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+      let header = out.len();
+      out.resize(header + block::get_maximum_output_size(data.len()), 0);
+      if let Ok(len) = block::compress_into(data, &mut out[header..]) {
+        if header - start + len < data.len() + 1 {
+          out.truncate(header + len);
+          return;
+        }
+      }
+      out.truncate(start);
+    }
+    out.push(STORED);
+    out.extend_from_slice(data);
+  }
+
+  /// append the decompressed payload to `out`
+  pub fn decompress_into(&self, data: &[u8], out: &mut Vec<u8>) -> Result<(), Error> {
+    match data.split_first() {
+      Some((&STORED, data)) => {
+        out.extend_from_slice(data);
+        Ok(())
+      }
+      Some((&LZ4, data)) => {
+        let (size, data) = block::uncompressed_size(data)?;
+        if size > self.max_decompressed_size {
+          return Err(Error::TooLarge {
+            size,
+            limit: self.max_decompressed_size,
+          });
+        }
+        let start = out.len();
+        out.resize(start + size, 0);
+        match block::decompress_into(data, &mut out[start..]) {
+          Ok(len) if len == size => Ok(()),
+          Ok(_) => {
+            out.truncate(start);
+            Err(Error::Truncated)
+          }
+          Err(err) => {
+            out.truncate(start);
+            Err(err.into())
+          }
+        }
+      }
+      Some((&method, _)) => Err(Error::UnknownMethod(method)),
+      None => Err(Error::Truncated),
+    }
+  }
+}
+
+#[derive(ThisError, Debug)]
+pub enum Error {
+  #[error("compressed payload is truncated")]
+  Truncated,
+  #[error("unknown compression method {0}")]
+  UnknownMethod(u8),
+  #[error("decompressed payload of {size} bytes exceeds the limit of {limit} bytes")]
+  TooLarge { size: usize, limit: usize },
+  #[error(transparent)]
+  Corrupted(#[from] DecompressError),
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use rand::{rngs::StdRng, RngCore, SeedableRng};
+
+  fn random_bytes(len: usize) -> Vec<u8> {
+    let mut data = vec![0; len];
+    StdRng::seed_from_u64(7).fill_bytes(&mut data);
+    data
+  }
+
+  fn round_trip(config: &CompressionConfig, data: &[u8]) -> Vec<u8> {
+    let mut compressed = vec![];
+    config.compress_into(data, &mut compressed);
+    let mut decompressed = vec![];
+    config
+      .decompress_into(&compressed, &mut decompressed)
+      .unwrap();
+    assert_eq!(decompressed, data);
+    compressed
+  }
+
+  #[test]
+  fn stores_short_payloads() {
+    let config = CompressionConfig::default();
+    let data = vec![0; config.threshold - 1];
+    let compressed = round_trip(&config, &data);
+    assert_eq!(compressed[0], STORED);
+    assert_eq!(compressed.len(), data.len() + 1);
+  }
+
+  #[test]
+  fn compresses_redundant_payloads() {
+    let config = CompressionConfig::default();
+    let data = b"hello chatroom ".repeat(100);
+    let compressed = round_trip(&config, &data);
+    assert_eq!(compressed[0], LZ4);
+    assert!(compressed.len() < data.len() / 4);
+  }
+
+  #[test]
+  fn never_grows_incompressible_payloads_by_more_than_a_byte() {
+    let config = CompressionConfig::default();
+    for len in [config.threshold, 1000, 60000] {
+      let data = random_bytes(len);
+      let compressed = round_trip(&config, &data);
+      assert_eq!(compressed[0], STORED);
+      assert_eq!(compressed.len(), data.len() + 1);
+    }
+  }
+
+  #[test]
+  fn appends_to_what_is_there() {
+    let config = CompressionConfig::default();
+    let data = vec![1; 1000];
+    let mut out = vec![9, 9];
+    config.compress_into(&data, &mut out);
+    assert_eq!(&out[..2], &[9, 9]);
+
+    let mut decompressed = vec![8];
+    config
+      .decompress_into(&out[2..], &mut decompressed)
+      .unwrap();
+    assert_eq!(decompressed[0], 8);
+    assert_eq!(&decompressed[1..], &data[..]);
+  }
+
+  #[test]
+  fn rejects_payloads_above_the_limit() {
+    let data = vec![0; 4096];
+    let mut compressed = vec![];
+    CompressionConfig::default().compress_into(&data, &mut compressed);
+
+    let config = CompressionConfig {
+      max_decompressed_size: 4095,
+      ..Default::default()
+    };
+    let mut out = vec![];
+    assert!(matches!(
+      config.decompress_into(&compressed, &mut out),
+      Err(Error::TooLarge {
+        size: 4096,
+        limit: 4095
+      })
+    ));
+    assert!(out.is_empty());
+
+    let config = CompressionConfig {
+      max_decompressed_size: 4096,
+      ..Default::default()
+    };
+    config.decompress_into(&compressed, &mut out).unwrap();
+    assert_eq!(out, data);
+  }
+
+  #[test]
+  fn rejects_forged_sizes_without_inflating() {
+    let mut forged = vec![LZ4];
+    forged.extend_from_slice(&u32::MAX.to_le_bytes());
+    forged.extend_from_slice(&[0; 8]);
+    let mut out = vec![];
+    assert!(matches!(
+      CompressionConfig::default().decompress_into(&forged, &mut out),
+      Err(Error::TooLarge { .. })
+    ));
+    assert_eq!(out.capacity(), 0);
+  }
+
+  #[test]
+  fn rejects_malformed_payloads() {
+    let config = CompressionConfig::default();
+    let mut out = vec![];
+    assert!(matches!(
+      config.decompress_into(&[], &mut out),
+      Err(Error::Truncated)
+    ));
+    assert!(matches!(
+      config.decompress_into(&[7, 1, 2], &mut out),
+      Err(Error::UnknownMethod(7))
+    ));
+
+    let mut compressed = vec![];
+    config.compress_into(&b"abcd".repeat(500), &mut compressed);
+    compressed.truncate(compressed.len() / 2);
+    assert!(config.decompress_into(&compressed, &mut out).is_err());
+    assert!(out.is_empty());
+  }
+}
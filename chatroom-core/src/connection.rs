@@ -1,5 +1,5 @@
 use std::{
-  collections::{BTreeMap, HashMap},
+  collections::{BTreeMap, HashMap, HashSet, VecDeque},
   iter,
   net::SocketAddr,
   result::Result,
@@ -9,24 +9,65 @@ use std::{
 
 use thiserror::Error as ThisError;
 
-use tokio::{net::UdpSocket, sync, task, time};
+use tokio::{sync, task, time};
 
 use parking_lot::{Mutex, RwLock};
 
 use serde::{Deserialize, Serialize};
 
-use bincode::Options;
-
 use byteorder::{ByteOrder, NetworkEndian};
 
 use futures::future::try_join_all;
 
-use crate::data::{serialize_with_meta, SecureMsg};
+use crate::{
+  buffer::{BufferPool, Frame, PooledBuf},
+  codec::{self, Codec},
+  compression::{self, CompressionConfig},
+  transport::Transport,
+};
 
-use crypto_box::{aead::Aead, generate_nonce, ChaChaBox, PublicKey, SecretKey};
+use crypto_box::{aead::AeadInPlace, generate_nonce, ChaChaBox, PublicKey, SecretKey};
 
 use rand::{rngs::StdRng, thread_rng, SeedableRng};
 
+use tracing::error;
+
+/// upper bound of the plaintext coalesced into a single datagram, kept below a typical MTU so
+/// that batches never get fragmented
+const MAX_BATCH_SIZE: usize = 1200;
+
+/// largest datagram we are able to receive
+const MAX_DATAGRAM_SIZE: usize = 65535;
+/// buffers kept around for reuse once they are released
+const MAX_POOLED_BUFFERS: usize = 64;
+/// size of the authentication tag appended to sealed payloads
+const TAG_SIZE: usize = 16;
+
+// envelope, the first byte of every datagram tells what follows
+/// public key of the sender, which expects a `PEER_KEY` in reply
+const MY_KEY: u8 = 0;
+/// public key of the sender, in reply to a `MY_KEY`
+const PEER_KEY: u8 = 1;
+/// a sealed payload
+const MSG: u8 = 2;
+/// several length prefixed payloads sealed together
+const BATCH: u8 = 3;
+
+/// what a connection has been through so far, see `SecureConnection::get_stats`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ConnectionStats {
+  /// public keys received from peers, both initiated by them and in reply
+  pub key_exchanges: u64,
+  /// sealed datagrams which failed to authenticate
+  pub decryption_failures: u64,
+}
+
+#[derive(Default)]
+struct Counters {
+  key_exchanges: atomic::AtomicU64,
+  decryption_failures: atomic::AtomicU64,
+}
+
 struct SecureBox {
   coder: ChaChaBox,
   en_nonce_gen: StdRng,
@@ -36,35 +77,52 @@ struct SecureBox {
 // TODO: maybe we should merge `SecureConnection` with `Connection`
 pub struct SecureConnection<Coder>
 where
-  Coder: Options + Copy,
+  Coder: Codec,
 {
-  sock: Arc<UdpSocket>,
+  sock: Arc<dyn Transport>,
   coder: Coder,
+  pool: Arc<BufferPool>,
+  /// datagrams are read into these, they stay at full length so that nothing gets zeroed again
+  recv_pool: Arc<BufferPool>,
   pub_keys: Arc<RwLock<HashMap<SocketAddr, PublicKey>>>,
   secure_boxes: RwLock<HashMap<SocketAddr, SecureBox>>,
   pub_key_sender: sync::mpsc::Sender<(PublicKey, SocketAddr)>,
   key_response_notifier: sync::Notify,
   secret_key: Mutex<SecretKey>,
+  compression: RwLock<CompressionConfig>,
+  compressed_peers: RwLock<HashSet<SocketAddr>>,
+  batched_peers: RwLock<HashSet<SocketAddr>>,
+  outbox: Mutex<HashMap<SocketAddr, Vec<PooledBuf>>>,
+  inbox: Mutex<VecDeque<(Frame, SocketAddr)>>,
+  counters: Counters,
 }
 
-impl<Coder: 'static + Options + Copy + Send + Sync> SecureConnection<Coder> {
-  pub fn new(
-    sock: UdpSocket,
+impl<Coder: Codec> SecureConnection<Coder> {
+  pub fn new<T: Transport>(
+    sock: T,
     pub_keys: Arc<RwLock<HashMap<SocketAddr, PublicKey>>>,
     coder: Coder,
   ) -> (Self, sync::mpsc::Receiver<(PublicKey, SocketAddr)>) {
-    let sock = Arc::new(sock);
+    let sock: Arc<dyn Transport> = Arc::new(sock);
     let secret_key = Mutex::new(SecretKey::generate(&mut thread_rng()));
     let (sender, receiver) = sync::mpsc::channel(100);
     let key_response_notifier = sync::Notify::new();
     let connection = Self {
       sock,
       coder,
+      pool: BufferPool::new(MAX_DATAGRAM_SIZE, MAX_POOLED_BUFFERS),
+      recv_pool: BufferPool::new_full(MAX_DATAGRAM_SIZE, MAX_POOLED_BUFFERS),
       pub_key_sender: sender,
       pub_keys,
       secret_key,
       key_response_notifier,
       secure_boxes: Default::default(),
+      compression: Default::default(),
+      compressed_peers: Default::default(),
+      batched_peers: Default::default(),
+      outbox: Default::default(),
+      inbox: Default::default(),
+      counters: Default::default(),
     };
     connection.sync_all_pub_keys();
     (connection, receiver)
@@ -75,56 +133,78 @@ impl<Coder: 'static + Options + Copy + Send + Sync> SecureConnection<Coder> {
     self.coder
   }
 
-  pub async fn recv_from_raw(&self, buf: &mut [u8]) -> Result<(Vec<u8>, SocketAddr), Error> {
+  pub fn get_buffer_pool(&self) -> Arc<BufferPool> {
+    self.pool.clone()
+  }
+
+  pub fn get_stats(&self) -> ConnectionStats {
+    ConnectionStats {
+      key_exchanges: self.counters.key_exchanges.load(atomic::Ordering::Relaxed),
+      decryption_failures: self
+        .counters
+        .decryption_failures
+        .load(atomic::Ordering::Relaxed),
+    }
+  }
+
+  pub async fn recv_from_raw(&self) -> Result<(Frame, SocketAddr), Error> {
+    if let Some(frame) = self.inbox.lock().pop_front() {
+      return Ok(frame);
+    }
+    let mut buf = self.recv_pool.get();
     loop {
-      let (len, addr) = self.sock.recv_from(buf).await?;
-      match self.coder.deserialize::<SecureMsg>(&buf[..len])? {
-        key_msg @ (SecureMsg::PeerKey(_) | SecureMsg::MyKey(_)) => {
-          let key = match &key_msg {
-            SecureMsg::MyKey(key) => key,
-            SecureMsg::PeerKey(key) => key,
-            _ => unreachable!(),
+      let (len, addr) = self.sock.recv_from(&mut buf[..]).await?;
+      let kind = buf[..len].first().copied();
+      match kind {
+        Some(kind @ (MY_KEY | PEER_KEY)) => {
+          let key = match <[u8; 32]>::try_from(&buf[1..len]) {
+            Ok(key) => key,
+            Err(_) => break Err(Error::MalformedDatagram),
           };
-          let public_key = PublicKey::from(key.clone());
+          let public_key = PublicKey::from(key);
           self.update_pub_keys(iter::once((public_key.clone(), addr)));
-          if let Err(_) = self.pub_key_sender.send((public_key.clone(), addr)).await {
+          self
+            .counters
+            .key_exchanges
+            .fetch_add(1, atomic::Ordering::Relaxed);
+          if let Err(_) = self.pub_key_sender.send((public_key, addr)).await {
             // TODO: log error
           }
 
-          if matches!(key_msg, SecureMsg::PeerKey(_)) {
+          if kind == PEER_KEY {
             self.key_response_notifier.notify_waiters();
-          }
-          if matches!(key_msg, SecureMsg::MyKey(_)) {
-            let msg = SecureMsg::PeerKey(self.get_public_key().as_bytes().clone());
-            let buf = self.coder.serialize(&msg)?;
+          } else {
+            let mut reply = [0u8; 33];
+            reply[0] = PEER_KEY;
+            reply[1..].copy_from_slice(self.get_public_key().as_bytes());
             let sock = self.sock.clone();
             tokio::spawn(async move {
-              if let Err(_) = sock.send_to(&buf, addr).await {
+              if let Err(_) = sock.send_to(&reply, addr).await {
                 // TODO: log error
               }
             });
           }
         }
-        SecureMsg::Msg(ciphertext) => {
-          let mut secure_boxes = self.secure_boxes.write();
-          if let Some(secure_box) = secure_boxes.get_mut(&addr) {
-            let nonce = generate_nonce(&mut secure_box.de_nonce_gen);
-            let plain_data = match secure_box.coder.decrypt(&nonce, &ciphertext[..]) {
-              Ok(s) => s,
-              Err(_) => break Err(Error::DecryptionFailed),
-            };
-            break Ok((plain_data, addr));
-          } else {
-            break Err(Error::NoSrcKey);
+        Some(MSG) => {
+          break Ok((self.open(buf, len, addr)?, addr));
+        }
+        Some(BATCH) => {
+          let data = self.open(buf, len, addr)?;
+          let mut frames = self.split_batch(&data)?.into_iter();
+          if let Some(first) = frames.next() {
+            self.inbox.lock().extend(frames.map(|frame| (frame, addr)));
+            break Ok((first, addr));
           }
+          buf = self.recv_pool.get();
         }
+        _ => break Err(Error::MalformedDatagram),
       }
     }
   }
 
   pub async fn send_to_raw(&self, buf: &[u8], addr: SocketAddr) -> Result<usize, Error> {
-    let encrypted_data = self.secure_serialize(buf, addr)?;
-    self.send_to_insecurely(&encrypted_data[..], addr).await
+    let datagram = self.secure_serialize(buf, addr)?;
+    self.send_to_insecurely(&datagram[..], addr).await
   }
 
   #[inline(always)]
@@ -132,22 +212,213 @@ impl<Coder: 'static + Options + Copy + Send + Sync> SecureConnection<Coder> {
     Ok(self.sock.send_to(buf, addr).await?)
   }
 
-  fn secure_serialize(&self, buf: &[u8], addr: SocketAddr) -> Result<Vec<u8>, Error> {
+  fn secure_serialize(&self, buf: &[u8], addr: SocketAddr) -> Result<PooledBuf, Error> {
+    self.seal(MSG, buf, addr)
+  }
+
+  /// build a datagram of the given kind out of a payload for `addr`, compressing (if negotiated)
+  /// and encrypting it in place
+  fn seal(&self, kind: u8, payload: &[u8], addr: SocketAddr) -> Result<PooledBuf, Error> {
+    let mut buf = self.pool.get();
+    buf.push(kind);
+    if self.is_compression_enabled(addr) {
+      self.compression.read().compress_into(payload, &mut buf);
+    } else {
+      buf.extend_from_slice(payload);
+    }
     let mut secure_boxes = self.secure_boxes.write();
     if let Some(b) = secure_boxes.get_mut(&addr) {
       let nonce = generate_nonce(&mut b.en_nonce_gen);
-      let encrypted_data = match b.coder.encrypt(&nonce, buf) {
-        Ok(s) => s,
+      let tag = match b
+        .coder
+        .encrypt_in_place_detached(&nonce, b"", &mut buf[1..])
+      {
+        Ok(tag) => tag,
         Err(_) => return Err(Error::EncryptionFailed),
       };
-      let secure_msg = SecureMsg::Msg(encrypted_data);
-      let msg = self.coder.serialize(&secure_msg)?;
-      Ok(msg)
+      buf.extend_from_slice(&tag);
+      Ok(buf)
     } else {
       Err(Error::NoDestKey)
     }
   }
 
+  /// decrypt and decompress (if negotiated) a datagram of `len` bytes from `addr` in place
+  fn open(&self, mut buf: PooledBuf, len: usize, addr: SocketAddr) -> Result<Frame, Error> {
+    if len < 1 + TAG_SIZE {
+      return Err(Error::DecryptionFailed);
+    }
+    let tag_start = len - TAG_SIZE;
+    {
+      let mut secure_boxes = self.secure_boxes.write();
+      let secure_box = match secure_boxes.get_mut(&addr) {
+        Some(secure_box) => secure_box,
+        None => return Err(Error::NoSrcKey),
+      };
+      let nonce = generate_nonce(&mut secure_box.de_nonce_gen);
+      let (data, tag) = buf[..len].split_at_mut(tag_start);
+      if secure_box
+        .coder
+        .decrypt_in_place_detached(&nonce, b"", &mut data[1..], (&*tag).into())
+        .is_err()
+      {
+        self
+          .counters
+          .decryption_failures
+          .fetch_add(1, atomic::Ordering::Relaxed);
+        return Err(Error::DecryptionFailed);
+      }
+    }
+    if self.is_compression_enabled(addr) {
+      let mut plain_data = self.pool.get();
+      self
+        .compression
+        .read()
+        .decompress_into(&buf[1..tag_start], &mut plain_data)?;
+      Ok(Frame::new(plain_data, 0))
+    } else {
+      Ok(Frame::with_range(Arc::new(buf), 1..tag_start))
+    }
+  }
+
+  /// serialize `data` behind a request id into a pooled buffer
+  fn encode<T>(&self, data: &T, id: u16) -> Result<PooledBuf, Error>
+  where
+    T: ?Sized + Serialize,
+  {
+    let mut buf = self.pool.get();
+    buf.resize(2, 0);
+    NetworkEndian::write_u16(&mut buf[..], id);
+    self.coder.serialize_into(&mut *buf, data)?;
+    Ok(buf)
+  }
+
+  // batching related
+  /// coalesce frames queued for `addr` into a single datagram from now on, the peer must be
+  /// able to read batched datagrams
+  pub fn enable_batching(&self, addr: SocketAddr) {
+    self.batched_peers.write().insert(addr);
+  }
+
+  pub fn disable_batching(&self, addr: SocketAddr) {
+    self.batched_peers.write().remove(&addr);
+  }
+
+  pub fn is_batching_enabled(&self, addr: SocketAddr) -> bool {
+    self.batched_peers.read().contains(&addr)
+  }
+
+  /// queue a frame for `addr` until the next `flush`, peers without batching get it immediately
+  pub async fn queue_to_raw(&self, buf: &[u8], addr: SocketAddr) -> Result<(), Error> {
+    if !self.is_batching_enabled(addr) {
+      self.send_to_raw(buf, addr).await?;
+      return Ok(());
+    }
+    let mut frame = self.pool.get();
+    frame.extend_from_slice(buf);
+    let full_batch = {
+      let mut outbox = self.outbox.lock();
+      let frames = outbox.entry(addr).or_default();
+      let size = frames.iter().map(|f| f.len() + 2).sum::<usize>();
+      if !frames.is_empty() && size + buf.len() + 2 > MAX_BATCH_SIZE {
+        Some(std::mem::replace(frames, vec![frame]))
+      } else {
+        frames.push(frame);
+        None
+      }
+    };
+    if let Some(frames) = full_batch {
+      self.send_batch(frames, addr).await?;
+    }
+    Ok(())
+  }
+
+  /// send every queued frame, one datagram per peer
+  pub async fn flush(&self) -> Result<(), Error> {
+    let outbox = std::mem::take(&mut *self.outbox.lock());
+    try_join_all(
+      outbox
+        .into_iter()
+        .map(|(addr, frames)| self.send_batch(frames, addr)),
+    )
+    .await?;
+    Ok(())
+  }
+
+  /// flush queued frames periodically, the returned task should be aborted along with the
+  /// connection
+  pub fn start_batching(self: &Arc<Self>, window: Duration) -> task::JoinHandle<()> {
+    let connection = self.clone();
+    tokio::spawn(async move {
+      let mut interval = time::interval(window);
+      loop {
+        interval.tick().await;
+        if let Err(err) = connection.flush().await {
+          error!(
+            source = "internal",
+            "error occurred during flushing batches: {}.", err
+          );
+        }
+      }
+    })
+  }
+
+  async fn send_batch(&self, frames: Vec<PooledBuf>, addr: SocketAddr) -> Result<usize, Error> {
+    if let [frame] = &frames[..] {
+      return self.send_to_raw(&frame[..], addr).await;
+    }
+    let mut buf = self.pool.get();
+    for frame in frames.iter() {
+      let mut len = [0u8; 2];
+      NetworkEndian::write_u16(&mut len, frame.len() as u16);
+      buf.extend_from_slice(&len);
+      buf.extend_from_slice(&frame[..]);
+    }
+    let datagram = self.seal(BATCH, &buf[..], addr)?;
+    self.send_to_insecurely(&datagram[..], addr).await
+  }
+
+  /// the frames of a batch, all of them sharing its buffer
+  fn split_batch(&self, data: &Frame) -> Result<Vec<Frame>, Error> {
+    let mut frames = Vec::new();
+    let mut start = 0;
+    while start < data.len() {
+      if data.len() - start < 2 {
+        return Err(Error::MalformedDatagram);
+      }
+      let len = NetworkEndian::read_u16(&data[start..start + 2]) as usize;
+      let end = start + 2 + len;
+      if end > data.len() {
+        return Err(Error::MalformedDatagram);
+      }
+      frames.push(data.slice(start + 2..end));
+      start = end;
+    }
+    Ok(frames)
+  }
+
+  // compression related
+  pub fn set_compression_config(&self, config: CompressionConfig) {
+    *self.compression.write() = config;
+  }
+
+  pub fn get_compression_config(&self) -> CompressionConfig {
+    *self.compression.read()
+  }
+
+  /// compress every payload exchanged with `addr` from now on, both sides must agree on it
+  pub fn enable_compression(&self, addr: SocketAddr) {
+    self.compressed_peers.write().insert(addr);
+  }
+
+  pub fn disable_compression(&self, addr: SocketAddr) {
+    self.compressed_peers.write().remove(&addr);
+  }
+
+  pub fn is_compression_enabled(&self, addr: SocketAddr) -> bool {
+    self.compressed_peers.read().contains(&addr)
+  }
+
   // secret key related
   pub fn refresh_secret_key(&self) {
     *self.secret_key.lock() = SecretKey::generate(&mut thread_rng());
@@ -162,15 +433,17 @@ impl<Coder: 'static + Options + Copy + Send + Sync> SecureConnection<Coder> {
   }
 
   pub async fn exchange_key_with(&self, addr: SocketAddr) -> Result<(), Error> {
-    let msg = SecureMsg::MyKey(self.get_public_key().as_bytes().clone());
-    let buf = self.coder.serialize(&msg)?;
-    self.send_to_insecurely(&buf, addr).await?;
+    let mut msg = [0u8; 33];
+    msg[0] = MY_KEY;
+    msg[1..].copy_from_slice(self.get_public_key().as_bytes());
+    self.send_to_insecurely(&msg, addr).await?;
     // TODO: maybe we should remove this?
     self.key_response_notifier.notified().await;
     Ok(())
   }
 
   // public keys related
+  /// a new key starts a new connection, whatever got negotiated under the old one is dropped
   pub fn update_pub_keys<I>(&self, iter: I)
   where
     I: Iterator<Item = (PublicKey, SocketAddr)>,
@@ -178,6 +451,8 @@ impl<Coder: 'static + Options + Copy + Send + Sync> SecureConnection<Coder> {
     let secret_key = self.secret_key.lock();
     let mut secure_boxes = self.secure_boxes.write();
     let mut pub_keys = self.pub_keys.write();
+    let mut compressed_peers = self.compressed_peers.write();
+    let mut batched_peers = self.batched_peers.write();
     let my_key = secret_key.public_key();
     for (key, addr) in iter {
       let coder = ChaChaBox::new(&key, &secret_key);
@@ -192,6 +467,8 @@ impl<Coder: 'static + Options + Copy + Send + Sync> SecureConnection<Coder> {
         },
       );
       pub_keys.insert(addr, key);
+      compressed_peers.remove(&addr);
+      batched_peers.remove(&addr);
     }
   }
 
@@ -242,14 +519,17 @@ impl<Coder: 'static + Options + Copy + Send + Sync> SecureConnection<Coder> {
   pub fn release(&self, addr: SocketAddr) {
     self.pub_keys.write().remove(&addr);
     self.secure_boxes.write().remove(&addr);
+    self.compressed_peers.write().remove(&addr);
+    self.batched_peers.write().remove(&addr);
+    self.outbox.lock().remove(&addr);
   }
 
   // recv and send helper
-  pub async fn recv_from<T>(&self, buf: &mut [u8]) -> Result<(T, SocketAddr), Error>
+  pub async fn recv_from<T>(&self) -> Result<(T, SocketAddr), Error>
   where
     T: for<'de> Deserialize<'de>,
   {
-    let (data, addr) = self.recv_from_raw(buf).await?;
+    let (data, addr) = self.recv_from_raw().await?;
     Ok((self.coder.deserialize(&data[..])?, addr))
   }
 
@@ -263,7 +543,7 @@ impl<Coder: 'static + Options + Copy + Send + Sync> SecureConnection<Coder> {
     T: Serialize,
     I: Iterator<Item = SocketAddr>,
   {
-    let buf = serialize_with_meta(self.coder, data, id)?;
+    let buf = self.encode(data, id)?;
     Ok(try_join_all(addrs.map(|addr| self.send_to_raw(&buf, addr))).await?)
   }
 
@@ -276,17 +556,30 @@ impl<Coder: 'static + Options + Copy + Send + Sync> SecureConnection<Coder> {
     T: Serialize,
     I: Iterator<Item = SocketAddr>,
   {
-    let mut buf = vec![0u8; 2];
-    self.coder.serialize_into(&mut buf, data)?;
-
+    let buf = self.encode(data, 0)?;
     Ok(try_join_all(addrs.map(|addr| self.send_to_raw(&buf, addr))).await?)
   }
 
+  pub async fn queue_to_multiple_with_empty_meta<T, I>(
+    &self,
+    data: &T,
+    addrs: I,
+  ) -> Result<(), Error>
+  where
+    T: Serialize,
+    I: Iterator<Item = SocketAddr>,
+  {
+    let buf = self.encode(data, 0)?;
+    try_join_all(addrs.map(|addr| self.queue_to_raw(&buf, addr))).await?;
+    Ok(())
+  }
+
   pub async fn send_to<T>(&self, data: &T, addr: SocketAddr) -> Result<usize, Error>
   where
     T: Serialize,
   {
-    let buf = self.coder.serialize(data)?;
+    let mut buf = self.pool.get();
+    self.coder.serialize_into(&mut *buf, data)?;
     Ok(self.send_to_raw(&buf, addr).await?)
   }
 
@@ -299,9 +592,7 @@ impl<Coder: 'static + Options + Copy + Send + Sync> SecureConnection<Coder> {
   where
     T: Serialize,
   {
-    let mut buf = vec![0u8; 2];
-    NetworkEndian::write_u16(&mut buf[..], id);
-    self.coder.serialize_into(&mut buf, data)?;
+    let buf = self.encode(data, id)?;
     Ok(self.send_to_raw(&buf, addr).await?)
   }
 
@@ -309,18 +600,17 @@ impl<Coder: 'static + Options + Copy + Send + Sync> SecureConnection<Coder> {
   where
     T: Serialize,
   {
-    let mut buf = vec![0u8; 2];
-    self.coder.serialize_into(&mut buf, data)?;
+    let buf = self.encode(data, 0)?;
     Ok(self.send_to_raw(&buf, addr).await?)
   }
 }
 
 pub struct Connection<Coder>
 where
-  Coder: Options + Copy,
+  Coder: Codec,
 {
   // TODO: use a flatten BtreeMap
-  pending_works: Arc<Mutex<BTreeMap<SocketAddr, BTreeMap<u16, sync::oneshot::Sender<Vec<u8>>>>>>,
+  pending_works: Arc<Mutex<BTreeMap<SocketAddr, BTreeMap<u16, sync::oneshot::Sender<Frame>>>>>,
   counters: Arc<Mutex<BTreeMap<SocketAddr, atomic::AtomicU16>>>,
   inner: Arc<SecureConnection<Coder>>,
   listener: task::JoinHandle<()>,
@@ -328,43 +618,45 @@ where
   retry_limits: u32,
 }
 
-impl<Coder: 'static + Options + Copy + Send + Sync> Connection<Coder> {
+impl<Coder: Codec> Connection<Coder> {
   pub fn as_inner(&self) -> &SecureConnection<Coder> {
     &self.inner
   }
 
-  pub fn new(
-    sock: UdpSocket,
+  pub fn new<T: Transport>(
+    sock: T,
     coder: Coder,
     pub_keys: Arc<RwLock<HashMap<SocketAddr, PublicKey>>>,
     timeout: Duration,
     retry_limits: u32,
   ) -> (
     Self,
-    sync::mpsc::Receiver<(Vec<u8>, SocketAddr)>,
+    sync::mpsc::Receiver<(Frame, SocketAddr)>,
     sync::mpsc::Receiver<(PublicKey, SocketAddr)>,
   ) {
     let pending_works = Arc::new(Mutex::new(BTreeMap::<
       SocketAddr,
-      BTreeMap<u16, sync::oneshot::Sender<Vec<u8>>>,
+      BTreeMap<u16, sync::oneshot::Sender<Frame>>,
     >::new()));
     let (connection, pub_key_receiver) = SecureConnection::new(sock, pub_keys, coder);
     let connection = Arc::new(connection);
 
-    let (sender, receiver) = sync::mpsc::channel::<(Vec<u8>, SocketAddr)>(100);
+    let (sender, receiver) = sync::mpsc::channel::<(Frame, SocketAddr)>(100);
 
     let listener = tokio::spawn({
       let connection = connection.clone();
       let pending_works = pending_works.clone();
       async move {
-        let mut buf = vec![0; 65535];
         loop {
-          let (data, addr) = match connection.recv_from_raw(&mut buf).await {
+          let (mut data, addr) = match connection.recv_from_raw().await {
             Ok(r) => r,
             Err(_) => continue, // TODO: log error
           };
+          if data.len() < 2 {
+            continue; // TODO: log error
+          }
           let id = NetworkEndian::read_u16(&data[..]);
-          let data = data[2..].to_vec();
+          data.advance(2);
           if id != 0 {
             let mut pending_works = pending_works.lock();
             if let Some(pending_works) = pending_works.get_mut(&addr) {
@@ -402,12 +694,8 @@ impl<Coder: 'static + Options + Copy + Send + Sync> Connection<Coder> {
     Req: Serialize,
     Res: for<'de> Deserialize<'de>,
   {
-    let mut buf = vec![0u8, 2];
     let id = self.get_unique_id(addr);
-    NetworkEndian::write_u16(&mut buf[..], id);
-
-    self.inner.get_coder().serialize_into(&mut buf, req)?;
-
+    let buf = self.inner.encode(req, id)?;
     let buf = self.inner.secure_serialize(&buf[..], addr)?;
 
     let mut retry_counter = self.retry_limits;
@@ -415,7 +703,7 @@ impl<Coder: 'static + Options + Copy + Send + Sync> Connection<Coder> {
     loop {
       self.inner.send_to_insecurely(&buf, addr).await?;
 
-      let (tx, rx) = sync::oneshot::channel::<Vec<u8>>();
+      let (tx, rx) = sync::oneshot::channel::<Frame>();
       self
         .pending_works
         .lock()
@@ -426,7 +714,7 @@ impl<Coder: 'static + Options + Copy + Send + Sync> Connection<Coder> {
       match time::timeout(self.timeout, rx).await {
         Ok(buf) => {
           let buf = buf?;
-          return Ok(self.inner.get_coder().deserialize::<Res>(&buf)?);
+          return Ok(self.inner.get_coder().deserialize::<Res>(&buf[..])?);
         }
         Err(err) => {
           retry_counter -= 1;
@@ -454,7 +742,7 @@ impl<Coder: 'static + Options + Copy + Send + Sync> Connection<Coder> {
   }
 }
 
-impl<Coder: Options + Copy> Drop for Connection<Coder> {
+impl<Coder: Codec> Drop for Connection<Coder> {
   fn drop(&mut self) {
     self.listener.abort();
   }
@@ -467,7 +755,7 @@ pub enum Error {
   #[error(transparent)]
   Timeout(#[from] time::error::Elapsed),
   #[error(transparent)]
-  CorruptedData(#[from] bincode::Error),
+  CorruptedData(#[from] codec::Error),
   #[error(transparent)]
   OneShotReceiveError(#[from] sync::oneshot::error::RecvError),
   #[error("mpsc channel closed")]
@@ -476,8 +764,179 @@ pub enum Error {
   EncryptionFailed,
   #[error("error occurred during decryption")]
   DecryptionFailed,
+  #[error(transparent)]
+  Compression(#[from] compression::Error),
+  #[error("datagram is malformed")]
+  MalformedDatagram,
   #[error("public key for given destination not found")]
   NoDestKey,
   #[error("public key for given source not found")]
   NoSrcKey,
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use tokio::net::UdpSocket;
+
+  use crate::data::default_coder;
+
+  async fn connection() -> SecureConnection<crate::data::DefaultCoder> {
+    let sock = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    SecureConnection::new(sock, Default::default(), default_coder()).0
+  }
+
+  #[tokio::test]
+  async fn new_key_drops_negotiated_codecs() {
+    let connection = connection().await;
+    let addr = "127.0.0.1:4000".parse().unwrap();
+    let key = SecretKey::generate(&mut thread_rng()).public_key();
+    connection.update_pub_keys(iter::once((key.clone(), addr)));
+    connection.enable_compression(addr);
+    connection.enable_batching(addr);
+
+    connection.update_pub_keys(iter::once((key, addr)));
+    assert!(!connection.is_compression_enabled(addr));
+    assert!(!connection.is_batching_enabled(addr));
+  }
+
+  /// two connections on localhost which know each other's key
+  async fn pair() -> (
+    SecureConnection<crate::data::DefaultCoder>,
+    SecureConnection<crate::data::DefaultCoder>,
+  ) {
+    let (a, b) = (connection().await, connection().await);
+    let a_addr = a.sock.local_addr().unwrap();
+    let b_addr = b.sock.local_addr().unwrap();
+    a.update_pub_keys(iter::once((b.get_public_key(), b_addr)));
+    b.update_pub_keys(iter::once((a.get_public_key(), a_addr)));
+    (a, b)
+  }
+
+  #[tokio::test]
+  async fn delivers_batched_frames_in_order() {
+    let (a, b) = pair().await;
+    let b_addr = b.sock.local_addr().unwrap();
+    a.enable_batching(b_addr);
+    for frame in [&b"one"[..], b"two", b"three"] {
+      a.queue_to_raw(frame, b_addr).await.unwrap();
+    }
+    a.flush().await.unwrap();
+
+    for expected in [&b"one"[..], b"two", b"three"] {
+      let (frame, addr) = b.recv_from_raw().await.unwrap();
+      assert_eq!(&frame[..], expected);
+      assert_eq!(addr, a.sock.local_addr().unwrap());
+    }
+  }
+
+  fn batch(frames: &[&[u8]]) -> Vec<u8> {
+    let mut data = vec![];
+    for frame in frames {
+      data.extend_from_slice(&(frame.len() as u16).to_be_bytes());
+      data.extend_from_slice(frame);
+    }
+    data
+  }
+
+  fn frame(connection: &SecureConnection<crate::data::DefaultCoder>, data: &[u8]) -> Frame {
+    let mut buf = connection.pool.get();
+    buf.extend_from_slice(data);
+    Frame::new(buf, 0)
+  }
+
+  #[tokio::test]
+  async fn splits_batches() {
+    let connection = connection().await;
+    let frames = connection
+      .split_batch(&frame(&connection, &batch(&[b"first", b"", b"third"])))
+      .unwrap();
+    let frames = frames.iter().map(|frame| &frame[..]).collect::<Vec<_>>();
+    assert_eq!(frames, [&b"first"[..], b"", b"third"]);
+    assert!(connection
+      .split_batch(&frame(&connection, &[]))
+      .unwrap()
+      .is_empty());
+  }
+
+  #[tokio::test]
+  async fn receives_without_copying_or_zeroing() {
+    let (a, b) = pair().await;
+    let b_addr = b.sock.local_addr().unwrap();
+    a.enable_batching(b_addr);
+    a.queue_to_raw(b"one", b_addr).await.unwrap();
+    a.queue_to_raw(b"two", b_addr).await.unwrap();
+    a.flush().await.unwrap();
+
+    let (first, _) = b.recv_from_raw().await.unwrap();
+    let (second, _) = b.recv_from_raw().await.unwrap();
+    assert_eq!(
+      first.as_ptr().wrapping_add(first.len() + 2),
+      second.as_ptr()
+    );
+
+    drop((first, second));
+    assert_eq!(b.recv_pool.pooled(), 1);
+    assert_eq!(b.recv_pool.get().len(), MAX_DATAGRAM_SIZE);
+  }
+
+  #[tokio::test]
+  async fn rejects_malformed_batches() {
+    let connection = connection().await;
+    let mut data = batch(&[b"first", b"second"]);
+
+    // a length prefix cut in half
+    data.push(0);
+    assert!(matches!(
+      connection.split_batch(&frame(&connection, &data)),
+      Err(Error::MalformedDatagram)
+    ));
+
+    // a frame longer than what is left
+    data.pop();
+    data.truncate(data.len() - 1);
+    assert!(matches!(
+      connection.split_batch(&frame(&connection, &data)),
+      Err(Error::MalformedDatagram)
+    ));
+
+    // a length prefix pointing far past the end
+    assert!(matches!(
+      connection.split_batch(&frame(&connection, &[0xff, 0xff, 1, 2, 3])),
+      Err(Error::MalformedDatagram)
+    ));
+  }
+
+  #[tokio::test]
+  async fn matches_responses_to_requests() {
+    let peer = connection().await;
+    let peer_addr = peer.sock.local_addr().unwrap();
+    let sock = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = sock.local_addr().unwrap();
+    let (client, _, _) = Connection::new(
+      sock,
+      default_coder(),
+      Default::default(),
+      Duration::from_secs(5),
+      1,
+    );
+    client
+      .as_inner()
+      .update_pub_keys(iter::once((peer.get_public_key(), peer_addr)));
+    peer.update_pub_keys(iter::once((client.as_inner().get_public_key(), addr)));
+
+    let answer = async {
+      let (frame, from) = peer.recv_from_raw().await.unwrap();
+      let id = NetworkEndian::read_u16(&frame[..]);
+      let request = default_coder().deserialize::<String>(&frame[2..]).unwrap();
+      peer
+        .send_to_with_meta(&format!("{} back", request), from, id)
+        .await
+        .unwrap();
+    };
+    let request = "hello".to_string();
+    let (response, _) = tokio::join!(client.request::<_, String>(&request, peer_addr), answer);
+    assert_eq!(response.unwrap(), "hello back");
+  }
+}
@@ -1,28 +1,84 @@
 use time::OffsetDateTime;
 
-use std::net::SocketAddr;
+use std::{
+  net::SocketAddr,
+  ops::{BitAnd, BitOr},
+};
 
 use thiserror::Error as ThisError;
 
 use serde::{Deserialize, Serialize};
 
 #[allow(deprecated)]
-use bincode::{config, DefaultOptions, Error as BinCodeError, Options};
+use bincode::{config, DefaultOptions, Options};
 
 use byteorder::{ByteOrder, NetworkEndian};
 
-#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
-pub enum SecureMsg {
-  MyKey([u8; 32]),
-  PeerKey([u8; 32]),
-  Msg(Vec<u8>),
+use crate::codec::{self, Bincode, Codec};
+
+/// version of the wire protocol spoken by this build
+pub const PROTOCOL_VERSION: u16 = 8;
+/// oldest protocol version this build is still able to talk to
+pub const MIN_PROTOCOL_VERSION: u16 = 8;
+
+/// set of optional protocol features, negotiated through `Command::Hello`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub struct Capabilities(u32);
+
+impl Capabilities {
+  pub const COMPRESSION: Self = Self(1 << 0);
+  pub const RELAY: Self = Self(1 << 1);
+  pub const BATCHING: Self = Self(1 << 2);
+
+  pub const fn empty() -> Self {
+    Self(0)
+  }
+
+  pub const fn bits(self) -> u32 {
+    self.0
+  }
+
+  pub const fn contains(self, other: Self) -> bool {
+    self.0 & other.0 == other.0
+  }
+
+  pub const fn intersection(self, other: Self) -> Self {
+    Self(self.0 & other.0)
+  }
+
+  pub const fn union(self, other: Self) -> Self {
+    Self(self.0 | other.0)
+  }
+}
+
+impl BitAnd for Capabilities {
+  type Output = Self;
+
+  fn bitand(self, rhs: Self) -> Self {
+    self.intersection(rhs)
+  }
+}
+
+impl BitOr for Capabilities {
+  type Output = Self;
+
+  fn bitor(self, rhs: Self) -> Self {
+    self.union(rhs)
+  }
 }
 
 #[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 pub struct User {
   pub name: String,
   pub password_hash: String,
-  pub online_info: Option<UserOnlineInfo>,
+  /// one for every device the user is logged in from
+  pub devices: Vec<UserOnlineInfo>,
+}
+
+impl User {
+  pub fn is_online(&self) -> bool {
+    !self.devices.is_empty()
+  }
 }
 
 impl From<(String, UserEssential)> for User {
@@ -31,12 +87,13 @@ impl From<(String, UserEssential)> for User {
     Self {
       name,
       password_hash,
-      online_info: None,
+      devices: vec![],
     }
   }
 }
 #[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 pub struct UserEssential {
+  /// an encoded `srp::Verifier`, or an argon2 hash left from before protocol version 3
   pub password_hash: String,
 }
 
@@ -58,43 +115,207 @@ pub struct UserOnlineInfo {
 #[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 pub struct UserInfo {
   pub name: String,
-  pub online_info: Option<UserOnlineInfo>,
+  /// messages to the user go to every one of them
+  pub devices: Vec<UserOnlineInfo>,
 }
 
 impl UserInfo {
   pub fn new(user: &User) -> Self {
-    let User {
-      name, online_info, ..
-    } = user.clone();
-    Self { name, online_info }
+    let User { name, devices, .. } = user.clone();
+    Self { name, devices }
   }
+
+  pub fn is_online(&self) -> bool {
+    !self.devices.is_empty()
+  }
+}
+
+/// handed out on login, lets a client pick its session back up with `Command::Resume` instead
+/// of proving its password again
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct SessionToken {
+  pub id: [u8; 16],
+  pub name: String,
+  pub expires: OffsetDateTime,
+  /// made by the server over the fields above
+  pub signature: [u8; 32],
+}
+
+/// a session of the user, as listed by `Command::ListSessions`
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct SessionInfo {
+  pub id: [u8; 16],
+  /// as named by the client which logged in
+  pub device: String,
+  /// where the session was last used from
+  pub ip_address: SocketAddr,
+  pub logged_in: OffsetDateTime,
+  pub last_heartbeat: OffsetDateTime,
+  pub expires: OffsetDateTime,
+  /// whether the device is online right now, the others may still resume the session
+  pub online: bool,
+  /// whether it is the session of the device asking
+  pub current: bool,
 }
 
 #[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 #[non_exhaustive]
 pub enum Command {
+  /// carries an `srp::Verifier` of the password, never the password itself
   Register {
     username: String,
-    password: [u8; 32],
+    salt: Vec<u8>,
+    verifier: Vec<u8>,
   },
+  /// answers the challenge of a preceding `Authenticate`, `code` is a one-time code or a
+  /// recovery code, required once the user has enrolled in two-factor authentication
   Login {
+    proof: Vec<u8>,
+    device: String,
+    code: Option<String>,
+  },
+  /// log in with the password itself, for servers checking passwords with a provider other
+  /// than their own store, which answer `Authenticate` with `ErrorCode::PasswordRequired`
+  PasswordLogin {
     username: String,
-    password: [u8; 32],
+    password: String,
+    device: String,
+    code: Option<String>,
   },
+  /// answers the challenge of a preceding `Authenticate` for the current password
   ChangePassword {
-    old: [u8; 32],
-    new: [u8; 32],
+    proof: Vec<u8>,
+    salt: Vec<u8>,
+    verifier: Vec<u8>,
   },
   GetChatroomStatus,
   Heartbeat,
   Logout,
+  Hello {
+    version: u16,
+    capabilities: Capabilities,
+  },
+  /// starts an srp handshake, answered with `ResponseData::Challenge`
+  Authenticate {
+    username: String,
+    public_key: Vec<u8>,
+  },
+  /// log in again from a new connection, answered with `ResponseData::ChatroomStatus`
+  Resume {
+    token: SessionToken,
+    device: String,
+  },
+  /// answered with `ResponseData::Sessions`
+  ListSessions,
+  /// sign a session of the user out, the device is told with `Notification::SessionRevoked`
+  RevokeSession {
+    id: [u8; 16],
+  },
+  /// answered with `ResponseData::TotpProvisioning`, it only takes effect once confirmed
+  EnrollTotp,
+  /// finish an enrolment with a code from the authenticator, answered with
+  /// `ResponseData::RecoveryCodes`
+  ConfirmTotp {
+    code: String,
+  },
+  /// `code` is a one-time code or a recovery code
+  DisableTotp {
+    code: String,
+  },
+  /// trade the password digest of an account from before srp, the sha-256 of the password
+  /// without leading whitespace, for a verifier, worth a try once logging in fails, as servers
+  /// do not tell which accounts predate srp
+  MigratePassword {
+    username: String,
+    digest: [u8; 32],
+    salt: Vec<u8>,
+    verifier: Vec<u8>,
+  },
+}
+
+impl Command {
+  /// names of the variants in the order of `index`, e.g. for labelling metrics
+  pub const NAMES: [&'static str; 16] = [
+    "Register",
+    "Login",
+    "PasswordLogin",
+    "ChangePassword",
+    "GetChatroomStatus",
+    "Heartbeat",
+    "Logout",
+    "Hello",
+    "Authenticate",
+    "Resume",
+    "ListSessions",
+    "RevokeSession",
+    "EnrollTotp",
+    "ConfirmTotp",
+    "DisableTotp",
+    "MigratePassword",
+  ];
+
+  /// position of the variant in `NAMES`
+  pub fn index(&self) -> usize {
+    match self {
+      Self::Register { .. } => 0,
+      Self::Login { .. } => 1,
+      Self::PasswordLogin { .. } => 2,
+      Self::ChangePassword { .. } => 3,
+      Self::GetChatroomStatus => 4,
+      Self::Heartbeat => 5,
+      Self::Logout => 6,
+      Self::Hello { .. } => 7,
+      Self::Authenticate { .. } => 8,
+      Self::Resume { .. } => 9,
+      Self::ListSessions => 10,
+      Self::RevokeSession { .. } => 11,
+      Self::EnrollTotp => 12,
+      Self::ConfirmTotp { .. } => 13,
+      Self::DisableTotp { .. } => 14,
+      Self::MigratePassword { .. } => 15,
+    }
+  }
+
+  /// name of the variant, e.g. for labelling metrics
+  pub fn name(&self) -> &'static str {
+    Self::NAMES[self.index()]
+  }
 }
 
 #[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 #[non_exhaustive]
 pub enum ResponseData {
   Success,
-  ChatroomStatus { users: Vec<UserInfo> },
+  ChatroomStatus {
+    users: Vec<UserInfo>,
+  },
+  Hello {
+    version: u16,
+    capabilities: Capabilities,
+  },
+  Challenge {
+    salt: Vec<u8>,
+    public_key: Vec<u8>,
+  },
+  /// `proof` lets the client check that the server knows its verifier, it is empty after
+  /// `Command::PasswordLogin`
+  LoggedIn {
+    proof: Vec<u8>,
+    users: Vec<UserInfo>,
+    token: SessionToken,
+  },
+  Sessions {
+    sessions: Vec<SessionInfo>,
+  },
+  /// `secret` in base32 for typing in, `uri` for showing as a qr code
+  TotpProvisioning {
+    secret: String,
+    uri: String,
+  },
+  /// each of them stands in for a one-time code once, they are not shown again
+  RecoveryCodes {
+    codes: Vec<String>,
+  },
 }
 
 pub type Response = Result<ResponseData, ErrorCode>;
@@ -102,15 +323,27 @@ pub type Response = Result<ResponseData, ErrorCode>;
 #[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 #[non_exhaustive]
 pub enum Notification {
+  /// a device of the user came online, the first one brings the user online
   Online {
     timestamp: OffsetDateTime,
     name: String,
     info: UserOnlineInfo,
   },
+  /// the device at `ip_address` went offline, the user is offline once none is left
   Offline {
     timestamp: OffsetDateTime,
     name: String,
+    ip_address: SocketAddr,
+  },
+  /// message from the server operator to everyone online
+  Announcement {
+    timestamp: OffsetDateTime,
+    msg: String,
   },
+  /// the session of this device got revoked from another one, it is logged out
+  SessionRevoked { timestamp: OffsetDateTime },
+  /// an operator kicked or banned the user, this device is logged out
+  Kicked { timestamp: OffsetDateTime },
 }
 
 #[derive(ThisError, Clone, Debug, PartialEq, Deserialize, Serialize)]
@@ -134,6 +367,95 @@ pub enum ErrorCode {
   // general
   #[error("operation is not supported")]
   Unsupported,
+  // handshake
+  #[error("protocol version {version} is not supported, expecting {min} to {max}")]
+  IncompatibleVersion { version: u16, min: u16, max: u16 },
+  // server
+  #[error("internal server error")]
+  Internal,
+  #[error("too many requests, please slow down")]
+  RateLimited,
+  // register
+  #[error("registration is closed")]
+  RegistrationClosed,
+  // login
+  #[error("user or address is banned")]
+  Banned,
+  #[error("too many failed login attempts, please retry after {retry_after} seconds")]
+  Throttled { retry_after: u64 },
+  #[error("server needs the password itself to check it")]
+  PasswordRequired,
+  // resume
+  #[error("session is expired or revoked, please login again")]
+  SessionExpired,
+  #[error("session is not existed")]
+  SessionNotExisted,
+  // two-factor
+  #[error("one-time code is required")]
+  SecondFactorRequired,
+  #[error("one-time code is invalid")]
+  InvalidOneTimeCode,
+  #[error("two-factor authentication is not enabled")]
+  TotpNotEnrolled,
+  #[error("two-factor authentication is already enabled")]
+  TotpEnrolled,
+}
+
+impl ErrorCode {
+  /// names of the variants in the order of `index`, e.g. for labelling metrics
+  pub const NAMES: [&'static str; 20] = [
+    "UserExisted",
+    "InvalidUserOrPass",
+    "LoginRequired",
+    "UserOffline",
+    "UserNotExisted",
+    "ConnectionNotSecure",
+    "Unsupported",
+    "IncompatibleVersion",
+    "Internal",
+    "RateLimited",
+    "RegistrationClosed",
+    "Banned",
+    "Throttled",
+    "PasswordRequired",
+    "SessionExpired",
+    "SessionNotExisted",
+    "SecondFactorRequired",
+    "InvalidOneTimeCode",
+    "TotpNotEnrolled",
+    "TotpEnrolled",
+  ];
+
+  /// position of the variant in `NAMES`
+  pub fn index(&self) -> usize {
+    match self {
+      Self::UserExisted => 0,
+      Self::InvalidUserOrPass => 1,
+      Self::LoginRequired => 2,
+      Self::UserOffline => 3,
+      Self::UserNotExisted => 4,
+      Self::ConnectionNotSecure => 5,
+      Self::Unsupported => 6,
+      Self::IncompatibleVersion { .. } => 7,
+      Self::Internal => 8,
+      Self::RateLimited => 9,
+      Self::RegistrationClosed => 10,
+      Self::Banned => 11,
+      Self::Throttled { .. } => 12,
+      Self::PasswordRequired => 13,
+      Self::SessionExpired => 14,
+      Self::SessionNotExisted => 15,
+      Self::SecondFactorRequired => 16,
+      Self::InvalidOneTimeCode => 17,
+      Self::TotpNotEnrolled => 18,
+      Self::TotpEnrolled => 19,
+    }
+  }
+
+  /// name of the variant, e.g. for labelling metrics
+  pub fn name(&self) -> &'static str {
+    Self::NAMES[self.index()]
+  }
 }
 
 #[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
@@ -143,24 +465,28 @@ pub struct Message {
   pub msg: String,
 }
 
-pub type DefaultCoder = config::WithOtherEndian<
-  config::WithOtherTrailing<
-    config::WithOtherIntEncoding<config::DefaultOptions, config::FixintEncoding>,
-    config::AllowTrailing,
+pub type DefaultCoder = Bincode<
+  config::WithOtherEndian<
+    config::WithOtherTrailing<
+      config::WithOtherIntEncoding<config::DefaultOptions, config::FixintEncoding>,
+      config::AllowTrailing,
+    >,
+    config::BigEndian,
   >,
-  config::BigEndian,
 >;
 
 pub fn default_coder() -> DefaultCoder {
-  DefaultOptions::new()
-    .with_fixint_encoding()
-    .allow_trailing_bytes()
-    .with_big_endian()
+  Bincode(
+    DefaultOptions::new()
+      .with_fixint_encoding()
+      .allow_trailing_bytes()
+      .with_big_endian(),
+  )
 }
 
-pub fn serialize_with_meta<C, T>(coder: C, data: &T, id: u16) -> Result<Vec<u8>, BinCodeError>
+pub fn serialize_with_meta<C, T>(coder: C, data: &T, id: u16) -> Result<Vec<u8>, codec::Error>
 where
-  C: Options,
+  C: Codec,
   T: Serialize,
 {
   let mut buf = vec![0u8; 2];
@@ -168,3 +494,119 @@ where
   coder.serialize_into(&mut buf, data)?;
   Ok(buf)
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// name of the variant as printed by `Debug`
+  fn variant(value: &impl std::fmt::Debug) -> String {
+    format!("{:?}", value)
+      .chars()
+      .take_while(char::is_ascii_alphanumeric)
+      .collect()
+  }
+
+  #[test]
+  fn names_every_command() {
+    let token = SessionToken {
+      id: [0; 16],
+      name: String::new(),
+      expires: OffsetDateTime::UNIX_EPOCH,
+      signature: [0; 32],
+    };
+    let commands = [
+      Command::Register {
+        username: String::new(),
+        salt: vec![],
+        verifier: vec![],
+      },
+      Command::Login {
+        proof: vec![],
+        device: String::new(),
+        code: None,
+      },
+      Command::PasswordLogin {
+        username: String::new(),
+        password: String::new(),
+        device: String::new(),
+        code: None,
+      },
+      Command::ChangePassword {
+        proof: vec![],
+        salt: vec![],
+        verifier: vec![],
+      },
+      Command::GetChatroomStatus,
+      Command::Heartbeat,
+      Command::Logout,
+      Command::Hello {
+        version: PROTOCOL_VERSION,
+        capabilities: Capabilities::empty(),
+      },
+      Command::Authenticate {
+        username: String::new(),
+        public_key: vec![],
+      },
+      Command::Resume {
+        token,
+        device: String::new(),
+      },
+      Command::ListSessions,
+      Command::RevokeSession { id: [0; 16] },
+      Command::EnrollTotp,
+      Command::ConfirmTotp {
+        code: String::new(),
+      },
+      Command::DisableTotp {
+        code: String::new(),
+      },
+      Command::MigratePassword {
+        username: String::new(),
+        digest: [0; 32],
+        salt: vec![],
+        verifier: vec![],
+      },
+    ];
+    assert_eq!(commands.len(), Command::NAMES.len());
+    for (i, command) in commands.iter().enumerate() {
+      assert_eq!(command.index(), i);
+      assert_eq!(command.name(), variant(command));
+    }
+  }
+
+  #[test]
+  fn names_every_error_code() {
+    let codes = [
+      ErrorCode::UserExisted,
+      ErrorCode::InvalidUserOrPass,
+      ErrorCode::LoginRequired,
+      ErrorCode::UserOffline,
+      ErrorCode::UserNotExisted,
+      ErrorCode::ConnectionNotSecure,
+      ErrorCode::Unsupported,
+      ErrorCode::IncompatibleVersion {
+        version: 0,
+        min: 0,
+        max: 0,
+      },
+      ErrorCode::Internal,
+      ErrorCode::RateLimited,
+      ErrorCode::RegistrationClosed,
+      ErrorCode::Banned,
+      ErrorCode::Throttled { retry_after: 0 },
+      ErrorCode::PasswordRequired,
+      ErrorCode::SessionExpired,
+      ErrorCode::SessionNotExisted,
+      ErrorCode::SecondFactorRequired,
+      ErrorCode::InvalidOneTimeCode,
+      ErrorCode::TotpNotEnrolled,
+      ErrorCode::TotpEnrolled,
+    ];
+    assert_eq!(codes.len(), ErrorCode::NAMES.len());
+    for (i, code) in codes.iter().enumerate() {
+      assert_eq!(code.index(), i);
+      assert_eq!(code.name(), variant(code));
+    }
+  }
+}
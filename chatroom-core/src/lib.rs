@@ -1,3 +1,11 @@
+pub mod buffer;
+pub mod codec;
+pub mod compression;
 pub mod connection;
 pub mod data;
+pub mod settings;
+pub mod sim;
+pub mod srp;
+pub mod totp;
+pub mod transport;
 pub mod utils;
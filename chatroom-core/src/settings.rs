@@ -0,0 +1,190 @@
+use thiserror::Error as ThisError;
+
+use std::{fs, io, path::Path};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+/// settings kept in a versioned json file
+pub trait Settings: Default + Serialize + DeserializeOwned {
+  /// bump whenever a change can not be handled by `#[serde(default)]` alone
+  const VERSION: u32;
+
+  fn validate(&self) -> Result<(), String>;
+
+  /// bring settings written by an older version up to date
+  fn migrate(version: u32, _settings: serde_json::Value) -> Result<Self, Error> {
+    Err(Error::UnknownVersion(version))
+  }
+}
+
+#[derive(Deserialize)]
+struct RawFile {
+  version: u32,
+  settings: serde_json::Value,
+}
+
+#[derive(Serialize)]
+struct File<'a, T> {
+  version: u32,
+  settings: &'a T,
+}
+
+/// read settings from `path`, defaults are returned if the file does not exist yet
+pub fn load<T: Settings>(path: &Path) -> Result<T, Error> {
+  let content = match fs::read_to_string(path) {
+    Ok(content) => content,
+    Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(T::default()),
+    Err(err) => return Err(err.into()),
+  };
+  let RawFile { version, settings } = serde_json::from_str(&content)?;
+  let settings = if version == T::VERSION {
+    serde_json::from_value(settings)?
+  } else if version < T::VERSION {
+    T::migrate(version, settings)?
+  } else {
+    return Err(Error::UnknownVersion(version));
+  };
+  settings.validate().map_err(Error::Invalid)?;
+  Ok(settings)
+}
+
+/// write settings to `path` through a temporary file, so a crash never leaves it half written
+pub fn save<T: Settings>(path: &Path, settings: &T) -> Result<(), Error> {
+  settings.validate().map_err(Error::Invalid)?;
+  if let Some(dir) = path.parent() {
+    fs::create_dir_all(dir)?;
+  }
+  let content = serde_json::to_string_pretty(&File {
+    version: T::VERSION,
+    settings,
+  })?;
+  let tmp = path.with_extension("tmp");
+  fs::write(&tmp, content)?;
+  fs::rename(&tmp, path)?;
+  Ok(())
+}
+
+#[derive(ThisError, Debug)]
+pub enum Error {
+  #[error(transparent)]
+  IO(#[from] io::Error),
+  #[error(transparent)]
+  Json(#[from] serde_json::Error),
+  #[error("settings version {0} is not supported")]
+  UnknownVersion(u32),
+  #[error("invalid settings: {0}")]
+  Invalid(String),
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use std::path::PathBuf;
+
+  use serde_json::json;
+
+  /// version 1 called the port `server_port`
+  #[derive(Debug, Default, PartialEq, Deserialize, Serialize)]
+  #[serde(default)]
+  struct Test {
+    port: u16,
+    name: String,
+  }
+
+  impl Settings for Test {
+    const VERSION: u32 = 2;
+
+    fn validate(&self) -> Result<(), String> {
+      if self.name.len() > 8 {
+        return Err("name is too long".to_string());
+      }
+      Ok(())
+    }
+
+    fn migrate(version: u32, settings: serde_json::Value) -> Result<Self, Error> {
+      match version {
+        1 => Ok(Self {
+          port: serde_json::from_value(settings["server_port"].clone())?,
+          name: serde_json::from_value(settings["name"].clone())?,
+        }),
+        _ => Err(Error::UnknownVersion(version)),
+      }
+    }
+  }
+
+  fn path(name: &str) -> PathBuf {
+    std::env::temp_dir()
+      .join(format!("chatroom-settings-{}", std::process::id()))
+      .join(name)
+  }
+
+  fn write(path: &Path, content: serde_json::Value) {
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    fs::write(path, content.to_string()).unwrap();
+  }
+
+  #[test]
+  fn defaults_without_a_file() {
+    assert_eq!(
+      load::<Test>(&path("missing.json")).unwrap(),
+      Test::default()
+    );
+  }
+
+  #[test]
+  fn round_trips() {
+    let path = path("round_trip.json");
+    let settings = Test {
+      port: 8080,
+      name: "alice".to_string(),
+    };
+    save(&path, &settings).unwrap();
+    assert_eq!(load::<Test>(&path).unwrap(), settings);
+    assert!(!path.with_extension("tmp").exists());
+  }
+
+  #[test]
+  fn migrates_older_versions() {
+    let path = path("migrate.json");
+    write(
+      &path,
+      json!({"version": 1, "settings": {"server_port": 8080, "name": "bob"}}),
+    );
+    let expected = Test {
+      port: 8080,
+      name: "bob".to_string(),
+    };
+    assert_eq!(load::<Test>(&path).unwrap(), expected);
+  }
+
+  #[test]
+  fn rejects_unknown_versions() {
+    let path = path("newer.json");
+    write(&path, json!({"version": 3, "settings": {"port": 8080}}));
+    assert!(matches!(load::<Test>(&path), Err(Error::UnknownVersion(3))));
+
+    let path = self::path("ancient.json");
+    write(&path, json!({"version": 0, "settings": {}}));
+    assert!(matches!(load::<Test>(&path), Err(Error::UnknownVersion(0))));
+  }
+
+  #[test]
+  fn rejects_invalid_settings() {
+    let path = path("invalid.json");
+    write(
+      &path,
+      json!({"version": 2, "settings": {"name": "much too long"}}),
+    );
+    assert!(matches!(load::<Test>(&path), Err(Error::Invalid(_))));
+
+    let settings = Test {
+      port: 0,
+      name: "much too long".to_string(),
+    };
+    assert!(matches!(
+      save(&self::path("invalid_save.json"), &settings),
+      Err(Error::Invalid(_))
+    ));
+  }
+}
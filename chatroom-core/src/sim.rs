@@ -0,0 +1,291 @@
+use std::{
+  io,
+  net::SocketAddr,
+  sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+  },
+  time::Duration,
+};
+
+use futures::future::BoxFuture;
+
+use parking_lot::{Mutex, RwLock};
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use tracing::error;
+
+use crate::transport::Transport;
+
+/// misbehaviors applied to every outgoing datagram, probabilities range from 0 to 1, others are
+/// clamped into the range and NaN is taken as 0
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct NetworkConditions {
+  /// probability of a datagram being dropped
+  pub loss: f64,
+  /// delay applied to every datagram
+  pub latency: Duration,
+  /// upper bound of the random delay added on top of `latency`
+  pub jitter: Duration,
+  /// probability of a datagram being delivered twice
+  pub duplication: f64,
+  /// probability of a datagram being held back by `reorder_delay`, so that later ones overtake it
+  pub reordering: f64,
+  pub reorder_delay: Duration,
+}
+
+/// what happened to the datagrams sent so far
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SimStats {
+  pub sent: u64,
+  pub dropped: u64,
+  pub duplicated: u64,
+  pub reordered: u64,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+  sent: AtomicU64,
+  dropped: AtomicU64,
+  duplicated: AtomicU64,
+  reordered: AtomicU64,
+}
+
+/// wrapper of a transport impairing the datagrams sent through it
+///
+/// every decision is drawn from a seeded rng in sending order, so a run can be reproduced with
+/// the same seed and traffic. only outgoing datagrams are affected, wrap both ends to impair both
+/// directions.
+pub struct SimulatedTransport {
+  inner: Arc<dyn Transport>,
+  conditions: RwLock<NetworkConditions>,
+  rng: Mutex<StdRng>,
+  counters: Counters,
+}
+
+impl SimulatedTransport {
+  pub fn new<T: Transport>(inner: T, conditions: NetworkConditions, seed: u64) -> Self {
+    Self {
+      inner: Arc::new(inner),
+      conditions: RwLock::new(conditions),
+      rng: Mutex::new(StdRng::seed_from_u64(seed)),
+      counters: Default::default(),
+    }
+  }
+
+  pub fn set_conditions(&self, conditions: NetworkConditions) {
+    *self.conditions.write() = conditions;
+  }
+
+  pub fn get_conditions(&self) -> NetworkConditions {
+    *self.conditions.read()
+  }
+
+  pub fn get_stats(&self) -> SimStats {
+    SimStats {
+      sent: self.counters.sent.load(Ordering::Relaxed),
+      dropped: self.counters.dropped.load(Ordering::Relaxed),
+      duplicated: self.counters.duplicated.load(Ordering::Relaxed),
+      reordered: self.counters.reordered.load(Ordering::Relaxed),
+    }
+  }
+
+  /// delays of every copy of the next datagram to be delivered, empty if it gets dropped
+  fn plan(&self) -> Vec<Duration> {
+    let conditions = self.get_conditions();
+    let mut rng = self.rng.lock();
+    self.counters.sent.fetch_add(1, Ordering::Relaxed);
+
+    if rng.gen_bool(probability(conditions.loss)) {
+      self.counters.dropped.fetch_add(1, Ordering::Relaxed);
+      return Vec::new();
+    }
+
+    let copies = if rng.gen_bool(probability(conditions.duplication)) {
+      self.counters.duplicated.fetch_add(1, Ordering::Relaxed);
+      2
+    } else {
+      1
+    };
+
+    (0..copies)
+      .map(|_| {
+        let mut delay = conditions.latency + conditions.jitter.mul_f64(rng.gen::<f64>());
+        if rng.gen_bool(probability(conditions.reordering)) {
+          self.counters.reordered.fetch_add(1, Ordering::Relaxed);
+          delay += conditions.reorder_delay;
+        }
+        delay
+      })
+      .collect()
+  }
+}
+
+/// `gen_bool` panics on anything outside of 0 to 1, NaN included
+fn probability(p: f64) -> f64 {
+  if p.is_nan() {
+    0.0
+  } else {
+    p.clamp(0.0, 1.0)
+  }
+}
+
+impl Transport for SimulatedTransport {
+  fn recv_from<'a>(&'a self, buf: &'a mut [u8]) -> BoxFuture<'a, io::Result<(usize, SocketAddr)>> {
+    self.inner.recv_from(buf)
+  }
+
+  fn send_to<'a>(&'a self, buf: &'a [u8], addr: SocketAddr) -> BoxFuture<'a, io::Result<usize>> {
+    let delays = self.plan();
+    Box::pin(async move {
+      for delay in delays {
+        if delay.is_zero() {
+          self.inner.send_to(buf, addr).await?;
+        } else {
+          let inner = self.inner.clone();
+          let datagram = buf.to_vec();
+          tokio::spawn(async move {
+            tokio::time::sleep(delay).await;
+            if let Err(err) = inner.send_to(&datagram, addr).await {
+              error!(
+                source = "internal",
+                "error occurred during sending delayed datagram to {}: {}.", addr, err
+              );
+            }
+          });
+        }
+      }
+      // like a real network, a lost datagram is still a successful send
+      Ok(buf.len())
+    })
+  }
+
+  fn local_addr(&self) -> io::Result<SocketAddr> {
+    self.inner.local_addr()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use std::time::Instant;
+
+  use tokio::{net::UdpSocket, time::timeout};
+
+  /// a simulated sender and a plain receiver on localhost
+  async fn pair(conditions: NetworkConditions, seed: u64) -> (SimulatedTransport, UdpSocket) {
+    let sender = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let receiver = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    (SimulatedTransport::new(sender, conditions, seed), receiver)
+  }
+
+  async fn recv(receiver: &UdpSocket) -> Option<Vec<u8>> {
+    let mut buf = [0u8; 64];
+    match timeout(Duration::from_millis(200), receiver.recv_from(&mut buf)).await {
+      Ok(res) => Some(buf[..res.unwrap().0].to_vec()),
+      Err(_) => None,
+    }
+  }
+
+  #[tokio::test]
+  async fn drops_everything_at_full_loss() {
+    let conditions = NetworkConditions {
+      loss: 1.0,
+      ..Default::default()
+    };
+    let (sender, receiver) = pair(conditions, 0).await;
+    let addr = receiver.local_addr().unwrap();
+    for _ in 0..10 {
+      assert_eq!(sender.send_to(b"lost", addr).await.unwrap(), 4);
+    }
+    assert_eq!(recv(&receiver).await, None);
+    let stats = sender.get_stats();
+    assert_eq!((stats.sent, stats.dropped), (10, 10));
+  }
+
+  #[tokio::test]
+  async fn losses_depend_only_on_the_seed() {
+    let conditions = NetworkConditions {
+      loss: 0.5,
+      ..Default::default()
+    };
+    let (a, receiver) = pair(conditions, 42).await;
+    let (b, _) = pair(conditions, 42).await;
+    let addr = receiver.local_addr().unwrap();
+    for _ in 0..100 {
+      a.send_to(b"a", addr).await.unwrap();
+      b.send_to(b"b", addr).await.unwrap();
+    }
+    assert_eq!(a.get_stats(), b.get_stats());
+    assert!(0 < a.get_stats().dropped && a.get_stats().dropped < 100);
+  }
+
+  #[tokio::test]
+  async fn delays_by_latency() {
+    let conditions = NetworkConditions {
+      latency: Duration::from_millis(50),
+      ..Default::default()
+    };
+    let (sender, receiver) = pair(conditions, 0).await;
+    let start = Instant::now();
+    sender
+      .send_to(b"late", receiver.local_addr().unwrap())
+      .await
+      .unwrap();
+    assert_eq!(recv(&receiver).await.as_deref(), Some(&b"late"[..]));
+    assert!(start.elapsed() >= Duration::from_millis(50));
+  }
+
+  #[tokio::test]
+  async fn reordered_datagrams_are_overtaken() {
+    let held_back = NetworkConditions {
+      reordering: 1.0,
+      reorder_delay: Duration::from_millis(50),
+      ..Default::default()
+    };
+    let (sender, receiver) = pair(held_back, 0).await;
+    let addr = receiver.local_addr().unwrap();
+    sender.send_to(b"first", addr).await.unwrap();
+    sender.set_conditions(Default::default());
+    sender.send_to(b"second", addr).await.unwrap();
+
+    assert_eq!(recv(&receiver).await.as_deref(), Some(&b"second"[..]));
+    assert_eq!(recv(&receiver).await.as_deref(), Some(&b"first"[..]));
+    assert_eq!(sender.get_stats().reordered, 1);
+  }
+
+  #[tokio::test]
+  async fn takes_nan_as_never() {
+    let conditions = NetworkConditions {
+      loss: f64::NAN,
+      duplication: f64::NAN,
+      reordering: f64::NAN,
+      ..Default::default()
+    };
+    let (sender, receiver) = pair(conditions, 0).await;
+    sender
+      .send_to(b"once", receiver.local_addr().unwrap())
+      .await
+      .unwrap();
+    assert_eq!(recv(&receiver).await.as_deref(), Some(&b"once"[..]));
+    assert_eq!(recv(&receiver).await, None);
+  }
+
+  #[tokio::test]
+  async fn duplicates_datagrams() {
+    let conditions = NetworkConditions {
+      duplication: 1.0,
+      ..Default::default()
+    };
+    let (sender, receiver) = pair(conditions, 0).await;
+    sender
+      .send_to(b"twice", receiver.local_addr().unwrap())
+      .await
+      .unwrap();
+    assert_eq!(recv(&receiver).await.as_deref(), Some(&b"twice"[..]));
+    assert_eq!(recv(&receiver).await.as_deref(), Some(&b"twice"[..]));
+    assert_eq!(recv(&receiver).await, None);
+  }
+}
@@ -0,0 +1,556 @@
+//! SRP-6a password authentication (RFC 5054), the password never leaves the client and the
+//! server only keeps a verifier, which is of no use for logging in by itself
+
+use thiserror::Error as ThisError;
+
+use num_bigint::BigUint;
+
+use rand::{thread_rng, RngCore};
+
+use sha2::{Digest, Sha256};
+
+/// prefix of verifiers encoded for storage
+const ENCODING_PREFIX: &str = "$srp6a$";
+
+/// length of the salts generated for new verifiers
+pub const SALT_LEN: usize = 16;
+
+/// length of the random private keys of both sides
+const PRIVATE_KEY_LEN: usize = 32;
+
+/// the 2048-bit group of RFC 5054, appendix A
+const N_HEX: &str = concat!(
+  "AC6BDB41324A9A9BF166DE5E1389582FAF72B6651987EE07FC3192943DB56050A37329CBB4A099ED8193E0757767A13D",
+  "D52312AB4B03310DCD7F48A9DA04FD50E8083969EDB767B0CF6095179A163AB3661A05FBD5FAAAE82918A9962F0B93B8",
+  "55F97993EC975EEAA80D740ADBF4FF747359D041D5C33EA71D281E446B14773BCA97B43A23FB801676BD207A436C6481",
+  "F1D2B9078717461A5B9D32E688F87748544523B524B0D57D5EA77A2775D2ECFA032CFBDBF52FB3786160279004E57AE6",
+  "AF874E7303CE53299CCC041C7BC308D82A5698F3A8D0C38271AE35F8E9DBFBB694B5C803D89F7AE435DE236D525F5475",
+  "9B65E372FCD68EF20FA7111F9E4AFF73",
+);
+const G: u32 = 2;
+
+struct Group {
+  n: BigUint,
+  g: BigUint,
+  /// multiplier parameter, `H(N | PAD(g))`
+  k: BigUint,
+  hash: fn(&[&[u8]]) -> Vec<u8>,
+}
+
+impl Group {
+  fn get() -> Self {
+    Self::new(N_HEX, G, hash::<Sha256>)
+  }
+
+  fn new(n_hex: &str, g: u32, hash: fn(&[&[u8]]) -> Vec<u8>) -> Self {
+    let n = BigUint::parse_bytes(n_hex.as_bytes(), 16).unwrap(); // constant
+    let g = BigUint::from(g);
+    let k = BigUint::from_bytes_be(&hash(&[&n.to_bytes_be(), &pad(&g, &n)]));
+    Self { n, g, k, hash }
+  }
+
+  fn len(&self) -> usize {
+    self.n.bits().div_ceil(8) as usize
+  }
+}
+
+fn hash<D: Digest>(parts: &[&[u8]]) -> Vec<u8> {
+  let mut hasher = D::new();
+  for part in parts {
+    hasher.update(part);
+  }
+  hasher.finalize().to_vec()
+}
+
+/// big-endian bytes of `x`, left padded to the length of `n`
+fn pad(x: &BigUint, n: &BigUint) -> Vec<u8> {
+  let len = n.bits().div_ceil(8) as usize;
+  let bytes = x.to_bytes_be();
+  let mut padded = vec![0u8; len.saturating_sub(bytes.len())];
+  padded.extend(bytes);
+  padded
+}
+
+fn random_private_key() -> BigUint {
+  let mut bytes = [0u8; PRIVATE_KEY_LEN];
+  thread_rng().fill_bytes(&mut bytes);
+  BigUint::from_bytes_be(&bytes)
+}
+
+/// `x = H(s | H(I | ":" | P))`, leading whitespace of the password is ignored as it always has
+/// been
+fn private_value(group: &Group, username: &str, password: &str, salt: &[u8]) -> BigUint {
+  let inner = (group.hash)(&[username.as_bytes(), b":", password.trim_start().as_bytes()]);
+  BigUint::from_bytes_be(&(group.hash)(&[salt, &inner]))
+}
+
+/// `u = H(PAD(A) | PAD(B))`
+fn scrambler(group: &Group, a_pub: &BigUint, b_pub: &BigUint) -> BigUint {
+  BigUint::from_bytes_be(&(group.hash)(&[
+    &pad(a_pub, &group.n),
+    &pad(b_pub, &group.n),
+  ]))
+}
+
+/// `M1 = H(H(N) xor H(g) | H(I) | s | A | B | K)`
+fn client_proof(
+  group: &Group,
+  username: &str,
+  salt: &[u8],
+  a_pub: &BigUint,
+  b_pub: &BigUint,
+  key: &[u8],
+) -> Vec<u8> {
+  let hn = (group.hash)(&[&group.n.to_bytes_be()]);
+  let hg = (group.hash)(&[&group.g.to_bytes_be()]);
+  let xored = hn.iter().zip(hg).map(|(n, g)| n ^ g).collect::<Vec<_>>();
+  (group.hash)(&[
+    &xored,
+    &(group.hash)(&[username.as_bytes()]),
+    salt,
+    &a_pub.to_bytes_be(),
+    &b_pub.to_bytes_be(),
+    key,
+  ])
+}
+
+/// `M2 = H(A | M1 | K)`
+fn server_proof(group: &Group, a_pub: &BigUint, client_proof: &[u8], key: &[u8]) -> Vec<u8> {
+  (group.hash)(&[&a_pub.to_bytes_be(), client_proof, key])
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+  a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+/// what the server keeps in place of a password
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Verifier {
+  pub salt: Vec<u8>,
+  pub verifier: Vec<u8>,
+}
+
+impl Verifier {
+  /// verifier of `password` under a fresh random salt
+  pub fn new(username: &str, password: &str) -> Self {
+    let mut salt = vec![0u8; SALT_LEN];
+    thread_rng().fill_bytes(&mut salt);
+    Self::with_salt(username, password, salt)
+  }
+
+  pub fn with_salt(username: &str, password: &str, salt: Vec<u8>) -> Self {
+    let group = Group::get();
+    let x = private_value(&group, username, password, &salt);
+    let verifier = group.g.modpow(&x, &group.n).to_bytes_be();
+    Self { salt, verifier }
+  }
+
+  /// a verifier nobody knows the password of, e.g. to answer for unknown users as if they
+  /// existed
+  pub fn random(salt: Vec<u8>) -> Self {
+    let group = Group::get();
+    let verifier = group
+      .g
+      .modpow(&random_private_key(), &group.n)
+      .to_bytes_be();
+    Self { salt, verifier }
+  }
+
+  /// whether the verifier could have come from `Verifier::new`, for checking what clients send
+  pub fn is_valid(&self) -> bool {
+    let group = Group::get();
+    let v = BigUint::from_bytes_be(&self.verifier);
+    !self.salt.is_empty() && self.salt.len() <= 64 && v > BigUint::from(1u32) && v < group.n
+  }
+
+  /// whether `password` is the one the verifier was made from, for servers given the password
+  /// itself instead of a proof
+  pub fn matches(&self, username: &str, password: &str) -> bool {
+    let candidate = Self::with_salt(username, password, self.salt.clone());
+    constant_time_eq(&candidate.verifier, &self.verifier)
+  }
+
+  /// `$srp6a$<salt>$<verifier>` in hex, as kept in `UserEssential::password_hash`
+  pub fn encode(&self) -> String {
+    format!(
+      "{}{}${}",
+      ENCODING_PREFIX,
+      to_hex(&self.salt),
+      to_hex(&self.verifier)
+    )
+  }
+
+  /// `None` if `encoded` was not produced by `Verifier::encode`, e.g. for a legacy password
+  /// hash
+  pub fn decode(encoded: &str) -> Option<Self> {
+    let (salt, verifier) = encoded.strip_prefix(ENCODING_PREFIX)?.split_once('$')?;
+    Some(Self {
+      salt: from_hex(salt)?,
+      verifier: from_hex(verifier)?,
+    })
+  }
+}
+
+/// what clients sent in place of the password before srp, only needed to migrate accounts from
+/// back then
+pub fn legacy_digest(password: &str) -> [u8; 32] {
+  Sha256::digest(password.trim_start().as_bytes()).into()
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+  bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+  let digit = |c: u8| (c as char).to_digit(16).map(|d| d as u8);
+  hex
+    .as_bytes()
+    .chunks(2)
+    .map(|pair| match *pair {
+      [high, low] => Some(digit(high)? << 4 | digit(low)?),
+      _ => None,
+    })
+    .collect()
+}
+
+/// the client side of a handshake, started before asking the server for a challenge
+pub struct ClientHandshake {
+  group: Group,
+  username: String,
+  password: String,
+  a: BigUint,
+  a_pub: BigUint,
+}
+
+impl ClientHandshake {
+  pub fn new(username: &str, password: &str) -> Self {
+    Self::with_private_key(Group::get(), username, password, random_private_key())
+  }
+
+  fn with_private_key(group: Group, username: &str, password: &str, a: BigUint) -> Self {
+    let a_pub = group.g.modpow(&a, &group.n);
+    Self {
+      group,
+      username: username.to_string(),
+      password: password.to_string(),
+      a,
+      a_pub,
+    }
+  }
+
+  /// `A`, sent along with the username
+  pub fn public_key(&self) -> Vec<u8> {
+    pad(&self.a_pub, &self.group.n)
+  }
+
+  /// answer the challenge of the server
+  pub fn respond(self, salt: &[u8], b_pub: &[u8]) -> Result<ClientSession, Error> {
+    let group = &self.group;
+    let b_pub = BigUint::from_bytes_be(b_pub);
+    if b_pub.clone() % &group.n == BigUint::default() {
+      return Err(Error::InvalidPublicKey);
+    }
+    let u = scrambler(group, &self.a_pub, &b_pub);
+    if u == BigUint::default() {
+      return Err(Error::InvalidPublicKey);
+    }
+    let x = private_value(group, &self.username, &self.password, salt);
+
+    // S = (B - k * g^x) ^ (a + u * x) mod N
+    let kgx = (&group.k * group.g.modpow(&x, &group.n)) % &group.n;
+    let base = (b_pub.clone() + &group.n - kgx) % &group.n;
+    let secret = base.modpow(&(&self.a + &u * &x), &group.n);
+    let key = (group.hash)(&[&pad(&secret, &group.n)]);
+
+    let proof = client_proof(group, &self.username, salt, &self.a_pub, &b_pub, &key);
+    let expected = server_proof(group, &self.a_pub, &proof, &key);
+    Ok(ClientSession {
+      proof,
+      expected,
+      key,
+    })
+  }
+}
+
+/// the client side of a handshake once the challenge is answered
+pub struct ClientSession {
+  proof: Vec<u8>,
+  expected: Vec<u8>,
+  key: Vec<u8>,
+}
+
+impl ClientSession {
+  /// `M1`, proves to the server that the client knows the password
+  pub fn proof(&self) -> &[u8] {
+    &self.proof
+  }
+
+  /// check `M2`, which proves that the server knows the verifier
+  pub fn verify_server(&self, proof: &[u8]) -> Result<(), Error> {
+    if constant_time_eq(&self.expected, proof) {
+      Ok(())
+    } else {
+      Err(Error::BadProof)
+    }
+  }
+
+  /// secret shared with the server
+  pub fn key(&self) -> &[u8] {
+    &self.key
+  }
+}
+
+/// the server side of a handshake, kept until the client answers the challenge
+pub struct ServerHandshake {
+  group: Group,
+  username: String,
+  salt: Vec<u8>,
+  verifier: BigUint,
+  a_pub: BigUint,
+  b: BigUint,
+  b_pub: BigUint,
+}
+
+impl std::fmt::Debug for ServerHandshake {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("ServerHandshake")
+      .field("username", &self.username)
+      .finish_non_exhaustive()
+  }
+}
+
+impl ServerHandshake {
+  /// start answering a client which sent `a_pub` as its public key
+  pub fn new(username: &str, verifier: &Verifier, a_pub: &[u8]) -> Result<Self, Error> {
+    Self::with_private_key(
+      Group::get(),
+      username,
+      verifier,
+      a_pub,
+      random_private_key(),
+    )
+  }
+
+  fn with_private_key(
+    group: Group,
+    username: &str,
+    verifier: &Verifier,
+    a_pub: &[u8],
+    b: BigUint,
+  ) -> Result<Self, Error> {
+    let a_pub = BigUint::from_bytes_be(a_pub);
+    if a_pub.clone() % &group.n == BigUint::default() || a_pub.bits() as usize > group.len() * 8 {
+      return Err(Error::InvalidPublicKey);
+    }
+    let v = BigUint::from_bytes_be(&verifier.verifier);
+    // B = k * v + g^b mod N
+    let b_pub = (&group.k * &v + group.g.modpow(&b, &group.n)) % &group.n;
+    Ok(Self {
+      group,
+      username: username.to_string(),
+      salt: verifier.salt.clone(),
+      verifier: v,
+      a_pub,
+      b,
+      b_pub,
+    })
+  }
+
+  pub fn username(&self) -> &str {
+    &self.username
+  }
+
+  pub fn salt(&self) -> &[u8] {
+    &self.salt
+  }
+
+  /// `B`, sent back along with the salt
+  pub fn public_key(&self) -> Vec<u8> {
+    pad(&self.b_pub, &self.group.n)
+  }
+
+  /// check `M1` from the client, returns `M2` to prove the server side in return
+  pub fn verify(&self, proof: &[u8]) -> Result<Vec<u8>, Error> {
+    let group = &self.group;
+    let u = scrambler(group, &self.a_pub, &self.b_pub);
+    if u == BigUint::default() {
+      return Err(Error::InvalidPublicKey);
+    }
+
+    // S = (A * v^u) ^ b mod N
+    let base = (&self.a_pub * self.verifier.modpow(&u, &group.n)) % &group.n;
+    let secret = base.modpow(&self.b, &group.n);
+    let key = (group.hash)(&[&pad(&secret, &group.n)]);
+
+    let expected = client_proof(
+      group,
+      &self.username,
+      &self.salt,
+      &self.a_pub,
+      &self.b_pub,
+      &key,
+    );
+    if !constant_time_eq(&expected, proof) {
+      return Err(Error::BadProof);
+    }
+    Ok(server_proof(group, &self.a_pub, proof, &key))
+  }
+}
+
+#[derive(ThisError, Debug, Clone, PartialEq)]
+pub enum Error {
+  #[error("public key of the other side is invalid")]
+  InvalidPublicKey,
+  #[error("proof of the other side is incorrect")]
+  BadProof,
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use sha1::Sha1;
+
+  /// the 1024-bit group of RFC 5054, appendix A
+  const RFC_N_HEX: &str = concat!(
+    "EEAF0AB9ADB38DD69C33F80AFA8FC5E86072618775FF3C0B9EA2314C9C256576D674DF7496EA81D3383B4813",
+    "D692C6E0E0D5D8E250B98BE48E495C1D6089DAD15DC7D7B46154D6B6CE8EF4AD69B15D4982559B297BCF1885",
+    "C529F566660E57EC68EDBC3C05726CC02FD4CBF4976EAA9AFD5138FE8376435B9FC61D2FC0EB06E3",
+  );
+
+  fn int(hex: &str) -> BigUint {
+    BigUint::parse_bytes(hex.as_bytes(), 16).unwrap()
+  }
+
+  fn bytes(hex: &str) -> Vec<u8> {
+    from_hex(hex).unwrap()
+  }
+
+  /// the test vectors of RFC 5054, appendix B, which uses sha-1 and the 1024-bit group
+  fn rfc_group() -> Group {
+    Group::new(RFC_N_HEX, 2, hash::<Sha1>)
+  }
+
+  const USERNAME: &str = "alice";
+  const PASSWORD: &str = "password123";
+  const SALT: &str = "beb25379d1a8581eb5a727673a2441ee";
+  const A: &str = "60975527035cf2ad1989806f0407210bc81edc04e2762a56afd529ddda2d4393";
+  const B: &str = "e487cb59d31ac550471e81f00f6928e01dda08e974a004f49e61f5d105284d20";
+  const V: &str = concat!(
+    "7e273de8696ffc4f4e337d05b4b375beb0dde1569e8fa00a9886d8129bada1f1822223ca1a605b530e379ba4",
+    "729fdc59f105b4787e5186f5c671085a1447b52a48cf1970b4fb6f8400bbf4cebfbb168152e08ab5ea53d15c",
+    "1aff87b2b9da6e04e058ad51cc72bfc9033b564e26480d78e955a5e29e7ab245db2be315e2099afb",
+  );
+  const A_PUB: &str = concat!(
+    "61d5e490f6f1b79547b0704c436f523dd0e560f0c64115bb72557ec44352e8903211c04692272d8b2d1a5358",
+    "a2cf1b6e0bfcf99f921530ec8e39356179eae45e42ba92aeaced825171e1e8b9af6d9c03e1327f44be087ef0",
+    "6530e69f66615261eef54073ca11cf5858f0edfdfe15efeab349ef5d76988a3672fac47b0769447b",
+  );
+  const B_PUB: &str = concat!(
+    "bd0c61512c692c0cb6d041fa01bb152d4916a1e77af46ae105393011baf38964dc46a0670dd125b95a981652",
+    "236f99d9b681cbf87837ec996c6da04453728610d0c6ddb58b318885d7d82c7f8deb75ce7bd4fbaa37089e6f",
+    "9c6059f388838e7a00030b331eb76840910440b1b27aaeaeeb4012b7d7665238a8e3fb004b117b58",
+  );
+  const PREMASTER_SECRET: &str = concat!(
+    "b0dc82babcf30674ae450c0287745e7990a3381f63b387aaf271a10d233861e359b48220f7c4693c9ae12b0a",
+    "6f67809f0876e2d013800d6c41bb59b6d5979b5c00a172b4a2a5903a0bdcaf8a709585eb2afafa8f3499b200",
+    "210dcc1f10eb33943cd67fc88a2f39a4be5bec4ec0a3212dc346d7e474b29ede8a469ffeca686e5a",
+  );
+  // the rfc leaves the proofs to the application, these follow RFC 2945 with K = H(PAD(S)) and
+  // were worked out apart from this module
+  const CLIENT_PROOF: &str = "3f3bc67169ea71302599cf1b0f5d408b7b65d347";
+  const SERVER_PROOF: &str = "9cab3c575a11de37d3ac1421a9f009236a48eb55";
+
+  fn rfc_handshakes() -> (ClientHandshake, ServerHandshake) {
+    let client = ClientHandshake::with_private_key(rfc_group(), USERNAME, PASSWORD, int(A));
+    let verifier = Verifier {
+      salt: bytes(SALT),
+      verifier: bytes(V),
+    };
+    let server =
+      ServerHandshake::with_private_key(rfc_group(), USERNAME, &verifier, &bytes(A_PUB), int(B))
+        .unwrap();
+    (client, server)
+  }
+
+  #[test]
+  fn matches_rfc_5054_parameters() {
+    let group = rfc_group();
+    assert_eq!(group.k, int("7556aa045aef2cdd07abaf0f665c3e818913186f"));
+    let x = private_value(&group, USERNAME, PASSWORD, &bytes(SALT));
+    assert_eq!(x, int("94b7555aabe9127cc58ccf4993db6cf84d16c124"));
+    assert_eq!(group.g.modpow(&x, &group.n), int(V));
+  }
+
+  #[test]
+  fn matches_rfc_5054_handshake() {
+    let group = rfc_group();
+    let (client, server) = rfc_handshakes();
+    assert_eq!(client.public_key(), bytes(A_PUB));
+    assert_eq!(server.public_key(), bytes(B_PUB));
+    assert_eq!(
+      scrambler(&group, &int(A_PUB), &int(B_PUB)),
+      int("ce38b9593487da98554ed47d70a7ae5f462ef019")
+    );
+
+    let session = client.respond(&bytes(SALT), &bytes(B_PUB)).unwrap();
+    let premaster_secret = pad(&int(PREMASTER_SECRET), &group.n);
+    assert_eq!(session.key(), hash::<Sha1>(&[&premaster_secret]));
+    assert_eq!(session.proof(), bytes(CLIENT_PROOF));
+
+    assert_eq!(server.verify(session.proof()).unwrap(), bytes(SERVER_PROOF));
+    assert!(session.verify_server(&bytes(SERVER_PROOF)).is_ok());
+  }
+
+  #[test]
+  fn rejects_wrong_passwords_and_proofs() {
+    let (_, server) = rfc_handshakes();
+    let client = ClientHandshake::with_private_key(rfc_group(), USERNAME, "password124", int(A));
+    let session = client.respond(&bytes(SALT), &bytes(B_PUB)).unwrap();
+    assert_eq!(server.verify(session.proof()), Err(Error::BadProof));
+    assert_eq!(
+      session.verify_server(&bytes(SERVER_PROOF)),
+      Err(Error::BadProof)
+    );
+  }
+
+  #[test]
+  fn rejects_degenerate_public_keys() {
+    let group = rfc_group();
+    let verifier = Verifier {
+      salt: bytes(SALT),
+      verifier: bytes(V),
+    };
+    for a_pub in [
+      vec![0],
+      group.n.to_bytes_be(),
+      (&group.n * 2u32).to_bytes_be(),
+    ] {
+      assert_eq!(
+        ServerHandshake::with_private_key(rfc_group(), USERNAME, &verifier, &a_pub, int(B))
+          .unwrap_err(),
+        Error::InvalidPublicKey
+      );
+    }
+    let (client, _) = rfc_handshakes();
+    assert!(matches!(
+      client.respond(&bytes(SALT), &group.n.to_bytes_be()),
+      Err(Error::InvalidPublicKey)
+    ));
+  }
+
+  #[test]
+  fn logs_in_with_the_default_group() {
+    let verifier = Verifier::new(USERNAME, PASSWORD);
+    assert!(verifier.is_valid());
+    assert!(verifier.matches(USERNAME, PASSWORD));
+    assert!(!verifier.matches(USERNAME, "password124"));
+    assert_eq!(Verifier::decode(&verifier.encode()), Some(verifier.clone()));
+
+    let client = ClientHandshake::new(USERNAME, PASSWORD);
+    let server = ServerHandshake::new(USERNAME, &verifier, &client.public_key()).unwrap();
+    let session = client.respond(server.salt(), &server.public_key()).unwrap();
+    let proof = server.verify(session.proof()).unwrap();
+    assert!(session.verify_server(&proof).is_ok());
+  }
+}
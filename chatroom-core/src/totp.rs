@@ -0,0 +1,221 @@
+//! time-based one-time passwords (RFC 6238) as authenticator apps generate them, HMAC-SHA1 over
+//! 30 second steps truncated to 6 digits
+
+use hmac::{Hmac, Mac};
+
+use rand::{thread_rng, RngCore};
+
+use sha1::Sha1;
+
+use time::OffsetDateTime;
+
+/// length of the generated secrets, as recommended by RFC 4226
+pub const SECRET_LEN: usize = 20;
+
+pub const DIGITS: usize = 6;
+
+/// seconds each code is valid for
+pub const STEP: u64 = 30;
+
+/// steps before and after the current one that are accepted as well, for clocks drifting apart
+pub const SKEW: u64 = 1;
+
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+pub fn generate_secret() -> Vec<u8> {
+  let mut secret = vec![0; SECRET_LEN];
+  thread_rng().fill_bytes(&mut secret);
+  secret
+}
+
+/// HOTP of RFC 4226, section 5.3
+pub fn hotp(secret: &[u8], counter: u64) -> u32 {
+  let mut mac = Hmac::<Sha1>::new_from_slice(secret).unwrap(); // any key length works
+  mac.update(&counter.to_be_bytes());
+  let digest = mac.finalize().into_bytes();
+  let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+  let binary = u32::from_be_bytes([
+    digest[offset],
+    digest[offset + 1],
+    digest[offset + 2],
+    digest[offset + 3],
+  ]) & 0x7fff_ffff;
+  binary % 10u32.pow(DIGITS as u32)
+}
+
+pub fn step_at(time: OffsetDateTime) -> u64 {
+  time.unix_timestamp().max(0) as u64 / STEP
+}
+
+/// the code an authenticator shows at `time`
+pub fn code_at(secret: &[u8], time: OffsetDateTime) -> String {
+  format!("{:0width$}", hotp(secret, step_at(time)), width = DIGITS)
+}
+
+/// the step `code` belongs to, if it is valid around `time`
+pub fn verify(secret: &[u8], code: &str, time: OffsetDateTime) -> Option<u64> {
+  let code = code.trim();
+  if code.len() != DIGITS || !code.bytes().all(|b| b.is_ascii_digit()) {
+    return None;
+  }
+  let code: u32 = code.parse().ok()?;
+  let now = step_at(time);
+  (now.saturating_sub(SKEW)..=now + SKEW).find(|&step| hotp(secret, step) == code)
+}
+
+/// RFC 4648 base32 without padding, the form authenticators take secrets in
+pub fn encode_base32(data: &[u8]) -> String {
+  let mut encoded = String::with_capacity((data.len() * 8).div_ceil(5));
+  let mut buffer = 0u32;
+  let mut bits = 0;
+  for &byte in data {
+    buffer = (buffer << 8) | byte as u32;
+    bits += 8;
+    while bits >= 5 {
+      bits -= 5;
+      encoded.push(BASE32_ALPHABET[((buffer >> bits) & 0x1f) as usize] as char);
+    }
+  }
+  if bits > 0 {
+    encoded.push(BASE32_ALPHABET[((buffer << (5 - bits)) & 0x1f) as usize] as char);
+  }
+  encoded
+}
+
+/// case, spaces and padding are ignored, as people tend to type secrets in however they like
+pub fn decode_base32(encoded: &str) -> Option<Vec<u8>> {
+  let mut decoded = Vec::with_capacity(encoded.len() * 5 / 8);
+  let mut buffer = 0u32;
+  let mut bits = 0;
+  for c in encoded.chars().filter(|c| !c.is_whitespace() && *c != '=') {
+    let value = BASE32_ALPHABET
+      .iter()
+      .position(|&a| a as char == c.to_ascii_uppercase())? as u32;
+    buffer = (buffer << 5) | value;
+    bits += 5;
+    if bits >= 8 {
+      bits -= 8;
+      decoded.push((buffer >> bits) as u8);
+    }
+  }
+  Some(decoded)
+}
+
+/// the `otpauth://` uri authenticator apps read from a qr code
+pub fn provisioning_uri(issuer: &str, account: &str, secret: &[u8]) -> String {
+  format!(
+    "otpauth://totp/{}:{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+    percent_encode(issuer),
+    percent_encode(account),
+    encode_base32(secret),
+    percent_encode(issuer),
+    DIGITS,
+    STEP
+  )
+}
+
+fn percent_encode(s: &str) -> String {
+  let mut encoded = String::with_capacity(s.len());
+  for byte in s.bytes() {
+    match byte {
+      b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+        encoded.push(byte as char)
+      }
+      _ => encoded.push_str(&format!("%{:02X}", byte)),
+    }
+  }
+  encoded
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// the sha-1 secret of the test vectors in RFC 4226 and RFC 6238
+  const SECRET: &[u8] = b"12345678901234567890";
+
+  /// RFC 4648, section 10, padded as there
+  const BASE32_VECTORS: [(&str, &str); 7] = [
+    ("", ""),
+    ("f", "MY======"),
+    ("fo", "MZXQ===="),
+    ("foo", "MZXW6==="),
+    ("foob", "MZXW6YQ="),
+    ("fooba", "MZXW6YTB"),
+    ("foobar", "MZXW6YTBOI======"),
+  ];
+
+  fn at(timestamp: i64) -> OffsetDateTime {
+    OffsetDateTime::from_unix_timestamp(timestamp).unwrap()
+  }
+
+  #[test]
+  fn matches_rfc_4226() {
+    let expected = [
+      755224, 287082, 359152, 969429, 338314, 254676, 287922, 162583, 399871, 520489,
+    ];
+    for (counter, code) in expected.iter().enumerate() {
+      assert_eq!(hotp(SECRET, counter as u64), *code, "counter {}", counter);
+    }
+  }
+
+  #[test]
+  fn matches_rfc_6238() {
+    // the last six of the eight digits in appendix b
+    let expected = [
+      (59, "287082"),
+      (1111111109, "081804"),
+      (1111111111, "050471"),
+      (1234567890, "005924"),
+      (2000000000, "279037"),
+      (20000000000, "353130"),
+    ];
+    for (timestamp, code) in expected {
+      assert_eq!(code_at(SECRET, at(timestamp)), code, "time {}", timestamp);
+      assert_eq!(
+        verify(SECRET, code, at(timestamp)),
+        Some(timestamp as u64 / STEP)
+      );
+    }
+  }
+
+  #[test]
+  fn accepts_codes_of_neighbouring_steps_only() {
+    let code = code_at(SECRET, at(1111111111));
+    assert!(verify(SECRET, &code, at(1111111111 - STEP as i64)).is_some());
+    assert!(verify(SECRET, &code, at(1111111111 + STEP as i64)).is_some());
+    assert_eq!(
+      verify(SECRET, &code, at(1111111111 + 3 * STEP as i64)),
+      None
+    );
+    assert_eq!(verify(SECRET, " 050471 ", at(1111111111)), Some(37037037));
+    assert_eq!(verify(SECRET, "50471", at(1111111111)), None);
+    assert_eq!(verify(SECRET, "05047a", at(1111111111)), None);
+  }
+
+  #[test]
+  fn encodes_base32_as_rfc_4648() {
+    for (data, encoded) in BASE32_VECTORS {
+      assert_eq!(
+        encode_base32(data.as_bytes()),
+        encoded.trim_end_matches('=')
+      );
+    }
+  }
+
+  #[test]
+  fn decodes_base32_as_rfc_4648() {
+    for (data, encoded) in BASE32_VECTORS {
+      assert_eq!(decode_base32(encoded).unwrap(), data.as_bytes());
+      assert_eq!(
+        decode_base32(&encoded.trim_end_matches('=').to_lowercase()).unwrap(),
+        data.as_bytes()
+      );
+    }
+    assert_eq!(decode_base32("MZXW 6YTB").unwrap(), b"fooba");
+    assert_eq!(decode_base32("MZXW1"), None);
+
+    let secret = generate_secret();
+    assert_eq!(decode_base32(&encode_base32(&secret)).unwrap(), secret);
+  }
+}
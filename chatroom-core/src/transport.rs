@@ -0,0 +1,224 @@
+use std::{
+  collections::HashMap,
+  io,
+  net::SocketAddr,
+  sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+  },
+  task::Poll,
+};
+
+use futures::future::{self, BoxFuture};
+
+use parking_lot::RwLock;
+
+use tokio::{io::ReadBuf, net::UdpSocket};
+
+/// peers whose routes are remembered at most, so that datagrams with spoofed sources cannot grow
+/// the table without bound, forgotten peers are routed by address family until heard again
+const MAX_ROUTES: usize = 4096;
+
+/// unreliable datagram transport underneath `SecureConnection`
+pub trait Transport: Send + Sync + 'static {
+  fn recv_from<'a>(&'a self, buf: &'a mut [u8]) -> BoxFuture<'a, io::Result<(usize, SocketAddr)>>;
+
+  fn send_to<'a>(&'a self, buf: &'a [u8], addr: SocketAddr) -> BoxFuture<'a, io::Result<usize>>;
+
+  fn local_addr(&self) -> io::Result<SocketAddr>;
+}
+
+impl Transport for UdpSocket {
+  fn recv_from<'a>(&'a self, buf: &'a mut [u8]) -> BoxFuture<'a, io::Result<(usize, SocketAddr)>> {
+    Box::pin(UdpSocket::recv_from(self, buf))
+  }
+
+  fn send_to<'a>(&'a self, buf: &'a [u8], addr: SocketAddr) -> BoxFuture<'a, io::Result<usize>> {
+    Box::pin(UdpSocket::send_to(self, buf, addr))
+  }
+
+  fn local_addr(&self) -> io::Result<SocketAddr> {
+    UdpSocket::local_addr(self)
+  }
+}
+
+impl<T: Transport> Transport for Arc<T> {
+  fn recv_from<'a>(&'a self, buf: &'a mut [u8]) -> BoxFuture<'a, io::Result<(usize, SocketAddr)>> {
+    T::recv_from(self, buf)
+  }
+
+  fn send_to<'a>(&'a self, buf: &'a [u8], addr: SocketAddr) -> BoxFuture<'a, io::Result<usize>> {
+    T::send_to(self, buf, addr)
+  }
+
+  fn local_addr(&self) -> io::Result<SocketAddr> {
+    T::local_addr(self)
+  }
+}
+
+/// several sockets behaving as one, e.g. to listen on both ipv4 and ipv6 addresses
+///
+/// datagrams to a peer leave from the socket it was last heard on, or else from the first socket
+/// of the same address family.
+pub struct MultiSocket {
+  socks: Vec<UdpSocket>,
+  routes: RwLock<HashMap<SocketAddr, usize>>,
+  max_routes: usize,
+  next: AtomicUsize,
+}
+
+impl MultiSocket {
+  pub async fn bind<I, A>(addrs: I) -> io::Result<Self>
+  where
+    I: IntoIterator<Item = A>,
+    A: tokio::net::ToSocketAddrs,
+  {
+    let mut socks = Vec::new();
+    for addr in addrs {
+      socks.push(UdpSocket::bind(addr).await?);
+    }
+    Self::new(socks)
+  }
+
+  pub fn new(socks: Vec<UdpSocket>) -> io::Result<Self> {
+    if socks.is_empty() {
+      return Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "no socket to listen on",
+      ));
+    }
+    Ok(Self {
+      socks,
+      routes: Default::default(),
+      max_routes: MAX_ROUTES,
+      next: AtomicUsize::new(0),
+    })
+  }
+
+  pub fn local_addrs(&self) -> io::Result<Vec<SocketAddr>> {
+    self.socks.iter().map(|sock| sock.local_addr()).collect()
+  }
+
+  /// remember that `addr` was heard on the `i`th socket, the table is only locked for writing
+  /// when that changes
+  fn learn_route(&self, addr: SocketAddr, i: usize) {
+    if self.routes.read().get(&addr) == Some(&i) {
+      return;
+    }
+    let mut routes = self.routes.write();
+    if routes.len() >= self.max_routes && !routes.contains_key(&addr) {
+      if let Some(&evicted) = routes.keys().next() {
+        routes.remove(&evicted);
+      }
+    }
+    routes.insert(addr, i);
+  }
+
+  fn route(&self, addr: SocketAddr) -> &UdpSocket {
+    if let Some(&i) = self.routes.read().get(&addr) {
+      return &self.socks[i];
+    }
+    self
+      .socks
+      .iter()
+      .find(|sock| matches!(sock.local_addr(), Ok(local) if local.is_ipv4() == addr.is_ipv4()))
+      .unwrap_or(&self.socks[0])
+  }
+}
+
+impl Transport for MultiSocket {
+  fn recv_from<'a>(&'a self, buf: &'a mut [u8]) -> BoxFuture<'a, io::Result<(usize, SocketAddr)>> {
+    // start polling from a different socket every time, so that a busy one cannot starve others
+    let start = self.next.fetch_add(1, Ordering::Relaxed);
+    Box::pin(future::poll_fn(move |cx| {
+      for offset in 0..self.socks.len() {
+        let i = (start + offset) % self.socks.len();
+        let mut read_buf = ReadBuf::new(&mut *buf);
+        if let Poll::Ready(result) = self.socks[i].poll_recv_from(cx, &mut read_buf) {
+          return Poll::Ready(result.map(|addr| {
+            self.learn_route(addr, i);
+            (read_buf.filled().len(), addr)
+          }));
+        }
+      }
+      Poll::Pending
+    }))
+  }
+
+  fn send_to<'a>(&'a self, buf: &'a [u8], addr: SocketAddr) -> BoxFuture<'a, io::Result<usize>> {
+    Box::pin(self.route(addr).send_to(buf, addr))
+  }
+
+  fn local_addr(&self) -> io::Result<SocketAddr> {
+    self.socks[0].local_addr()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  async fn recv_from(socket: &MultiSocket, sender: &UdpSocket) -> SocketAddr {
+    sender
+      .send_to(b"ping", socket.local_addr().unwrap())
+      .await
+      .unwrap();
+    let mut buf = [0; 16];
+    let (len, addr) = socket.recv_from(&mut buf).await.unwrap();
+    assert_eq!(&buf[..len], b"ping");
+    addr
+  }
+
+  #[tokio::test]
+  async fn routes_replies_through_the_socket_heard_on() {
+    let socket = MultiSocket::bind(["127.0.0.1:0", "127.0.0.1:0"])
+      .await
+      .unwrap();
+    let local_addrs = socket.local_addrs().unwrap();
+    let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    peer.send_to(b"ping", local_addrs[1]).await.unwrap();
+    let mut buf = [0; 16];
+    let (_, addr) = socket.recv_from(&mut buf).await.unwrap();
+
+    socket.send_to(b"pong", addr).await.unwrap();
+    let (_, from) = peer.recv_from(&mut buf).await.unwrap();
+    assert_eq!(from, local_addrs[1]);
+  }
+
+  #[tokio::test]
+  async fn bounds_the_routes() {
+    let mut socket = MultiSocket::bind(["127.0.0.1:0"]).await.unwrap();
+    socket.max_routes = 2;
+    let mut peers = vec![];
+    for _ in 0..3 {
+      peers.push(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+    }
+    for peer in &peers {
+      recv_from(&socket, peer).await;
+    }
+    let last = recv_from(&socket, &peers[2]).await;
+
+    let routes = socket.routes.read();
+    assert_eq!(routes.len(), 2);
+    assert_eq!(routes.get(&last), Some(&0));
+  }
+
+  #[tokio::test]
+  async fn routes_unknown_peers_by_address_family() {
+    let socket = MultiSocket::bind(["127.0.0.1:0"]).await.unwrap();
+    let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    socket
+      .send_to(b"hello", peer.local_addr().unwrap())
+      .await
+      .unwrap();
+    let mut buf = [0; 16];
+    let (len, from) = peer.recv_from(&mut buf).await.unwrap();
+    assert_eq!(&buf[..len], b"hello");
+    assert_eq!(from, socket.local_addr().unwrap());
+  }
+
+  #[tokio::test]
+  async fn refuses_to_listen_on_nothing() {
+    assert!(MultiSocket::new(vec![]).is_err());
+  }
+}
@@ -2,8 +2,6 @@ use thiserror::Error as ThisError;
 
 use std::{fmt::Display, future::Future, time::Duration};
 
-use bincode;
-
 use serde::{Deserialize, Serialize};
 
 pub struct SeqDisplay<'a, T: Display>(pub &'a [T]);
@@ -44,11 +42,13 @@ pub enum Error {
   #[error(transparent)]
   Server(#[from] crate::data::ErrorCode),
   #[error(transparent)]
-  CorruptedData(#[from] bincode::Error),
+  CorruptedData(#[from] crate::codec::Error),
   #[error(transparent)]
   Connection(#[from] crate::connection::Error),
   #[error(transparent)]
   InvalidSockAddr(#[from] std::net::AddrParseError),
+  #[error(transparent)]
+  Handshake(#[from] crate::srp::Error),
   #[error("response not supported")]
   UnsupportedResponse,
 }
@@ -0,0 +1,266 @@
+use std::{
+  fs::{self, File},
+  io,
+  net::{IpAddr, SocketAddr},
+  path::PathBuf,
+};
+
+use thiserror::Error as ThisError;
+
+use clap::{Parser, Subcommand};
+
+use chatroom_server_core::{
+  accounts::{self, AccountFormat, ConflictMode},
+  control::{self, ControlClient, ControlRequest, ControlResponse},
+  storage::BanTarget,
+};
+
+/// Operate a running chatroomd through its control channel
+#[derive(Parser, Debug)]
+#[clap(about, version, author)]
+struct Args {
+  /// specify address of the control channel
+  #[clap(short, long, default_value = "127.0.0.1:9100")]
+  addr: SocketAddr,
+  /// specify file holding the control token
+  #[clap(short, long, default_value = "/var/lib/chatroomd/control.token")]
+  token_file: PathBuf,
+  #[clap(subcommand)]
+  command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+  /// List online users with their addresses
+  Online,
+  /// Log a user out and revoke their sessions, so that they have to log in with the password
+  Kick { name: String },
+  /// Ban a user or an address, whoever is online under it gets kicked
+  Ban {
+    #[clap(long, conflicts_with = "ip", required_unless_present = "ip")]
+    user: Option<String>,
+    #[clap(long)]
+    ip: Option<IpAddr>,
+    #[clap(long)]
+    reason: Option<String>,
+    /// specify seconds the ban lasts, it is permanent if absent
+    #[clap(long)]
+    duration: Option<u64>,
+  },
+  /// Lift the ban on a user or an address
+  Unban {
+    #[clap(long, conflicts_with = "ip", required_unless_present = "ip")]
+    user: Option<String>,
+    #[clap(long)]
+    ip: Option<IpAddr>,
+  },
+  /// List bans in effect
+  Bans,
+  /// Let a user or an address locked out for failed logins try again
+  Unlock {
+    #[clap(long, conflicts_with = "ip", required_unless_present = "ip")]
+    user: Option<String>,
+    #[clap(long)]
+    ip: Option<IpAddr>,
+  },
+  /// List users and addresses which have to wait before logging in again
+  Lockouts,
+  /// Delete an account for good
+  Delete { name: String },
+  /// Set a new password for a user, read from the first line of stdin
+  ResetPassword { name: String },
+  /// Turn two-factor authentication off for a user who lost their authenticator
+  DisableTotp { name: String },
+  /// Read accounts from a file into the running server
+  Import {
+    input: PathBuf,
+    /// specify account format, one of "json" and "csv", guessed from the file name if absent
+    #[clap(short, long)]
+    format: Option<AccountFormat>,
+    /// specify what to do with taken names, one of "skip", "overwrite" and "fail"
+    #[clap(long, default_value = "fail")]
+    on_conflict: ConflictMode,
+  },
+  /// Send a message to everyone online
+  Announce { msg: String },
+  /// Show what the server is up to
+  Stats,
+  /// Read the config file again, as on SIGHUP
+  Reload,
+}
+
+#[derive(ThisError, Debug)]
+enum Error {
+  #[error("failed to read token file \"{path}\": {err}")]
+  TokenFile { path: PathBuf, err: io::Error },
+  #[error(transparent)]
+  IO(#[from] io::Error),
+  #[error(transparent)]
+  Control(#[from] control::Error),
+  #[error(transparent)]
+  Accounts(#[from] accounts::Error),
+  #[error("account format of \"{0}\" is unknown, please specify it")]
+  UnknownAccountFormat(PathBuf),
+  #[error("unexpected response: {0:?}")]
+  UnexpectedResponse(ControlResponse),
+}
+
+#[tokio::main]
+async fn main() {
+  if let Err(err) = run(Args::parse()).await {
+    eprintln!("chatroom-admin: {}", err);
+    std::process::exit(1);
+  }
+}
+
+fn ban_target(user: Option<String>, ip: Option<IpAddr>) -> BanTarget {
+  match (user, ip) {
+    (Some(user), _) => BanTarget::User(user),
+    (None, Some(ip)) => BanTarget::Ip(ip),
+    (None, None) => unreachable!("clap requires one of them"),
+  }
+}
+
+fn format_target(target: BanTarget) -> String {
+  match target {
+    BanTarget::User(name) => format!("user {}", name),
+    BanTarget::Ip(ip) => format!("ip {}", ip),
+  }
+}
+
+async fn run(args: Args) -> Result<(), Error> {
+  let token = fs::read_to_string(&args.token_file).map_err(|err| Error::TokenFile {
+    path: args.token_file.clone(),
+    err,
+  })?;
+  let request = match args.command {
+    Command::Online => ControlRequest::ListOnline,
+    Command::Kick { name } => ControlRequest::Kick { name },
+    Command::Ban {
+      user,
+      ip,
+      reason,
+      duration,
+    } => ControlRequest::Ban {
+      target: ban_target(user, ip),
+      reason,
+      duration,
+    },
+    Command::Unban { user, ip } => ControlRequest::Unban {
+      target: ban_target(user, ip),
+    },
+    Command::Bans => ControlRequest::ListBans,
+    Command::Unlock { user, ip } => ControlRequest::Unlock {
+      target: ban_target(user, ip),
+    },
+    Command::Lockouts => ControlRequest::ListLockouts,
+    Command::Delete { name } => ControlRequest::DeleteUser { name },
+    Command::ResetPassword { name } => {
+      let mut password = String::new();
+      io::stdin().read_line(&mut password)?;
+      let password = password.trim_end_matches(&['\r', '\n'][..]).to_string();
+      ControlRequest::ResetPassword { name, password }
+    }
+    Command::DisableTotp { name } => ControlRequest::DisableTotp { name },
+    Command::Import {
+      input,
+      format,
+      on_conflict,
+    } => {
+      let format = format
+        .or_else(|| AccountFormat::from_path(&input))
+        .ok_or_else(|| Error::UnknownAccountFormat(input.clone()))?;
+      ControlRequest::ImportAccounts {
+        accounts: accounts::read(format, File::open(&input)?)?,
+        on_conflict,
+      }
+    }
+    Command::Announce { msg } => ControlRequest::Announce { msg },
+    Command::Stats => ControlRequest::Stats,
+    Command::Reload => ControlRequest::Reload,
+  };
+
+  let mut client = ControlClient::connect(args.addr, token.trim().to_string()).await?;
+  match client.request(&request).await? {
+    ControlResponse::Ok => println!("done."),
+    ControlResponse::Online { users } => {
+      for (name, addr) in users {
+        println!("{}\t{}", name, addr);
+      }
+    }
+    ControlResponse::Kicked { users } => {
+      for name in users {
+        println!("kicked \"{}\".", name);
+      }
+    }
+    ControlResponse::Unbanned { lifted } => {
+      println!(
+        "{}",
+        if lifted {
+          "ban is lifted."
+        } else {
+          "no such ban."
+        }
+      )
+    }
+    ControlResponse::Bans { bans } => {
+      for ban in bans {
+        let target = format_target(ban.target);
+        let until = match ban.until {
+          Some(until) => until.to_string(),
+          None => "forever".to_string(),
+        };
+        println!(
+          "{}\tuntil {}\t{}",
+          target,
+          until,
+          ban.reason.unwrap_or_default()
+        );
+      }
+    }
+    ControlResponse::Unlocked { lifted } => {
+      println!(
+        "{}",
+        if lifted {
+          "lockout is lifted."
+        } else {
+          "no such lockout."
+        }
+      )
+    }
+    ControlResponse::TotpDisabled { disabled } => {
+      println!(
+        "{}",
+        if disabled {
+          "two-factor authentication is disabled."
+        } else {
+          "two-factor authentication was not enabled."
+        }
+      )
+    }
+    ControlResponse::Imported { summary } => println!(
+      "imported {} accounts, overwrote {} and skipped {}.",
+      summary.imported, summary.overwritten, summary.skipped
+    ),
+    ControlResponse::Lockouts { lockouts } => {
+      for lockout in lockouts {
+        println!(
+          "{}\tuntil {}\t{} failures",
+          format_target(lockout.target),
+          lockout.until,
+          lockout.failures
+        );
+      }
+    }
+    ControlResponse::Announced { receivers } => println!("announced to {} users.", receivers),
+    ControlResponse::Stats { stats } => {
+      println!("uptime: {}s", stats.uptime_secs);
+      println!("users: {}", stats.users);
+      println!("online: {}", stats.online);
+      println!("devices: {}", stats.devices);
+      println!("bans: {}", stats.bans);
+    }
+    response => return Err(Error::UnexpectedResponse(response)),
+  }
+  Ok(())
+}
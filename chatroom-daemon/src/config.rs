@@ -0,0 +1,184 @@
+use std::{fs, net::SocketAddr, path::Path, path::PathBuf, time::Duration};
+
+use serde::{Deserialize, Serialize};
+
+use chatroom_core::codec::Format;
+use chatroom_server_core::config::{LoginThrottle, RateLimit, RegistrationPolicy, ServerConfig};
+
+use crate::Error;
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+  /// addresses to listen on
+  pub bind: Vec<String>,
+  /// seconds without heartbeat before a user is considered offline
+  pub heartbeat_interval: u64,
+  /// directory where server data is kept
+  pub storage: PathBuf,
+  pub codec: Format,
+  pub registration: RegistrationPolicy,
+  pub rate_limit: Option<RateLimitConfig>,
+  pub login_throttle: LoginThrottleConfig,
+  /// seconds a session token stays good for resuming
+  pub session_lifetime: u64,
+  pub auth: AuthConfig,
+  /// the control channel is off if absent
+  pub control: Option<ControlConfig>,
+  /// loopback address to serve prometheus metrics on, off if absent
+  pub metrics: Option<SocketAddr>,
+  pub log: LogConfig,
+}
+
+impl Default for Config {
+  fn default() -> Self {
+    Self {
+      bind: vec!["0.0.0.0:9000".into()],
+      heartbeat_interval: 60,
+      storage: "/var/lib/chatroomd".into(),
+      codec: Format::default(),
+      registration: Default::default(),
+      rate_limit: None,
+      login_throttle: Default::default(),
+      session_lifetime: ServerConfig::default().session_lifetime.as_secs(),
+      auth: Default::default(),
+      control: None,
+      metrics: None,
+      log: Default::default(),
+    }
+  }
+}
+
+impl Config {
+  pub fn load(path: &Path) -> Result<Self, Error> {
+    let content = fs::read_to_string(path).map_err(|err| Error::Config {
+      path: path.to_owned(),
+      msg: err.to_string(),
+    })?;
+    toml::from_str(&content).map_err(|err| Error::Config {
+      path: path.to_owned(),
+      msg: err.to_string(),
+    })
+  }
+
+  /// the part of the config a running server picks up on reload
+  pub fn server_config(&self) -> ServerConfig {
+    ServerConfig {
+      heartbeat_interval: Duration::from_secs(self.heartbeat_interval),
+      registration: self.registration,
+      rate_limit: self.rate_limit.map(|rate_limit| RateLimit {
+        requests: rate_limit.requests,
+        window: Duration::from_secs(rate_limit.window),
+      }),
+      login_throttle: LoginThrottle {
+        free_attempts: self.login_throttle.free_attempts,
+        base_delay: Duration::from_secs(self.login_throttle.base_delay),
+        lockout_after: self.login_throttle.lockout_after,
+        lockout: Duration::from_secs(self.login_throttle.lockout),
+        forget_after: Duration::from_secs(self.login_throttle.forget_after),
+      },
+      session_lifetime: Duration::from_secs(self.session_lifetime),
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimitConfig {
+  /// requests allowed from one address within the window
+  pub requests: u32,
+  /// length of the window in seconds
+  pub window: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoginThrottleConfig {
+  /// failed logins allowed before any delay
+  pub free_attempts: u32,
+  /// seconds to wait after the first failure beyond those, doubled with every further one
+  pub base_delay: u64,
+  /// failed logins after which the account or address is locked out
+  pub lockout_after: u32,
+  /// seconds a lockout lasts
+  pub lockout: u64,
+  /// seconds without failures after which they are forgotten
+  pub forget_after: u64,
+}
+
+impl Default for LoginThrottleConfig {
+  fn default() -> Self {
+    let throttle = LoginThrottle::default();
+    Self {
+      free_attempts: throttle.free_attempts,
+      base_delay: throttle.base_delay.as_secs(),
+      lockout_after: throttle.lockout_after,
+      lockout: throttle.lockout.as_secs(),
+      forget_after: throttle.forget_after.as_secs(),
+    }
+  }
+}
+
+/// where passwords are checked
+#[derive(Debug, Clone, PartialEq, Default, Deserialize, Serialize)]
+#[serde(tag = "provider", rename_all = "lowercase", deny_unknown_fields)]
+pub enum AuthConfig {
+  /// accounts registered through the server or imported
+  #[default]
+  Local,
+  /// a file of `name:hash` lines with argon2 or bcrypt hashes
+  Htpasswd { path: PathBuf },
+  /// a simple bind as the user
+  Ldap {
+    url: String,
+    /// `{username}` is replaced with the name of the user
+    bind_dn: String,
+    #[serde(default)]
+    starttls: bool,
+    /// seconds to wait for the directory
+    #[serde(default = "default_ldap_timeout")]
+    timeout: u64,
+  },
+}
+
+fn default_ldap_timeout() -> u64 {
+  5
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ControlConfig {
+  /// must be a loopback address
+  pub bind: SocketAddr,
+  /// file holding the token, `control.token` in the storage directory if absent
+  pub token_file: Option<PathBuf>,
+}
+
+impl Default for ControlConfig {
+  fn default() -> Self {
+    Self {
+      bind: ([127, 0, 0, 1], 9100).into(),
+      token_file: None,
+    }
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+  /// one of "error", "warn", "info", "debug" and "trace"
+  pub level: String,
+  /// journald stamps entries by itself, turn this off when running under it
+  pub timestamps: bool,
+  pub ansi: bool,
+}
+
+impl Default for LogConfig {
+  fn default() -> Self {
+    Self {
+      level: "info".into(),
+      timestamps: true,
+      ansi: false,
+    }
+  }
+}
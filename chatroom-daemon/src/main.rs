@@ -0,0 +1,518 @@
+mod config;
+
+use std::{
+  fs::{self, File, OpenOptions, TryLockError},
+  io::{self, BufReader, Write},
+  path::{Path, PathBuf},
+  result::Result,
+  sync::Arc,
+  time::{Duration, SystemTime},
+};
+
+use thiserror::Error as ThisError;
+
+use clap::{Parser, Subcommand};
+
+use rand::Rng;
+
+use tokio::sync::mpsc;
+
+use tracing::{error, info, warn, Level};
+
+use chatroom_core::{
+  codec::{Codec, Format},
+  transport::MultiSocket,
+};
+use chatroom_server_core::{
+  accounts::{self, AccountFormat, ConflictMode},
+  audit::{self, AuditHead, AuditTrail},
+  auth::{self, AuthProvider, HtpasswdProvider, LdapConfig, LdapProvider, LocalProvider},
+  config::RegistrationPolicy,
+  control::{self, ReloadRequest},
+  metrics,
+  server::{Server, ServerState},
+  storage::{self, SqliteStorage, Storage},
+};
+
+use config::{AuthConfig, Config, LogConfig};
+
+/// locked by a running daemon in its storage directory
+const LOCK_FILE: &str = "chatroomd.lock";
+/// how often the config file is checked for changes
+const CONFIG_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Headless chatroom server
+#[derive(Parser, Debug)]
+#[clap(about, version, author)]
+struct Args {
+  /// specify path of the config file
+  #[clap(short, long)]
+  config: Option<PathBuf>,
+  /// specify socket address to listen on, may be given more than once
+  #[clap(short, long)]
+  bind: Vec<String>,
+  /// specify seconds without heartbeat before a user is considered offline
+  #[clap(long)]
+  heartbeat_interval: Option<u64>,
+  /// specify directory where server data is kept
+  #[clap(short, long)]
+  storage: Option<PathBuf>,
+  /// specify log level, one of "error", "warn", "info", "debug" and "trace"
+  #[clap(long)]
+  log_level: Option<String>,
+  /// specify wire format, one of "bincode", "msgpack" and "json"
+  #[clap(long)]
+  codec: Option<Format>,
+  /// specify whether new accounts may register, one of "open" and "closed"
+  #[clap(long)]
+  registration: Option<RegistrationPolicy>,
+  #[clap(subcommand)]
+  command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+  /// Write every account to a file, or stdout if none is given
+  Export {
+    /// specify account format, one of "json" and "csv", guessed from the file name if absent
+    #[clap(short, long)]
+    format: Option<AccountFormat>,
+    #[clap(short, long)]
+    output: Option<PathBuf>,
+  },
+  /// Read accounts from a file while no daemon is running, `chatroom-admin import` brings them
+  /// into a running one
+  Import {
+    input: PathBuf,
+    /// specify account format, one of "json" and "csv", guessed from the file name if absent
+    #[clap(short, long)]
+    format: Option<AccountFormat>,
+    /// specify what to do with taken names, one of "skip", "overwrite" and "fail"
+    #[clap(long, default_value = "fail")]
+    on_conflict: ConflictMode,
+  },
+  /// Write the audit trail as json lines to a file, or stdout if none is given
+  ExportAudit {
+    #[clap(short, long)]
+    output: Option<PathBuf>,
+  },
+  /// Check that the audit trail is not tampered with, an exported one if a file is given
+  VerifyAudit {
+    input: Option<PathBuf>,
+    /// specify the last head logged as "audit trail head is <seq>:<hash>", so that records
+    /// dropped from the end are noticed as well
+    #[clap(long)]
+    head: Option<AuditHead>,
+  },
+}
+
+#[derive(ThisError, Debug)]
+pub enum Error {
+  #[error("failed to load config file \"{path}\": {msg}")]
+  Config { path: PathBuf, msg: String },
+  #[error("invalid log level \"{0}\"")]
+  InvalidLogLevel(String),
+  #[error("invalid config: {0}")]
+  InvalidConfig(String),
+  #[error(transparent)]
+  IO(#[from] io::Error),
+  #[error(transparent)]
+  Storage(#[from] storage::Error),
+  #[error(transparent)]
+  Accounts(#[from] accounts::Error),
+  #[error(transparent)]
+  Audit(#[from] audit::Error),
+  #[error(transparent)]
+  Auth(#[from] auth::Error),
+  #[error(transparent)]
+  Control(#[from] control::Error),
+  #[error(transparent)]
+  Metrics(#[from] metrics::Error),
+  #[error("account format of \"{0}\" is unknown, please specify it")]
+  UnknownAccountFormat(PathBuf),
+  #[error("storage \"{0}\" is in use by a running chatroomd")]
+  StorageInUse(PathBuf),
+  #[error(
+    "storage \"{0}\" is in use by a running chatroomd, which would not see the accounts, import \
+     them with `chatroom-admin import` instead"
+  )]
+  ImportWhileRunning(PathBuf),
+  #[error(transparent)]
+  Chatroom(#[from] chatroom_core::utils::Error),
+}
+
+#[tokio::main]
+async fn main() {
+  if let Err(err) = run(Args::parse()).await {
+    eprintln!("chatroomd: {}", err);
+    std::process::exit(1);
+  }
+}
+
+async fn run(mut args: Args) -> Result<(), Error> {
+  let command = args.command.take();
+  let config = load_config(&args)?;
+
+  fs::create_dir_all(&config.storage)?;
+  // taken before the database is opened, so that nothing reads it halfway through a migration
+  let _lock = lock_storage(&config.storage).map_err(|err| match (err, &command) {
+    (Error::StorageInUse(path), Some(Command::Import { .. })) => Error::ImportWhileRunning(path),
+    (err, _) => err,
+  })?;
+  let storage = Arc::new(SqliteStorage::open(config.storage.join("users.db"))?);
+
+  match command {
+    Some(Command::Export { format, output }) => {
+      let users = storage.load_users()?;
+      let count = match output {
+        Some(path) => {
+          let format = guess_format(format, &path)?;
+          accounts::export(&users, format, File::create(&path)?)?
+        }
+        None => accounts::export(&users, format.unwrap_or(AccountFormat::Json), io::stdout())?,
+      };
+      eprintln!("exported {} accounts.", count);
+      return Ok(());
+    }
+    Some(Command::Import {
+      input,
+      format,
+      on_conflict,
+    }) => {
+      let format = guess_format(format, &input)?;
+      let accounts = accounts::read(format, File::open(&input)?)?;
+      let trail = AuditTrail::new(storage.clone())?;
+      let summary = accounts::import(&*storage, &trail, &accounts, on_conflict)?;
+      trail.flush();
+      eprintln!(
+        "imported {} accounts, overwrote {} and skipped {}.",
+        summary.imported, summary.overwritten, summary.skipped
+      );
+      return Ok(());
+    }
+    Some(Command::ExportAudit { output }) => {
+      let records = storage.load_audit_records()?;
+      let count = match output {
+        Some(path) => audit::export(&records, File::create(&path)?)?,
+        None => audit::export(&records, io::stdout())?,
+      };
+      eprintln!("exported {} audit records.", count);
+      return Ok(());
+    }
+    Some(Command::VerifyAudit { input, head }) => {
+      let records = match input {
+        Some(path) => audit::read(BufReader::new(File::open(&path)?))?,
+        None => storage.load_audit_records()?,
+      };
+      audit::verify(&records, head.as_ref())?;
+      match records.last() {
+        Some(last) => eprintln!(
+          "{} audit records are intact, the last one is {} with hash {}.",
+          records.len(),
+          last.seq,
+          last.hash
+        ),
+        None => eprintln!("audit trail is empty."),
+      }
+      return Ok(());
+    }
+    None => {}
+  }
+
+  init_logging(&config.log)?;
+  let auth = auth_provider(&config.auth, storage.clone())?;
+  let state = ServerState::with_storage(config.server_config(), storage.clone())?;
+  state.set_auth_provider(auth);
+  info!(
+    source = "server",
+    "loaded {} users.",
+    state.users.read().len()
+  );
+
+  let sock = MultiSocket::bind(config.bind.iter()).await?;
+  for addr in sock.local_addrs()? {
+    info!(source = "server", "listening on {}.", addr);
+  }
+
+  let server = Arc::new(Server::with_transport(
+    config.codec,
+    state,
+    Arc::new(()),
+    sock,
+  )?);
+
+  let (reload_sender, mut reload_requests) = mpsc::channel::<ReloadRequest>(1);
+  let _control = match &config.control {
+    Some(control) => {
+      let token_file = match &control.token_file {
+        Some(token_file) => token_file.clone(),
+        None => config.storage.join("control.token"),
+      };
+      let token = control_token(&token_file)?;
+      Some(
+        control::serve(
+          server.clone(),
+          control.bind,
+          token,
+          Some(reload_sender.clone()),
+        )
+        .await?,
+      )
+    }
+    None => None,
+  };
+  let _metrics = match config.metrics {
+    Some(addr) => Some(metrics::serve(server.clone(), addr).await?),
+    None => None,
+  };
+
+  let mut signals = Signals::new()?;
+  let mut config_poll = tokio::time::interval(CONFIG_POLL_INTERVAL);
+  let mut config_modified = args.config.as_deref().and_then(modified);
+  loop {
+    tokio::select! {
+      signal = signals.recv() => match signal {
+        Signal::Shutdown => break,
+        Signal::Reload => log_reload(reload(&args, &config, &server, &storage)),
+      },
+      Some(reply) = reload_requests.recv() => {
+        let result = reload(&args, &config, &server, &storage);
+        let _ = reply.send(result.as_ref().map(|_| ()).map_err(ToString::to_string));
+        log_reload(result);
+      }
+      _ = config_poll.tick(), if args.config.is_some() => {
+        let current = args.config.as_deref().and_then(modified);
+        if current != config_modified {
+          config_modified = current;
+          info!(source = "server", "config file is changed.");
+          log_reload(reload(&args, &config, &server, &storage));
+        }
+      }
+    }
+  }
+  info!(source = "server", "shutting down.");
+
+  server.logout_all().await;
+
+  Ok(())
+}
+
+/// read the config file if any, command line flags take precedence over it
+fn load_config(args: &Args) -> Result<Config, Error> {
+  let mut config = match &args.config {
+    Some(path) => Config::load(path)?,
+    None => Config::default(),
+  };
+  if !args.bind.is_empty() {
+    config.bind = args.bind.clone();
+  }
+  if let Some(heartbeat_interval) = args.heartbeat_interval {
+    config.heartbeat_interval = heartbeat_interval;
+  }
+  if let Some(storage) = &args.storage {
+    config.storage = storage.clone();
+  }
+  if let Some(log_level) = &args.log_level {
+    config.log.level = log_level.clone();
+  }
+  if let Some(codec) = args.codec {
+    config.codec = codec;
+  }
+  if let Some(registration) = args.registration {
+    config.registration = registration;
+  }
+  config
+    .server_config()
+    .validate()
+    .map_err(Error::InvalidConfig)?;
+  Ok(config)
+}
+
+/// apply whatever in the config file can change without dropping online users
+fn reload<C: Codec>(
+  args: &Args,
+  running: &Config,
+  server: &Server<C>,
+  storage: &Arc<SqliteStorage>,
+) -> Result<(), Error> {
+  info!(source = "server", "reloading config.");
+  let config = load_config(args)?;
+  if config.bind != running.bind
+    || config.storage != running.storage
+    || config.codec != running.codec
+    || config.control != running.control
+    || config.metrics != running.metrics
+    || config.log != running.log
+  {
+    warn!(
+      source = "server",
+      "changes to bind, storage, codec, control, metrics and log only take effect after a restart."
+    );
+  }
+  // the htpasswd file gets read again as well, nothing changes if it is broken
+  let provider = auth_provider(&config.auth, storage.clone())?;
+  server.apply_config(config.server_config());
+  server.get_state().set_auth_provider(provider);
+  Ok(())
+}
+
+fn log_reload(result: Result<(), Error>) {
+  if let Err(err) = result {
+    error!(source = "server", "failed to reload config: {}.", err);
+  }
+}
+
+/// none for a missing file or on platforms without modification times
+fn modified(path: &Path) -> Option<SystemTime> {
+  fs::metadata(path).and_then(|meta| meta.modified()).ok()
+}
+
+fn auth_provider(
+  config: &AuthConfig,
+  storage: Arc<SqliteStorage>,
+) -> Result<Arc<dyn AuthProvider>, Error> {
+  Ok(match config {
+    AuthConfig::Local => Arc::new(LocalProvider::new(storage)),
+    AuthConfig::Htpasswd { path } => Arc::new(HtpasswdProvider::open(path)?),
+    AuthConfig::Ldap {
+      url,
+      bind_dn,
+      starttls,
+      timeout,
+    } => Arc::new(LdapProvider::new(LdapConfig {
+      url: url.clone(),
+      bind_dn: bind_dn.clone(),
+      starttls: *starttls,
+      timeout: Duration::from_secs(*timeout),
+    })),
+  })
+}
+
+/// read the control token, generating one on first start, whoever can read the file is an operator
+fn control_token(path: &Path) -> Result<String, Error> {
+  match fs::read_to_string(path) {
+    Ok(token) => match token.trim() {
+      "" => Err(Error::InvalidConfig(format!(
+        "control token file {} is empty",
+        path.display()
+      ))),
+      token => Ok(token.to_string()),
+    },
+    Err(err) if err.kind() == io::ErrorKind::NotFound => {
+      let mut bytes = [0u8; 32];
+      rand::thread_rng().fill(&mut bytes);
+      let token = bytes
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<String>();
+
+      let mut options = OpenOptions::new();
+      options.write(true).create_new(true);
+      #[cfg(unix)]
+      {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+      }
+      options.open(path)?.write_all(token.as_bytes())?;
+      info!(
+        source = "server",
+        "control token is written to \"{}\".",
+        path.display()
+      );
+      Ok(token)
+    }
+    Err(err) => Err(err.into()),
+  }
+}
+
+/// keep other daemons and offline imports away from the storage for as long as the file is open
+fn lock_storage(dir: &Path) -> Result<File, Error> {
+  let file = OpenOptions::new()
+    .create(true)
+    .write(true)
+    .truncate(false)
+    .open(dir.join(LOCK_FILE))?;
+  match file.try_lock() {
+    Ok(()) => Ok(file),
+    Err(TryLockError::WouldBlock) => Err(Error::StorageInUse(dir.to_owned())),
+    Err(TryLockError::Error(err)) => Err(err.into()),
+  }
+}
+
+fn guess_format(format: Option<AccountFormat>, path: &Path) -> Result<AccountFormat, Error> {
+  format
+    .or_else(|| AccountFormat::from_path(path))
+    .ok_or_else(|| Error::UnknownAccountFormat(path.to_owned()))
+}
+
+fn init_logging(config: &LogConfig) -> Result<(), Error> {
+  let level = config
+    .level
+    .parse::<Level>()
+    .map_err(|_| Error::InvalidLogLevel(config.level.clone()))?;
+  let builder = tracing_subscriber::fmt()
+    .with_writer(io::stdout)
+    .with_max_level(level)
+    .with_ansi(config.ansi)
+    .with_target(false);
+  if config.timestamps {
+    builder.init();
+  } else {
+    builder.without_time().init();
+  }
+  Ok(())
+}
+
+enum Signal {
+  /// SIGHUP, never sent on platforms without it
+  Reload,
+  /// SIGTERM or ctrl-c
+  Shutdown,
+}
+
+/// signals the daemon listens for, registered once so that none gets lost between two waits
+struct Signals {
+  #[cfg(unix)]
+  hangup: tokio::signal::unix::Signal,
+  #[cfg(unix)]
+  terminate: tokio::signal::unix::Signal,
+  #[cfg(unix)]
+  interrupt: tokio::signal::unix::Signal,
+}
+
+impl Signals {
+  fn new() -> io::Result<Self> {
+    #[cfg(unix)]
+    {
+      use tokio::signal::unix::{signal, SignalKind};
+      Ok(Self {
+        hangup: signal(SignalKind::hangup())?,
+        terminate: signal(SignalKind::terminate())?,
+        interrupt: signal(SignalKind::interrupt())?,
+      })
+    }
+    #[cfg(not(unix))]
+    {
+      Ok(Self {})
+    }
+  }
+
+  async fn recv(&mut self) -> Signal {
+    #[cfg(unix)]
+    {
+      tokio::select! {
+        _ = self.hangup.recv() => Signal::Reload,
+        _ = self.terminate.recv() => Signal::Shutdown,
+        _ = self.interrupt.recv() => Signal::Shutdown,
+      }
+    }
+    #[cfg(not(unix))]
+    {
+      // an error means ctrl-c cannot be listened for, so it never comes
+      if tokio::signal::ctrl_c().await.is_err() {
+        std::future::pending::<()>().await
+      }
+      Signal::Shutdown
+    }
+  }
+}
@@ -0,0 +1,370 @@
+use std::{
+  collections::{HashMap, HashSet},
+  fmt::Display,
+  io::{Read, Write},
+  path::Path,
+  str::FromStr,
+};
+
+use thiserror::Error as ThisError;
+
+use serde::{Deserialize, Serialize};
+
+use chatroom_core::{data::UserEssential, srp::Verifier};
+
+use crate::{
+  audit::{AuditEvent, AuditTrail},
+  auth::EXTERNAL_PASSWORD_HASH,
+  storage::{self, Storage},
+};
+
+/// a user as it appears in exported files
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Account {
+  pub name: String,
+  pub password_hash: String,
+}
+
+impl Account {
+  fn validate(&self) -> Result<(), String> {
+    if self.name.is_empty() {
+      return Err("name is empty".into());
+    }
+    // argon2 hashes from before srp are kept, the account works again once its password is reset
+    if Verifier::decode(&self.password_hash).is_none()
+      && !self.password_hash.starts_with("$argon2")
+      && self.password_hash != EXTERNAL_PASSWORD_HASH
+    {
+      return Err(format!(
+        "password hash of \"{}\" is neither an srp verifier, argon2 nor external",
+        self.name
+      ));
+    }
+    Ok(())
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AccountFormat {
+  Json,
+  Csv,
+}
+
+impl AccountFormat {
+  /// guess the format from the file extension
+  pub fn from_path(path: &Path) -> Option<Self> {
+    path.extension()?.to_str()?.parse().ok()
+  }
+}
+
+impl Display for AccountFormat {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.write_str(match self {
+      Self::Json => "json",
+      Self::Csv => "csv",
+    })
+  }
+}
+
+impl FromStr for AccountFormat {
+  type Err = Error;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s.to_ascii_lowercase().as_str() {
+      "json" => Ok(Self::Json),
+      "csv" => Ok(Self::Csv),
+      _ => Err(Error::UnknownFormat(s.to_string())),
+    }
+  }
+}
+
+/// what to do with an imported account whose name is already taken
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConflictMode {
+  /// keep the existing account
+  Skip,
+  /// replace the password of the existing account
+  Overwrite,
+  /// import nothing at all
+  Fail,
+}
+
+impl FromStr for ConflictMode {
+  type Err = Error;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s.to_ascii_lowercase().as_str() {
+      "skip" => Ok(Self::Skip),
+      "overwrite" => Ok(Self::Overwrite),
+      "fail" => Ok(Self::Fail),
+      _ => Err(Error::UnknownConflictMode(s.to_string())),
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+pub struct ImportSummary {
+  pub imported: usize,
+  pub overwritten: usize,
+  pub skipped: usize,
+}
+
+/// write accounts sorted by name
+pub fn export<W: Write>(
+  users: &HashMap<String, UserEssential>,
+  format: AccountFormat,
+  mut writer: W,
+) -> Result<usize, Error> {
+  let mut accounts = users
+    .iter()
+    .map(|(name, user)| Account {
+      name: name.clone(),
+      password_hash: user.password_hash.clone(),
+    })
+    .collect::<Vec<_>>();
+  accounts.sort_by(|a, b| a.name.cmp(&b.name));
+
+  match format {
+    AccountFormat::Json => {
+      serde_json::to_writer_pretty(&mut writer, &accounts)?;
+      writer.write_all(b"\n")?;
+    }
+    AccountFormat::Csv => {
+      let mut writer = csv::Writer::from_writer(writer);
+      for account in accounts.iter() {
+        writer.serialize(account)?;
+      }
+      writer.flush()?;
+    }
+  }
+  Ok(accounts.len())
+}
+
+/// read and validate accounts
+pub fn read<R: Read>(format: AccountFormat, reader: R) -> Result<Vec<Account>, Error> {
+  let accounts: Vec<Account> = match format {
+    AccountFormat::Json => serde_json::from_reader(reader)?,
+    AccountFormat::Csv => csv::Reader::from_reader(reader)
+      .deserialize()
+      .collect::<Result<_, _>>()?,
+  };
+  for (i, account) in accounts.iter().enumerate() {
+    account
+      .validate()
+      .map_err(|reason| Error::InvalidAccount { index: i, reason })?;
+  }
+  Ok(accounts)
+}
+
+/// add accounts to the storage all at once, in fail mode every account is checked before anything
+/// is written, the accounts written end up in `audit`
+pub fn import(
+  storage: &dyn Storage,
+  audit: &AuditTrail,
+  accounts: &[Account],
+  mode: ConflictMode,
+) -> Result<ImportSummary, Error> {
+  let mut existing = storage.load_users()?.into_keys().collect::<HashSet<_>>();
+
+  if mode == ConflictMode::Fail {
+    let mut seen = existing.clone();
+    let conflicts = accounts
+      .iter()
+      .filter(|account| !seen.insert(account.name.clone()))
+      .map(|account| account.name.clone())
+      .collect::<Vec<_>>();
+    if !conflicts.is_empty() {
+      return Err(Error::Conflict(conflicts));
+    }
+  }
+
+  let mut summary = ImportSummary::default();
+  let (mut inserted, mut updated) = (vec![], vec![]);
+  for account in accounts {
+    let user = UserEssential {
+      password_hash: account.password_hash.clone(),
+    };
+    if existing.contains(&account.name) {
+      match mode {
+        ConflictMode::Overwrite => {
+          updated.push((account.name.clone(), user));
+          summary.overwritten += 1;
+        }
+        _ => summary.skipped += 1,
+      }
+    } else {
+      existing.insert(account.name.clone());
+      inserted.push((account.name.clone(), user));
+      summary.imported += 1;
+    }
+  }
+  // written as a whole, a failure halfway through leaves the storage as it was
+  storage.import_users(&inserted, &updated)?;
+
+  let names =
+    |users: Vec<(String, UserEssential)>| users.into_iter().map(|(name, _)| name).collect();
+  audit.record(AuditEvent::AccountsImported {
+    imported: names(inserted),
+    overwritten: names(updated),
+  });
+  Ok(summary)
+}
+
+#[derive(ThisError, Debug)]
+pub enum Error {
+  #[error(transparent)]
+  IO(#[from] std::io::Error),
+  #[error(transparent)]
+  Json(#[from] serde_json::Error),
+  #[error(transparent)]
+  Csv(#[from] csv::Error),
+  #[error(transparent)]
+  Storage(#[from] storage::Error),
+  #[error("account #{index} is invalid: {reason}")]
+  InvalidAccount { index: usize, reason: String },
+  #[error("accounts already existed: {}", .0.join(", "))]
+  Conflict(Vec<String>),
+  #[error("unknown account format \"{0}\"")]
+  UnknownFormat(String),
+  #[error("unknown conflict mode \"{0}\", expecting one of \"skip\", \"overwrite\" and \"fail\"")]
+  UnknownConflictMode(String),
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use std::sync::Arc;
+
+  use crate::storage::MemoryStorage;
+
+  const OLD_HASH: &str = "$argon2i$v=19$m=4096,t=3,p=1$old";
+  const NEW_HASH: &str = "$argon2i$v=19$m=4096,t=3,p=1$new";
+
+  fn storage(users: &[(&str, &str)]) -> MemoryStorage {
+    MemoryStorage::from_users(
+      users
+        .iter()
+        .map(|(name, hash)| {
+          (
+            name.to_string(),
+            UserEssential {
+              password_hash: hash.to_string(),
+            },
+          )
+        })
+        .collect(),
+    )
+  }
+
+  /// accounts exported from a server with alice and carol, in both formats
+  fn exported() -> Vec<(AccountFormat, Vec<u8>)> {
+    let users = storage(&[("alice", NEW_HASH), ("carol", NEW_HASH)])
+      .load_users()
+      .unwrap();
+    [AccountFormat::Json, AccountFormat::Csv]
+      .into_iter()
+      .map(|format| {
+        let mut buf = vec![];
+        assert_eq!(export(&users, format, &mut buf).unwrap(), 2);
+        (format, buf)
+      })
+      .collect()
+  }
+
+  fn audit() -> AuditTrail {
+    AuditTrail::new(Arc::new(MemoryStorage::default())).unwrap()
+  }
+
+  fn hash_of(storage: &MemoryStorage, name: &str) -> Option<String> {
+    storage
+      .load_users()
+      .unwrap()
+      .get(name)
+      .map(|user| user.password_hash.clone())
+  }
+
+  #[test]
+  fn round_trips_accounts() {
+    for (format, buf) in exported() {
+      let accounts = read(format, &buf[..]).unwrap();
+      let names = accounts.iter().map(|a| a.name.as_str()).collect::<Vec<_>>();
+      assert_eq!(names, ["alice", "carol"], "{}", format);
+      assert!(accounts.iter().all(|a| a.password_hash == NEW_HASH));
+    }
+  }
+
+  #[test]
+  fn skips_existing_accounts() {
+    for (format, buf) in exported() {
+      let target = storage(&[("alice", OLD_HASH), ("bob", OLD_HASH)]);
+      let accounts = read(format, &buf[..]).unwrap();
+      let summary = import(&target, &audit(), &accounts, ConflictMode::Skip).unwrap();
+      assert_eq!(
+        summary,
+        ImportSummary {
+          imported: 1,
+          overwritten: 0,
+          skipped: 1,
+        }
+      );
+      assert_eq!(hash_of(&target, "alice").unwrap(), OLD_HASH);
+      assert_eq!(hash_of(&target, "carol").unwrap(), NEW_HASH);
+    }
+  }
+
+  #[test]
+  fn overwrites_existing_accounts() {
+    for (format, buf) in exported() {
+      let target = storage(&[("alice", OLD_HASH), ("bob", OLD_HASH)]);
+      let accounts = read(format, &buf[..]).unwrap();
+      let summary = import(&target, &audit(), &accounts, ConflictMode::Overwrite).unwrap();
+      assert_eq!(
+        summary,
+        ImportSummary {
+          imported: 1,
+          overwritten: 1,
+          skipped: 0,
+        }
+      );
+      assert_eq!(hash_of(&target, "alice").unwrap(), NEW_HASH);
+      assert_eq!(hash_of(&target, "bob").unwrap(), OLD_HASH);
+      assert_eq!(hash_of(&target, "carol").unwrap(), NEW_HASH);
+    }
+  }
+
+  #[test]
+  fn fails_without_writing_on_conflicts() {
+    for (format, buf) in exported() {
+      let target = storage(&[("alice", OLD_HASH), ("bob", OLD_HASH)]);
+      let accounts = read(format, &buf[..]).unwrap();
+      match import(&target, &audit(), &accounts, ConflictMode::Fail) {
+        Err(Error::Conflict(names)) => assert_eq!(names, ["alice"]),
+        res => panic!("expecting a conflict, got {:?}", res),
+      }
+      assert_eq!(hash_of(&target, "alice").unwrap(), OLD_HASH);
+      assert_eq!(hash_of(&target, "carol"), None);
+    }
+
+    // names repeated within the file conflict as well
+    let duplicated = [("dave", NEW_HASH), ("dave", OLD_HASH)].map(|(name, hash)| Account {
+      name: name.to_string(),
+      password_hash: hash.to_string(),
+    });
+    assert!(matches!(
+      import(&storage(&[]), &audit(), &duplicated, ConflictMode::Fail),
+      Err(Error::Conflict(names)) if names == ["dave"]
+    ));
+  }
+
+  #[test]
+  fn rejects_invalid_accounts() {
+    let json = br#"[{"name": "alice", "password_hash": "plaintext"}]"#;
+    assert!(matches!(
+      read(AccountFormat::Json, &json[..]),
+      Err(Error::InvalidAccount { index: 0, .. })
+    ));
+  }
+}
@@ -0,0 +1,328 @@
+use thiserror::Error as ThisError;
+
+use std::net::SocketAddr;
+
+use time::OffsetDateTime;
+
+use tracing::{error, info};
+
+use chatroom_core::{codec::Codec, data::Notification, srp::Verifier};
+
+use crate::{
+  accounts::{self, Account, ConflictMode, ImportSummary},
+  audit::AuditEvent,
+  event::ServerEvent,
+  lockout::Lockout,
+  server::Server,
+  storage::{self, Ban, BanTarget},
+  two_factor,
+};
+
+/// operations for server operators, each one is written to the log with `source = "audit"`
+impl<Coder> Server<Coder>
+where
+  Coder: Codec,
+{
+  /// log a user out on all devices and revoke their sessions, the others are told that they went
+  /// offline
+  pub async fn kick(&self, name: &str) -> Result<(), Error> {
+    if !self.get_state().users.read().contains_key(name) {
+      return Err(Error::UserNotExisted(name.to_string()));
+    }
+    // without its sessions the user has to prove the password to come back
+    let revoked = self.get_state().sessions.revoke_user(name, None);
+    self.tell_kicked(name).await;
+    if !self.force_logout(name).await && revoked == 0 {
+      return Err(Error::UserOffline(name.to_string()));
+    }
+    self.get_listener().on_event(ServerEvent::Kicked {
+      name: name.to_string(),
+    });
+    self.get_state().audit.record(AuditEvent::Kicked {
+      name: name.to_string(),
+    });
+    info!(source = "audit", "user \"{}\" is kicked.", name);
+    Ok(())
+  }
+
+  /// tell everyone online something, returns how many users it went to
+  pub async fn announce(&self, msg: String) -> Result<usize, Error> {
+    let addrs = self
+      .get_state()
+      .addr2user
+      .read()
+      .keys()
+      .cloned()
+      .collect::<Vec<_>>();
+    let notification = Notification::Announcement {
+      timestamp: OffsetDateTime::now_utc(),
+      msg: msg.clone(),
+    };
+    self
+      .get_connection()
+      .queue_to_multiple_with_empty_meta(&notification, addrs.iter().cloned())
+      .await?;
+    info!(
+      source = "audit",
+      "announced to {} users: {}",
+      addrs.len(),
+      msg
+    );
+    Ok(addrs.len())
+  }
+
+  /// ban a user or an address, anyone online under it gets kicked, returns their names
+  pub async fn ban(&self, ban: Ban) -> Result<Vec<String>, Error> {
+    let state = self.get_state();
+    if let BanTarget::User(name) = &ban.target {
+      if !state.users.read().contains_key(name) {
+        return Err(Error::UserNotExisted(name.clone()));
+      }
+    }
+    let stored = ban.clone();
+    storage::unblock(&state.storage, move |storage| storage.insert_ban(&stored)).await?;
+    state.bans.write().insert(ban.target.clone(), ban.clone());
+
+    let mut kicked = vec![];
+    match &ban.target {
+      BanTarget::User(name) => {
+        state.sessions.revoke_user(name, None);
+        self.tell_kicked(name).await;
+        if self.force_logout(name).await {
+          kicked.push(name.clone());
+        }
+      }
+      // devices of the same users elsewhere stay online
+      BanTarget::Ip(ip) => {
+        state.sessions.revoke_ip(*ip);
+        let online = state
+          .addr2user
+          .read()
+          .iter()
+          .filter(|(addr, _)| addr.ip() == *ip)
+          .map(|(&addr, name)| (addr, name.clone()))
+          .collect::<Vec<_>>();
+        self.send_kicked(online.iter().map(|&(addr, _)| addr)).await;
+        for (addr, name) in online {
+          if self.force_logout_device(&name, addr).await && !kicked.contains(&name) {
+            kicked.push(name);
+          }
+        }
+      }
+    }
+
+    self.get_listener().on_event(ServerEvent::Banned {
+      target: ban.target.clone(),
+    });
+    state.audit.record(AuditEvent::Banned {
+      target: ban.target.clone(),
+      reason: ban.reason.clone(),
+      until: ban.until,
+    });
+    info!(
+      source = "audit",
+      "{:?} is banned until {}, reason: {}.",
+      ban.target,
+      ban
+        .until
+        .map_or_else(|| "forever".to_string(), |until| until.to_string()),
+      ban.reason.as_deref().unwrap_or("none")
+    );
+    Ok(kicked)
+  }
+
+  /// tell the devices of a user that an operator logs them out, before they are taken offline and
+  /// no longer get notifications
+  async fn tell_kicked(&self, name: &str) {
+    let addrs = match self.get_state().users.read().get(name) {
+      Some(user) => user
+        .devices
+        .iter()
+        .map(|device| device.ip_address)
+        .collect::<Vec<_>>(),
+      None => return,
+    };
+    self.send_kicked(addrs.into_iter()).await;
+  }
+
+  async fn send_kicked(&self, addrs: impl Iterator<Item = SocketAddr>) {
+    let notification = Notification::Kicked {
+      timestamp: OffsetDateTime::now_utc(),
+    };
+    if let Err(err) = self
+      .get_connection()
+      .send_to_multiple_with_empty_meta(&notification, addrs)
+      .await
+    {
+      error!(
+        source = "internal",
+        "failed to tell kicked devices: {}.", err
+      );
+    }
+  }
+
+  /// returns whether there was a ban to lift
+  pub async fn unban(&self, target: &BanTarget) -> Result<bool, Error> {
+    let state = self.get_state();
+    let lifted = target.clone();
+    let removed =
+      storage::unblock(&state.storage, move |storage| storage.remove_ban(&lifted)).await?;
+    state.bans.write().remove(target);
+    if removed {
+      self.get_listener().on_event(ServerEvent::Unbanned {
+        target: target.clone(),
+      });
+      state.audit.record(AuditEvent::Unbanned {
+        target: target.clone(),
+      });
+      info!(source = "audit", "{:?} is unbanned.", target);
+    }
+    Ok(removed)
+  }
+
+  pub fn get_bans(&self) -> Vec<Ban> {
+    self.get_state().bans.read().values().cloned().collect()
+  }
+
+  /// let a user or an address try logging in again right away, returns whether it had to wait
+  pub fn unlock(&self, target: &BanTarget) -> bool {
+    let state = self.get_state();
+    let unlocked = state.login_guard.unlock(target);
+    if unlocked {
+      self.get_listener().on_event(ServerEvent::Unlocked {
+        target: target.clone(),
+      });
+      state.audit.record(AuditEvent::Unlocked {
+        target: target.clone(),
+      });
+      info!(source = "audit", "{:?} is unlocked.", target);
+    }
+    unlocked
+  }
+
+  pub fn get_lockouts(&self) -> Vec<Lockout> {
+    self.get_state().login_guard.get_lockouts()
+  }
+
+  /// remove an account for good, logging it out first if it is online
+  pub async fn delete_user(&self, name: &str) -> Result<(), Error> {
+    let state = self.get_state();
+    if !state.users.read().contains_key(name) {
+      return Err(Error::UserNotExisted(name.to_string()));
+    }
+    state.sessions.revoke_user(name, None);
+    self.force_logout(name).await;
+    let deleted = name.to_string();
+    storage::unblock(&state.storage, move |storage| storage.delete_user(&deleted)).await?;
+    state.users.write().remove(name);
+    state.two_factor.forget(name);
+
+    self.get_listener().on_event(ServerEvent::Deleted {
+      name: name.to_string(),
+    });
+    state.audit.record(AuditEvent::Deleted {
+      name: name.to_string(),
+    });
+    info!(source = "audit", "user \"{}\" is deleted.", name);
+    Ok(())
+  }
+
+  /// replace the password of a user, they stay online but none of their sessions can be resumed
+  pub async fn reset_password(&self, name: &str, password: &str) -> Result<(), Error> {
+    let state = self.get_state();
+    if !state.users.read().contains_key(name) {
+      return Err(Error::UserNotExisted(name.to_string()));
+    }
+    // the verifier takes a while and storage may block, neither holds up logins or the runtime
+    let (user, password) = (name.to_string(), password.to_string());
+    let stored = storage::unblock(&state.storage, move |storage| {
+      let password_hash = Verifier::new(&user, &password).encode();
+      storage
+        .update_password(&user, &password_hash)
+        .map(|()| password_hash)
+    })
+    .await;
+    let password_hash = match stored {
+      Ok(password_hash) => password_hash,
+      // deleted in the meantime
+      Err(storage::Error::UserNotExisted(_)) => {
+        return Err(Error::UserNotExisted(name.to_string()))
+      }
+      Err(err) => return Err(err.into()),
+    };
+    match state.users.write().get_mut(name) {
+      Some(user) => user.password_hash = password_hash,
+      None => return Err(Error::UserNotExisted(name.to_string())),
+    }
+    state.sessions.revoke_user(name, None);
+
+    self.get_listener().on_event(ServerEvent::PasswordReset {
+      name: name.to_string(),
+    });
+    state.audit.record(AuditEvent::PasswordReset {
+      name: name.to_string(),
+    });
+    info!(source = "audit", "password of user \"{}\" is reset.", name);
+    Ok(())
+  }
+
+  /// import accounts into the storage and the running server, online users stay online
+  pub async fn import_accounts(
+    &self,
+    accounts: &[Account],
+    mode: ConflictMode,
+  ) -> Result<ImportSummary, Error> {
+    let summary = self.get_state().import_accounts(accounts, mode).await?;
+    info!(
+      source = "audit",
+      "imported {} accounts, overwrote {} and skipped {}.",
+      summary.imported,
+      summary.overwritten,
+      summary.skipped
+    );
+    Ok(summary)
+  }
+
+  /// turn two-factor authentication off for a user who lost their authenticator along with the
+  /// recovery codes, returns whether they had it on
+  pub async fn disable_totp(&self, name: &str) -> Result<bool, Error> {
+    let state = self.get_state();
+    if !state.users.read().contains_key(name) {
+      return Err(Error::UserNotExisted(name.to_string()));
+    }
+    // storage may block, so it is written off the runtime
+    let (shared, user) = (state.clone(), name.to_string());
+    let disabled = tokio::task::spawn_blocking(move || shared.two_factor.disable(&user))
+      .await
+      .map_err(storage::Error::from)??;
+    if disabled {
+      self.get_listener().on_event(ServerEvent::TotpReset {
+        name: name.to_string(),
+      });
+      state.audit.record(AuditEvent::TotpReset {
+        name: name.to_string(),
+      });
+      info!(
+        source = "audit",
+        "two-factor authentication of user \"{}\" is disabled.", name
+      );
+    }
+    Ok(disabled)
+  }
+}
+
+#[derive(ThisError, Debug)]
+pub enum Error {
+  #[error(transparent)]
+  Storage(#[from] storage::Error),
+  #[error(transparent)]
+  Connection(#[from] chatroom_core::connection::Error),
+  #[error(transparent)]
+  TwoFactor(#[from] two_factor::Error),
+  #[error(transparent)]
+  Accounts(#[from] accounts::Error),
+  #[error("user \"{0}\" is not existed")]
+  UserNotExisted(String),
+  #[error("user \"{0}\" is offline")]
+  UserOffline(String),
+}
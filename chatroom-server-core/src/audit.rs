@@ -0,0 +1,504 @@
+use thiserror::Error as ThisError;
+
+use std::{
+  fmt::{self, Display},
+  io::{self, BufRead, Write},
+  net::SocketAddr,
+  str::FromStr,
+  sync::{mpsc, Arc},
+  thread,
+};
+
+use parking_lot::Mutex;
+
+use serde::{Deserialize, Serialize};
+
+use sha2::{Digest, Sha256};
+
+use time::OffsetDateTime;
+
+use tracing::{error, info, warn};
+
+use crate::storage::{self, BanTarget, Storage};
+
+/// `prev` of the first record
+const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// something security relevant that happened to an account
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum AuditEvent {
+  Registered {
+    name: String,
+    addr: SocketAddr,
+  },
+  LoginSucceeded {
+    name: String,
+    addr: SocketAddr,
+  },
+  /// logged in again with a session token
+  Resumed {
+    name: String,
+    addr: SocketAddr,
+  },
+  LoginFailed {
+    name: String,
+    addr: SocketAddr,
+    reason: String,
+  },
+  PasswordChanged {
+    name: String,
+    addr: SocketAddr,
+  },
+  /// the argon2 hash from before srp replaced by a verifier
+  PasswordMigrated {
+    name: String,
+    addr: SocketAddr,
+  },
+  LoggedOut {
+    name: String,
+    addr: SocketAddr,
+  },
+  HeartbeatExpired {
+    name: String,
+  },
+  /// a session got signed out by the user from another device
+  SessionRevoked {
+    name: String,
+    addr: SocketAddr,
+    revoked: SocketAddr,
+  },
+  TotpEnrolled {
+    name: String,
+    addr: SocketAddr,
+  },
+  TotpDisabled {
+    name: String,
+    addr: SocketAddr,
+  },
+  /// logged in or disabled two-factor authentication with a recovery code
+  RecoveryCodeUsed {
+    name: String,
+    addr: SocketAddr,
+    remaining: usize,
+  },
+  /// too many failed logins in a row
+  LockedOut {
+    target: BanTarget,
+  },
+  // admin actions
+  Kicked {
+    name: String,
+  },
+  Banned {
+    target: BanTarget,
+    reason: Option<String>,
+    #[serde(with = "time::serde::rfc3339::option")]
+    until: Option<OffsetDateTime>,
+  },
+  Unbanned {
+    target: BanTarget,
+  },
+  Unlocked {
+    target: BanTarget,
+  },
+  Deleted {
+    name: String,
+  },
+  PasswordReset {
+    name: String,
+  },
+  /// two-factor authentication turned off for a user who lost their authenticator
+  TotpReset {
+    name: String,
+  },
+  /// accounts read from a file, the skipped ones are left out
+  AccountsImported {
+    imported: Vec<String>,
+    overwritten: Vec<String>,
+  },
+}
+
+/// an entry of the audit trail, `hash` covers the entry itself along with `prev`, which is the
+/// hash of the entry before it, so altering or dropping any entry breaks every hash after it
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct AuditRecord {
+  pub seq: u64,
+  #[serde(with = "time::serde::rfc3339")]
+  pub timestamp: OffsetDateTime,
+  pub event: AuditEvent,
+  pub prev: String,
+  pub hash: String,
+}
+
+/// the part of a record its hash covers
+#[derive(Serialize)]
+struct Hashed<'a> {
+  seq: u64,
+  #[serde(with = "time::serde::rfc3339")]
+  timestamp: OffsetDateTime,
+  event: &'a AuditEvent,
+  prev: &'a str,
+}
+
+impl AuditRecord {
+  fn compute_hash(&self) -> Result<String, serde_json::Error> {
+    let body = serde_json::to_vec(&Hashed {
+      seq: self.seq,
+      timestamp: self.timestamp,
+      event: &self.event,
+      prev: &self.prev,
+    })?;
+    let digest = Sha256::digest(&body);
+    Ok(digest.iter().map(|b| format!("{:02x}", b)).collect())
+  }
+}
+
+/// the last record of the trail, it is logged whenever a record is stored, so that records dropped
+/// from the end can be told by verifying against the one logged last
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuditHead {
+  pub seq: u64,
+  pub hash: String,
+}
+
+impl Display for AuditHead {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}:{}", self.seq, self.hash)
+  }
+}
+
+impl FromStr for AuditHead {
+  type Err = String;
+
+  /// as displayed, `<seq>:<hash>`
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let (seq, hash) = s
+      .split_once(':')
+      .ok_or_else(|| format!("audit head \"{}\" is not of the form <seq>:<hash>", s))?;
+    let seq = seq
+      .parse()
+      .map_err(|err| format!("invalid audit head sequence number \"{}\": {}", seq, err))?;
+    Ok(Self {
+      seq,
+      hash: hash.to_string(),
+    })
+  }
+}
+
+enum Job {
+  Append(AuditRecord),
+  /// answered once everything sent before is written
+  Flush(mpsc::Sender<()>),
+}
+
+/// appends events to the audit trail in storage, keeping the chain intact, records are written by
+/// a thread of its own so that nobody waits on storage for them
+#[derive(Debug)]
+pub struct AuditTrail {
+  /// the last record handed to the writer, new ones chain onto it
+  head: Mutex<Option<AuditHead>>,
+  jobs: Mutex<mpsc::Sender<Job>>,
+}
+
+impl AuditTrail {
+  pub fn new(storage: Arc<dyn Storage>) -> Result<Self, storage::Error> {
+    let records = storage.load_audit_records()?;
+    if let Err(err) = verify(&records, None) {
+      warn!(source = "audit", "audit trail is not intact: {}.", err);
+    }
+    let head = records.last().map(|record| AuditHead {
+      seq: record.seq,
+      hash: record.hash.clone(),
+    });
+    if let Some(head) = &head {
+      info!(source = "audit", "audit trail head is {}.", head);
+    }
+    let (jobs, receiver) = mpsc::channel();
+    thread::Builder::new()
+      .name("audit-writer".into())
+      .spawn(move || write(storage, receiver))
+      .expect("failed to spawn the audit writer");
+    Ok(Self {
+      head: Mutex::new(head),
+      jobs: Mutex::new(jobs),
+    })
+  }
+
+  /// failures are logged rather than returned, an event is never undone for its record, a record
+  /// which fails to be stored leaves a gap that `verify` points out
+  pub fn record(&self, event: AuditEvent) {
+    let mut head = self.head.lock();
+    let (seq, prev) = match &*head {
+      Some(head) => (head.seq + 1, head.hash.clone()),
+      None => (0, GENESIS_HASH.to_string()),
+    };
+    let mut record = AuditRecord {
+      seq,
+      timestamp: OffsetDateTime::now_utc(),
+      event,
+      prev,
+      hash: String::new(),
+    };
+    record.hash = match record.compute_hash() {
+      Ok(hash) => hash,
+      Err(err) => {
+        error!(source = "internal", "failed to hash audit record: {}.", err);
+        return;
+      }
+    };
+    let next = AuditHead {
+      seq: record.seq,
+      hash: record.hash.clone(),
+    };
+    // sent under the lock of the head, so that records reach the writer in order
+    if self.jobs.lock().send(Job::Append(record)).is_err() {
+      error!(source = "internal", "audit writer is gone.");
+      return;
+    }
+    *head = Some(next);
+  }
+
+  /// block until every record so far is written
+  pub fn flush(&self) {
+    let (done, wait) = mpsc::channel();
+    if self.jobs.lock().send(Job::Flush(done)).is_ok() {
+      let _ = wait.recv();
+    }
+  }
+}
+
+fn write(storage: Arc<dyn Storage>, jobs: mpsc::Receiver<Job>) {
+  for job in jobs {
+    match job {
+      Job::Append(record) => match storage.append_audit_record(&record) {
+        Ok(()) => info!(
+          source = "audit",
+          "audit trail head is {}:{}.", record.seq, record.hash
+        ),
+        Err(err) => error!(
+          source = "internal",
+          "failed to store audit record {:?}: {}.", record.event, err
+        ),
+      },
+      Job::Flush(done) => {
+        let _ = done.send(());
+      }
+    }
+  }
+}
+
+/// check that every record is unaltered and in place, the first one may be anywhere in the trail,
+/// with `head` the trail must also reach up to it
+pub fn verify(records: &[AuditRecord], head: Option<&AuditHead>) -> Result<(), Error> {
+  let mut expected: Option<(u64, &str)> = None;
+  for record in records {
+    if let Some((seq, prev)) = expected {
+      if record.seq != seq {
+        return Err(Error::Broken {
+          seq: record.seq,
+          reason: format!("expecting record {}", seq),
+        });
+      }
+      if record.prev != prev {
+        return Err(Error::Broken {
+          seq: record.seq,
+          reason: "it does not follow the record before".into(),
+        });
+      }
+    } else if record.seq == 0 && record.prev != GENESIS_HASH {
+      return Err(Error::Broken {
+        seq: 0,
+        reason: "it does not start the trail".into(),
+      });
+    }
+    if record.compute_hash()? != record.hash {
+      return Err(Error::Broken {
+        seq: record.seq,
+        reason: "its content does not match its hash".into(),
+      });
+    }
+    expected = Some((record.seq + 1, &record.hash));
+  }
+  if let Some(head) = head {
+    match records.iter().find(|record| record.seq == head.seq) {
+      Some(record) if record.hash != head.hash => {
+        return Err(Error::Broken {
+          seq: record.seq,
+          reason: "it does not match the given head".into(),
+        })
+      }
+      Some(_) => {}
+      // an export starting after the head has nothing to compare with
+      None if matches!(records.first(), Some(first) if first.seq > head.seq) => {}
+      None => return Err(Error::Truncated { head: head.seq }),
+    }
+  }
+  Ok(())
+}
+
+/// write records as json lines, returns how many there were
+pub fn export<W: Write>(records: &[AuditRecord], mut writer: W) -> Result<usize, Error> {
+  for record in records {
+    serde_json::to_writer(&mut writer, record)?;
+    writer.write_all(b"\n")?;
+  }
+  writer.flush()?;
+  Ok(records.len())
+}
+
+/// read records written by `export`
+pub fn read<R: BufRead>(reader: R) -> Result<Vec<AuditRecord>, Error> {
+  let mut records = vec![];
+  for line in reader.lines() {
+    let line = line?;
+    if !line.trim().is_empty() {
+      records.push(serde_json::from_str(&line)?);
+    }
+  }
+  Ok(records)
+}
+
+#[derive(ThisError, Debug)]
+pub enum Error {
+  #[error(transparent)]
+  IO(#[from] io::Error),
+  #[error(transparent)]
+  Json(#[from] serde_json::Error),
+  #[error("audit record {seq} is tampered with, {reason}")]
+  Broken { seq: u64, reason: String },
+  #[error("audit trail is truncated, it does not reach record {head}")]
+  Truncated { head: u64 },
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use crate::storage::MemoryStorage;
+
+  fn trail(len: usize) -> Vec<AuditRecord> {
+    let storage = Arc::new(MemoryStorage::default());
+    let trail = AuditTrail::new(storage.clone()).unwrap();
+    for i in 0..len {
+      trail.record(AuditEvent::Kicked {
+        name: format!("user{}", i),
+      });
+    }
+    trail.flush();
+    storage.load_audit_records().unwrap()
+  }
+
+  fn head(record: &AuditRecord) -> AuditHead {
+    AuditHead {
+      seq: record.seq,
+      hash: record.hash.clone(),
+    }
+  }
+
+  #[test]
+  fn chains_records_in_order() {
+    let records = trail(5);
+    assert_eq!(records.len(), 5);
+    assert_eq!(records[0].prev, GENESIS_HASH);
+    assert!(records.iter().map(|record| record.seq).eq(0..5));
+    assert!(verify(&records, Some(&head(&records[4]))).is_ok());
+    // an export may start anywhere
+    assert!(verify(&records[2..], None).is_ok());
+  }
+
+  #[test]
+  fn continues_the_chain_after_a_restart() {
+    let storage = Arc::new(MemoryStorage::default());
+    for _ in 0..2 {
+      let trail = AuditTrail::new(storage.clone()).unwrap();
+      trail.record(AuditEvent::Deleted {
+        name: "alice".to_string(),
+      });
+      trail.flush();
+    }
+    let records = storage.load_audit_records().unwrap();
+    assert_eq!(records.len(), 2);
+    assert!(verify(&records, None).is_ok());
+  }
+
+  #[test]
+  fn detects_tampered_records() {
+    let mut records = trail(3);
+    records[1].event = AuditEvent::Kicked {
+      name: "mallory".to_string(),
+    };
+    assert!(matches!(
+      verify(&records, None),
+      Err(Error::Broken { seq: 1, .. })
+    ));
+
+    // rehashing the altered record breaks the link to it instead
+    records[1].hash = records[1].compute_hash().unwrap();
+    assert!(matches!(
+      verify(&records, None),
+      Err(Error::Broken { seq: 2, .. })
+    ));
+  }
+
+  #[test]
+  fn detects_dropped_and_forged_records() {
+    let mut records = trail(4);
+    records.remove(2);
+    assert!(matches!(
+      verify(&records, None),
+      Err(Error::Broken { seq: 3, .. })
+    ));
+
+    let mut records = trail(2);
+    records[0].prev = "f".repeat(64);
+    records[0].hash = records[0].compute_hash().unwrap();
+    assert!(matches!(
+      verify(&records, None),
+      Err(Error::Broken { seq: 0, .. })
+    ));
+  }
+
+  #[test]
+  fn detects_truncation_against_the_head() {
+    let records = trail(4);
+    let last = head(&records[3]);
+    assert!(verify(&records[..3], None).is_ok());
+    assert!(matches!(
+      verify(&records[..3], Some(&last)),
+      Err(Error::Truncated { head: 3 })
+    ));
+    assert!(matches!(
+      verify(&[], Some(&last)),
+      Err(Error::Truncated { head: 3 })
+    ));
+
+    let forged = AuditHead {
+      seq: 3,
+      hash: GENESIS_HASH.to_string(),
+    };
+    assert!(matches!(
+      verify(&records, Some(&forged)),
+      Err(Error::Broken { seq: 3, .. })
+    ));
+  }
+
+  #[test]
+  fn parses_heads_as_logged() {
+    let head = AuditHead {
+      seq: 42,
+      hash: "ab".repeat(32),
+    };
+    assert_eq!(head.to_string().parse::<AuditHead>().unwrap(), head);
+    assert!("42".parse::<AuditHead>().is_err());
+    assert!("x:ab".parse::<AuditHead>().is_err());
+  }
+
+  #[test]
+  fn round_trips_exports() {
+    let records = trail(3);
+    let mut out = vec![];
+    assert_eq!(export(&records, &mut out).unwrap(), 3);
+    assert_eq!(read(&out[..]).unwrap(), records);
+  }
+}
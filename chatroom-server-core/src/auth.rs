@@ -0,0 +1,399 @@
+use thiserror::Error as ThisError;
+
+use std::{
+  collections::HashMap,
+  fmt::Debug,
+  fs,
+  path::{Path, PathBuf},
+  sync::Arc,
+  time::Duration,
+};
+
+use argon2::{Argon2, PasswordHash, PasswordVerifier};
+
+use ldap3::{LdapConn, LdapConnSettings, LdapError};
+
+use serde::{Deserialize, Serialize};
+
+use chatroom_core::srp::{self, Verifier};
+
+use crate::storage::{self, Storage};
+
+/// kept as the password hash of users added to the roster by an external provider, it matches no
+/// password
+pub const EXTERNAL_PASSWORD_HASH: &str = "$external$";
+
+/// result code of a bind with a wrong password or an unknown dn, RFC 4511 appendix A
+const LDAP_INVALID_CREDENTIALS: u32 = 49;
+
+/// checks the passwords of users logging in, users it vouches for get an entry in the roster on
+/// their first login
+pub trait AuthProvider: Debug + Send + Sync + 'static {
+  /// for logs, e.g. "ldap"
+  fn name(&self) -> &'static str;
+
+  /// whether passwords are the srp verifiers kept in storage, so that clients are able to log in
+  /// without ever sending them, registration is only open with such a provider
+  fn supports_srp(&self) -> bool {
+    false
+  }
+
+  /// `Ok(false)` for a wrong password and an unknown user alike, it may block on network io
+  fn check_password(&self, username: &str, password: &str) -> Result<bool, Error>;
+}
+
+/// run `AuthProvider::check_password` off the async runtime
+pub async fn check_password(
+  provider: Arc<dyn AuthProvider>,
+  username: String,
+  password: String,
+) -> Result<bool, Error> {
+  tokio::task::spawn_blocking(move || provider.check_password(&username, &password)).await?
+}
+
+/// the accounts in storage, registered through the server or imported
+#[derive(Debug)]
+pub struct LocalProvider {
+  storage: Arc<dyn Storage>,
+}
+
+impl LocalProvider {
+  pub fn new(storage: Arc<dyn Storage>) -> Self {
+    Self { storage }
+  }
+}
+
+impl AuthProvider for LocalProvider {
+  fn name(&self) -> &'static str {
+    "local"
+  }
+
+  fn supports_srp(&self) -> bool {
+    true
+  }
+
+  fn check_password(&self, username: &str, password: &str) -> Result<bool, Error> {
+    Ok(match self.storage.load_user(username)? {
+      Some(user) => match Verifier::decode(&user.password_hash) {
+        Some(verifier) => verifier.matches(username, password),
+        // argon2 hashes from before srp were made of the digest clients sent back then
+        None => verify_legacy_digest(&user.password_hash, &srp::legacy_digest(password)),
+      },
+      None => false,
+    })
+  }
+}
+
+/// an htpasswd file of `name:hash` lines with argon2 or bcrypt hashes, read once on creation
+#[derive(Debug)]
+pub struct HtpasswdProvider {
+  hashes: HashMap<String, String>,
+}
+
+impl HtpasswdProvider {
+  pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+    let path = path.as_ref();
+    let content = fs::read_to_string(path).map_err(|err| Error::Htpasswd {
+      path: path.to_owned(),
+      msg: err.to_string(),
+    })?;
+    let mut hashes = HashMap::new();
+    for (i, line) in content.lines().enumerate() {
+      let line = line.trim();
+      if line.is_empty() || line.starts_with('#') {
+        continue;
+      }
+      let invalid = |msg: String| Error::Htpasswd {
+        path: path.to_owned(),
+        msg: format!("line {}: {}", i + 1, msg),
+      };
+      let (name, hash) = line
+        .split_once(':')
+        .ok_or_else(|| invalid("expecting \"name:hash\"".into()))?;
+      if !is_argon2(hash) && !is_bcrypt(hash) {
+        return Err(invalid(format!(
+          "hash of \"{}\" is neither argon2 nor bcrypt",
+          name
+        )));
+      }
+      hashes.insert(name.to_string(), hash.to_string());
+    }
+    Ok(Self { hashes })
+  }
+}
+
+impl AuthProvider for HtpasswdProvider {
+  fn name(&self) -> &'static str {
+    "htpasswd"
+  }
+
+  fn check_password(&self, username: &str, password: &str) -> Result<bool, Error> {
+    Ok(
+      self
+        .hashes
+        .get(username)
+        .is_some_and(|hash| verify_hash(hash, password)),
+    )
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct LdapConfig {
+  /// e.g. `ldap://localhost:389` or `ldaps://ldap.example.org`
+  pub url: String,
+  /// dn to bind as, `{username}` is replaced with the escaped name, e.g.
+  /// `uid={username},ou=people,dc=example,dc=org`
+  pub bind_dn: String,
+  /// upgrade a plain `ldap://` connection with StartTLS before binding
+  pub starttls: bool,
+  pub timeout: Duration,
+}
+
+/// a directory checking passwords with a simple bind as the user
+#[derive(Debug)]
+pub struct LdapProvider {
+  config: LdapConfig,
+}
+
+impl LdapProvider {
+  pub fn new(config: LdapConfig) -> Self {
+    Self { config }
+  }
+}
+
+impl AuthProvider for LdapProvider {
+  fn name(&self) -> &'static str {
+    "ldap"
+  }
+
+  fn check_password(&self, username: &str, password: &str) -> Result<bool, Error> {
+    // a bind without password is an unauthenticated one, which succeeds for any dn
+    if username.is_empty() || password.is_empty() {
+      return Ok(false);
+    }
+    let settings = LdapConnSettings::new()
+      .set_conn_timeout(self.config.timeout)
+      .set_starttls(self.config.starttls);
+    let mut conn = LdapConn::with_settings(settings, &self.config.url)?;
+    let dn = self
+      .config
+      .bind_dn
+      .replace("{username}", &escape_dn_value(username));
+    let result = conn
+      .with_timeout(self.config.timeout)
+      .simple_bind(&dn, password)?;
+    let _ = conn.unbind();
+    match result.rc {
+      0 => Ok(true),
+      LDAP_INVALID_CREDENTIALS => Ok(false),
+      _ => Err(LdapError::LdapResult { result }.into()),
+    }
+  }
+}
+
+fn is_argon2(hash: &str) -> bool {
+  hash.starts_with("$argon2")
+}
+
+fn is_bcrypt(hash: &str) -> bool {
+  ["$2a$", "$2b$", "$2y$"]
+    .iter()
+    .any(|prefix| hash.starts_with(prefix))
+}
+
+/// whether a stored password is an argon2 hash of the digest clients sent before srp
+pub fn is_legacy_hash(hash: &str) -> bool {
+  is_argon2(hash)
+}
+
+/// check a digest from [`srp::legacy_digest`] against the argon2 hash kept for it back then
+pub fn verify_legacy_digest(hash: &str, digest: &[u8]) -> bool {
+  is_legacy_hash(hash) && verify_argon2(hash, digest)
+}
+
+fn verify_argon2(hash: &str, password: &[u8]) -> bool {
+  PasswordHash::new(hash)
+    .is_ok_and(|hash| Argon2::default().verify_password(password, &hash).is_ok())
+}
+
+/// check `password` against an argon2 or bcrypt hash in the usual `$id$...` form
+fn verify_hash(hash: &str, password: &str) -> bool {
+  if is_argon2(hash) {
+    verify_argon2(hash, password.as_bytes())
+  } else if is_bcrypt(hash) {
+    bcrypt::verify(password, hash).unwrap_or(false)
+  } else {
+    false
+  }
+}
+
+/// escape an attribute value to put into a dn, RFC 4514 section 2.4
+fn escape_dn_value(value: &str) -> String {
+  let last = value.chars().count().saturating_sub(1);
+  let mut escaped = String::with_capacity(value.len());
+  for (i, c) in value.chars().enumerate() {
+    match c {
+      '\0' => escaped.push_str("\\00"),
+      ',' | '+' | '"' | '\\' | '<' | '>' | ';' | '=' => {
+        escaped.push('\\');
+        escaped.push(c);
+      }
+      '#' | ' ' if i == 0 => {
+        escaped.push('\\');
+        escaped.push(c);
+      }
+      ' ' if i == last => escaped.push_str("\\ "),
+      _ => escaped.push(c),
+    }
+  }
+  escaped
+}
+
+#[derive(ThisError, Debug)]
+pub enum Error {
+  #[error(transparent)]
+  Storage(#[from] storage::Error),
+  #[error("failed to read htpasswd file \"{path}\": {msg}")]
+  Htpasswd { path: PathBuf, msg: String },
+  #[error(transparent)]
+  Ldap(#[from] LdapError),
+  #[error("password check is aborted: {0}")]
+  Aborted(#[from] tokio::task::JoinError),
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use chatroom_core::data::UserEssential;
+
+  /// as written by rust-argon2 before srp, for "correct horse" and a salt of sevens
+  const LEGACY_HASH: &str = "$argon2i$v=19$m=4096,t=3,p=1$BwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwc$gau1B3okNp6RKH1cx9OZ7fCw67KcV7Y9VC0e9y8WwqI";
+
+  #[test]
+  fn verifies_legacy_digests() {
+    assert!(is_legacy_hash(LEGACY_HASH));
+    assert!(verify_legacy_digest(
+      LEGACY_HASH,
+      &srp::legacy_digest("  correct horse")
+    ));
+    assert!(!verify_legacy_digest(
+      LEGACY_HASH,
+      &srp::legacy_digest("correct horse ")
+    ));
+    // the plain password was never hashed
+    assert!(!verify_hash(LEGACY_HASH, "correct horse"));
+    assert!(!verify_legacy_digest(
+      &Verifier::new("alice", "correct horse").encode(),
+      &srp::legacy_digest("correct horse")
+    ));
+  }
+
+  fn htpasswd(name: &str, content: &str) -> HtpasswdProvider {
+    let path =
+      std::env::temp_dir().join(format!("chatroom-htpasswd-{}-{}", name, std::process::id()));
+    fs::write(&path, content).unwrap();
+    let provider = HtpasswdProvider::open(&path);
+    fs::remove_file(&path).unwrap();
+    provider.unwrap()
+  }
+
+  #[test]
+  fn tells_legacy_hashes_from_verifiers() {
+    assert!(is_legacy_hash(LEGACY_HASH));
+    assert!(!is_legacy_hash(&Verifier::new("alice", "secret").encode()));
+    assert!(!is_legacy_hash(EXTERNAL_PASSWORD_HASH));
+    assert!(!is_legacy_hash(&bcrypt::hash("secret", 4).unwrap()));
+  }
+
+  #[test]
+  fn htpasswd_provider_checks_argon2_and_bcrypt() {
+    use argon2::{password_hash::SaltString, PasswordHasher};
+
+    let salt = SaltString::new("c2FsdHNhbHRzYWx0").unwrap();
+    let argon2 = Argon2::default()
+      .hash_password(b"correct horse", &salt)
+      .unwrap()
+      .to_string();
+    let bcrypt = bcrypt::hash("battery staple", 4).unwrap();
+    let provider = htpasswd(
+      "hashes",
+      &format!("# operators\nalice:{}\n\nbob:{}\n", argon2, bcrypt),
+    );
+
+    assert!(provider.check_password("alice", "correct horse").unwrap());
+    assert!(!provider.check_password("alice", "battery staple").unwrap());
+    assert!(provider.check_password("bob", "battery staple").unwrap());
+    assert!(!provider.check_password("bob", "correct horse").unwrap());
+    assert!(!provider.check_password("carol", "correct horse").unwrap());
+  }
+
+  #[test]
+  fn htpasswd_provider_rejects_other_hashes() {
+    let path = std::env::temp_dir().join(format!("chatroom-htpasswd-md5-{}", std::process::id()));
+    fs::write(&path, "alice:$apr1$salt$hash\n").unwrap();
+    let result = HtpasswdProvider::open(&path);
+    fs::remove_file(&path).unwrap();
+    assert!(matches!(result, Err(Error::Htpasswd { msg, .. }) if msg.starts_with("line 1:")));
+  }
+
+  #[test]
+  fn escapes_dn_values() {
+    assert_eq!(escape_dn_value("alice"), "alice");
+    assert_eq!(escape_dn_value("a,b+c=d"), "a\\,b\\+c\\=d");
+    assert_eq!(escape_dn_value("# alice "), "\\# alice\\ ");
+  }
+
+  /// binds against a live directory, e.g. the `osixia/openldap` image with its default
+  /// `dc=example,dc=org` and `CHATROOM_LDAP_BIND_DN=cn={username},dc=example,dc=org`,
+  /// `CHATROOM_LDAP_USER=admin` and `CHATROOM_LDAP_PASSWORD=admin`
+  #[test]
+  #[ignore = "needs an openldap server, see the doc comment"]
+  fn ldap_provider_binds_as_the_user() {
+    let var = |name: &str| std::env::var(name).unwrap_or_else(|_| panic!("{} is not set", name));
+    let provider = LdapProvider::new(LdapConfig {
+      url: std::env::var("CHATROOM_LDAP_URL").unwrap_or_else(|_| "ldap://localhost:389".into()),
+      bind_dn: var("CHATROOM_LDAP_BIND_DN"),
+      starttls: false,
+      timeout: Duration::from_secs(5),
+    });
+    let user = var("CHATROOM_LDAP_USER");
+    let password = var("CHATROOM_LDAP_PASSWORD");
+
+    assert!(provider.check_password(&user, &password).unwrap());
+    assert!(!provider
+      .check_password(&user, &format!("{}!", password))
+      .unwrap());
+    assert!(!provider.check_password("nobody", &password).unwrap());
+    assert!(!provider.check_password(&user, "").unwrap());
+  }
+
+  #[test]
+  fn ldap_provider_never_binds_without_password() {
+    let provider = LdapProvider::new(LdapConfig {
+      url: "ldap://192.0.2.1:389".into(),
+      bind_dn: "uid={username},dc=example,dc=org".into(),
+      starttls: false,
+      timeout: Duration::from_millis(1),
+    });
+    assert!(!provider.check_password("alice", "").unwrap());
+  }
+
+  #[test]
+  fn local_provider_checks_legacy_hashes() {
+    let storage = Arc::new(storage::MemoryStorage::default());
+    for (name, password_hash) in [
+      ("alice", LEGACY_HASH.to_string()),
+      ("bob", Verifier::new("bob", "battery staple").encode()),
+    ] {
+      storage
+        .insert_user(name, &UserEssential { password_hash })
+        .unwrap();
+    }
+    let provider = LocalProvider::new(storage);
+    assert!(provider.check_password("alice", "correct horse").unwrap());
+    assert!(!provider.check_password("alice", "wrong horse").unwrap());
+    assert!(provider.check_password("bob", "battery staple").unwrap());
+    assert!(!provider.check_password("carol", "correct horse").unwrap());
+  }
+}
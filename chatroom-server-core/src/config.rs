@@ -0,0 +1,138 @@
+use std::{fmt::Display, str::FromStr, time::Duration};
+
+use serde::{Deserialize, Serialize};
+
+/// whether strangers may create accounts
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RegistrationPolicy {
+  #[default]
+  Open,
+  /// only existing accounts may log in, new ones come from imports
+  Closed,
+}
+
+impl Display for RegistrationPolicy {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.write_str(match self {
+      Self::Open => "open",
+      Self::Closed => "closed",
+    })
+  }
+}
+
+impl FromStr for RegistrationPolicy {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s.to_ascii_lowercase().as_str() {
+      "open" => Ok(Self::Open),
+      "closed" => Ok(Self::Closed),
+      _ => Err(format!(
+        "unknown registration policy \"{}\", expecting \"open\" or \"closed\"",
+        s
+      )),
+    }
+  }
+}
+
+/// at most `requests` requests from one address within `window`, bursts included
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub struct RateLimit {
+  pub requests: u32,
+  pub window: Duration,
+}
+
+/// how failed logins slow down further attempts, counted per account and per address alike
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub struct LoginThrottle {
+  /// failures allowed before any delay
+  pub free_attempts: u32,
+  /// delay after the first failure beyond those, doubled with every further one
+  pub base_delay: Duration,
+  /// failures after which the account or address is locked out for `lockout`
+  pub lockout_after: u32,
+  pub lockout: Duration,
+  /// failures are forgotten after this long without another one
+  pub forget_after: Duration,
+}
+
+impl Default for LoginThrottle {
+  fn default() -> Self {
+    Self {
+      free_attempts: 3,
+      base_delay: Duration::from_secs(1),
+      lockout_after: 10,
+      lockout: Duration::from_secs(15 * 60),
+      forget_after: Duration::from_secs(60 * 60),
+    }
+  }
+}
+
+impl LoginThrottle {
+  /// how long to wait after the given number of failures in a row
+  pub fn delay(&self, failures: u32) -> Duration {
+    if failures >= self.lockout_after {
+      return self.lockout;
+    }
+    if failures <= self.free_attempts {
+      return Duration::ZERO;
+    }
+    1u32
+      .checked_shl(failures - self.free_attempts - 1)
+      .and_then(|factor| self.base_delay.checked_mul(factor))
+      .map_or(self.lockout, |delay| delay.min(self.lockout))
+  }
+
+  pub fn validate(&self) -> Result<(), String> {
+    if self.lockout_after <= self.free_attempts {
+      return Err("login lockout must come after the free attempts".into());
+    }
+    if self.base_delay.is_zero() || self.lockout.is_zero() || self.forget_after.is_zero() {
+      return Err("login throttle durations must not be zero".into());
+    }
+    Ok(())
+  }
+}
+
+/// settings of a server which may change while it is running
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServerConfig {
+  /// time without heartbeat before a user is considered offline
+  pub heartbeat_interval: Duration,
+  pub registration: RegistrationPolicy,
+  /// requests are unlimited if absent, heartbeats are never limited
+  pub rate_limit: Option<RateLimit>,
+  pub login_throttle: LoginThrottle,
+  /// how long a session token stays good for resuming
+  pub session_lifetime: Duration,
+}
+
+impl Default for ServerConfig {
+  fn default() -> Self {
+    Self {
+      heartbeat_interval: Duration::from_secs(60),
+      registration: Default::default(),
+      rate_limit: None,
+      login_throttle: Default::default(),
+      session_lifetime: Duration::from_secs(7 * 24 * 60 * 60),
+    }
+  }
+}
+
+impl ServerConfig {
+  pub fn validate(&self) -> Result<(), String> {
+    if self.heartbeat_interval.is_zero() {
+      return Err("heartbeat interval must not be zero".into());
+    }
+    if self.session_lifetime.is_zero() {
+      return Err("session lifetime must not be zero".into());
+    }
+    if let Some(rate_limit) = self.rate_limit {
+      if rate_limit.requests == 0 || rate_limit.window.is_zero() {
+        return Err("rate limit must allow at least one request in a non-empty window".into());
+      }
+    }
+    self.login_throttle.validate()
+  }
+}
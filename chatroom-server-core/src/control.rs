@@ -0,0 +1,521 @@
+use thiserror::Error as ThisError;
+
+use std::{net::SocketAddr, sync::Arc};
+
+use tokio::{
+  io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, Lines},
+  net::{
+    tcp::{OwnedReadHalf, OwnedWriteHalf},
+    TcpListener, TcpStream,
+  },
+  sync::{mpsc, oneshot},
+  task::JoinHandle,
+};
+
+use serde::{Deserialize, Serialize};
+
+use time::{Duration, OffsetDateTime};
+
+use tracing::{error, info};
+
+use chatroom_core::codec::Codec;
+
+use crate::{
+  accounts::{Account, ConflictMode, ImportSummary},
+  lockout::Lockout,
+  server::{Server, ServerStats},
+  storage::{Ban, BanTarget},
+};
+
+/// longest line read from a client who has not presented the token yet
+const MAX_AUTH_LINE: u64 = 1024;
+/// longest line read afterwards, room for importing plenty of accounts at once
+const MAX_LINE: u64 = 16 * 1024 * 1024;
+
+/// one json object per line in both directions, the first request of a connection must be `Auth`
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum ControlRequest {
+  Auth {
+    token: String,
+  },
+  ListOnline,
+  Kick {
+    name: String,
+  },
+  /// `duration` in seconds, the ban is permanent if absent
+  Ban {
+    target: BanTarget,
+    reason: Option<String>,
+    duration: Option<u64>,
+  },
+  Unban {
+    target: BanTarget,
+  },
+  ListBans,
+  /// let a user or an address locked out for failed logins try again
+  Unlock {
+    target: BanTarget,
+  },
+  ListLockouts,
+  DeleteUser {
+    name: String,
+  },
+  ResetPassword {
+    name: String,
+    password: String,
+  },
+  /// for a user who lost their authenticator along with the recovery codes
+  DisableTotp {
+    name: String,
+  },
+  ImportAccounts {
+    accounts: Vec<Account>,
+    on_conflict: ConflictMode,
+  },
+  Announce {
+    msg: String,
+  },
+  Stats,
+  /// read the config file again, as on SIGHUP
+  Reload,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "result", rename_all = "snake_case")]
+pub enum ControlResponse {
+  Ok,
+  Online { users: Vec<(String, SocketAddr)> },
+  Kicked { users: Vec<String> },
+  Unbanned { lifted: bool },
+  Bans { bans: Vec<Ban> },
+  Unlocked { lifted: bool },
+  TotpDisabled { disabled: bool },
+  Imported { summary: ImportSummary },
+  Lockouts { lockouts: Vec<Lockout> },
+  Announced { receivers: usize },
+  Stats { stats: ServerStats },
+  Error { msg: String },
+}
+
+/// handed to whoever owns the config, which answers whether reloading it worked
+pub type ReloadRequest = oneshot::Sender<Result<(), String>>;
+
+/// listen for operators on a loopback address, connections must present `token` first, which must
+/// not be empty, `Reload` is refused without `reload`
+pub async fn serve<Coder: Codec>(
+  server: Arc<Server<Coder>>,
+  addr: SocketAddr,
+  token: String,
+  reload: Option<mpsc::Sender<ReloadRequest>>,
+) -> Result<JoinHandle<()>, Error> {
+  if !addr.ip().is_loopback() {
+    return Err(Error::NotLoopback(addr));
+  }
+  // every connection sending an empty token as well would be let in
+  if token.is_empty() {
+    return Err(Error::EmptyToken);
+  }
+  let listener = TcpListener::bind(addr).await?;
+  info!(
+    source = "server",
+    "control channel is listening on {}.",
+    listener.local_addr()?
+  );
+  let token = Arc::new(token);
+  Ok(tokio::spawn(async move {
+    loop {
+      let (stream, peer) = match listener.accept().await {
+        Ok(conn) => conn,
+        Err(err) => {
+          error!(
+            source = "internal",
+            "error occurred during accepting control connection: {}.", err
+          );
+          continue;
+        }
+      };
+      let server = server.clone();
+      let token = token.clone();
+      let reload = reload.clone();
+      tokio::spawn(async move {
+        if let Err(err) = handle(server, stream, &token, reload).await {
+          error!(
+            source = "internal",
+            "error occurred on control connection from {}: {}.", peer, err
+          );
+        }
+      });
+    }
+  }))
+}
+
+async fn handle<Coder: Codec>(
+  server: Arc<Server<Coder>>,
+  stream: TcpStream,
+  token: &str,
+  reload: Option<mpsc::Sender<ReloadRequest>>,
+) -> Result<(), Error> {
+  let peer = stream.peer_addr()?;
+  let (reader, mut writer) = stream.into_split();
+  let mut reader = BufReader::new(reader);
+  let mut authenticated = false;
+  let mut line = String::new();
+  loop {
+    line.clear();
+    let limit = if authenticated {
+      MAX_LINE
+    } else {
+      MAX_AUTH_LINE
+    };
+    if (&mut reader).take(limit).read_line(&mut line).await? == 0 {
+      break;
+    }
+    if !line.ends_with('\n') && line.len() as u64 == limit {
+      error!(
+        source = "server",
+        "control client {} sent a line longer than {} bytes.", peer, limit
+      );
+      let msg = "request is too long".to_string();
+      write_line(&mut writer, &ControlResponse::Error { msg }).await?;
+      break;
+    }
+    let request = match serde_json::from_str::<ControlRequest>(&line) {
+      Ok(request) => request,
+      Err(err) => {
+        let msg = format!("malformed request: {}", err);
+        write_line(&mut writer, &ControlResponse::Error { msg }).await?;
+        continue;
+      }
+    };
+    let response = match request {
+      ControlRequest::Auth { token: given } => {
+        if !constant_time_eq(given.as_bytes(), token.as_bytes()) {
+          error!(
+            source = "server",
+            "control client {} used a wrong token.", peer
+          );
+          let msg = "wrong token".to_string();
+          write_line(&mut writer, &ControlResponse::Error { msg }).await?;
+          break;
+        }
+        info!(
+          source = "server",
+          "control client {} is authenticated.", peer
+        );
+        authenticated = true;
+        ControlResponse::Ok
+      }
+      _ if !authenticated => {
+        let msg = "authentication is required".to_string();
+        write_line(&mut writer, &ControlResponse::Error { msg }).await?;
+        break;
+      }
+      request => execute(&server, request, reload.as_ref()).await,
+    };
+    write_line(&mut writer, &response).await?;
+  }
+  Ok(())
+}
+
+async fn execute<Coder: Codec>(
+  server: &Server<Coder>,
+  request: ControlRequest,
+  reload: Option<&mpsc::Sender<ReloadRequest>>,
+) -> ControlResponse {
+  let result = match request {
+    ControlRequest::Auth { .. } => Ok(ControlResponse::Ok),
+    ControlRequest::ListOnline => {
+      let mut users = server
+        .get_state()
+        .addr2user
+        .read()
+        .iter()
+        .map(|(&addr, name)| (name.clone(), addr))
+        .collect::<Vec<_>>();
+      users.sort();
+      Ok(ControlResponse::Online { users })
+    }
+    ControlRequest::Kick { name } => server
+      .kick(&name)
+      .await
+      .map(|_| ControlResponse::Kicked { users: vec![name] }),
+    ControlRequest::Ban {
+      target,
+      reason,
+      duration,
+    } => {
+      let until = match duration {
+        Some(secs) => match i64::try_from(secs)
+          .ok()
+          .and_then(|secs| OffsetDateTime::now_utc().checked_add(Duration::seconds(secs)))
+        {
+          Some(until) => Some(until),
+          None => {
+            let msg = format!("ban duration of {} seconds is too long", secs);
+            return ControlResponse::Error { msg };
+          }
+        },
+        None => None,
+      };
+      let ban = Ban {
+        target,
+        reason,
+        until,
+      };
+      server
+        .ban(ban)
+        .await
+        .map(|users| ControlResponse::Kicked { users })
+    }
+    ControlRequest::Unban { target } => server
+      .unban(&target)
+      .await
+      .map(|lifted| ControlResponse::Unbanned { lifted }),
+    ControlRequest::ListBans => Ok(ControlResponse::Bans {
+      bans: server.get_bans(),
+    }),
+    ControlRequest::Unlock { target } => Ok(ControlResponse::Unlocked {
+      lifted: server.unlock(&target),
+    }),
+    ControlRequest::ListLockouts => Ok(ControlResponse::Lockouts {
+      lockouts: server.get_lockouts(),
+    }),
+    ControlRequest::DeleteUser { name } => {
+      server.delete_user(&name).await.map(|_| ControlResponse::Ok)
+    }
+    ControlRequest::ResetPassword { name, password } => server
+      .reset_password(&name, &password)
+      .await
+      .map(|_| ControlResponse::Ok),
+    ControlRequest::DisableTotp { name } => server
+      .disable_totp(&name)
+      .await
+      .map(|disabled| ControlResponse::TotpDisabled { disabled }),
+    ControlRequest::ImportAccounts {
+      accounts,
+      on_conflict,
+    } => server
+      .import_accounts(&accounts, on_conflict)
+      .await
+      .map(|summary| ControlResponse::Imported { summary }),
+    ControlRequest::Announce { msg } => server
+      .announce(msg)
+      .await
+      .map(|receivers| ControlResponse::Announced { receivers }),
+    ControlRequest::Stats => Ok(ControlResponse::Stats {
+      stats: server.get_stats(),
+    }),
+    ControlRequest::Reload => {
+      let reload = match reload {
+        Some(reload) => reload,
+        None => {
+          let msg = "reloading is not supported by this server".to_string();
+          return ControlResponse::Error { msg };
+        }
+      };
+      let (reply, result) = oneshot::channel();
+      if reload.send(reply).await.is_err() {
+        let msg = "server is shutting down".to_string();
+        return ControlResponse::Error { msg };
+      }
+      return match result.await {
+        Ok(Ok(())) => ControlResponse::Ok,
+        Ok(Err(msg)) => ControlResponse::Error { msg },
+        Err(_) => ControlResponse::Error {
+          msg: "server is shutting down".to_string(),
+        },
+      };
+    }
+  };
+  result.unwrap_or_else(|err| ControlResponse::Error {
+    msg: err.to_string(),
+  })
+}
+
+async fn write_line(writer: &mut OwnedWriteHalf, response: &ControlResponse) -> Result<(), Error> {
+  let mut line = serde_json::to_vec(response)?;
+  line.push(b'\n');
+  writer.write_all(&line).await?;
+  Ok(())
+}
+
+/// compare without leaking where the first difference is
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+  a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// talks to the control channel of a running server
+pub struct ControlClient {
+  lines: Lines<BufReader<OwnedReadHalf>>,
+  writer: OwnedWriteHalf,
+}
+
+impl ControlClient {
+  pub async fn connect(addr: SocketAddr, token: String) -> Result<Self, Error> {
+    let (reader, writer) = TcpStream::connect(addr).await?.into_split();
+    let mut client = Self {
+      lines: BufReader::new(reader).lines(),
+      writer,
+    };
+    client.request(&ControlRequest::Auth { token }).await?;
+    Ok(client)
+  }
+
+  /// `ControlResponse::Error` is turned into `Error::Rejected`
+  pub async fn request(&mut self, request: &ControlRequest) -> Result<ControlResponse, Error> {
+    let mut line = serde_json::to_vec(request)?;
+    line.push(b'\n');
+    self.writer.write_all(&line).await?;
+    let line = self
+      .lines
+      .next_line()
+      .await?
+      .ok_or(Error::ConnectionClosed)?;
+    match serde_json::from_str(&line)? {
+      ControlResponse::Error { msg } => Err(Error::Rejected(msg)),
+      response => Ok(response),
+    }
+  }
+}
+
+#[derive(ThisError, Debug)]
+pub enum Error {
+  #[error(transparent)]
+  IO(#[from] std::io::Error),
+  #[error(transparent)]
+  Json(#[from] serde_json::Error),
+  #[error("control channel must listen on a loopback address, got {0}")]
+  NotLoopback(SocketAddr),
+  #[error("control token must not be empty")]
+  EmptyToken,
+  #[error("control connection is closed by the server")]
+  ConnectionClosed,
+  #[error("{0}")]
+  Rejected(String),
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use chatroom_core::data::{default_coder, DefaultCoder};
+
+  use crate::server::ServerState;
+
+  const TOKEN: &str = "secret token";
+
+  /// a server with its control channel on a free loopback port
+  async fn start() -> (Arc<Server<DefaultCoder>>, SocketAddr, JoinHandle<()>) {
+    let state = ServerState::new(std::time::Duration::from_secs(30));
+    let server = Server::new(default_coder(), state, Arc::new(()), "127.0.0.1:0")
+      .await
+      .unwrap();
+    let server = Arc::new(server);
+    let addr = TcpListener::bind("127.0.0.1:0")
+      .await
+      .unwrap()
+      .local_addr()
+      .unwrap();
+    let handle = serve(server.clone(), addr, TOKEN.to_string(), None)
+      .await
+      .unwrap();
+    (server, addr, handle)
+  }
+
+  #[tokio::test]
+  async fn refuses_to_listen_beyond_loopback() {
+    let (server, _, handle) = start().await;
+    let addr = "0.0.0.0:0".parse().unwrap();
+    assert!(matches!(
+      serve(server, addr, TOKEN.to_string(), None).await,
+      Err(Error::NotLoopback(a)) if a == addr
+    ));
+    handle.abort();
+  }
+
+  #[tokio::test]
+  async fn refuses_to_listen_without_a_token() {
+    let (server, _, handle) = start().await;
+    let addr = "127.0.0.1:0".parse().unwrap();
+    assert!(matches!(
+      serve(server, addr, String::new(), None).await,
+      Err(Error::EmptyToken)
+    ));
+    handle.abort();
+  }
+
+  #[tokio::test]
+  async fn serves_clients_with_the_token() {
+    let (_, addr, handle) = start().await;
+    let mut client = ControlClient::connect(addr, TOKEN.to_string())
+      .await
+      .unwrap();
+    let target = BanTarget::Ip("192.0.2.1".parse().unwrap());
+    let ban = ControlRequest::Ban {
+      target: target.clone(),
+      reason: None,
+      duration: None,
+    };
+    assert_eq!(
+      client.request(&ban).await.unwrap(),
+      ControlResponse::Kicked { users: vec![] }
+    );
+    match client.request(&ControlRequest::ListBans).await.unwrap() {
+      ControlResponse::Bans { bans } => assert_eq!(bans[0].target, target),
+      response => panic!("unexpected response {:?}", response),
+    }
+    let unban = ControlRequest::Unban { target };
+    assert_eq!(
+      client.request(&unban).await.unwrap(),
+      ControlResponse::Unbanned { lifted: true }
+    );
+    handle.abort();
+  }
+
+  #[tokio::test]
+  async fn drops_clients_with_a_wrong_token() {
+    let (_, addr, handle) = start().await;
+    assert!(matches!(
+      ControlClient::connect(addr, "guess".to_string()).await,
+      Err(Error::Rejected(msg)) if msg == "wrong token"
+    ));
+    handle.abort();
+  }
+
+  #[tokio::test]
+  async fn drops_overlong_lines_before_authentication() {
+    let (_, addr, handle) = start().await;
+    let (reader, mut writer) = TcpStream::connect(addr).await.unwrap().into_split();
+    let mut lines = BufReader::new(reader).lines();
+    let token = "x".repeat(MAX_AUTH_LINE as usize);
+    let auth = format!("{{\"op\":\"auth\",\"token\":\"{}\"}}\n", token);
+    writer.write_all(auth.as_bytes()).await.unwrap();
+    let line = lines.next_line().await.unwrap().unwrap();
+    assert_eq!(
+      serde_json::from_str::<ControlResponse>(&line).unwrap(),
+      ControlResponse::Error {
+        msg: "request is too long".to_string()
+      }
+    );
+    assert!(lines.next_line().await.unwrap().is_none());
+    handle.abort();
+  }
+
+  #[tokio::test]
+  async fn requires_authentication_first() {
+    let (_, addr, handle) = start().await;
+    let (reader, mut writer) = TcpStream::connect(addr).await.unwrap().into_split();
+    let mut lines = BufReader::new(reader).lines();
+    writer.write_all(b"{\"op\":\"stats\"}\n").await.unwrap();
+    let line = lines.next_line().await.unwrap().unwrap();
+    assert_eq!(
+      serde_json::from_str::<ControlResponse>(&line).unwrap(),
+      ControlResponse::Error {
+        msg: "authentication is required".to_string()
+      }
+    );
+    assert!(lines.next_line().await.unwrap().is_none());
+    handle.abort();
+  }
+}
@@ -0,0 +1,78 @@
+use std::net::SocketAddr;
+
+use tokio::sync::mpsc;
+
+use crate::storage::BanTarget;
+
+/// something that happened inside the server, for front ends to reflect
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ServerEvent {
+  Registered {
+    name: String,
+  },
+  LoggedIn {
+    name: String,
+    addr: SocketAddr,
+  },
+  LoggedOut {
+    name: String,
+  },
+  /// the user did not send any heartbeat in time and is considered offline
+  HeartbeatLost {
+    name: String,
+  },
+  PasswordChanged {
+    name: String,
+  },
+  PublicKeyUpdated {
+    name: String,
+  },
+  // admin actions
+  Kicked {
+    name: String,
+  },
+  Banned {
+    target: BanTarget,
+  },
+  Unbanned {
+    target: BanTarget,
+  },
+  Unlocked {
+    target: BanTarget,
+  },
+  Deleted {
+    name: String,
+  },
+  PasswordReset {
+    name: String,
+  },
+  TotpReset {
+    name: String,
+  },
+}
+
+/// receiver of server events, it is called from within the server tasks so it should not block
+pub trait EventListener: Send + Sync + 'static {
+  fn on_event(&self, event: ServerEvent);
+}
+
+/// drop every event
+impl EventListener for () {
+  fn on_event(&self, _event: ServerEvent) {}
+}
+
+impl<F> EventListener for F
+where
+  F: Fn(ServerEvent) + Send + Sync + 'static,
+{
+  fn on_event(&self, event: ServerEvent) {
+    self(event)
+  }
+}
+
+impl EventListener for mpsc::UnboundedSender<ServerEvent> {
+  fn on_event(&self, event: ServerEvent) {
+    // an error means the receiver is gone, nobody is interested anymore
+    let _ = self.send(event);
+  }
+}
@@ -0,0 +1,15 @@
+pub mod accounts;
+pub mod admin;
+pub mod audit;
+pub mod auth;
+pub mod config;
+pub mod control;
+pub mod event;
+pub mod limiter;
+pub mod lockout;
+pub mod logs;
+pub mod metrics;
+pub mod server;
+pub mod session;
+pub mod storage;
+pub mod two_factor;
@@ -0,0 +1,50 @@
+use std::{collections::HashMap, net::SocketAddr, time::Instant};
+
+use parking_lot::Mutex;
+
+use crate::config::RateLimit;
+
+/// buckets are only swept once there are this many of them
+const SWEEP_THRESHOLD: usize = 1024;
+
+#[derive(Debug)]
+struct Bucket {
+  tokens: f64,
+  updated: Instant,
+}
+
+/// token buckets per address, refilled continuously at the configured rate
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+  buckets: Mutex<HashMap<SocketAddr, Bucket>>,
+}
+
+impl RateLimiter {
+  /// take a token for `addr`, returns false if it has run out of them
+  pub fn check(&self, addr: SocketAddr, limit: &RateLimit) -> bool {
+    let now = Instant::now();
+    let capacity = limit.requests as f64;
+    let refill = |bucket: &Bucket| {
+      let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+      (bucket.tokens + elapsed / limit.window.as_secs_f64() * capacity).min(capacity)
+    };
+
+    let mut buckets = self.buckets.lock();
+    if buckets.len() >= SWEEP_THRESHOLD {
+      // a full bucket is no different from a missing one
+      buckets.retain(|_, bucket| refill(bucket) < capacity);
+    }
+    let bucket = buckets.entry(addr).or_insert(Bucket {
+      tokens: capacity,
+      updated: now,
+    });
+    bucket.tokens = refill(bucket);
+    bucket.updated = now;
+    if bucket.tokens >= 1.0 {
+      bucket.tokens -= 1.0;
+      true
+    } else {
+      false
+    }
+  }
+}
@@ -0,0 +1,232 @@
+use std::{
+  collections::HashMap,
+  net::IpAddr,
+  time::{Duration, Instant},
+};
+
+use parking_lot::Mutex;
+
+use serde::{Deserialize, Serialize};
+
+use time::OffsetDateTime;
+
+use crate::{config::LoginThrottle, storage::BanTarget};
+
+/// entries are only swept once there are this many of them
+const SWEEP_THRESHOLD: usize = 1024;
+
+#[derive(Debug)]
+struct Failures {
+  /// in a row, since the last success or the last time they were forgotten
+  count: u32,
+  last: Instant,
+  blocked_until: Instant,
+}
+
+/// a user or an address that has to wait before trying to log in again
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Lockout {
+  pub target: BanTarget,
+  pub failures: u32,
+  pub until: OffsetDateTime,
+}
+
+/// failed logins per account and per address, consulted before any password gets checked
+#[derive(Debug, Default)]
+pub struct LoginGuard {
+  failures: Mutex<HashMap<BanTarget, Failures>>,
+}
+
+impl LoginGuard {
+  /// time left before `name` may try again from `ip`, the longer wait of the two
+  pub fn check(&self, name: &str, ip: IpAddr) -> Option<Duration> {
+    let now = Instant::now();
+    let failures = self.failures.lock();
+    [BanTarget::User(name.to_string()), BanTarget::Ip(ip)]
+      .iter()
+      .filter_map(|target| failures.get(target))
+      .filter_map(|failures| failures.blocked_until.checked_duration_since(now))
+      .filter(|wait| !wait.is_zero())
+      .max()
+  }
+
+  /// count a failure against both the account and the address, returns those of them which
+  /// have just been locked out
+  pub fn record_failure(&self, name: &str, ip: IpAddr, policy: &LoginThrottle) -> Vec<BanTarget> {
+    let now = Instant::now();
+    let mut failures = self.failures.lock();
+    if failures.len() >= SWEEP_THRESHOLD {
+      failures.retain(|_, f| f.blocked_until > now || now - f.last < policy.forget_after);
+    }
+
+    let mut locked = vec![];
+    for target in [BanTarget::User(name.to_string()), BanTarget::Ip(ip)] {
+      let entry = failures.entry(target.clone()).or_insert(Failures {
+        count: 0,
+        last: now,
+        blocked_until: now,
+      });
+      if entry.blocked_until <= now && now - entry.last >= policy.forget_after {
+        entry.count = 0;
+      }
+      entry.count += 1;
+      entry.last = now;
+      entry.blocked_until = now + policy.delay(entry.count);
+      if entry.count == policy.lockout_after {
+        locked.push(target);
+      }
+    }
+    locked
+  }
+
+  /// forget the failures of an account once it logs in, the address keeps its own
+  pub fn record_success(&self, name: &str) {
+    self
+      .failures
+      .lock()
+      .remove(&BanTarget::User(name.to_string()));
+  }
+
+  /// returns whether there was anything to lift
+  pub fn unlock(&self, target: &BanTarget) -> bool {
+    self.failures.lock().remove(target).is_some()
+  }
+
+  /// everyone who has to wait right now
+  pub fn get_lockouts(&self) -> Vec<Lockout> {
+    let now = Instant::now();
+    let now_utc = OffsetDateTime::now_utc();
+    self
+      .failures
+      .lock()
+      .iter()
+      .filter(|(_, failures)| failures.blocked_until > now)
+      .map(|(target, failures)| Lockout {
+        target: target.clone(),
+        failures: failures.count,
+        until: now_utc + (failures.blocked_until - now),
+      })
+      .collect()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use std::{net::Ipv4Addr, thread};
+
+  const IP: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+  const OTHER_IP: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+
+  fn fail(guard: &LoginGuard, name: &str, ip: IpAddr, times: u32) -> Vec<BanTarget> {
+    let policy = LoginThrottle::default();
+    (0..times)
+      .flat_map(|_| guard.record_failure(name, ip, &policy))
+      .collect()
+  }
+
+  #[test]
+  fn doubles_the_delay_up_to_the_lockout() {
+    let policy = LoginThrottle::default();
+    let delays = (1..=11).map(|n| policy.delay(n)).collect::<Vec<_>>();
+    let secs = Duration::from_secs;
+    assert_eq!(
+      delays,
+      [
+        Duration::ZERO,
+        Duration::ZERO,
+        Duration::ZERO,
+        secs(1),
+        secs(2),
+        secs(4),
+        secs(8),
+        secs(16),
+        secs(32),
+        secs(15 * 60),
+        secs(15 * 60),
+      ]
+    );
+  }
+
+  #[test]
+  fn caps_the_delay_instead_of_overflowing() {
+    let policy = LoginThrottle {
+      lockout_after: u32::MAX,
+      ..Default::default()
+    };
+    assert_eq!(policy.delay(40), policy.lockout);
+    assert_eq!(policy.delay(u32::MAX - 1), policy.lockout);
+  }
+
+  #[test]
+  fn lets_free_attempts_through() {
+    let guard = LoginGuard::default();
+    assert!(fail(&guard, "alice", IP, 3).is_empty());
+    assert_eq!(guard.check("alice", IP), None);
+
+    fail(&guard, "alice", IP, 1);
+    let wait = guard.check("alice", IP).unwrap();
+    assert!(wait > Duration::from_millis(900) && wait <= Duration::from_secs(1));
+  }
+
+  #[test]
+  fn locks_out_the_account_and_the_address() {
+    let guard = LoginGuard::default();
+    let locked = fail(&guard, "alice", IP, 12);
+    assert_eq!(
+      locked,
+      [BanTarget::User("alice".to_string()), BanTarget::Ip(IP)]
+    );
+    // the account is locked out from everywhere, the address for everyone
+    assert!(guard.check("alice", OTHER_IP).unwrap() > Duration::from_secs(14 * 60));
+    assert!(guard.check("bob", IP).unwrap() > Duration::from_secs(14 * 60));
+    assert_eq!(guard.check("bob", OTHER_IP), None);
+
+    let mut lockouts = guard.get_lockouts();
+    lockouts.sort_by_key(|lockout| matches!(lockout.target, BanTarget::Ip(_)));
+    assert_eq!(lockouts.len(), 2);
+    assert_eq!(lockouts[0].target, BanTarget::User("alice".to_string()));
+    assert_eq!(lockouts[0].failures, 12);
+
+    assert!(guard.unlock(&BanTarget::Ip(IP)));
+    assert!(!guard.unlock(&BanTarget::Ip(IP)));
+    assert_eq!(guard.check("bob", IP), None);
+  }
+
+  #[test]
+  fn takes_the_longer_wait() {
+    let guard = LoginGuard::default();
+    fail(&guard, "alice", OTHER_IP, 5);
+    fail(&guard, "bob", IP, 4);
+    let wait = guard.check("alice", IP).unwrap();
+    assert!(wait > Duration::from_millis(1900) && wait <= Duration::from_secs(2));
+  }
+
+  #[test]
+  fn success_only_clears_the_account() {
+    let guard = LoginGuard::default();
+    fail(&guard, "alice", IP, 5);
+    guard.record_success("alice");
+    assert_eq!(guard.check("alice", OTHER_IP), None);
+    assert!(guard.check("alice", IP).is_some());
+  }
+
+  #[test]
+  fn forgets_old_failures() {
+    let guard = LoginGuard::default();
+    let policy = LoginThrottle {
+      free_attempts: 1,
+      base_delay: Duration::from_millis(1),
+      lockout_after: 3,
+      lockout: Duration::from_millis(5),
+      forget_after: Duration::from_millis(20),
+    };
+    assert!(guard.record_failure("alice", IP, &policy).is_empty());
+    assert!(guard.record_failure("alice", IP, &policy).is_empty());
+    thread::sleep(Duration::from_millis(30));
+    // counting starts over, so this is no lockout
+    assert!(guard.record_failure("alice", IP, &policy).is_empty());
+    assert_eq!(guard.check("alice", IP), None);
+  }
+}
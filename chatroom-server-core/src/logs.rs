@@ -0,0 +1,576 @@
+use thiserror::Error as ThisError;
+
+use std::{
+  collections::{BTreeMap, VecDeque},
+  fs::{self, File},
+  io::{self, BufRead, BufReader},
+  path::Path,
+  str::FromStr,
+};
+
+use serde::{Deserialize, Serialize};
+
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+use tracing::{Level, Subscriber};
+use tracing_appender::rolling::{self, RollingFileAppender, Rotation};
+use tracing_subscriber::{
+  filter::{EnvFilter, ParseError},
+  fmt,
+  registry::LookupSpan,
+  reload, Layer,
+};
+
+/// log files are named `server.<date>.log`, or `server.log` if they never rotate
+const FILE_PREFIX: &str = "server";
+const FILE_SUFFIX: &str = "log";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+  /// the same lines as the live feed, searches split messages and fields on a best-effort basis
+  Text,
+  /// one json object per line, searched exactly
+  Json,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogRotation {
+  Hourly,
+  Daily,
+  Never,
+}
+
+impl From<LogRotation> for Rotation {
+  fn from(rotation: LogRotation) -> Self {
+    match rotation {
+      LogRotation::Hourly => Rotation::HOURLY,
+      LogRotation::Daily => Rotation::DAILY,
+      LogRotation::Never => Rotation::NEVER,
+    }
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct LogConfig {
+  /// `EnvFilter` directives, e.g. `warn,[LOGIN]=info` keeps warnings plus everything inside `LOGIN`
+  pub filter: String,
+  pub format: LogFormat,
+  pub rotation: LogRotation,
+  /// older files are deleted once there are more, all of them are kept if absent
+  pub max_files: Option<usize>,
+}
+
+impl Default for LogConfig {
+  fn default() -> Self {
+    Self {
+      filter: "info".into(),
+      format: LogFormat::Text,
+      rotation: LogRotation::Daily,
+      max_files: Some(30),
+    }
+  }
+}
+
+impl LogConfig {
+  pub fn validate(&self) -> Result<(), String> {
+    if let Err(err) = EnvFilter::try_new(&self.filter) {
+      return Err(format!("log filter \"{}\": {}", self.filter, err));
+    }
+    if self.max_files == Some(0) {
+      return Err("at least one log file must be kept".into());
+    }
+    Ok(())
+  }
+}
+
+pub type BoxedLayer<S> = Box<dyn Layer<S> + Send + Sync>;
+
+/// swaps the filter of a layer from `file_layer` while it is running
+pub type FilterHandle<S> = reload::Handle<EnvFilter, S>;
+
+/// a layer writing into rotating files under `dir`, format and rotation are fixed once it is built
+pub fn file_layer<S>(
+  dir: &Path,
+  config: &LogConfig,
+) -> Result<(BoxedLayer<S>, FilterHandle<S>), Error>
+where
+  S: Subscriber + for<'a> LookupSpan<'a>,
+{
+  fs::create_dir_all(dir)?;
+  let mut builder = RollingFileAppender::builder()
+    .rotation(config.rotation.into())
+    .filename_prefix(FILE_PREFIX)
+    .filename_suffix(FILE_SUFFIX);
+  if let Some(max_files) = config.max_files {
+    builder = builder.max_log_files(max_files);
+  }
+  let appender = builder.build(dir)?;
+
+  let (filter, handle) = reload::Layer::new(EnvFilter::try_new(&config.filter)?);
+  let layer = fmt::layer()
+    .with_writer(appender)
+    .with_ansi(false)
+    .with_target(false);
+  let layer = match config.format {
+    LogFormat::Text => layer.with_filter(filter).boxed(),
+    LogFormat::Json => layer
+      .json()
+      .with_current_span(false)
+      .with_filter(filter)
+      .boxed(),
+  };
+  Ok((layer, handle))
+}
+
+pub fn parse_filter(filter: &str) -> Result<EnvFilter, Error> {
+  Ok(EnvFilter::try_new(filter)?)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct LogSpan {
+  pub name: String,
+  pub fields: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct LogEntry {
+  #[serde(with = "time::serde::rfc3339")]
+  pub timestamp: OffsetDateTime,
+  pub level: String,
+  /// outermost first
+  pub spans: Vec<LogSpan>,
+  pub message: String,
+  pub fields: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct LogQuery {
+  #[serde(with = "time::serde::rfc3339::option")]
+  pub since: Option<OffsetDateTime>,
+  #[serde(with = "time::serde::rfc3339::option")]
+  pub until: Option<OffsetDateTime>,
+  /// entries inside a span with this `username`, or mentioning `user "<name>"`
+  pub user: Option<String>,
+  /// entries inside a span of this name, e.g. `LOGIN`
+  pub span: Option<String>,
+  /// the least severe level to include
+  pub level: Option<String>,
+  /// only the newest entries are returned if more match, none at all for 0
+  pub limit: usize,
+}
+
+impl Default for LogQuery {
+  fn default() -> Self {
+    Self {
+      since: None,
+      until: None,
+      user: None,
+      span: None,
+      level: None,
+      limit: 1000,
+    }
+  }
+}
+
+impl LogQuery {
+  fn matches(&self, entry: &LogEntry, level: Option<Level>) -> bool {
+    if matches!(self.since, Some(since) if entry.timestamp < since)
+      || matches!(self.until, Some(until) if entry.timestamp > until)
+    {
+      return false;
+    }
+    if let Some(level) = level {
+      // more verbose levels compare greater
+      match Level::from_str(&entry.level) {
+        Ok(entry_level) if entry_level <= level => {}
+        _ => return false,
+      }
+    }
+    if let Some(span) = &self.span {
+      if !entry
+        .spans
+        .iter()
+        .any(|s| s.name.eq_ignore_ascii_case(span))
+      {
+        return false;
+      }
+    }
+    if let Some(user) = &self.user {
+      let in_span = entry
+        .spans
+        .iter()
+        .any(|s| s.fields.get("username") == Some(user));
+      if !in_span && !entry.message.contains(&format!("user \"{}\"", user)) {
+        return false;
+      }
+    }
+    true
+  }
+}
+
+/// search the log files under `dir`, entries come out oldest first
+pub fn query(dir: &Path, query: &LogQuery) -> Result<Vec<LogEntry>, Error> {
+  let level = match &query.level {
+    Some(level) => Some(Level::from_str(level).map_err(|_| Error::InvalidLevel(level.clone()))?),
+    None => None,
+  };
+  if query.limit == 0 {
+    return Ok(vec![]);
+  }
+
+  let mut files = match fs::read_dir(dir) {
+    Ok(entries) => entries
+      .filter_map(|entry| entry.ok())
+      .map(|entry| entry.path())
+      .filter(|path| {
+        matches!(
+          path.file_name().and_then(|name| name.to_str()),
+          Some(name) if name.starts_with(FILE_PREFIX) && name.ends_with(FILE_SUFFIX)
+        )
+      })
+      .collect::<Vec<_>>(),
+    Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+    Err(err) => return Err(err.into()),
+  };
+  // dates in the names sort chronologically
+  files.sort();
+
+  let mut entries = VecDeque::new();
+  for path in files {
+    for line in BufReader::new(File::open(path)?).lines() {
+      let entry = match parse_line(&line?) {
+        Some(entry) => entry,
+        None => continue,
+      };
+      if query.matches(&entry, level) {
+        if entries.len() == query.limit {
+          entries.pop_front();
+        }
+        entries.push_back(entry);
+      }
+    }
+  }
+  Ok(entries.into())
+}
+
+fn parse_line(line: &str) -> Option<LogEntry> {
+  if line.starts_with('{') {
+    parse_json_line(line)
+  } else {
+    parse_text_line(line)
+  }
+}
+
+fn json_fields(object: &serde_json::Map<String, serde_json::Value>) -> BTreeMap<String, String> {
+  object
+    .iter()
+    .map(|(key, value)| {
+      let value = match value {
+        serde_json::Value::String(s) => s.clone(),
+        value => value.to_string(),
+      };
+      (key.clone(), value)
+    })
+    .collect()
+}
+
+fn parse_json_line(line: &str) -> Option<LogEntry> {
+  let value = serde_json::from_str::<serde_json::Value>(line).ok()?;
+  let timestamp = OffsetDateTime::parse(value.get("timestamp")?.as_str()?, &Rfc3339).ok()?;
+  let level = value.get("level")?.as_str()?.to_string();
+  let mut fields = json_fields(value.get("fields")?.as_object()?);
+  let message = fields.remove("message").unwrap_or_default();
+  let spans = match value.get("spans") {
+    Some(spans) => spans
+      .as_array()?
+      .iter()
+      .filter_map(|span| {
+        let mut fields = json_fields(span.as_object()?);
+        let name = fields.remove("name")?;
+        Some(LogSpan { name, fields })
+      })
+      .collect(),
+    None => vec![],
+  };
+  Some(LogEntry {
+    timestamp,
+    level,
+    spans,
+    message,
+    fields,
+  })
+}
+
+/// split `s` on spaces outside of double quotes, yielding where each piece starts and ends
+fn tokenize(s: &str) -> Vec<(usize, usize)> {
+  let mut tokens = vec![];
+  let mut start = None;
+  let mut quoted = false;
+  let mut escaped = false;
+  for (i, c) in s.char_indices() {
+    match c {
+      _ if escaped => escaped = false,
+      '\\' if quoted => escaped = true,
+      '"' => quoted = !quoted,
+      ' ' if !quoted => {
+        if let Some(start) = start.take() {
+          tokens.push((start, i));
+        }
+        continue;
+      }
+      _ => {}
+    }
+    start.get_or_insert(i);
+  }
+  if let Some(start) = start {
+    tokens.push((start, s.len()));
+  }
+  tokens
+}
+
+/// `key=value` with a plain identifier as the key
+fn parse_field(token: &str) -> Option<(String, String)> {
+  let (key, value) = token.split_once('=')?;
+  if key.is_empty() || !key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+    return None;
+  }
+  let value = match value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+    Some(quoted) => quoted.replace("\\\"", "\"").replace("\\\\", "\\"),
+    None => value.to_string(),
+  };
+  Some((key.to_string(), value))
+}
+
+/// parse the leading `NAME{fields}:NAME{fields}: ` chain, returns the spans and what follows
+fn parse_text_spans(mut rest: &str) -> (Vec<LogSpan>, &str) {
+  let original = rest;
+  let mut spans = vec![];
+  loop {
+    let name_len = rest
+      .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+      .unwrap_or(rest.len());
+    if name_len == 0 {
+      return (vec![], original);
+    }
+    let (name, after) = rest.split_at(name_len);
+    let (fields, after) = match after.strip_prefix('{') {
+      Some(after) => {
+        let end = match closing_brace(after) {
+          Some(end) => end,
+          None => return (vec![], original),
+        };
+        let fields = tokenize(&after[..end])
+          .into_iter()
+          .filter_map(|(start, end)| parse_field(&after[start..end]))
+          .collect();
+        (fields, &after[end + 1..])
+      }
+      None => (BTreeMap::new(), after),
+    };
+    let after = match after.strip_prefix(':') {
+      Some(after) => after,
+      None => return (vec![], original),
+    };
+    spans.push(LogSpan {
+      name: name.to_string(),
+      fields,
+    });
+    if let Some(after) = after.strip_prefix(' ') {
+      return (spans, after);
+    }
+    rest = after;
+  }
+}
+
+/// index of the `}` closing a span's fields, braces inside quotes do not count
+fn closing_brace(s: &str) -> Option<usize> {
+  let mut quoted = false;
+  let mut escaped = false;
+  for (i, c) in s.char_indices() {
+    match c {
+      _ if escaped => escaped = false,
+      '\\' if quoted => escaped = true,
+      '"' => quoted = !quoted,
+      '}' if !quoted => return Some(i),
+      _ => {}
+    }
+  }
+  None
+}
+
+fn parse_text_line(line: &str) -> Option<LogEntry> {
+  let (timestamp, rest) = line.split_once(' ')?;
+  let timestamp = OffsetDateTime::parse(timestamp, &Rfc3339).ok()?;
+  let (level, rest) = rest.trim_start().split_once(' ')?;
+  Level::from_str(level).ok()?;
+  let (spans, rest) = parse_text_spans(rest);
+
+  // trailing `key=value` pairs are the fields, whatever comes before is the message
+  let tokens = tokenize(rest);
+  let mut fields = BTreeMap::new();
+  let mut message_end = rest.len();
+  for &(start, end) in tokens.iter().rev() {
+    match parse_field(&rest[start..end]) {
+      Some((key, value)) => {
+        fields.insert(key, value);
+        message_end = start;
+      }
+      None => break,
+    }
+  }
+  Some(LogEntry {
+    timestamp,
+    level: level.to_string(),
+    spans,
+    message: rest[..message_end].trim_end().to_string(),
+    fields,
+  })
+}
+
+#[derive(ThisError, Debug)]
+pub enum Error {
+  #[error(transparent)]
+  IO(#[from] io::Error),
+  #[error(transparent)]
+  Filter(#[from] ParseError),
+  #[error(transparent)]
+  Appender(#[from] rolling::InitError),
+  #[error("invalid log level \"{0}\"")]
+  InvalidLevel(String),
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use std::path::PathBuf;
+
+  use tracing::{debug, info, info_span, warn};
+  use tracing_subscriber::prelude::*;
+
+  fn dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir()
+      .join(format!("chatroom-logs-{}", std::process::id()))
+      .join(name);
+    let _ = fs::remove_dir_all(&dir);
+    dir
+  }
+
+  /// write a login of alice and a registration of bob into `dir`
+  fn write_logs(dir: &Path, format: LogFormat) {
+    let config = LogConfig {
+      format,
+      rotation: LogRotation::Never,
+      ..Default::default()
+    };
+    let (layer, _) = file_layer(dir, &config).unwrap();
+    let subscriber = tracing_subscriber::registry().with(layer);
+    tracing::subscriber::with_default(subscriber, || {
+      info_span!("LOGIN", username = "alice").in_scope(|| {
+        warn!(source = "server", "wrong password, retry.");
+        info!(source = "server", "logged in.");
+      });
+      debug!(source = "server", "below the filter.");
+      info!(source = "server", "user \"bob\" registered.");
+    });
+  }
+
+  fn messages(dir: &Path, query: LogQuery) -> Vec<String> {
+    super::query(dir, &query)
+      .unwrap()
+      .into_iter()
+      .map(|entry| entry.message)
+      .collect()
+  }
+
+  #[test]
+  fn searches_what_was_written() {
+    for format in [LogFormat::Text, LogFormat::Json] {
+      let dir = dir(&format!("{:?}", format));
+      write_logs(&dir, format);
+
+      let entries = super::query(&dir, &LogQuery::default()).unwrap();
+      assert_eq!(entries.len(), 3, "{:?}", format);
+      assert_eq!(entries[0].level, "WARN");
+      assert_eq!(entries[0].spans[0].name, "LOGIN");
+      assert_eq!(entries[0].spans[0].fields["username"], "alice");
+      assert_eq!(entries[0].fields["source"], "server");
+
+      let alice = LogQuery {
+        user: Some("alice".into()),
+        ..Default::default()
+      };
+      assert_eq!(
+        messages(&dir, alice),
+        ["wrong password, retry.", "logged in."]
+      );
+      let bob = LogQuery {
+        user: Some("bob".into()),
+        ..Default::default()
+      };
+      assert_eq!(messages(&dir, bob), ["user \"bob\" registered."]);
+      let login = LogQuery {
+        span: Some("login".into()),
+        ..Default::default()
+      };
+      assert_eq!(messages(&dir, login).len(), 2);
+      let warnings = LogQuery {
+        level: Some("warn".into()),
+        ..Default::default()
+      };
+      assert_eq!(messages(&dir, warnings), ["wrong password, retry."]);
+      let newest = LogQuery {
+        limit: 1,
+        ..Default::default()
+      };
+      assert_eq!(messages(&dir, newest), ["user \"bob\" registered."]);
+      let none = LogQuery {
+        limit: 0,
+        ..Default::default()
+      };
+      assert!(messages(&dir, none).is_empty());
+      let future = LogQuery {
+        since: Some(OffsetDateTime::now_utc() + time::Duration::hours(1)),
+        ..Default::default()
+      };
+      assert!(messages(&dir, future).is_empty());
+    }
+  }
+
+  #[test]
+  fn parses_text_lines() {
+    let entry = parse_text_line(
+      r#"2021-12-01T08:00:00.5Z  INFO LOGIN{username="a b" addr=127.0.0.1:1}:SESSION: user "a b" logged in. source="server" id=3"#,
+    )
+    .unwrap();
+    assert_eq!(entry.level, "INFO");
+    assert_eq!(entry.spans.len(), 2);
+    assert_eq!(entry.spans[0].fields["username"], "a b");
+    assert_eq!(entry.spans[0].fields["addr"], "127.0.0.1:1");
+    assert_eq!(entry.spans[1].name, "SESSION");
+    assert_eq!(entry.message, r#"user "a b" logged in."#);
+    assert_eq!(entry.fields["source"], "server");
+    assert_eq!(entry.fields["id"], "3");
+
+    assert!(parse_text_line("not a log line").is_none());
+  }
+
+  #[test]
+  fn finds_nothing_without_logs() {
+    assert!(super::query(&dir("missing"), &LogQuery::default())
+      .unwrap()
+      .is_empty());
+    let bad_level = LogQuery {
+      level: Some("loud".into()),
+      ..Default::default()
+    };
+    assert!(matches!(
+      super::query(&dir("missing"), &bad_level),
+      Err(Error::InvalidLevel(_))
+    ));
+  }
+}
@@ -0,0 +1,348 @@
+use thiserror::Error as ThisError;
+
+use std::{
+  fmt::Write as _,
+  net::SocketAddr,
+  sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+  },
+  time::{Duration, Instant},
+};
+
+use tokio::{
+  io::{AsyncReadExt, AsyncWriteExt},
+  net::{TcpListener, TcpStream},
+  task::JoinHandle,
+  time,
+};
+
+use tracing::{error, info};
+
+use chatroom_core::{
+  codec::Codec,
+  data::{Command, ErrorCode},
+};
+
+use crate::server::Server;
+
+/// upper bounds in seconds of the latency buckets, srp handshakes take a few milliseconds
+const LATENCY_BUCKETS: [f64; 10] = [0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5];
+
+/// requests heads larger than this are rejected
+const MAX_REQUEST_HEAD: usize = 8192;
+
+/// connections which have not sent the whole request head by then are dropped
+const REQUEST_HEAD_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Default)]
+struct Histogram {
+  /// not cumulative, the exposition sums them up
+  buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+  sum_nanos: AtomicU64,
+  count: AtomicU64,
+}
+
+impl Histogram {
+  fn observe(&self, elapsed: Duration) {
+    let secs = elapsed.as_secs_f64();
+    if let Some(i) = LATENCY_BUCKETS.iter().position(|&bound| secs <= bound) {
+      self.buckets[i].fetch_add(1, Ordering::Relaxed);
+    }
+    let nanos = u64::try_from(elapsed.as_nanos()).unwrap_or(u64::MAX);
+    self.sum_nanos.fetch_add(nanos, Ordering::Relaxed);
+    self.count.fetch_add(1, Ordering::Relaxed);
+  }
+}
+
+/// counters updated by `process`, one per command or error code so that requests never wait on
+/// each other, gauges are read from the server when rendering
+#[derive(Debug, Default)]
+pub struct Metrics {
+  requests: [AtomicU64; Command::NAMES.len()],
+  errors: [AtomicU64; ErrorCode::NAMES.len()],
+  latencies: [Histogram; Command::NAMES.len()],
+  heartbeat_expiries: AtomicU64,
+}
+
+impl Metrics {
+  /// count a request, its latency is recorded once the returned timer is dropped, however
+  /// processing it ends
+  pub fn record_request(&self, command: &Command) -> RequestTimer<'_> {
+    self.requests[command.index()].fetch_add(1, Ordering::Relaxed);
+    RequestTimer {
+      histogram: &self.latencies[command.index()],
+      started: Instant::now(),
+    }
+  }
+
+  pub fn record_error(&self, code: &ErrorCode) {
+    self.errors[code.index()].fetch_add(1, Ordering::Relaxed);
+  }
+
+  pub fn record_heartbeat_expiry(&self) {
+    self.heartbeat_expiries.fetch_add(1, Ordering::Relaxed);
+  }
+}
+
+#[must_use = "the latency is recorded when the timer is dropped"]
+pub struct RequestTimer<'a> {
+  histogram: &'a Histogram,
+  started: Instant,
+}
+
+impl Drop for RequestTimer<'_> {
+  fn drop(&mut self) {
+    self.histogram.observe(self.started.elapsed());
+  }
+}
+
+/// metrics of the server in the prometheus text format
+pub fn render<Coder: Codec>(server: &Server<Coder>) -> String {
+  let state = server.get_state();
+  let metrics = &state.metrics;
+  let stats = server.get_stats();
+  let connection = server.get_connection().get_stats();
+  let mut out = String::new();
+
+  // writing into a string never fails
+  let _ = writeln!(
+    out,
+    "# HELP chatroom_uptime_seconds Seconds since the server started."
+  );
+  let _ = writeln!(out, "# TYPE chatroom_uptime_seconds gauge");
+  let _ = writeln!(out, "chatroom_uptime_seconds {}", stats.uptime_secs);
+  let _ = writeln!(out, "# HELP chatroom_users Registered users.");
+  let _ = writeln!(out, "# TYPE chatroom_users gauge");
+  let _ = writeln!(out, "chatroom_users {}", stats.users);
+  let _ = writeln!(out, "# HELP chatroom_online_users Users currently online.");
+  let _ = writeln!(out, "# TYPE chatroom_online_users gauge");
+  let _ = writeln!(out, "chatroom_online_users {}", stats.online);
+  let _ = writeln!(
+    out,
+    "# HELP chatroom_online_devices Devices currently logged in."
+  );
+  let _ = writeln!(out, "# TYPE chatroom_online_devices gauge");
+  let _ = writeln!(out, "chatroom_online_devices {}", stats.devices);
+
+  let _ = writeln!(
+    out,
+    "# HELP chatroom_requests_total Requests received by command."
+  );
+  let _ = writeln!(out, "# TYPE chatroom_requests_total counter");
+  for (command, count) in Command::NAMES.iter().zip(&metrics.requests) {
+    let _ = writeln!(
+      out,
+      "chatroom_requests_total{{command=\"{}\"}} {}",
+      command,
+      count.load(Ordering::Relaxed)
+    );
+  }
+  let _ = writeln!(
+    out,
+    "# HELP chatroom_errors_total Error responses by error code."
+  );
+  let _ = writeln!(out, "# TYPE chatroom_errors_total counter");
+  for (code, count) in ErrorCode::NAMES.iter().zip(&metrics.errors) {
+    let _ = writeln!(
+      out,
+      "chatroom_errors_total{{code=\"{}\"}} {}",
+      code,
+      count.load(Ordering::Relaxed)
+    );
+  }
+
+  let _ = writeln!(
+    out,
+    "# HELP chatroom_heartbeat_expiries_total Users dropped for missing heartbeats."
+  );
+  let _ = writeln!(out, "# TYPE chatroom_heartbeat_expiries_total counter");
+  let _ = writeln!(
+    out,
+    "chatroom_heartbeat_expiries_total {}",
+    metrics.heartbeat_expiries.load(Ordering::Relaxed)
+  );
+  let _ = writeln!(
+    out,
+    "# HELP chatroom_key_exchanges_total Public keys received from peers."
+  );
+  let _ = writeln!(out, "# TYPE chatroom_key_exchanges_total counter");
+  let _ = writeln!(
+    out,
+    "chatroom_key_exchanges_total {}",
+    connection.key_exchanges
+  );
+  let _ = writeln!(
+    out,
+    "# HELP chatroom_decryption_failures_total Datagrams which failed to authenticate."
+  );
+  let _ = writeln!(out, "# TYPE chatroom_decryption_failures_total counter");
+  let _ = writeln!(
+    out,
+    "chatroom_decryption_failures_total {}",
+    connection.decryption_failures
+  );
+
+  let _ = writeln!(
+    out,
+    "# HELP chatroom_request_duration_seconds Time spent processing requests by command."
+  );
+  let _ = writeln!(out, "# TYPE chatroom_request_duration_seconds histogram");
+  for (command, histogram) in Command::NAMES.iter().zip(&metrics.latencies) {
+    let mut cumulative = 0;
+    for (bound, count) in LATENCY_BUCKETS.iter().zip(histogram.buckets.iter()) {
+      cumulative += count.load(Ordering::Relaxed);
+      let _ = writeln!(
+        out,
+        "chatroom_request_duration_seconds_bucket{{command=\"{}\",le=\"{}\"}} {}",
+        command, bound, cumulative
+      );
+    }
+    // the counters race with requests in flight, the total must not fall short of the buckets
+    let count = histogram.count.load(Ordering::Relaxed).max(cumulative);
+    let sum = histogram.sum_nanos.load(Ordering::Relaxed) as f64 / 1e9;
+    let _ = writeln!(
+      out,
+      "chatroom_request_duration_seconds_bucket{{command=\"{}\",le=\"+Inf\"}} {}",
+      command, count
+    );
+    let _ = writeln!(
+      out,
+      "chatroom_request_duration_seconds_sum{{command=\"{}\"}} {}",
+      command, sum
+    );
+    let _ = writeln!(
+      out,
+      "chatroom_request_duration_seconds_count{{command=\"{}\"}} {}",
+      command, count
+    );
+  }
+  out
+}
+
+/// answer `GET /metrics` over http on a loopback address
+pub async fn serve<Coder: Codec>(
+  server: Arc<Server<Coder>>,
+  addr: SocketAddr,
+) -> Result<JoinHandle<()>, Error> {
+  if !addr.ip().is_loopback() {
+    return Err(Error::NotLoopback(addr));
+  }
+  let listener = TcpListener::bind(addr).await?;
+  info!(
+    source = "server",
+    "metrics are served on http://{}/metrics.",
+    listener.local_addr()?
+  );
+  Ok(tokio::spawn(async move {
+    loop {
+      let (stream, _) = match listener.accept().await {
+        Ok(conn) => conn,
+        Err(err) => {
+          error!(
+            source = "internal",
+            "error occurred during accepting metrics connection: {}.", err
+          );
+          continue;
+        }
+      };
+      let server = server.clone();
+      tokio::spawn(async move {
+        // scrapers hanging up early is nothing to worry about
+        let _ = respond(&server, stream).await;
+      });
+    }
+  }))
+}
+
+async fn respond<Coder: Codec>(server: &Server<Coder>, mut stream: TcpStream) -> Result<(), Error> {
+  let head = match time::timeout(REQUEST_HEAD_TIMEOUT, read_head(&mut stream)).await {
+    Ok(Ok(Some(head))) => head,
+    // too large, cut short or too slow
+    Ok(Ok(None)) | Err(_) => return Ok(()),
+    Ok(Err(err)) => return Err(err.into()),
+  };
+
+  let response = if head.starts_with(b"GET /metrics ") {
+    let body = render(server);
+    format!(
+      "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+      body.len(),
+      body
+    )
+  } else {
+    "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+  };
+  stream.write_all(response.as_bytes()).await?;
+  stream.shutdown().await?;
+  Ok(())
+}
+
+/// read up to the end of the request head, `None` if it is too large or the connection closes first
+async fn read_head(stream: &mut TcpStream) -> std::io::Result<Option<Vec<u8>>> {
+  let mut head = Vec::new();
+  let mut buf = [0u8; 1024];
+  while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+    let len = stream.read(&mut buf).await?;
+    if len == 0 || head.len() + len > MAX_REQUEST_HEAD {
+      return Ok(None);
+    }
+    head.extend_from_slice(&buf[..len]);
+  }
+  Ok(Some(head))
+}
+
+#[derive(ThisError, Debug)]
+pub enum Error {
+  #[error(transparent)]
+  IO(#[from] std::io::Error),
+  #[error("metrics must be served on a loopback address, got {0}")]
+  NotLoopback(SocketAddr),
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn records_latency_however_processing_ends() {
+    let metrics = Metrics::default();
+    let process = |fail: bool| -> Result<(), ()> {
+      let _timer = metrics.record_request(&Command::Heartbeat);
+      if fail {
+        return Err(());
+      }
+      Ok(())
+    };
+    assert!(process(false).is_ok());
+    assert!(process(true).is_err());
+
+    let index = Command::Heartbeat.index();
+    assert_eq!(metrics.requests[index].load(Ordering::Relaxed), 2);
+    let histogram = &metrics.latencies[index];
+    assert_eq!(histogram.count.load(Ordering::Relaxed), 2);
+    assert_eq!(histogram.buckets[0].load(Ordering::Relaxed), 2);
+    assert_eq!(
+      metrics.latencies[Command::Logout.index()]
+        .count
+        .load(Ordering::Relaxed),
+      0
+    );
+  }
+
+  #[test]
+  fn buckets_by_upper_bound() {
+    let histogram = Histogram::default();
+    histogram.observe(Duration::from_millis(5));
+    histogram.observe(Duration::from_millis(6));
+    histogram.observe(Duration::from_secs(10));
+    let buckets = histogram
+      .buckets
+      .iter()
+      .map(|count| count.load(Ordering::Relaxed))
+      .collect::<Vec<_>>();
+    assert_eq!(buckets, [0, 1, 1, 0, 0, 0, 0, 0, 0, 0]);
+    assert_eq!(histogram.count.load(Ordering::Relaxed), 3);
+    assert_eq!(histogram.sum_nanos.load(Ordering::Relaxed), 10_011_000_000);
+  }
+}
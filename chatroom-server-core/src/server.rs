@@ -0,0 +1,2039 @@
+use std::{
+  collections::HashMap,
+  iter,
+  net::{IpAddr, SocketAddr},
+  result::Result,
+  sync::Arc,
+  time::{Duration, Instant},
+};
+
+use time::OffsetDateTime;
+use tokio::{self, net::UdpSocket, task::JoinHandle};
+
+use chatroom_core::{
+  buffer::Frame,
+  codec::Codec,
+  connection::SecureConnection,
+  data::{
+    Capabilities, Command, ErrorCode, Notification, Response, ResponseData, User, UserEssential,
+    UserInfo, UserOnlineInfo, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+  },
+  srp::{self, ServerHandshake, Verifier},
+  totp,
+  transport::Transport,
+  utils::Error,
+};
+
+use crate::{
+  accounts::{self, Account, ConflictMode, ImportSummary},
+  audit::{AuditEvent, AuditTrail},
+  auth::{self, AuthProvider, LocalProvider, EXTERNAL_PASSWORD_HASH},
+  config::{RegistrationPolicy, ServerConfig},
+  event::{EventListener, ServerEvent},
+  limiter::RateLimiter,
+  lockout::LoginGuard,
+  metrics::Metrics,
+  session::SessionKeeper,
+  storage::{self, Ban, BanTarget, MemoryStorage, Storage},
+  two_factor::{self, TwoFactor, Verified},
+};
+
+use serde::{Deserialize, Serialize};
+
+use rand::Rng;
+
+use sha2::{Digest, Sha256};
+
+use parking_lot::{RwLock, RwLockUpgradableReadGuard, RwLockWriteGuard};
+
+use byteorder::{ByteOrder, NetworkEndian};
+
+use crypto_box::PublicKey;
+
+use time;
+
+use tracing::{error, info, info_span, warn, Instrument};
+
+type RwHashMap<K, V> = RwLock<HashMap<K, V>>;
+
+/// optional protocol features this server is able to provide
+const SUPPORTED_CAPABILITIES: Capabilities =
+  Capabilities::COMPRESSION.union(Capabilities::BATCHING);
+
+/// how long notifications may wait to be coalesced with others to the same peer
+const BATCH_WINDOW: Duration = Duration::from_millis(20);
+
+/// shown by authenticator apps next to the account name
+const TOTP_ISSUER: &str = "Chatroom";
+
+/// how long a challenge stays open for the client to answer
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+/// pending handshakes are only swept once there are this many of them
+const HANDSHAKE_SWEEP_THRESHOLD: usize = 1024;
+
+/// how long a peer may stay without logging in after its hello before it is forgotten
+const PEER_TIMEOUT: Duration = Duration::from_secs(600);
+/// capabilities of peers are only swept once there are this many of them
+const PEER_SWEEP_THRESHOLD: usize = 1024;
+
+/// a login or password change waiting for the client to answer its challenge
+#[derive(Debug)]
+pub struct PendingHandshake {
+  handshake: ServerHandshake,
+  /// recorded as the reason once the proof turns out to be wrong
+  failure: &'static str,
+  /// the account still has a password hash from before srp, which the client is only told once
+  /// it answered the challenge like for any other account
+  migrate: bool,
+  started: Instant,
+}
+
+/// what a peer negotiated in its hello
+#[derive(Debug, Clone, Copy)]
+pub struct PeerCapabilities {
+  pub capabilities: Capabilities,
+  negotiated: Instant,
+}
+
+#[derive(Debug)]
+pub struct ServerState {
+  pub addr2user: RwHashMap<SocketAddr, String>,
+  pub users: RwHashMap<String, User>,
+  /// one for every logged in device, keyed by its address
+  pub user_active_timers: RwHashMap<SocketAddr, JoinHandle<()>>,
+  pub pub_keys: Arc<RwHashMap<SocketAddr, PublicKey>>,
+  pub peer_capabilities: RwHashMap<SocketAddr, PeerCapabilities>,
+  /// may be swapped while running through `Server::apply_config`
+  pub config: RwLock<ServerConfig>,
+  pub rate_limiter: RateLimiter,
+  pub login_guard: LoginGuard,
+  pub bans: RwHashMap<BanTarget, Ban>,
+  pub metrics: Metrics,
+  pub audit: AuditTrail,
+  pub handshakes: RwHashMap<SocketAddr, PendingHandshake>,
+  pub sessions: SessionKeeper,
+  pub two_factor: TwoFactor,
+  /// checks passwords, swapped through `set_auth_provider`
+  auth: RwLock<Arc<dyn AuthProvider>>,
+  /// salts the verifiers made up for accounts without a usable one
+  decoy_key: [u8; 32],
+  /// where durable data is kept, changes are written through before being applied in memory
+  pub storage: Arc<dyn Storage>,
+}
+
+impl ServerState {
+  pub fn new(heartbeat_interval: Duration) -> Self {
+    Self::from_user_essentials(heartbeat_interval, iter::empty())
+  }
+
+  pub fn from_user_essentials<I>(heartbeat_interval: Duration, iter: I) -> Self
+  where
+    I: Iterator<Item = (String, UserEssential)>,
+  {
+    let storage = MemoryStorage::from_users(iter.collect());
+    let config = ServerConfig {
+      heartbeat_interval,
+      ..Default::default()
+    };
+    Self::with_storage(config, Arc::new(storage)).unwrap() // memory storage never fails
+  }
+
+  pub fn with_storage(
+    config: ServerConfig,
+    storage: Arc<dyn Storage>,
+  ) -> Result<Self, storage::Error> {
+    let users: HashMap<String, User> = storage
+      .load_users()?
+      .into_iter()
+      .map(|(n, d)| (n.clone(), (n, d).into()))
+      .collect();
+    let legacy = users
+      .values()
+      .filter(|user| auth::is_legacy_hash(&user.password_hash))
+      .count();
+    if legacy > 0 {
+      warn!(
+        source = "server",
+        "{} users have passwords from before srp, they get migrated on the next login.", legacy
+      );
+    }
+    let users = RwLock::new(users);
+    let bans = storage
+      .load_bans()?
+      .into_iter()
+      .map(|ban| (ban.target.clone(), ban))
+      .collect();
+    let audit = AuditTrail::new(storage.clone())?;
+    let two_factor = TwoFactor::new(storage.clone())?;
+    let auth: Arc<dyn AuthProvider> = Arc::new(LocalProvider::new(storage.clone()));
+    Ok(Self {
+      addr2user: Default::default(),
+      users,
+      user_active_timers: Default::default(),
+      pub_keys: Default::default(),
+      peer_capabilities: Default::default(),
+      config: RwLock::new(config),
+      rate_limiter: Default::default(),
+      login_guard: Default::default(),
+      bans: RwLock::new(bans),
+      metrics: Default::default(),
+      audit,
+      handshakes: Default::default(),
+      sessions: Default::default(),
+      two_factor,
+      auth: RwLock::new(auth),
+      decoy_key: rand::thread_rng().gen(),
+      storage,
+    })
+  }
+
+  pub fn auth_provider(&self) -> Arc<dyn AuthProvider> {
+    self.auth.read().clone()
+  }
+
+  /// check passwords with `provider` from now on, e.g. a directory instead of the own store, the
+  /// users it vouches for get added to the roster on their first login
+  pub fn set_auth_provider(&self, provider: Arc<dyn AuthProvider>) {
+    info!(
+      source = "server",
+      "passwords are checked by the {} provider.",
+      provider.name()
+    );
+    *self.auth.write() = provider;
+  }
+
+  /// a verifier to answer for `username` when there is no usable one, its salt stays the same
+  /// across attempts so that the challenge tells nothing about whether the account exists
+  fn decoy_verifier(&self, username: &str) -> Verifier {
+    let mut hasher = Sha256::new();
+    hasher.update(self.decoy_key);
+    hasher.update(username);
+    Verifier::random(hasher.finalize()[..srp::SALT_LEN].to_vec())
+  }
+
+  fn insert_handshake(&self, addr: SocketAddr, handshake: PendingHandshake) {
+    let mut handshakes = self.handshakes.write();
+    if handshakes.len() >= HANDSHAKE_SWEEP_THRESHOLD {
+      handshakes.retain(|_, pending| pending.started.elapsed() < HANDSHAKE_TIMEOUT);
+    }
+    handshakes.insert(addr, handshake);
+  }
+
+  /// remember what `addr` negotiated, returns how many peers got forgotten to make room, they said
+  /// hello long ago and never logged in, their codecs stay until they say hello again
+  fn insert_capabilities(&self, addr: SocketAddr, capabilities: Capabilities) -> usize {
+    let mut peers = self.peer_capabilities.write();
+    let before = peers.len();
+    if before >= PEER_SWEEP_THRESHOLD {
+      let addr2user = self.addr2user.read();
+      peers.retain(|addr, peer| {
+        addr2user.contains_key(addr) || peer.negotiated.elapsed() < PEER_TIMEOUT
+      });
+    }
+    let forgotten = before - peers.len();
+    peers.insert(
+      addr,
+      PeerCapabilities {
+        capabilities,
+        negotiated: Instant::now(),
+      },
+    );
+    forgotten
+  }
+
+  /// the handshake started from `addr`, unless it timed out, each one is answered only once
+  fn take_handshake(&self, addr: SocketAddr) -> Option<PendingHandshake> {
+    self
+      .handshakes
+      .write()
+      .remove(&addr)
+      .filter(|pending| pending.started.elapsed() < HANDSHAKE_TIMEOUT)
+  }
+
+  /// import accounts into the storage and the running server, online users stay online but the
+  /// sessions of those whose password got overwritten can no longer be resumed
+  pub async fn import_accounts(
+    self: &Arc<Self>,
+    accounts: &[Account],
+    mode: ConflictMode,
+  ) -> Result<ImportSummary, accounts::Error> {
+    // storage may block, so it is read and written off the runtime, the lock is only taken to
+    // bring the roster up to date
+    let (state, accounts) = (self.clone(), accounts.to_vec());
+    let (summary, stored) = tokio::task::spawn_blocking(move || {
+      let summary = accounts::import(&*state.storage, &state.audit, &accounts, mode)?;
+      Ok::<_, accounts::Error>((summary, state.storage.load_users()?))
+    })
+    .await
+    .map_err(storage::Error::from)??;
+    let mut overwritten = vec![];
+    {
+      let mut users = self.users.write();
+      for (name, essential) in stored {
+        match users.get_mut(&name) {
+          Some(user) => {
+            if user.password_hash != essential.password_hash {
+              user.password_hash = essential.password_hash;
+              overwritten.push(name);
+            }
+          }
+          None => {
+            users.insert(name.clone(), (name, essential).into());
+          }
+        }
+      }
+    }
+    for name in overwritten {
+      self.sessions.revoke_user(&name, None);
+    }
+    Ok(summary)
+  }
+
+  /// the ban in effect on either the user or the address, expired ones are ignored
+  pub fn find_ban(&self, name: &str, ip: IpAddr) -> Option<Ban> {
+    let now = OffsetDateTime::now_utc();
+    let bans = self.bans.read();
+    [BanTarget::User(name.to_string()), BanTarget::Ip(ip)]
+      .iter()
+      .filter_map(|target| bans.get(target))
+      .find(|ban| ban.until.is_none_or(|until| until > now))
+      .cloned()
+  }
+
+  pub fn get_config(&self) -> ServerConfig {
+    self.config.read().clone()
+  }
+
+  pub fn get_user_essentials(&self) -> HashMap<String, UserEssential> {
+    self
+      .users
+      .read()
+      .iter()
+      .map(|(n, d)| (n.clone(), d.into()))
+      .collect()
+  }
+}
+
+/// a snapshot of what a running server is up to
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ServerStats {
+  pub uptime_secs: u64,
+  pub users: usize,
+  pub online: usize,
+  /// a user online from several devices counts for each of them
+  pub devices: usize,
+  pub bans: usize,
+}
+
+pub struct Server<Coder>
+where
+  Coder: Codec,
+{
+  started: Instant,
+  state: Arc<ServerState>,
+  connection: Arc<SecureConnection<Coder>>,
+  listener: Arc<dyn EventListener>,
+
+  key_receiver: Option<JoinHandle<()>>,
+  req_receiver: Option<JoinHandle<()>>,
+  batch_flusher: Option<JoinHandle<()>>,
+}
+
+impl<Coder> Server<Coder>
+where
+  Coder: Codec,
+{
+  pub async fn new(
+    coder: Coder,
+    state: ServerState,
+    listener: Arc<dyn EventListener>,
+    server_addr: &str,
+  ) -> Result<Server<Coder>, Error> {
+    let sock = UdpSocket::bind(server_addr).await?;
+    Self::with_transport(coder, state, listener, sock)
+  }
+
+  pub fn with_transport<T: Transport>(
+    coder: Coder,
+    state: ServerState,
+    listener: Arc<dyn EventListener>,
+    sock: T,
+  ) -> Result<Server<Coder>, Error> {
+    let state = Arc::new(state);
+
+    info!(
+      source = "server",
+      "server started at {}.",
+      sock.local_addr()?
+    );
+
+    let (connection, key_receiver) = SecureConnection::new(sock, state.pub_keys.clone(), coder);
+    let connection = Arc::new(connection);
+    let batch_flusher = connection.start_batching(BATCH_WINDOW);
+
+    let key_receiver = tokio::spawn({
+      let state = state.clone();
+      let mut key_receiver = key_receiver;
+      let listener = listener.clone();
+      async move {
+        loop {
+          if let Some((key, addr)) = key_receiver.recv().await {
+            let name = state.addr2user.read().get(&addr).cloned();
+            if let Some(name) = name {
+              if let Some(user) = state.users.write().get_mut(&name) {
+                let device = user
+                  .devices
+                  .iter_mut()
+                  .find(|device| device.ip_address == addr);
+                if let Some(info) = device {
+                  info.pub_key = *key.as_bytes();
+                  listener.on_event(ServerEvent::PublicKeyUpdated { name });
+                }
+              }
+            }
+          }
+        }
+      }
+    });
+
+    let req_receiver = tokio::spawn({
+      let connection = connection.clone();
+      let state = state.clone();
+      let listener = listener.clone();
+      async move {
+        loop {
+          let (buf, addr) = match connection.recv_from_raw().await {
+            Ok(req) => req,
+            Err(err) => {
+              error!(
+                source = "internal",
+                "error occurred during receiving request: {}.", err
+              );
+              continue;
+            }
+          };
+
+          let connection = connection.clone();
+          let state = state.clone();
+          tokio::spawn({
+            let listener = listener.clone();
+            async move {
+              if let Err(err) = process(state, connection, listener, buf, addr).await {
+                error!(
+                  source = "internal",
+                  "error occurred during processing request: {}.", err
+                );
+              }
+            }
+          });
+        }
+      }
+    });
+
+    Ok(Self {
+      started: Instant::now(),
+      state,
+      connection,
+      listener,
+      key_receiver: Some(key_receiver),
+      req_receiver: Some(req_receiver),
+      batch_flusher: Some(batch_flusher),
+    })
+  }
+
+  pub fn get_state(&self) -> Arc<ServerState> {
+    self.state.clone()
+  }
+
+  pub fn get_connection(&self) -> Arc<SecureConnection<Coder>> {
+    self.connection.clone()
+  }
+
+  pub fn get_listener(&self) -> Arc<dyn EventListener> {
+    self.listener.clone()
+  }
+
+  pub fn get_stats(&self) -> ServerStats {
+    ServerStats {
+      uptime_secs: self.started.elapsed().as_secs(),
+      users: self.state.users.read().len(),
+      online: self
+        .state
+        .users
+        .read()
+        .values()
+        .filter(|user| user.is_online())
+        .count(),
+      devices: self.state.user_active_timers.read().len(),
+      bans: self.state.bans.read().len(),
+    }
+  }
+
+  /// swap settings without disconnecting anyone, activity timers restart with a changed heartbeat
+  /// interval so nobody is dropped early
+  pub fn apply_config(&self, config: ServerConfig) {
+    let heartbeat_changed = {
+      let mut current = self.state.config.write();
+      let changed = current.heartbeat_interval != config.heartbeat_interval;
+      *current = config;
+      changed
+    };
+    if heartbeat_changed {
+      // a snapshot, so that the two locks are never held at once
+      let addr2user = self.state.addr2user.read().clone();
+      for (addr, timer) in self.state.user_active_timers.write().iter_mut() {
+        if let Some(username) = addr2user.get(addr) {
+          timer.abort();
+          *timer = expire_after_heartbeat(
+            self.state.clone(),
+            self.connection.clone(),
+            self.listener.clone(),
+            username.clone(),
+            *addr,
+          );
+        }
+      }
+    }
+    info!(source = "server", "settings are applied.");
+  }
+
+  /// take a user offline on all of their devices and let the others know, returns false if they
+  /// were not online
+  pub async fn force_logout(&self, username: &str) -> bool {
+    let addrs = match self.state.users.read().get(username) {
+      Some(user) => user
+        .devices
+        .iter()
+        .map(|device| device.ip_address)
+        .collect::<Vec<_>>(),
+      None => return false,
+    };
+    let mut logged_out = false;
+    for addr in addrs {
+      logged_out |= self.force_logout_device(username, addr).await;
+    }
+    logged_out
+  }
+
+  /// take the device of a user at `addr` offline and let the others know, returns false if it was
+  /// not online
+  pub async fn force_logout_device(&self, username: &str, addr: SocketAddr) -> bool {
+    logout_device(
+      &self.state,
+      &self.connection,
+      &self.listener,
+      username,
+      addr,
+    )
+    .await
+  }
+
+  /// log every online user out and let the others know, e.g. before shutting down
+  pub async fn logout_all(&self) {
+    let online_devices = self
+      .state
+      .addr2user
+      .read()
+      .iter()
+      .map(|(&addr, username)| (addr, username.clone()))
+      .collect::<Vec<_>>();
+    for (addr, username) in online_devices {
+      self.force_logout_device(&username, addr).await;
+    }
+    // their logouts are on the way to storage, wait for them
+    let state = self.state.clone();
+    let _ = tokio::task::spawn_blocking(move || state.audit.flush()).await;
+    if let Err(err) = self.connection.flush().await {
+      error!(
+        source = "internal",
+        "error occurred during flushing notifications: {}.", err
+      );
+    }
+  }
+}
+
+impl<Coder> Drop for Server<Coder>
+where
+  Coder: Codec,
+{
+  fn drop(&mut self) {
+    if let Some(handle) = self.key_receiver.take() {
+      handle.abort();
+    }
+    if let Some(handle) = self.req_receiver.take() {
+      handle.abort();
+    }
+    if let Some(handle) = self.batch_flusher.take() {
+      handle.abort();
+    }
+    for (_, timer) in self.state.user_active_timers.write().iter() {
+      timer.abort();
+    }
+  }
+}
+
+async fn process<Coder: Codec>(
+  state: Arc<ServerState>,
+  connection: Arc<SecureConnection<Coder>>,
+  listener: Arc<dyn EventListener>,
+  buf: Frame,
+  addr: SocketAddr,
+) -> Result<(), Error> {
+  let id = NetworkEndian::read_u16(&buf[..]);
+  let command = connection.get_coder().deserialize::<Command>(&buf[2..])?;
+  let _timer = state.metrics.record_request(&command);
+
+  if !matches!(command, Command::Heartbeat) {
+    let rate_limit = state.config.read().rate_limit;
+    if let Some(rate_limit) = rate_limit {
+      if !state.rate_limiter.check(addr, &rate_limit) {
+        error!(source = "server", "{} exceeded the rate limit.", addr);
+        state.metrics.record_error(&ErrorCode::RateLimited);
+        let response: Response = Err(ErrorCode::RateLimited);
+        connection.send_to_with_meta(&response, addr, id).await?;
+        return Ok(());
+      }
+    }
+  }
+
+  let response: Option<Response> = match command {
+    Command::Register {
+      username,
+      salt,
+      verifier,
+    } => {
+      let span = info_span!("REGISTER", %addr, username = username.as_str());
+      async {
+        info!("new request.");
+        Some('handler: {
+          // accounts of other providers are managed there, the users get added on first login
+          if state.config.read().registration == RegistrationPolicy::Closed
+            || !state.auth_provider().supports_srp()
+          {
+            error!(source = "server", "registration is closed.");
+            break 'handler Err(ErrorCode::RegistrationClosed);
+          }
+
+          if state.find_ban(&username, addr.ip()).is_some() {
+            error!(source = "server", "address {} is banned.", addr.ip());
+            break 'handler Err(ErrorCode::Banned);
+          }
+
+          if state.users.read().contains_key(&username) {
+            error!(source = "server", "user \"{}\" is occupied.", &username);
+            break 'handler Err(ErrorCode::UserExisted);
+          }
+
+          let verifier = Verifier { salt, verifier };
+          if !verifier.is_valid() {
+            error!(
+              source = "server",
+              "verifier of user \"{}\" is invalid.", &username
+            );
+            break 'handler Err(ErrorCode::InvalidUserOrPass);
+          }
+          let password_hash = verifier.encode();
+
+          let essential = UserEssential {
+            password_hash: password_hash.clone(),
+          };
+          // storage may block, so it is written off the runtime without holding the lock and tells
+          // if someone took the name in the meantime
+          let name = username.clone();
+          match storage::unblock(&state.storage, move |storage| {
+            storage.insert_user(&name, &essential)
+          })
+          .await
+          {
+            Ok(()) => {}
+            Err(storage::Error::UserExisted(_)) => {
+              error!(source = "server", "user \"{}\" is occupied.", &username);
+              break 'handler Err(ErrorCode::UserExisted);
+            }
+            Err(err) => {
+              error!(
+                source = "internal",
+                "failed to store user \"{}\": {}.", &username, err
+              );
+              break 'handler Err(ErrorCode::Internal);
+            }
+          }
+
+          state.users.write().insert(
+            username.clone(),
+            User {
+              name: username.clone(),
+              password_hash,
+              devices: vec![],
+            },
+          );
+
+          listener.on_event(ServerEvent::Registered {
+            name: username.clone(),
+          });
+          state.audit.record(AuditEvent::Registered {
+            name: username.clone(),
+            addr,
+          });
+          info!(
+            source = "server",
+            "user \"{}\" registered successfully.", &username
+          );
+          Ok(ResponseData::Success)
+        })
+      }
+      .instrument(span)
+      .await
+    }
+    Command::Authenticate {
+      username,
+      public_key,
+    } => {
+      let _span = info_span!("AUTHENTICATE", %addr, username = username.as_str()).entered();
+      info!("new request.");
+      Some('handler: {
+        if !state.auth_provider().supports_srp() {
+          error!(
+            source = "server",
+            "passwords are checked by a provider without srp."
+          );
+          break 'handler Err(ErrorCode::PasswordRequired);
+        }
+
+        if let Err(code) = check_login_allowed(&state, &username, addr) {
+          break 'handler Err(code);
+        }
+
+        // accounts without a usable verifier get a challenge nobody is able to answer, so that
+        // they look no different from the others
+        let stored = state
+          .users
+          .read()
+          .get(&username)
+          .map(|user| user.password_hash.clone());
+        let migrate = stored.as_deref().is_some_and(auth::is_legacy_hash);
+        let (verifier, failure) = match stored {
+          Some(_) if migrate => (state.decoy_verifier(&username), "password predates srp"),
+          Some(hash) => match Verifier::decode(&hash) {
+            Some(verifier) => (verifier, "wrong password"),
+            None => (state.decoy_verifier(&username), "no local password"),
+          },
+          None => (state.decoy_verifier(&username), "unknown user"),
+        };
+
+        let handshake = match ServerHandshake::new(&username, &verifier, &public_key) {
+          Ok(handshake) => handshake,
+          Err(err) => {
+            error!(source = "server", "failed to start handshake: {}.", err);
+            break 'handler Err(ErrorCode::InvalidUserOrPass);
+          }
+        };
+        let challenge = ResponseData::Challenge {
+          salt: handshake.salt().to_vec(),
+          public_key: handshake.public_key(),
+        };
+        state.insert_handshake(
+          addr,
+          PendingHandshake {
+            handshake,
+            failure,
+            migrate,
+            started: Instant::now(),
+          },
+        );
+        Ok(challenge)
+      })
+    }
+    Command::Login {
+      proof,
+      device,
+      code,
+    } => {
+      let pending = state.take_handshake(addr);
+      let username = pending
+        .as_ref()
+        .map(|pending| pending.handshake.username().to_string())
+        .unwrap_or_default();
+      let _span = info_span!("LOGIN", %addr, username = username.as_str()).entered();
+      info!("new request.");
+      let response: Response = 'handler: {
+        let pending = match pending {
+          Some(pending) => pending,
+          None => {
+            error!(
+              source = "server",
+              "no handshake is pending for the address."
+            );
+            break 'handler Err(ErrorCode::InvalidUserOrPass);
+          }
+        };
+
+        // checked again, handshakes started before a failure got counted must not get around it
+        if let Err(code) = check_login_allowed(&state, &username, addr) {
+          break 'handler Err(code);
+        }
+
+        let server_proof = match pending.handshake.verify(&proof) {
+          Ok(server_proof) => server_proof,
+          Err(_) => {
+            error!(
+              source = "server",
+              "user \"{}\" failed to log in: {}.", &username, pending.failure
+            );
+            // answered like any other failure, telling which accounts predate srp would tell
+            // which exist, the password gets checked and counted once it is migrated
+            if !pending.migrate {
+              record_login_failure(&state, &username, addr, pending.failure);
+            }
+            break 'handler Err(ErrorCode::InvalidUserOrPass);
+          }
+        };
+
+        if state.two_factor.is_enrolled(&username) {
+          let code = match &code {
+            Some(code) => code,
+            None => {
+              info!(
+                source = "server",
+                "user \"{}\" has to provide a one-time code.", &username
+              );
+              break 'handler Err(ErrorCode::SecondFactorRequired);
+            }
+          };
+          if let Err(err) = check_second_factor(&state, &username, addr, code) {
+            break 'handler Err(err);
+          }
+        }
+
+        let users_info = match bring_online(&state, &connection, &listener, &username, addr) {
+          Ok(users_info) => users_info,
+          Err(code) => break 'handler Err(code),
+        };
+
+        let session_lifetime = state.config.read().session_lifetime;
+        let token = state
+          .sessions
+          .issue(&username, addr, &device, session_lifetime);
+
+        listener.on_event(ServerEvent::LoggedIn {
+          name: username.clone(),
+          addr,
+        });
+        state.login_guard.record_success(&username);
+        state.audit.record(AuditEvent::LoginSucceeded {
+          name: username.clone(),
+          addr,
+        });
+        info!(
+          source = "server",
+          "user \"{}\" logged in successfully.", &username
+        );
+
+        Ok(ResponseData::LoggedIn {
+          proof: server_proof,
+          users: users_info,
+          token,
+        })
+      };
+      Some(response)
+    }
+    Command::PasswordLogin {
+      username,
+      password,
+      device,
+      code,
+    } => {
+      let provider = state.auth_provider();
+      let provider_name = provider.name();
+      let allowed = {
+        let _span = info_span!("PASSWORD_LOGIN", %addr, username = username.as_str()).entered();
+        info!("new request.");
+        if provider.supports_srp() {
+          // the password would only ever be sent by a client tricked into doing so
+          error!(
+            source = "server",
+            "passwords are checked with srp, refusing to take one in plain."
+          );
+          Err(ErrorCode::Unsupported)
+        } else {
+          check_login_allowed(&state, &username, addr)
+        }
+      };
+      let checked = match allowed {
+        Ok(()) => Ok(auth::check_password(provider, username.clone(), password).await),
+        Err(code) => Err(code),
+      };
+
+      let span = info_span!("PASSWORD_LOGIN", %addr, username = username.as_str());
+      async {
+        Some('handler: {
+          match checked {
+            Ok(Ok(true)) => {}
+            Ok(Ok(false)) => {
+              error!(
+                source = "server",
+                "user \"{}\" failed to log in: wrong password.", &username
+              );
+              record_login_failure(&state, &username, addr, "wrong password");
+              break 'handler Err(ErrorCode::InvalidUserOrPass);
+            }
+            Ok(Err(err)) => {
+              error!(
+                source = "internal",
+                "{} provider failed to check the password of user \"{}\": {}.",
+                provider_name,
+                &username,
+                err
+              );
+              break 'handler Err(ErrorCode::Internal);
+            }
+            Err(code) => break 'handler Err(code),
+          }
+
+          if let Err(code) = add_to_roster(&state, &listener, &username, addr).await {
+            break 'handler Err(code);
+          }
+
+          if state.two_factor.is_enrolled(&username) {
+            let code = match &code {
+              Some(code) => code,
+              None => {
+                info!(
+                  source = "server",
+                  "user \"{}\" has to provide a one-time code.", &username
+                );
+                break 'handler Err(ErrorCode::SecondFactorRequired);
+              }
+            };
+            if let Err(err) = check_second_factor(&state, &username, addr, code) {
+              break 'handler Err(err);
+            }
+          }
+
+          let users_info = match bring_online(&state, &connection, &listener, &username, addr) {
+            Ok(users_info) => users_info,
+            Err(code) => break 'handler Err(code),
+          };
+
+          let session_lifetime = state.config.read().session_lifetime;
+          let token = state
+            .sessions
+            .issue(&username, addr, &device, session_lifetime);
+
+          listener.on_event(ServerEvent::LoggedIn {
+            name: username.clone(),
+            addr,
+          });
+          state.login_guard.record_success(&username);
+          state.audit.record(AuditEvent::LoginSucceeded {
+            name: username.clone(),
+            addr,
+          });
+          info!(
+            source = "server",
+            "user \"{}\" logged in through the {} provider successfully.", &username, provider_name
+          );
+
+          Ok(ResponseData::LoggedIn {
+            proof: vec![],
+            users: users_info,
+            token,
+          })
+        })
+      }
+      .instrument(span)
+      .await
+    }
+    Command::Resume { token, device } => {
+      let username = token.name.clone();
+      let _span = info_span!("RESUME", %addr, username = username.as_str()).entered();
+      info!("new request.");
+      Some('handler: {
+        if state.find_ban(&username, addr.ip()).is_some() {
+          error!(
+            source = "server",
+            "user \"{}\" or address {} is banned.",
+            &username,
+            addr.ip()
+          );
+          state.audit.record(AuditEvent::LoginFailed {
+            name: username.clone(),
+            addr,
+            reason: "banned".into(),
+          });
+          break 'handler Err(ErrorCode::Banned);
+        }
+
+        if let Err(err) = state.sessions.resume(&token, addr, &device) {
+          error!(
+            source = "server",
+            "user \"{}\" failed to resume: {}.", &username, err
+          );
+          state.audit.record(AuditEvent::LoginFailed {
+            name: username.clone(),
+            addr,
+            reason: err.to_string(),
+          });
+          break 'handler Err(ErrorCode::SessionExpired);
+        }
+
+        let users_info = match bring_online(&state, &connection, &listener, &username, addr) {
+          Ok(users_info) => users_info,
+          Err(code) => break 'handler Err(code),
+        };
+
+        listener.on_event(ServerEvent::LoggedIn {
+          name: username.clone(),
+          addr,
+        });
+        state.audit.record(AuditEvent::Resumed {
+          name: username.clone(),
+          addr,
+        });
+        info!(
+          source = "server",
+          "user \"{}\" resumed the session successfully.", &username
+        );
+
+        Ok(ResponseData::ChatroomStatus { users: users_info })
+      })
+    }
+    Command::ChangePassword {
+      proof,
+      salt,
+      verifier,
+    } => {
+      let (response, changed) = async {
+        info!("new request.");
+        let mut changed = None;
+        let response: Response = 'handler: {
+          // copied out, logins lock the users before the map of addresses
+          let username = match state.addr2user.read().get(&addr).cloned() {
+            Some(s) => s,
+            None => {
+              error!(source = "server", "no online user binds to the address.");
+              break 'handler Err(ErrorCode::LoginRequired);
+            }
+          };
+
+          if !state.user_active_timers.read().contains_key(&addr) {
+            error!(source = "server", "user \"{}\" is not online.", &username);
+            break 'handler Err(ErrorCode::LoginRequired);
+          }
+
+          let pending = match state.take_handshake(addr) {
+            Some(pending) if pending.handshake.username() == username => pending,
+            _ => {
+              error!(
+                source = "server",
+                "no handshake for user \"{}\" is pending.", &username
+              );
+              break 'handler Err(ErrorCode::InvalidUserOrPass);
+            }
+          };
+          if let Err(code) = check_login_allowed(&state, &username, addr) {
+            break 'handler Err(code);
+          }
+          if pending.handshake.verify(&proof).is_err() {
+            error!(
+              source = "server",
+              "old password for user \"{}\" is incorrect.", &username
+            );
+            record_login_failure(&state, &username, addr, pending.failure);
+            break 'handler Err(ErrorCode::InvalidUserOrPass);
+          }
+
+          let verifier = Verifier { salt, verifier };
+          if !verifier.is_valid() {
+            error!(
+              source = "server",
+              "new verifier of user \"{}\" is invalid.", &username
+            );
+            break 'handler Err(ErrorCode::InvalidUserOrPass);
+          }
+          let password_hash = verifier.encode();
+
+          // storage may block, the lock is only taken to swap the hash
+          let (name, hash) = (username.clone(), password_hash.clone());
+          if let Err(err) = storage::unblock(&state.storage, move |storage| {
+            storage.update_password(&name, &hash)
+          })
+          .await
+          {
+            error!(
+              source = "internal",
+              "failed to store password of user \"{}\": {}.", &username, err
+            );
+            break 'handler Err(ErrorCode::Internal);
+          }
+
+          let others = match state.users.write().get_mut(&username) {
+            Some(user) => {
+              user.password_hash = password_hash;
+              user
+                .devices
+                .iter()
+                .map(|device| device.ip_address)
+                .filter(|&other| other != addr)
+                .collect::<Vec<_>>()
+            }
+            None => {
+              error!(source = "server", "user \"{}\" is deleted.", &username);
+              break 'handler Err(ErrorCode::UserNotExisted);
+            }
+          };
+          listener.on_event(ServerEvent::PasswordChanged {
+            name: username.clone(),
+          });
+          state.audit.record(AuditEvent::PasswordChanged {
+            name: username.clone(),
+            addr,
+          });
+          // whoever got hold of the old password must not keep a way back in
+          let revoked = state.sessions.revoke_user(&username, Some(addr));
+          info!(
+            source = "server",
+            "user \"{}\" changed password successfully, {} other sessions are revoked.",
+            &username,
+            revoked
+          );
+          changed = Some((username, others));
+
+          Ok(ResponseData::Success)
+        };
+        (response, changed)
+      }
+      .instrument(info_span!("CHANGE_PASSWORD", %addr))
+      .await;
+
+      // the other devices are logged out along with their sessions
+      if let Some((username, others)) = changed {
+        for other in others {
+          revoke_device(&state, &connection, &listener, &username, other).await;
+        }
+      }
+      Some(response)
+    }
+    Command::GetChatroomStatus => Some('handler: {
+      let _span = info_span!("GET_CHATROOM_STATUS", %addr).entered();
+      info!("new request.");
+
+      let addr2user = state.addr2user.read();
+      let username = match addr2user.get(&addr) {
+        Some(s) => s,
+        None => {
+          error!(source = "server", "no online user binds to the address.");
+          break 'handler Err(ErrorCode::LoginRequired);
+        }
+      };
+
+      let user_active_timers = state.user_active_timers.read();
+
+      if !user_active_timers.contains_key(&addr) {
+        error!(source = "server", "user \"{}\" is not online.", &username);
+        break 'handler Err(ErrorCode::LoginRequired);
+      }
+
+      let respond = Ok(ResponseData::ChatroomStatus {
+        users: state
+          .users
+          .read()
+          .values()
+          .map(UserInfo::new)
+          .collect::<Vec<_>>(),
+      });
+
+      info!(
+        source = "server",
+        "user \"{}\" queried status successfully.", &username
+      );
+
+      respond
+    }),
+    Command::Heartbeat => {
+      let _span = info_span!("HEARTBEAT", %addr).entered();
+      info!("new request.");
+      if let Some(username) = state.addr2user.read().get(&addr).cloned() {
+        if let Some(timer) = state.user_active_timers.write().get_mut(&addr) {
+          timer.abort();
+          *timer = expire_after_heartbeat(
+            state.clone(),
+            connection.clone(),
+            listener.clone(),
+            username.clone(),
+            addr,
+          );
+          state.sessions.touch(addr);
+          info!(
+            source = "server",
+            "activity timer for user \"{}\" is updated.", &username
+          );
+        } else {
+          error!(source = "server", "user \"{}\" is not online.", &username);
+        }
+      } else {
+        error!(source = "server", "no online user binds to the address.");
+      };
+      None
+    }
+    Command::Logout => {
+      let _span = info_span!("LOGOUT", %addr).entered();
+      info!("new request.");
+      let username = state.addr2user.read().get(&addr).cloned();
+      Some(match username {
+        Some(username) => {
+          'handler: {
+            let timer = state.user_active_timers.write().remove(&addr);
+            if let Some(timer) = timer {
+              timer.abort();
+            } else {
+              error!(source = "internal", "user \"{}\" is not online.", &username);
+              state.addr2user.write().remove(&addr);
+              break 'handler Err(ErrorCode::LoginRequired);
+            }
+
+            // the other devices of the user stay online
+            if take_device_offline(&state, &username, addr) {
+              state.sessions.revoke_addr(addr);
+              state.audit.record(AuditEvent::LoggedOut {
+                name: username.clone(),
+                addr,
+              });
+              let state = state.clone();
+              let sock = connection.clone();
+              tokio::spawn({
+                let username = username.clone();
+                async move { announce_offline(state, username, addr, sock).await }
+              });
+
+              listener.on_event(ServerEvent::LoggedOut {
+                name: username.clone(),
+              });
+              info!(
+                source = "server",
+                "user \"{}\" logout successfully.", &username
+              );
+              Ok(ResponseData::Success)
+            } else {
+              error!(
+                source = "internal",
+                "user \"{}\" is not online from the address.", &username
+              );
+              Err(ErrorCode::LoginRequired)
+            }
+          }
+        }
+        None => {
+          error!(source = "server", "no online user binds to the address.");
+          Err(ErrorCode::LoginRequired)
+        }
+      })
+    }
+    Command::ListSessions => Some('handler: {
+      let _span = info_span!("LIST_SESSIONS", %addr).entered();
+      info!("new request.");
+
+      let username = match state.addr2user.read().get(&addr) {
+        Some(s) => s.clone(),
+        None => {
+          error!(source = "server", "no online user binds to the address.");
+          break 'handler Err(ErrorCode::LoginRequired);
+        }
+      };
+
+      if !state.user_active_timers.read().contains_key(&addr) {
+        error!(source = "server", "user \"{}\" is not online.", &username);
+        break 'handler Err(ErrorCode::LoginRequired);
+      }
+
+      let sessions = {
+        let addr2user = state.addr2user.read();
+        state.sessions.list(&username, addr, |a| {
+          addr2user.get(&a).is_some_and(|name| name == &username)
+        })
+      };
+
+      info!(
+        source = "server",
+        "user \"{}\" listed {} sessions successfully.",
+        &username,
+        sessions.len()
+      );
+
+      Ok(ResponseData::Sessions { sessions })
+    }),
+    Command::RevokeSession { id } => {
+      let (response, revoked) = {
+        let _span = info_span!("REVOKE_SESSION", %addr).entered();
+        info!("new request.");
+
+        let username = state.addr2user.read().get(&addr).cloned();
+        let mut revoked = None;
+        let response: Response = 'handler: {
+          let username = match &username {
+            Some(s) => s,
+            None => {
+              error!(source = "server", "no online user binds to the address.");
+              break 'handler Err(ErrorCode::LoginRequired);
+            }
+          };
+
+          if !state.user_active_timers.read().contains_key(&addr) {
+            error!(source = "server", "user \"{}\" is not online.", &username);
+            break 'handler Err(ErrorCode::LoginRequired);
+          }
+
+          let revoked_addr = match state.sessions.revoke(username, &id) {
+            Some(revoked_addr) => revoked_addr,
+            None => {
+              error!(
+                source = "server",
+                "user \"{}\" has no such session.", &username
+              );
+              break 'handler Err(ErrorCode::SessionNotExisted);
+            }
+          };
+          revoked = Some((username.clone(), revoked_addr));
+
+          state.audit.record(AuditEvent::SessionRevoked {
+            name: username.clone(),
+            addr,
+            revoked: revoked_addr,
+          });
+          info!(
+            source = "server",
+            "user \"{}\" revoked the session at {} successfully.", &username, revoked_addr
+          );
+          Ok(ResponseData::Success)
+        };
+        (response, revoked)
+      };
+
+      if let Some((username, revoked)) = revoked {
+        revoke_device(&state, &connection, &listener, &username, revoked).await;
+      }
+      Some(response)
+    }
+    Command::EnrollTotp => Some('handler: {
+      let _span = info_span!("ENROLL_TOTP", %addr).entered();
+      info!("new request.");
+
+      let username = match state.addr2user.read().get(&addr) {
+        Some(s) => s.clone(),
+        None => {
+          error!(source = "server", "no online user binds to the address.");
+          break 'handler Err(ErrorCode::LoginRequired);
+        }
+      };
+
+      if !state.user_active_timers.read().contains_key(&addr) {
+        error!(source = "server", "user \"{}\" is not online.", &username);
+        break 'handler Err(ErrorCode::LoginRequired);
+      }
+
+      // switching authenticators takes a code from the old one, by disabling it first
+      if state.two_factor.is_enrolled(&username) {
+        error!(
+          source = "server",
+          "user \"{}\" has two-factor authentication enabled already.", &username
+        );
+        break 'handler Err(ErrorCode::TotpEnrolled);
+      }
+
+      let secret = state.two_factor.begin_enrollment(&username);
+      info!(
+        source = "server",
+        "user \"{}\" started enrolling in two-factor authentication.", &username
+      );
+      Ok(ResponseData::TotpProvisioning {
+        secret: totp::encode_base32(&secret),
+        uri: totp::provisioning_uri(TOTP_ISSUER, &username, &secret),
+      })
+    }),
+    Command::ConfirmTotp { code } => Some('handler: {
+      let _span = info_span!("CONFIRM_TOTP", %addr).entered();
+      info!("new request.");
+
+      let username = match state.addr2user.read().get(&addr) {
+        Some(s) => s.clone(),
+        None => {
+          error!(source = "server", "no online user binds to the address.");
+          break 'handler Err(ErrorCode::LoginRequired);
+        }
+      };
+
+      if !state.user_active_timers.read().contains_key(&addr) {
+        error!(source = "server", "user \"{}\" is not online.", &username);
+        break 'handler Err(ErrorCode::LoginRequired);
+      }
+
+      let codes = match state.two_factor.confirm_enrollment(&username, &code) {
+        Ok(codes) => codes,
+        Err(two_factor::Error::Storage(err)) => {
+          error!(
+            source = "internal",
+            "failed to store second factor of user \"{}\": {}.", &username, err
+          );
+          break 'handler Err(ErrorCode::Internal);
+        }
+        Err(err) => {
+          error!(
+            source = "server",
+            "user \"{}\" failed to confirm two-factor authentication: {}.", &username, err
+          );
+          break 'handler Err(ErrorCode::InvalidOneTimeCode);
+        }
+      };
+
+      state.audit.record(AuditEvent::TotpEnrolled {
+        name: username.clone(),
+        addr,
+      });
+      info!(
+        source = "server",
+        "user \"{}\" enabled two-factor authentication successfully.", &username
+      );
+      Ok(ResponseData::RecoveryCodes { codes })
+    }),
+    Command::DisableTotp { code } => Some('handler: {
+      let _span = info_span!("DISABLE_TOTP", %addr).entered();
+      info!("new request.");
+
+      let username = match state.addr2user.read().get(&addr) {
+        Some(s) => s.clone(),
+        None => {
+          error!(source = "server", "no online user binds to the address.");
+          break 'handler Err(ErrorCode::LoginRequired);
+        }
+      };
+
+      if !state.user_active_timers.read().contains_key(&addr) {
+        error!(source = "server", "user \"{}\" is not online.", &username);
+        break 'handler Err(ErrorCode::LoginRequired);
+      }
+
+      if !state.two_factor.is_enrolled(&username) {
+        error!(
+          source = "server",
+          "user \"{}\" has no two-factor authentication to disable.", &username
+        );
+        break 'handler Err(ErrorCode::TotpNotEnrolled);
+      }
+
+      if let Err(err) = check_second_factor(&state, &username, addr, &code) {
+        break 'handler Err(err);
+      }
+
+      if let Err(err) = state.two_factor.disable(&username) {
+        error!(
+          source = "internal",
+          "failed to remove second factor of user \"{}\": {}.", &username, err
+        );
+        break 'handler Err(ErrorCode::Internal);
+      }
+
+      state.audit.record(AuditEvent::TotpDisabled {
+        name: username.clone(),
+        addr,
+      });
+      info!(
+        source = "server",
+        "user \"{}\" disabled two-factor authentication successfully.", &username
+      );
+      Ok(ResponseData::Success)
+    }),
+    Command::Hello {
+      version,
+      capabilities,
+    } => {
+      let mut forgotten = 0;
+      let response: Response = {
+        let _span = info_span!("HELLO", %addr, version).entered();
+        info!("new request.");
+        if version < MIN_PROTOCOL_VERSION {
+          error!(
+            source = "server",
+            "protocol version {} is not supported.", version
+          );
+          Err(ErrorCode::IncompatibleVersion {
+            version,
+            min: MIN_PROTOCOL_VERSION,
+            max: PROTOCOL_VERSION,
+          })
+        } else {
+          let version = version.min(PROTOCOL_VERSION);
+          let capabilities = capabilities & SUPPORTED_CAPABILITIES;
+          forgotten = state.insert_capabilities(addr, capabilities);
+          info!(
+            source = "server",
+            "negotiated protocol version {} with capabilities {:#x}.",
+            version,
+            capabilities.bits()
+          );
+          Ok(ResponseData::Hello {
+            version,
+            capabilities,
+          })
+        }
+      };
+      if forgotten > 0 {
+        info!(
+          source = "server",
+          "forgot {} peers which never logged in.", forgotten
+        );
+      }
+      match response {
+        Ok(ResponseData::Hello { capabilities, .. }) => {
+          // the reply itself goes out uncompressed, the peer switches after reading it, even when
+          // this hello is a retry of one whose reply got lost
+          connection.disable_compression(addr);
+          connection.disable_batching(addr);
+          connection.send_to_with_meta(&response, addr, id).await?;
+          if capabilities.contains(Capabilities::COMPRESSION) {
+            connection.enable_compression(addr);
+          }
+          if capabilities.contains(Capabilities::BATCHING) {
+            connection.enable_batching(addr);
+          }
+          None
+        }
+        response => Some(response),
+      }
+    }
+    Command::MigratePassword {
+      username,
+      digest,
+      salt,
+      verifier,
+    } => {
+      let span = info_span!("MIGRATE_PASSWORD", %addr, username = username.as_str());
+      async {
+        info!("new request.");
+        Some('handler: {
+          if let Err(code) = check_login_allowed(&state, &username, addr) {
+            break 'handler Err(code);
+          }
+
+          // checked without holding the lock, argon2 takes a while
+          let legacy_hash = match state.users.read().get(&username) {
+            Some(user) if auth::is_legacy_hash(&user.password_hash) => user.password_hash.clone(),
+            _ => {
+              error!(
+                source = "server",
+                "user \"{}\" has no password to migrate.", &username
+              );
+              // not counted, clients try this after a failed login, which got counted already
+              break 'handler Err(ErrorCode::InvalidUserOrPass);
+            }
+          };
+          if !auth::verify_legacy_digest(&legacy_hash, &digest) {
+            error!(
+              source = "server",
+              "user \"{}\" failed to migrate the password: wrong password.", &username
+            );
+            record_login_failure(&state, &username, addr, "wrong password");
+            break 'handler Err(ErrorCode::InvalidUserOrPass);
+          }
+
+          let verifier = Verifier { salt, verifier };
+          if !verifier.is_valid() {
+            error!(
+              source = "server",
+              "new verifier of user \"{}\" is invalid.", &username
+            );
+            break 'handler Err(ErrorCode::InvalidUserOrPass);
+          }
+          let password_hash = verifier.encode();
+
+          let (name, hash) = (username.clone(), password_hash.clone());
+          if let Err(err) = storage::unblock(&state.storage, move |storage| {
+            storage.update_password(&name, &hash)
+          })
+          .await
+          {
+            error!(
+              source = "internal",
+              "failed to store password of user \"{}\": {}.", &username, err
+            );
+            break 'handler Err(ErrorCode::Internal);
+          }
+
+          // an admin may have reset the password in the meantime
+          match state.users.write().get_mut(&username) {
+            Some(user) if user.password_hash == legacy_hash => user.password_hash = password_hash,
+            _ => {
+              error!(
+                source = "server",
+                "password of user \"{}\" changed during the migration.", &username
+              );
+              break 'handler Err(ErrorCode::InvalidUserOrPass);
+            }
+          }
+          listener.on_event(ServerEvent::PasswordChanged {
+            name: username.clone(),
+          });
+          state.audit.record(AuditEvent::PasswordMigrated {
+            name: username.clone(),
+            addr,
+          });
+          info!(
+            source = "server",
+            "user \"{}\" migrated the password successfully.", &username
+          );
+          Ok(ResponseData::Success)
+        })
+      }
+      .instrument(span)
+      .await
+    }
+    cmd => {
+      error!(source = "internal", "Unsupported Message: \"{:?}\".", &cmd);
+      Some(Err(ErrorCode::Unsupported))
+    }
+  };
+
+  if let Some(response) = response {
+    if let Err(code) = &response {
+      state.metrics.record_error(code);
+    }
+    connection.send_to_with_meta(&response, addr, id).await?;
+  }
+
+  Ok(())
+}
+
+/// bind a user who just proved who they are to `addr` and tell everyone else, returns all users
+/// as the user gets to see them
+fn bring_online<Coder: Codec>(
+  state: &Arc<ServerState>,
+  connection: &Arc<SecureConnection<Coder>>,
+  listener: &Arc<dyn EventListener>,
+  username: &str,
+  addr: SocketAddr,
+) -> Result<Vec<UserInfo>, ErrorCode> {
+  let users = state.users.upgradable_read();
+  if !users.contains_key(username) {
+    error!(source = "server", "user \"{}\" is deleted.", username);
+    return Err(ErrorCode::UserNotExisted);
+  }
+
+  let pub_key = match state.pub_keys.read().get(&addr) {
+    Some(pub_key) => *pub_key.as_bytes(),
+    _ => {
+      error!(
+        source = "server",
+        "failed to find public key of user \"{}\".", username
+      );
+      return Err(ErrorCode::ConnectionNotSecure);
+    }
+  };
+
+  // update activity timer of the device
+  let old_timer = state.user_active_timers.write().insert(
+    addr,
+    expire_after_heartbeat(
+      state.clone(),
+      connection.clone(),
+      listener.clone(),
+      username.to_string(),
+      addr,
+    ),
+  );
+
+  if let Some(old_timer) = old_timer {
+    old_timer.abort();
+  }
+
+  // update user and map from addr to user, devices of the user elsewhere stay online
+  let mut users = RwLockUpgradableReadGuard::<_>::upgrade(users);
+  let previous_owner = state.addr2user.write().insert(addr, username.to_string());
+  if let Some(previous_owner) = previous_owner.filter(|name| name != username) {
+    if let Some(user) = users.get_mut(&previous_owner) {
+      user.devices.retain(|device| device.ip_address != addr);
+    }
+    let state = state.clone();
+    let sock = connection.clone();
+    tokio::spawn(async move { announce_offline(state, previous_owner, addr, sock).await });
+  }
+  let user_info = {
+    let user = users.get_mut(username).unwrap();
+    let info = UserOnlineInfo {
+      ip_address: addr,
+      pub_key,
+    };
+    match user
+      .devices
+      .iter_mut()
+      .find(|device| device.ip_address == addr)
+    {
+      Some(device) => *device = info.clone(),
+      None => user.devices.push(info.clone()),
+    }
+    info
+  };
+  let users = RwLockWriteGuard::<_>::downgrade_to_upgradable(users);
+
+  // broadcast online message
+  {
+    let state = state.clone();
+    let sock = connection.clone();
+    let username = username.to_string();
+    tokio::spawn(async move { announce_online(state, username, user_info, sock).await });
+  }
+
+  // generate all user info
+  Ok(users.values().map(UserInfo::new).collect::<Vec<_>>())
+}
+
+/// count a failed login against the account and the address, locking them out if it was one
+/// too many
+fn record_login_failure(state: &ServerState, username: &str, addr: SocketAddr, reason: &str) {
+  state.audit.record(AuditEvent::LoginFailed {
+    name: username.to_string(),
+    addr,
+    reason: reason.to_string(),
+  });
+  let policy = state.config.read().login_throttle;
+  for target in state
+    .login_guard
+    .record_failure(username, addr.ip(), &policy)
+  {
+    warn!(
+      source = "server",
+      "{:?} is locked out for {:?} after {} failed logins.",
+      target,
+      policy.lockout,
+      policy.lockout_after
+    );
+    state.audit.record(AuditEvent::LockedOut { target });
+  }
+}
+
+/// refuse banned users and addresses, and those who have to wait after failed logins, before any
+/// password gets checked, so that guessing is slowed down and costs no cpu
+fn check_login_allowed(
+  state: &ServerState,
+  username: &str,
+  addr: SocketAddr,
+) -> Result<(), ErrorCode> {
+  if state.find_ban(username, addr.ip()).is_some() {
+    error!(
+      source = "server",
+      "user \"{}\" or address {} is banned.",
+      username,
+      addr.ip()
+    );
+    state.audit.record(AuditEvent::LoginFailed {
+      name: username.to_string(),
+      addr,
+      reason: "banned".into(),
+    });
+    return Err(ErrorCode::Banned);
+  }
+
+  if let Some(wait) = state.login_guard.check(username, addr.ip()) {
+    error!(
+      source = "server",
+      "user \"{}\" or address {} has to wait {:?} before trying again.",
+      username,
+      addr.ip(),
+      wait
+    );
+    let retry_after = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+    return Err(ErrorCode::Throttled { retry_after });
+  }
+  Ok(())
+}
+
+/// give a user vouched for by an external provider an entry in the roster on their first login,
+/// so that the others see them and are able to leave them messages, a local account of the same
+/// name is never handed over to them
+async fn add_to_roster(
+  state: &ServerState,
+  listener: &Arc<dyn EventListener>,
+  username: &str,
+  addr: SocketAddr,
+) -> Result<(), ErrorCode> {
+  let existing = state
+    .users
+    .read()
+    .get(username)
+    .map(|user| user.password_hash.clone());
+  if let Some(password_hash) = existing {
+    if password_hash == EXTERNAL_PASSWORD_HASH {
+      return Ok(());
+    }
+    error!(
+      source = "server",
+      "user \"{}\" is a local account, refusing to log in as them through the provider.", username
+    );
+    state.audit.record(AuditEvent::LoginFailed {
+      name: username.to_string(),
+      addr,
+      reason: "local account".into(),
+    });
+    return Err(ErrorCode::InvalidUserOrPass);
+  }
+
+  let essential = UserEssential {
+    password_hash: EXTERNAL_PASSWORD_HASH.to_string(),
+  };
+  // storage may block, so it is written off the runtime without holding the lock
+  let (name, user) = (username.to_string(), essential.clone());
+  match storage::unblock(&state.storage, move |storage| {
+    storage.insert_user(&name, &user)
+  })
+  .await
+  {
+    Ok(()) => {}
+    // taken in the meantime, a retry finds out by whom
+    Err(storage::Error::UserExisted(_)) => {
+      error!(
+        source = "server",
+        "user \"{}\" is added to the roster in the meantime.", username
+      );
+      return Err(ErrorCode::InvalidUserOrPass);
+    }
+    Err(err) => {
+      error!(
+        source = "internal",
+        "failed to store user \"{}\": {}.", username, err
+      );
+      return Err(ErrorCode::Internal);
+    }
+  }
+  state.users.write().insert(
+    username.to_string(),
+    (username.to_string(), essential).into(),
+  );
+
+  listener.on_event(ServerEvent::Registered {
+    name: username.to_string(),
+  });
+  state.audit.record(AuditEvent::Registered {
+    name: username.to_string(),
+    addr,
+  });
+  info!(
+    source = "server",
+    "user \"{}\" is added to the roster on their first login.", username
+  );
+  Ok(())
+}
+
+/// check a one-time code or a recovery code of a user who enrolled, a wrong one counts as a failed
+/// login
+fn check_second_factor(
+  state: &ServerState,
+  username: &str,
+  addr: SocketAddr,
+  code: &str,
+) -> Result<(), ErrorCode> {
+  match state.two_factor.verify(username, code) {
+    Ok(Verified::OneTimeCode) => Ok(()),
+    Ok(Verified::RecoveryCode { remaining }) => {
+      warn!(
+        source = "server",
+        "user \"{}\" used a recovery code, {} of them are left.", username, remaining
+      );
+      state.audit.record(AuditEvent::RecoveryCodeUsed {
+        name: username.to_string(),
+        addr,
+        remaining,
+      });
+      Ok(())
+    }
+    Err(two_factor::Error::Storage(err)) => {
+      error!(
+        source = "internal",
+        "failed to store second factor of user \"{}\": {}.", username, err
+      );
+      Err(ErrorCode::Internal)
+    }
+    Err(err) => {
+      error!(
+        source = "server",
+        "user \"{}\" failed the second factor: {}.", username, err
+      );
+      record_login_failure(state, username, addr, "wrong one-time code");
+      Err(ErrorCode::InvalidOneTimeCode)
+    }
+  }
+}
+
+/// take the device of the user at `addr` offline and let the others know, returns false if it was
+/// not online
+async fn logout_device<Coder: Codec>(
+  state: &Arc<ServerState>,
+  connection: &Arc<SecureConnection<Coder>>,
+  listener: &Arc<dyn EventListener>,
+  username: &str,
+  addr: SocketAddr,
+) -> bool {
+  if let Some(timer) = state.user_active_timers.write().remove(&addr) {
+    timer.abort();
+  }
+  if !take_device_offline(state, username, addr) {
+    return false;
+  }
+  listener.on_event(ServerEvent::LoggedOut {
+    name: username.to_string(),
+  });
+  info!(
+    source = "server",
+    "user \"{}\" is logged out from {}.", username, addr
+  );
+  announce_offline(
+    state.clone(),
+    username.to_string(),
+    addr,
+    connection.clone(),
+  )
+  .await;
+  true
+}
+
+/// log the device of a user at `addr` out after its session got revoked, it is told before so that
+/// the notification still reaches it
+async fn revoke_device<Coder: Codec>(
+  state: &Arc<ServerState>,
+  connection: &Arc<SecureConnection<Coder>>,
+  listener: &Arc<dyn EventListener>,
+  username: &str,
+  addr: SocketAddr,
+) {
+  let online = state
+    .addr2user
+    .read()
+    .get(&addr)
+    .is_some_and(|name| name == username);
+  if !online {
+    return;
+  }
+  let notification = Notification::SessionRevoked {
+    timestamp: OffsetDateTime::now_utc(),
+  };
+  if let Err(err) = connection
+    .send_to_with_empty_meta(&notification, addr)
+    .await
+  {
+    error!(
+      source = "internal",
+      "failed to tell {} about the revoked session: {}.", addr, err
+    );
+  }
+  logout_device(state, connection, listener, username, addr).await;
+}
+
+/// take the device of the user at `addr` offline once the heartbeat interval elapses, unless the
+/// returned timer gets aborted by a newer heartbeat
+fn expire_after_heartbeat<Coder: Codec>(
+  state: Arc<ServerState>,
+  connection: Arc<SecureConnection<Coder>>,
+  listener: Arc<dyn EventListener>,
+  username: String,
+  addr: SocketAddr,
+) -> JoinHandle<()> {
+  tokio::spawn(async move {
+    let heartbeat_interval = state.config.read().heartbeat_interval;
+    tokio::time::sleep(heartbeat_interval).await;
+    state.metrics.record_heartbeat_expiry();
+    state.user_active_timers.write().remove(&addr);
+    take_device_offline(&state, &username, addr);
+    listener.on_event(ServerEvent::HeartbeatLost {
+      name: username.clone(),
+    });
+    state.audit.record(AuditEvent::HeartbeatExpired {
+      name: username.clone(),
+    });
+    info!(
+      source = "server",
+      "heartbeat signal of user \"{}\" from {} is lost.", &username, addr
+    );
+    announce_offline(state, username, addr, connection).await;
+  })
+}
+
+/// forget everything about the device of the user at `addr` except its activity timer, which is
+/// left for the caller, and the codecs it negotiated, which it keeps using until its next hello,
+/// returns false if the user was not online there
+fn take_device_offline(state: &ServerState, username: &str, addr: SocketAddr) -> bool {
+  let removed = match state.users.write().get_mut(username) {
+    Some(user) => {
+      let before = user.devices.len();
+      user.devices.retain(|device| device.ip_address != addr);
+      user.devices.len() != before
+    }
+    None => false,
+  };
+  {
+    let mut addr2user = state.addr2user.write();
+    if addr2user.get(&addr).is_some_and(|name| name == username) {
+      addr2user.remove(&addr);
+    }
+  }
+  removed
+}
+
+async fn announce_online<Coder: Codec>(
+  state: Arc<ServerState>,
+  name: String,
+  info: UserOnlineInfo,
+  connection: Arc<SecureConnection<Coder>>,
+) {
+  // other devices of the same user get to know about it as well
+  let addrs = state
+    .addr2user
+    .read()
+    .keys()
+    .filter(|&&addr| addr != info.ip_address)
+    .cloned()
+    .collect::<Vec<_>>();
+
+  let notification = Notification::Online {
+    timestamp: OffsetDateTime::now_utc(),
+    name: name.clone(),
+    info,
+  };
+
+  if let Err(err) = connection
+    .queue_to_multiple_with_empty_meta(&notification, addrs.into_iter())
+    .await
+  {
+    error!(
+      source = "internal",
+      "failed to announce that user \"{}\" is online: {}.", name, err
+    );
+  }
+}
+
+async fn announce_offline<Coder: Codec>(
+  state: Arc<ServerState>,
+  name: String,
+  addr: SocketAddr,
+  connection: Arc<SecureConnection<Coder>>,
+) {
+  let addrs = state
+    .addr2user
+    .read()
+    .keys()
+    .filter(|&&a| a != addr)
+    .cloned()
+    .collect::<Vec<_>>();
+
+  let notification = Notification::Offline {
+    timestamp: OffsetDateTime::now_utc(),
+    name: name.clone(),
+    ip_address: addr,
+  };
+
+  if let Err(err) = connection
+    .queue_to_multiple_with_empty_meta(&notification, addrs.into_iter())
+    .await
+  {
+    error!(
+      source = "internal",
+      "failed to announce that user \"{}\" is offline: {}.", name, err
+    );
+  }
+}
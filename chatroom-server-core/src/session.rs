@@ -0,0 +1,361 @@
+use thiserror::Error as ThisError;
+
+use std::{
+  collections::{hash_map::Entry, HashMap},
+  net::{IpAddr, SocketAddr},
+  time::Duration,
+};
+
+use parking_lot::RwLock;
+
+use hmac::{Hmac, Mac};
+
+use rand::Rng;
+
+use sha2::Sha256;
+
+use time::OffsetDateTime;
+
+use chatroom_core::data::{SessionInfo, SessionToken};
+
+/// sessions are only swept once there are this many of them
+const SWEEP_THRESHOLD: usize = 1024;
+
+#[derive(Debug)]
+struct Session {
+  name: String,
+  expires: OffsetDateTime,
+  /// where the session is currently resumed from, logging out from there revokes it
+  addr: SocketAddr,
+  device: String,
+  logged_in: OffsetDateTime,
+  last_heartbeat: OffsetDateTime,
+}
+
+/// sessions by id, indexed by the address they are resumed from
+#[derive(Debug, Default)]
+struct Sessions {
+  by_id: HashMap<[u8; 16], Session>,
+  by_addr: HashMap<SocketAddr, Vec<[u8; 16]>>,
+}
+
+impl Sessions {
+  fn insert(&mut self, id: [u8; 16], session: Session) {
+    self.by_addr.entry(session.addr).or_default().push(id);
+    self.by_id.insert(id, session);
+  }
+
+  fn remove(&mut self, id: &[u8; 16]) -> Option<Session> {
+    let session = self.by_id.remove(id)?;
+    self.unindex(id, session.addr);
+    Some(session)
+  }
+
+  fn unindex(&mut self, id: &[u8; 16], addr: SocketAddr) {
+    if let Entry::Occupied(mut ids) = self.by_addr.entry(addr) {
+      if let Some(i) = ids.get().iter().position(|other| other == id) {
+        ids.get_mut().swap_remove(i);
+      }
+      if ids.get().is_empty() {
+        ids.remove();
+      }
+    }
+  }
+
+  /// move a session over to `addr`
+  fn rebind(&mut self, id: &[u8; 16], addr: SocketAddr) {
+    if let Some(session) = self.by_id.get_mut(id) {
+      let old = std::mem::replace(&mut session.addr, addr);
+      if old != addr {
+        self.unindex(id, old);
+        self.by_addr.entry(addr).or_default().push(*id);
+      }
+    }
+  }
+
+  /// remove the sessions `revoke` is true for, returns how many there were
+  fn remove_where(&mut self, revoke: impl Fn(&Session) -> bool) -> usize {
+    let ids = self
+      .by_id
+      .iter()
+      .filter(|(_, session)| revoke(session))
+      .map(|(&id, _)| id)
+      .collect::<Vec<_>>();
+    for id in &ids {
+      self.remove(id);
+    }
+    ids.len()
+  }
+}
+
+/// issues session tokens and keeps track of which ones are still good, tokens are signed with a
+/// key that lives as long as the server does, so none of them survives a restart
+#[derive(Debug)]
+pub struct SessionKeeper {
+  key: [u8; 32],
+  sessions: RwLock<Sessions>,
+}
+
+impl Default for SessionKeeper {
+  fn default() -> Self {
+    Self {
+      key: rand::thread_rng().gen(),
+      sessions: Default::default(),
+    }
+  }
+}
+
+impl SessionKeeper {
+  /// mac over everything a token claims
+  fn mac(&self, id: &[u8; 16], name: &str, expires: OffsetDateTime) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).unwrap(); // any key length works
+    mac.update(id);
+    mac.update(&(name.len() as u64).to_be_bytes());
+    mac.update(name.as_bytes());
+    mac.update(&expires.unix_timestamp().to_be_bytes());
+    mac
+  }
+
+  /// start a session for `name`, who just logged in from `device` at `addr`
+  pub fn issue(
+    &self,
+    name: &str,
+    addr: SocketAddr,
+    device: &str,
+    lifetime: Duration,
+  ) -> SessionToken {
+    let now = OffsetDateTime::now_utc();
+    let id = rand::thread_rng().gen();
+    // whole seconds, so that the signature does not depend on how precisely it gets encoded
+    let expires = OffsetDateTime::from_unix_timestamp((now + lifetime).unix_timestamp())
+      .unwrap_or(now + lifetime);
+    let signature = self.mac(&id, name, expires).finalize().into_bytes().into();
+
+    let mut sessions = self.sessions.write();
+    if sessions.by_id.len() >= SWEEP_THRESHOLD {
+      sessions.remove_where(|session| session.expires <= now);
+    }
+    sessions.insert(
+      id,
+      Session {
+        name: name.to_string(),
+        expires,
+        addr,
+        device: device.to_string(),
+        logged_in: now,
+        last_heartbeat: now,
+      },
+    );
+    SessionToken {
+      id,
+      name: name.to_string(),
+      expires,
+      signature,
+    }
+  }
+
+  /// check `token` and move its session over to `device` at `addr`
+  pub fn resume(&self, token: &SessionToken, addr: SocketAddr, device: &str) -> Result<(), Error> {
+    // the comparison takes the same time wherever the signatures differ
+    if self
+      .mac(&token.id, &token.name, token.expires)
+      .verify_slice(&token.signature)
+      .is_err()
+    {
+      return Err(Error::Forged);
+    }
+    let mut sessions = self.sessions.write();
+    let session = match sessions.by_id.get_mut(&token.id) {
+      Some(session) if session.name == token.name => session,
+      _ => return Err(Error::Revoked),
+    };
+    let now = OffsetDateTime::now_utc();
+    if session.expires <= now {
+      sessions.remove(&token.id);
+      return Err(Error::Expired);
+    }
+    session.device = device.to_string();
+    session.last_heartbeat = now;
+    sessions.rebind(&token.id, addr);
+    Ok(())
+  }
+
+  /// note that the device at `addr` is still there
+  pub fn touch(&self, addr: SocketAddr) {
+    let now = OffsetDateTime::now_utc();
+    let mut sessions = self.sessions.write();
+    let Sessions { by_id, by_addr } = &mut *sessions;
+    for id in by_addr.get(&addr).into_iter().flatten() {
+      if let Some(session) = by_id.get_mut(id) {
+        session.last_heartbeat = now;
+      }
+    }
+  }
+
+  /// sessions of a user which are not expired yet, `current` is the address of the device asking
+  /// and `is_online` tells which addresses the user is online at
+  pub fn list(
+    &self,
+    name: &str,
+    current: SocketAddr,
+    is_online: impl Fn(SocketAddr) -> bool,
+  ) -> Vec<SessionInfo> {
+    let now = OffsetDateTime::now_utc();
+    let mut sessions = self
+      .sessions
+      .read()
+      .by_id
+      .iter()
+      .filter(|(_, session)| session.name == name && session.expires > now)
+      .map(|(&id, session)| SessionInfo {
+        id,
+        device: session.device.clone(),
+        ip_address: session.addr,
+        logged_in: session.logged_in,
+        last_heartbeat: session.last_heartbeat,
+        expires: session.expires,
+        online: is_online(session.addr),
+        current: session.addr == current,
+      })
+      .collect::<Vec<_>>();
+    sessions.sort_by_key(|session| session.logged_in);
+    sessions
+  }
+
+  /// revoke a session of `name`, returns where it was last used from
+  pub fn revoke(&self, name: &str, id: &[u8; 16]) -> Option<SocketAddr> {
+    let mut sessions = self.sessions.write();
+    match sessions.by_id.get(id) {
+      Some(session) if session.name == name => sessions.remove(id).map(|session| session.addr),
+      _ => None,
+    }
+  }
+
+  /// revoke the session resumed from `addr`, e.g. when logging out from there
+  pub fn revoke_addr(&self, addr: SocketAddr) -> bool {
+    let mut sessions = self.sessions.write();
+    let ids = sessions.by_addr.remove(&addr).unwrap_or_default();
+    for id in &ids {
+      sessions.by_id.remove(id);
+    }
+    !ids.is_empty()
+  }
+
+  /// revoke every session last used from `ip`, e.g. when it gets banned, returns how many there
+  /// were
+  pub fn revoke_ip(&self, ip: IpAddr) -> usize {
+    self
+      .sessions
+      .write()
+      .remove_where(|session| session.addr.ip() == ip)
+  }
+
+  /// revoke every session of a user but the one resumed from `except`, returns how many there
+  /// were
+  pub fn revoke_user(&self, name: &str, except: Option<SocketAddr>) -> usize {
+    self
+      .sessions
+      .write()
+      .remove_where(|session| session.name == name && Some(session.addr) != except)
+  }
+}
+
+#[derive(ThisError, Debug, Clone, PartialEq)]
+pub enum Error {
+  #[error("session token is not signed by this server")]
+  Forged,
+  #[error("session is expired")]
+  Expired,
+  #[error("session is revoked")]
+  Revoked,
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const LIFETIME: Duration = Duration::from_secs(60);
+
+  fn addr(port: u16) -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], port))
+  }
+
+  #[test]
+  fn resuming_moves_the_session() {
+    let keeper = SessionKeeper::default();
+    let token = keeper.issue("alice", addr(1), "laptop", LIFETIME);
+    keeper.resume(&token, addr(2), "phone").unwrap();
+
+    let sessions = keeper.list("alice", addr(2), |_| false);
+    assert_eq!(sessions.len(), 1);
+    assert!(sessions[0].current);
+    assert_eq!(sessions[0].device, "phone");
+    assert!(!keeper.revoke_addr(addr(1)));
+    assert!(keeper.revoke_addr(addr(2)));
+    assert_eq!(keeper.resume(&token, addr(2), "phone"), Err(Error::Revoked));
+  }
+
+  #[test]
+  fn touches_only_the_address() {
+    let keeper = SessionKeeper::default();
+    keeper.issue("alice", addr(1), "laptop", LIFETIME);
+    keeper.issue("alice", addr(2), "phone", LIFETIME);
+    let before = keeper.list("alice", addr(1), |_| false);
+    keeper.touch(addr(2));
+    let after = keeper.list("alice", addr(1), |_| false);
+    let heartbeat = |sessions: &[SessionInfo], port| {
+      sessions
+        .iter()
+        .find(|session| session.ip_address == addr(port))
+        .unwrap()
+        .last_heartbeat
+    };
+    assert_eq!(heartbeat(&before, 1), heartbeat(&after, 1));
+    assert!(heartbeat(&before, 2) <= heartbeat(&after, 2));
+  }
+
+  #[test]
+  fn revokes_all_sessions_of_a_user_but_one() {
+    let keeper = SessionKeeper::default();
+    let current = keeper.issue("alice", addr(1), "laptop", LIFETIME);
+    let other = keeper.issue("alice", addr(2), "phone", LIFETIME);
+    let bob = keeper.issue("bob", addr(3), "laptop", LIFETIME);
+
+    assert_eq!(keeper.revoke_user("alice", Some(addr(1))), 1);
+    assert_eq!(keeper.resume(&other, addr(2), "phone"), Err(Error::Revoked));
+    assert_eq!(keeper.resume(&current, addr(1), "laptop"), Ok(()));
+    assert_eq!(keeper.resume(&bob, addr(3), "laptop"), Ok(()));
+
+    assert_eq!(keeper.revoke_user("alice", None), 1);
+    assert!(keeper.list("alice", addr(1), |_| false).is_empty());
+  }
+
+  #[test]
+  fn revokes_the_sessions_of_an_ip() {
+    let keeper = SessionKeeper::default();
+    let alice = keeper.issue("alice", addr(1), "laptop", LIFETIME);
+    let bob = keeper.issue("bob", addr(2), "laptop", LIFETIME);
+    let elsewhere = keeper.issue(
+      "alice",
+      SocketAddr::from(([10, 0, 0, 1], 1)),
+      "phone",
+      LIFETIME,
+    );
+
+    assert_eq!(keeper.revoke_ip(addr(1).ip()), 2);
+    assert_eq!(
+      keeper.resume(&alice, addr(1), "laptop"),
+      Err(Error::Revoked)
+    );
+    assert_eq!(keeper.resume(&bob, addr(2), "laptop"), Err(Error::Revoked));
+    assert_eq!(keeper.resume(&elsewhere, addr(3), "phone"), Ok(()));
+  }
+
+  #[test]
+  fn rejects_forged_tokens() {
+    let keeper = SessionKeeper::default();
+    let mut token = keeper.issue("alice", addr(1), "laptop", LIFETIME);
+    token.name = "bob".to_string();
+    assert_eq!(keeper.resume(&token, addr(1), "laptop"), Err(Error::Forged));
+  }
+}
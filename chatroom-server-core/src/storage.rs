@@ -0,0 +1,410 @@
+pub mod memory;
+pub mod sqlite;
+
+use std::{
+  collections::{BTreeSet, HashMap},
+  fmt::Debug,
+  net::IpAddr,
+  sync::Arc,
+};
+
+use thiserror::Error as ThisError;
+
+use serde::{Deserialize, Serialize};
+
+use time::OffsetDateTime;
+
+use chatroom_core::data::UserEssential;
+
+use crate::audit::AuditRecord;
+
+pub use memory::MemoryStorage;
+pub use sqlite::SqliteStorage;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub enum BanTarget {
+  User(String),
+  Ip(IpAddr),
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Ban {
+  pub target: BanTarget,
+  pub reason: Option<String>,
+  /// the ban is permanent if absent
+  pub until: Option<OffsetDateTime>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Room {
+  pub name: String,
+  pub members: BTreeSet<String>,
+}
+
+/// message kept for a user until they come back online
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct OfflineMessage {
+  pub from: String,
+  pub timestamp: OffsetDateTime,
+  pub msg: String,
+}
+
+/// totp secret of a user who enrolled in two-factor authentication
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct SecondFactor {
+  pub secret: Vec<u8>,
+  /// sha-256 in hex of the recovery codes not used yet
+  pub recovery_codes: Vec<String>,
+  /// last time step a one-time code got accepted for, so that none is accepted twice, even
+  /// across restarts
+  #[serde(default)]
+  pub last_step: u64,
+}
+
+/// durable data of the server, every write must be persisted before it returns
+pub trait Storage: Debug + Send + Sync + 'static {
+  // users
+  fn load_users(&self) -> Result<HashMap<String, UserEssential>, Error>;
+  fn load_user(&self, name: &str) -> Result<Option<UserEssential>, Error>;
+  /// fails with `Error::UserExisted` if the name is taken
+  fn insert_user(&self, name: &str, user: &UserEssential) -> Result<(), Error>;
+  fn update_password(&self, name: &str, password_hash: &str) -> Result<(), Error>;
+  /// remove the user along with their room memberships, offline messages and second factor
+  fn delete_user(&self, name: &str) -> Result<(), Error>;
+  /// insert `inserted` and replace the passwords of `updated` as a whole, failing like
+  /// `insert_user` and `update_password` without writing anything
+  fn import_users(
+    &self,
+    inserted: &[(String, UserEssential)],
+    updated: &[(String, UserEssential)],
+  ) -> Result<(), Error>;
+
+  // two-factor authentication
+  fn load_second_factors(&self) -> Result<HashMap<String, SecondFactor>, Error>;
+  /// enroll the user, replacing their previous second factor
+  fn set_second_factor(&self, name: &str, second_factor: &SecondFactor) -> Result<(), Error>;
+  /// returns whether the user was enrolled
+  fn remove_second_factor(&self, name: &str) -> Result<bool, Error>;
+
+  // bans
+  fn load_bans(&self) -> Result<Vec<Ban>, Error>;
+  /// add a ban, replacing the previous one on the same target
+  fn insert_ban(&self, ban: &Ban) -> Result<(), Error>;
+  /// returns whether there was a ban to lift
+  fn remove_ban(&self, target: &BanTarget) -> Result<bool, Error>;
+
+  // rooms
+  fn load_rooms(&self) -> Result<Vec<Room>, Error>;
+  /// fails with `Error::RoomExisted` if the name is taken
+  fn insert_room(&self, room: &Room) -> Result<(), Error>;
+  fn delete_room(&self, name: &str) -> Result<(), Error>;
+  fn add_room_member(&self, room: &str, member: &str) -> Result<(), Error>;
+  fn remove_room_member(&self, room: &str, member: &str) -> Result<(), Error>;
+
+  // offline messages
+  fn load_offline_messages(&self) -> Result<HashMap<String, Vec<OfflineMessage>>, Error>;
+  fn push_offline_message(&self, to: &str, msg: &OfflineMessage) -> Result<(), Error>;
+  /// remove and return the messages kept for `to`, oldest first
+  fn take_offline_messages(&self, to: &str) -> Result<Vec<OfflineMessage>, Error>;
+
+  // audit trail, it is only ever appended to
+  /// oldest first
+  fn load_audit_records(&self) -> Result<Vec<AuditRecord>, Error>;
+  fn append_audit_record(&self, record: &AuditRecord) -> Result<(), Error>;
+}
+
+/// copy everything from one storage into another, e.g. to move from memory onto disk
+pub fn transfer(from: &dyn Storage, to: &dyn Storage) -> Result<(), Error> {
+  for (name, user) in from.load_users()? {
+    to.insert_user(&name, &user)?;
+  }
+  for (name, second_factor) in from.load_second_factors()? {
+    to.set_second_factor(&name, &second_factor)?;
+  }
+  for ban in from.load_bans()? {
+    to.insert_ban(&ban)?;
+  }
+  for room in from.load_rooms()? {
+    to.insert_room(&room)?;
+  }
+  for (to_user, msgs) in from.load_offline_messages()? {
+    for msg in msgs.iter() {
+      to.push_offline_message(&to_user, msg)?;
+    }
+  }
+  for record in from.load_audit_records()? {
+    to.append_audit_record(&record)?;
+  }
+  Ok(())
+}
+
+/// run `op` on a thread meant for blocking, storage may wait on the disk, which must not hold up
+/// the async runtime
+pub async fn unblock<T, F>(storage: &Arc<dyn Storage>, op: F) -> Result<T, Error>
+where
+  T: Send + 'static,
+  F: FnOnce(&dyn Storage) -> Result<T, Error> + Send + 'static,
+{
+  let storage = storage.clone();
+  tokio::task::spawn_blocking(move || op(&*storage)).await?
+}
+
+#[derive(ThisError, Debug)]
+pub enum Error {
+  #[error(transparent)]
+  Sqlite(#[from] rusqlite::Error),
+  #[error("user \"{0}\" is already existed")]
+  UserExisted(String),
+  #[error("user \"{0}\" is not existed")]
+  UserNotExisted(String),
+  #[error("room \"{0}\" is already existed")]
+  RoomExisted(String),
+  #[error("room \"{0}\" is not existed")]
+  RoomNotExisted(String),
+  #[error("storage schema version {0} is newer than supported")]
+  UnknownSchema(u32),
+  #[error("stored data is corrupted: {0}")]
+  Corrupted(String),
+  #[error(transparent)]
+  Task(#[from] tokio::task::JoinError),
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use time::Duration;
+
+  fn backends() -> Vec<Box<dyn Storage>> {
+    vec![
+      Box::new(MemoryStorage::default()),
+      Box::new(SqliteStorage::open_in_memory().unwrap()),
+    ]
+  }
+
+  fn user(password_hash: &str) -> UserEssential {
+    UserEssential {
+      password_hash: password_hash.to_string(),
+    }
+  }
+
+  #[test]
+  fn round_trips_users() {
+    for storage in backends() {
+      storage.insert_user("alice", &user("hash-a")).unwrap();
+      storage.insert_user("bob", &user("hash-b")).unwrap();
+      storage.update_password("alice", "hash-c").unwrap();
+      storage.delete_user("bob").unwrap();
+
+      let users = storage.load_users().unwrap();
+      assert_eq!(users.len(), 1, "{:?}", storage);
+      assert_eq!(users["alice"].password_hash, "hash-c");
+    }
+  }
+
+  #[test]
+  fn rejects_conflicting_user_writes() {
+    for storage in backends() {
+      storage.insert_user("alice", &user("hash-a")).unwrap();
+      assert!(matches!(
+        storage.insert_user("alice", &user("hash-b")),
+        Err(Error::UserExisted(_))
+      ));
+      assert!(matches!(
+        storage.update_password("bob", "hash-b"),
+        Err(Error::UserNotExisted(_))
+      ));
+      assert!(matches!(
+        storage.delete_user("bob"),
+        Err(Error::UserNotExisted(_))
+      ));
+      assert_eq!(
+        storage.load_users().unwrap()["alice"].password_hash,
+        "hash-a"
+      );
+    }
+  }
+
+  #[test]
+  fn imports_users_as_a_whole() {
+    for storage in backends() {
+      storage.insert_user("alice", &user("hash-a")).unwrap();
+      let inserted = [
+        ("bob".to_string(), user("hash-b")),
+        ("alice".to_string(), user("hash-c")),
+      ];
+      assert!(matches!(
+        storage.import_users(&inserted, &[]),
+        Err(Error::UserExisted(name)) if name == "alice"
+      ));
+      let updated = [("carol".to_string(), user("hash-c"))];
+      assert!(matches!(
+        storage.import_users(&inserted[..1], &updated),
+        Err(Error::UserNotExisted(name)) if name == "carol"
+      ));
+      assert_eq!(storage.load_users().unwrap().len(), 1, "{:?}", storage);
+
+      let updated = [("alice".to_string(), user("hash-c"))];
+      storage.import_users(&inserted[..1], &updated).unwrap();
+      let users = storage.load_users().unwrap();
+      assert_eq!(users["alice"].password_hash, "hash-c");
+      assert_eq!(users["bob"].password_hash, "hash-b");
+    }
+  }
+
+  #[test]
+  fn round_trips_bans() {
+    // sqlite keeps timestamps to the second in rfc 3339
+    let until = OffsetDateTime::from_unix_timestamp(1_700_000_000).unwrap();
+    let user_ban = Ban {
+      target: BanTarget::User("alice".to_string()),
+      reason: Some("spam".to_string()),
+      until: Some(until),
+    };
+    let ip_ban = Ban {
+      target: BanTarget::Ip("192.0.2.1".parse().unwrap()),
+      reason: None,
+      until: None,
+    };
+    for storage in backends() {
+      storage.insert_ban(&user_ban).unwrap();
+      storage.insert_ban(&ip_ban).unwrap();
+      let longer = Ban {
+        until: Some(until + Duration::days(1)),
+        ..user_ban.clone()
+      };
+      storage.insert_ban(&longer).unwrap();
+
+      let mut bans = storage.load_bans().unwrap();
+      bans.sort_by_key(|ban| matches!(ban.target, BanTarget::Ip(_)));
+      assert_eq!(bans, [longer, ip_ban.clone()], "{:?}", storage);
+
+      assert!(storage.remove_ban(&user_ban.target).unwrap());
+      assert!(!storage.remove_ban(&user_ban.target).unwrap());
+      assert_eq!(storage.load_bans().unwrap(), std::slice::from_ref(&ip_ban));
+    }
+  }
+
+  #[test]
+  fn round_trips_rooms() {
+    let room = Room {
+      name: "lobby".to_string(),
+      members: ["alice".to_string()].into_iter().collect(),
+    };
+    for storage in backends() {
+      storage.insert_user("alice", &user("hash-a")).unwrap();
+      storage.insert_user("bob", &user("hash-b")).unwrap();
+      storage.insert_room(&room).unwrap();
+      assert!(matches!(
+        storage.insert_room(&room),
+        Err(Error::RoomExisted(_))
+      ));
+      storage.add_room_member("lobby", "bob").unwrap();
+      storage.remove_room_member("lobby", "alice").unwrap();
+      assert!(matches!(
+        storage.add_room_member("attic", "bob"),
+        Err(Error::RoomNotExisted(_))
+      ));
+
+      let rooms = storage.load_rooms().unwrap();
+      assert_eq!(rooms.len(), 1, "{:?}", storage);
+      assert_eq!(rooms[0].members.iter().collect::<Vec<_>>(), ["bob"]);
+
+      // deleting a user takes their memberships along
+      storage.delete_user("bob").unwrap();
+      assert!(storage.load_rooms().unwrap()[0].members.is_empty());
+      storage.delete_room("lobby").unwrap();
+      assert!(storage.load_rooms().unwrap().is_empty());
+      assert!(matches!(
+        storage.delete_room("lobby"),
+        Err(Error::RoomNotExisted(_))
+      ));
+    }
+  }
+
+  #[test]
+  fn round_trips_offline_messages() {
+    let msgs = (0..3)
+      .map(|i| OfflineMessage {
+        from: "alice".to_string(),
+        timestamp: OffsetDateTime::from_unix_timestamp(1_700_000_000 + i).unwrap(),
+        msg: format!("message {}", i),
+      })
+      .collect::<Vec<_>>();
+    for storage in backends() {
+      storage.insert_user("bob", &user("hash-b")).unwrap();
+      storage.insert_user("carol", &user("hash-c")).unwrap();
+      for msg in &msgs {
+        storage.push_offline_message("bob", msg).unwrap();
+      }
+      storage.push_offline_message("carol", &msgs[0]).unwrap();
+
+      let kept = storage.load_offline_messages().unwrap();
+      assert_eq!(kept["bob"], msgs, "{:?}", storage);
+      assert_eq!(storage.take_offline_messages("bob").unwrap(), msgs);
+      assert!(storage.take_offline_messages("bob").unwrap().is_empty());
+
+      // deleting a user takes the messages kept for them along
+      storage.delete_user("carol").unwrap();
+      assert!(storage.load_offline_messages().unwrap().is_empty());
+    }
+  }
+
+  #[test]
+  fn round_trips_audit_records() {
+    let records = (0..3)
+      .map(|seq| AuditRecord {
+        seq,
+        timestamp: OffsetDateTime::from_unix_timestamp(1_700_000_000 + seq as i64).unwrap(),
+        event: crate::audit::AuditEvent::Banned {
+          target: BanTarget::Ip("192.0.2.1".parse().unwrap()),
+          reason: Some(format!("reason {}", seq)),
+          until: None,
+        },
+        prev: format!("prev {}", seq),
+        hash: format!("hash {}", seq),
+      })
+      .collect::<Vec<_>>();
+    for storage in backends() {
+      for record in &records {
+        storage.append_audit_record(record).unwrap();
+      }
+      assert_eq!(
+        storage.load_audit_records().unwrap(),
+        records,
+        "{:?}",
+        storage
+      );
+    }
+  }
+
+  #[test]
+  fn round_trips_second_factors() {
+    let second_factor = SecondFactor {
+      secret: vec![1, 2, 3, 255],
+      recovery_codes: vec!["hash-1".to_string(), "hash-2".to_string()],
+      last_step: 56_666_666,
+    };
+    for storage in backends() {
+      storage.insert_user("alice", &user("hash-a")).unwrap();
+      storage.insert_user("bob", &user("hash-b")).unwrap();
+      storage.set_second_factor("alice", &second_factor).unwrap();
+      storage.set_second_factor("bob", &second_factor).unwrap();
+      let fewer = SecondFactor {
+        recovery_codes: vec!["hash-2".to_string()],
+        ..second_factor.clone()
+      };
+      storage.set_second_factor("alice", &fewer).unwrap();
+
+      let second_factors = storage.load_second_factors().unwrap();
+      assert_eq!(second_factors.len(), 2, "{:?}", storage);
+      assert_eq!(second_factors["alice"], fewer);
+
+      assert!(storage.remove_second_factor("alice").unwrap());
+      assert!(!storage.remove_second_factor("alice").unwrap());
+      // deleting a user takes their second factor along
+      storage.delete_user("bob").unwrap();
+      assert!(storage.load_second_factors().unwrap().is_empty());
+    }
+  }
+}
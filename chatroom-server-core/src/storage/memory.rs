@@ -0,0 +1,205 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use parking_lot::RwLock;
+
+use chatroom_core::data::UserEssential;
+
+use crate::audit::AuditRecord;
+
+use super::{Ban, BanTarget, Error, OfflineMessage, Room, SecondFactor, Storage};
+
+type RwHashMap<K, V> = RwLock<HashMap<K, V>>;
+
+/// storage living as long as the process does, for tests and throwaway servers
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+  users: RwHashMap<String, UserEssential>,
+  second_factors: RwHashMap<String, SecondFactor>,
+  bans: RwHashMap<BanTarget, Ban>,
+  rooms: RwHashMap<String, BTreeSet<String>>,
+  offline_messages: RwHashMap<String, Vec<OfflineMessage>>,
+  audit_records: RwLock<Vec<AuditRecord>>,
+}
+
+impl MemoryStorage {
+  pub fn from_users(users: HashMap<String, UserEssential>) -> Self {
+    Self {
+      users: RwLock::new(users),
+      ..Default::default()
+    }
+  }
+}
+
+impl Storage for MemoryStorage {
+  fn load_users(&self) -> Result<HashMap<String, UserEssential>, Error> {
+    Ok(self.users.read().clone())
+  }
+
+  fn load_user(&self, name: &str) -> Result<Option<UserEssential>, Error> {
+    Ok(self.users.read().get(name).cloned())
+  }
+
+  fn insert_user(&self, name: &str, user: &UserEssential) -> Result<(), Error> {
+    let mut users = self.users.write();
+    if users.contains_key(name) {
+      return Err(Error::UserExisted(name.to_string()));
+    }
+    users.insert(name.to_string(), user.clone());
+    Ok(())
+  }
+
+  fn update_password(&self, name: &str, password_hash: &str) -> Result<(), Error> {
+    match self.users.write().get_mut(name) {
+      Some(user) => {
+        user.password_hash = password_hash.to_string();
+        Ok(())
+      }
+      None => Err(Error::UserNotExisted(name.to_string())),
+    }
+  }
+
+  fn import_users(
+    &self,
+    inserted: &[(String, UserEssential)],
+    updated: &[(String, UserEssential)],
+  ) -> Result<(), Error> {
+    let mut users = self.users.write();
+    let mut taken = users.keys().collect::<HashSet<_>>();
+    if let Some((name, _)) = inserted.iter().find(|(name, _)| !taken.insert(name)) {
+      return Err(Error::UserExisted(name.clone()));
+    }
+    if let Some((name, _)) = updated.iter().find(|(name, _)| !taken.contains(name)) {
+      return Err(Error::UserNotExisted(name.clone()));
+    }
+    for (name, user) in inserted {
+      users.insert(name.clone(), user.clone());
+    }
+    for (name, user) in updated {
+      if let Some(existing) = users.get_mut(name) {
+        existing.password_hash = user.password_hash.clone();
+      }
+    }
+    Ok(())
+  }
+
+  fn delete_user(&self, name: &str) -> Result<(), Error> {
+    if self.users.write().remove(name).is_none() {
+      return Err(Error::UserNotExisted(name.to_string()));
+    }
+    for members in self.rooms.write().values_mut() {
+      members.remove(name);
+    }
+    self.offline_messages.write().remove(name);
+    self.second_factors.write().remove(name);
+    Ok(())
+  }
+
+  fn load_second_factors(&self) -> Result<HashMap<String, SecondFactor>, Error> {
+    Ok(self.second_factors.read().clone())
+  }
+
+  fn set_second_factor(&self, name: &str, second_factor: &SecondFactor) -> Result<(), Error> {
+    if !self.users.read().contains_key(name) {
+      return Err(Error::UserNotExisted(name.to_string()));
+    }
+    self
+      .second_factors
+      .write()
+      .insert(name.to_string(), second_factor.clone());
+    Ok(())
+  }
+
+  fn remove_second_factor(&self, name: &str) -> Result<bool, Error> {
+    Ok(self.second_factors.write().remove(name).is_some())
+  }
+
+  fn load_bans(&self) -> Result<Vec<Ban>, Error> {
+    Ok(self.bans.read().values().cloned().collect())
+  }
+
+  fn insert_ban(&self, ban: &Ban) -> Result<(), Error> {
+    self.bans.write().insert(ban.target.clone(), ban.clone());
+    Ok(())
+  }
+
+  fn remove_ban(&self, target: &BanTarget) -> Result<bool, Error> {
+    Ok(self.bans.write().remove(target).is_some())
+  }
+
+  fn load_rooms(&self) -> Result<Vec<Room>, Error> {
+    Ok(
+      self
+        .rooms
+        .read()
+        .iter()
+        .map(|(name, members)| Room {
+          name: name.clone(),
+          members: members.clone(),
+        })
+        .collect(),
+    )
+  }
+
+  fn insert_room(&self, room: &Room) -> Result<(), Error> {
+    let mut rooms = self.rooms.write();
+    if rooms.contains_key(&room.name) {
+      return Err(Error::RoomExisted(room.name.clone()));
+    }
+    rooms.insert(room.name.clone(), room.members.clone());
+    Ok(())
+  }
+
+  fn delete_room(&self, name: &str) -> Result<(), Error> {
+    match self.rooms.write().remove(name) {
+      Some(_) => Ok(()),
+      None => Err(Error::RoomNotExisted(name.to_string())),
+    }
+  }
+
+  fn add_room_member(&self, room: &str, member: &str) -> Result<(), Error> {
+    match self.rooms.write().get_mut(room) {
+      Some(members) => {
+        members.insert(member.to_string());
+        Ok(())
+      }
+      None => Err(Error::RoomNotExisted(room.to_string())),
+    }
+  }
+
+  fn remove_room_member(&self, room: &str, member: &str) -> Result<(), Error> {
+    match self.rooms.write().get_mut(room) {
+      Some(members) => {
+        members.remove(member);
+        Ok(())
+      }
+      None => Err(Error::RoomNotExisted(room.to_string())),
+    }
+  }
+
+  fn load_offline_messages(&self) -> Result<HashMap<String, Vec<OfflineMessage>>, Error> {
+    Ok(self.offline_messages.read().clone())
+  }
+
+  fn push_offline_message(&self, to: &str, msg: &OfflineMessage) -> Result<(), Error> {
+    self
+      .offline_messages
+      .write()
+      .entry(to.to_string())
+      .or_default()
+      .push(msg.clone());
+    Ok(())
+  }
+
+  fn take_offline_messages(&self, to: &str) -> Result<Vec<OfflineMessage>, Error> {
+    Ok(self.offline_messages.write().remove(to).unwrap_or_default())
+  }
+
+  fn load_audit_records(&self) -> Result<Vec<AuditRecord>, Error> {
+    Ok(self.audit_records.read().clone())
+  }
+
+  fn append_audit_record(&self, record: &AuditRecord) -> Result<(), Error> {
+    self.audit_records.write().push(record.clone());
+    Ok(())
+  }
+}
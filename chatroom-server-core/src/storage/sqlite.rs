@@ -0,0 +1,619 @@
+use std::{
+  collections::{BTreeSet, HashMap},
+  path::Path,
+};
+
+use parking_lot::Mutex;
+
+use rusqlite::{params, Connection, OptionalExtension, Row, Transaction, TransactionBehavior};
+
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+use chatroom_core::data::UserEssential;
+
+use crate::audit::AuditRecord;
+
+use super::{Ban, BanTarget, Error, OfflineMessage, Room, SecondFactor, Storage};
+
+/// bumped whenever the schema changes, see `migrate`
+const SCHEMA_VERSION: u32 = 1;
+
+/// storage kept in a sqlite database, every write is a transaction of its own
+#[derive(Debug)]
+pub struct SqliteStorage {
+  conn: Mutex<Connection>,
+}
+
+impl SqliteStorage {
+  pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+    let conn = Connection::open(path)?;
+    conn.pragma_update(None, "journal_mode", "WAL")?;
+    conn.pragma_update(None, "synchronous", "FULL")?;
+    Self::from_connection(conn)
+  }
+
+  pub fn open_in_memory() -> Result<Self, Error> {
+    Self::from_connection(Connection::open_in_memory()?)
+  }
+
+  fn from_connection(mut conn: Connection) -> Result<Self, Error> {
+    conn.pragma_update(None, "foreign_keys", true)?;
+    migrate(&mut conn)?;
+    Ok(Self {
+      conn: Mutex::new(conn),
+    })
+  }
+
+  fn write<T, F>(&self, f: F) -> Result<T, Error>
+  where
+    F: FnOnce(&Transaction) -> Result<T, Error>,
+  {
+    let mut conn = self.conn.lock();
+    let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    let result = f(&tx)?;
+    tx.commit()?;
+    Ok(result)
+  }
+}
+
+impl Storage for SqliteStorage {
+  fn load_users(&self) -> Result<HashMap<String, UserEssential>, Error> {
+    let conn = self.conn.lock();
+    let mut stmt = conn.prepare("SELECT name, password_hash FROM users")?;
+    let users = stmt
+      .query_map([], |row| {
+        Ok((
+          row.get::<_, String>(0)?,
+          UserEssential {
+            password_hash: row.get(1)?,
+          },
+        ))
+      })?
+      .collect::<Result<_, _>>()?;
+    Ok(users)
+  }
+
+  fn load_user(&self, name: &str) -> Result<Option<UserEssential>, Error> {
+    let conn = self.conn.lock();
+    let user = conn
+      .query_row(
+        "SELECT password_hash FROM users WHERE name = ?1",
+        [name],
+        |row| {
+          Ok(UserEssential {
+            password_hash: row.get(0)?,
+          })
+        },
+      )
+      .optional()?;
+    Ok(user)
+  }
+
+  fn insert_user(&self, name: &str, user: &UserEssential) -> Result<(), Error> {
+    self.write(|tx| insert_user(tx, name, user))
+  }
+
+  fn update_password(&self, name: &str, password_hash: &str) -> Result<(), Error> {
+    self.write(|tx| update_password(tx, name, password_hash))
+  }
+
+  fn delete_user(&self, name: &str) -> Result<(), Error> {
+    self.write(|tx| {
+      if tx.execute("DELETE FROM users WHERE name = ?1", [name])? == 0 {
+        return Err(Error::UserNotExisted(name.to_string()));
+      }
+      tx.execute("DELETE FROM room_members WHERE member = ?1", [name])?;
+      tx.execute("DELETE FROM offline_messages WHERE recipient = ?1", [name])?;
+      tx.execute("DELETE FROM second_factors WHERE name = ?1", [name])?;
+      Ok(())
+    })
+  }
+
+  fn import_users(
+    &self,
+    inserted: &[(String, UserEssential)],
+    updated: &[(String, UserEssential)],
+  ) -> Result<(), Error> {
+    self.write(|tx| {
+      for (name, user) in inserted {
+        insert_user(tx, name, user)?;
+      }
+      for (name, user) in updated {
+        update_password(tx, name, &user.password_hash)?;
+      }
+      Ok(())
+    })
+  }
+
+  fn load_second_factors(&self) -> Result<HashMap<String, SecondFactor>, Error> {
+    let conn = self.conn.lock();
+    let mut stmt =
+      conn.prepare("SELECT name, secret, recovery_codes, last_step FROM second_factors")?;
+    let rows = stmt
+      .query_map([], |row| {
+        Ok((
+          row.get::<_, String>(0)?,
+          row.get::<_, Vec<u8>>(1)?,
+          row.get::<_, String>(2)?,
+          row.get::<_, i64>(3)?,
+        ))
+      })?
+      .collect::<Result<Vec<_>, _>>()?;
+    rows
+      .into_iter()
+      .map(|(name, secret, recovery_codes, last_step)| {
+        let recovery_codes =
+          serde_json::from_str(&recovery_codes).map_err(|err| Error::Corrupted(err.to_string()))?;
+        let last_step = u64::try_from(last_step)
+          .map_err(|_| Error::Corrupted(format!("negative totp step {}", last_step)))?;
+        Ok((
+          name,
+          SecondFactor {
+            secret,
+            recovery_codes,
+            last_step,
+          },
+        ))
+      })
+      .collect()
+  }
+
+  fn set_second_factor(&self, name: &str, second_factor: &SecondFactor) -> Result<(), Error> {
+    let recovery_codes = serde_json::to_string(&second_factor.recovery_codes)
+      .map_err(|err| Error::Corrupted(err.to_string()))?;
+    let last_step = i64::try_from(second_factor.last_step).map_err(|_| {
+      Error::Corrupted(format!(
+        "totp step {} is out of range",
+        second_factor.last_step
+      ))
+    })?;
+    self.write(|tx| {
+      let existed = tx
+        .query_row("SELECT 1 FROM users WHERE name = ?1", [name], |_| Ok(()))
+        .optional()?
+        .is_some();
+      if !existed {
+        return Err(Error::UserNotExisted(name.to_string()));
+      }
+      tx.execute(
+        "INSERT OR REPLACE INTO second_factors (name, secret, recovery_codes, last_step)
+        VALUES (?1, ?2, ?3, ?4)",
+        params![name, second_factor.secret, recovery_codes, last_step],
+      )?;
+      Ok(())
+    })
+  }
+
+  fn remove_second_factor(&self, name: &str) -> Result<bool, Error> {
+    self.write(|tx| Ok(tx.execute("DELETE FROM second_factors WHERE name = ?1", [name])? > 0))
+  }
+
+  fn load_bans(&self) -> Result<Vec<Ban>, Error> {
+    let conn = self.conn.lock();
+    let mut stmt = conn.prepare("SELECT kind, target, reason, until FROM bans")?;
+    let rows = stmt
+      .query_map([], |row| {
+        Ok((
+          row.get::<_, String>(0)?,
+          row.get::<_, String>(1)?,
+          row.get::<_, Option<String>>(2)?,
+          row.get::<_, Option<String>>(3)?,
+        ))
+      })?
+      .collect::<Result<Vec<_>, _>>()?;
+    rows
+      .into_iter()
+      .map(|(kind, target, reason, until)| {
+        Ok(Ban {
+          target: decode_ban_target(&kind, &target)?,
+          reason,
+          until: until.as_deref().map(parse_timestamp).transpose()?,
+        })
+      })
+      .collect()
+  }
+
+  fn insert_ban(&self, ban: &Ban) -> Result<(), Error> {
+    let (kind, target) = encode_ban_target(&ban.target);
+    let until = ban.until.map(format_timestamp).transpose()?;
+    self.write(|tx| {
+      tx.execute(
+        "INSERT OR REPLACE INTO bans (kind, target, reason, until) VALUES (?1, ?2, ?3, ?4)",
+        params![kind, target, ban.reason, until],
+      )?;
+      Ok(())
+    })
+  }
+
+  fn remove_ban(&self, target: &BanTarget) -> Result<bool, Error> {
+    let (kind, target) = encode_ban_target(target);
+    self.write(|tx| {
+      Ok(
+        tx.execute(
+          "DELETE FROM bans WHERE kind = ?1 AND target = ?2",
+          params![kind, target],
+        )? > 0,
+      )
+    })
+  }
+
+  fn load_rooms(&self) -> Result<Vec<Room>, Error> {
+    let conn = self.conn.lock();
+    let mut rooms = conn
+      .prepare("SELECT name FROM rooms")?
+      .query_map([], |row| row.get::<_, String>(0))?
+      .map(|name| {
+        name.map(|name| {
+          (
+            name.clone(),
+            Room {
+              name,
+              members: BTreeSet::new(),
+            },
+          )
+        })
+      })
+      .collect::<Result<HashMap<_, _>, _>>()?;
+    let mut stmt = conn.prepare("SELECT room, member FROM room_members")?;
+    let members = stmt.query_map([], |row| {
+      Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+    })?;
+    for member in members {
+      let (room, member) = member?;
+      if let Some(room) = rooms.get_mut(&room) {
+        room.members.insert(member);
+      }
+    }
+    Ok(rooms.into_values().collect())
+  }
+
+  fn insert_room(&self, room: &Room) -> Result<(), Error> {
+    self.write(|tx| {
+      let existed = tx
+        .query_row("SELECT 1 FROM rooms WHERE name = ?1", [&room.name], |_| {
+          Ok(())
+        })
+        .optional()?
+        .is_some();
+      if existed {
+        return Err(Error::RoomExisted(room.name.clone()));
+      }
+      tx.execute("INSERT INTO rooms (name) VALUES (?1)", [&room.name])?;
+      for member in room.members.iter() {
+        tx.execute(
+          "INSERT INTO room_members (room, member) VALUES (?1, ?2)",
+          params![room.name, member],
+        )?;
+      }
+      Ok(())
+    })
+  }
+
+  fn delete_room(&self, name: &str) -> Result<(), Error> {
+    self.write(|tx| {
+      if tx.execute("DELETE FROM rooms WHERE name = ?1", [name])? == 0 {
+        return Err(Error::RoomNotExisted(name.to_string()));
+      }
+      Ok(())
+    })
+  }
+
+  fn add_room_member(&self, room: &str, member: &str) -> Result<(), Error> {
+    self.write(|tx| {
+      ensure_room(tx, room)?;
+      tx.execute(
+        "INSERT OR IGNORE INTO room_members (room, member) VALUES (?1, ?2)",
+        params![room, member],
+      )?;
+      Ok(())
+    })
+  }
+
+  fn remove_room_member(&self, room: &str, member: &str) -> Result<(), Error> {
+    self.write(|tx| {
+      ensure_room(tx, room)?;
+      tx.execute(
+        "DELETE FROM room_members WHERE room = ?1 AND member = ?2",
+        params![room, member],
+      )?;
+      Ok(())
+    })
+  }
+
+  fn load_offline_messages(&self) -> Result<HashMap<String, Vec<OfflineMessage>>, Error> {
+    let conn = self.conn.lock();
+    let mut stmt =
+      conn.prepare("SELECT recipient, sender, timestamp, msg FROM offline_messages ORDER BY id")?;
+    let rows = stmt
+      .query_map([], |row| {
+        Ok((row.get::<_, String>(0)?, read_offline_message(row)?))
+      })?
+      .collect::<Result<Vec<_>, _>>()?;
+    let mut msgs = HashMap::<String, Vec<OfflineMessage>>::new();
+    for (to, (from, timestamp, msg)) in rows {
+      msgs.entry(to).or_default().push(OfflineMessage {
+        from,
+        timestamp: parse_timestamp(&timestamp)?,
+        msg,
+      });
+    }
+    Ok(msgs)
+  }
+
+  fn push_offline_message(&self, to: &str, msg: &OfflineMessage) -> Result<(), Error> {
+    let timestamp = format_timestamp(msg.timestamp)?;
+    self.write(|tx| {
+      tx.execute(
+        "INSERT INTO offline_messages (recipient, sender, timestamp, msg) VALUES (?1, ?2, ?3, ?4)",
+        params![to, msg.from, timestamp, msg.msg],
+      )?;
+      Ok(())
+    })
+  }
+
+  fn take_offline_messages(&self, to: &str) -> Result<Vec<OfflineMessage>, Error> {
+    self.write(|tx| {
+      let rows = tx
+        .prepare(
+          "SELECT recipient, sender, timestamp, msg FROM offline_messages
+          WHERE recipient = ?1 ORDER BY id",
+        )?
+        .query_map([to], read_offline_message)?
+        .collect::<Result<Vec<_>, _>>()?;
+      tx.execute("DELETE FROM offline_messages WHERE recipient = ?1", [to])?;
+      rows
+        .into_iter()
+        .map(|(from, timestamp, msg)| {
+          Ok(OfflineMessage {
+            from,
+            timestamp: parse_timestamp(&timestamp)?,
+            msg,
+          })
+        })
+        .collect()
+    })
+  }
+
+  fn load_audit_records(&self) -> Result<Vec<AuditRecord>, Error> {
+    let conn = self.conn.lock();
+    let mut stmt =
+      conn.prepare("SELECT seq, timestamp, event, prev, hash FROM audit_log ORDER BY seq")?;
+    let rows = stmt
+      .query_map([], |row| {
+        Ok((
+          row.get::<_, i64>(0)?,
+          row.get::<_, String>(1)?,
+          row.get::<_, String>(2)?,
+          row.get::<_, String>(3)?,
+          row.get::<_, String>(4)?,
+        ))
+      })?
+      .collect::<Result<Vec<_>, _>>()?;
+    rows
+      .into_iter()
+      .map(|(seq, timestamp, event, prev, hash)| {
+        Ok(AuditRecord {
+          seq: seq as u64,
+          timestamp: parse_timestamp(&timestamp)?,
+          event: serde_json::from_str(&event).map_err(|err| Error::Corrupted(err.to_string()))?,
+          prev,
+          hash,
+        })
+      })
+      .collect()
+  }
+
+  fn append_audit_record(&self, record: &AuditRecord) -> Result<(), Error> {
+    let timestamp = format_timestamp(record.timestamp)?;
+    let event =
+      serde_json::to_string(&record.event).map_err(|err| Error::Corrupted(err.to_string()))?;
+    self.write(|tx| {
+      tx.execute(
+        "INSERT INTO audit_log (seq, timestamp, event, prev, hash) VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![
+          record.seq as i64,
+          timestamp,
+          event,
+          record.prev,
+          record.hash
+        ],
+      )?;
+      Ok(())
+    })
+  }
+}
+
+fn migrate(conn: &mut Connection) -> Result<(), Error> {
+  let version: u32 = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+  if version > SCHEMA_VERSION {
+    return Err(Error::UnknownSchema(version));
+  }
+  let tx = conn.transaction()?;
+  if version < 1 {
+    tx.execute_batch(
+      "CREATE TABLE users (
+        name TEXT PRIMARY KEY NOT NULL,
+        password_hash TEXT NOT NULL
+      );
+      CREATE TABLE bans (
+        kind TEXT NOT NULL,
+        target TEXT NOT NULL,
+        reason TEXT,
+        until TEXT,
+        PRIMARY KEY (kind, target)
+      );
+      CREATE TABLE rooms (
+        name TEXT PRIMARY KEY NOT NULL
+      );
+      CREATE TABLE room_members (
+        room TEXT NOT NULL REFERENCES rooms (name) ON DELETE CASCADE,
+        member TEXT NOT NULL,
+        PRIMARY KEY (room, member)
+      );
+      CREATE TABLE offline_messages (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        recipient TEXT NOT NULL,
+        sender TEXT NOT NULL,
+        timestamp TEXT NOT NULL,
+        msg TEXT NOT NULL
+      );
+      CREATE INDEX offline_messages_recipient ON offline_messages (recipient);
+      CREATE TABLE audit_log (
+        seq INTEGER PRIMARY KEY NOT NULL,
+        timestamp TEXT NOT NULL,
+        event TEXT NOT NULL,
+        prev TEXT NOT NULL,
+        hash TEXT NOT NULL
+      );
+      CREATE TABLE second_factors (
+        name TEXT PRIMARY KEY NOT NULL REFERENCES users (name) ON DELETE CASCADE,
+        secret BLOB NOT NULL,
+        recovery_codes TEXT NOT NULL,
+        last_step INTEGER NOT NULL DEFAULT 0
+      );",
+    )?;
+  }
+  tx.pragma_update(None, "user_version", SCHEMA_VERSION)?;
+  tx.commit()?;
+  Ok(())
+}
+
+fn insert_user(tx: &Transaction, name: &str, user: &UserEssential) -> Result<(), Error> {
+  let existed = tx
+    .query_row("SELECT 1 FROM users WHERE name = ?1", [name], |_| Ok(()))
+    .optional()?
+    .is_some();
+  if existed {
+    return Err(Error::UserExisted(name.to_string()));
+  }
+  tx.execute(
+    "INSERT INTO users (name, password_hash) VALUES (?1, ?2)",
+    params![name, user.password_hash],
+  )?;
+  Ok(())
+}
+
+fn update_password(tx: &Transaction, name: &str, password_hash: &str) -> Result<(), Error> {
+  let updated = tx.execute(
+    "UPDATE users SET password_hash = ?2 WHERE name = ?1",
+    params![name, password_hash],
+  )?;
+  if updated == 0 {
+    return Err(Error::UserNotExisted(name.to_string()));
+  }
+  Ok(())
+}
+
+fn ensure_room(tx: &Transaction, room: &str) -> Result<(), Error> {
+  let existed = tx
+    .query_row("SELECT 1 FROM rooms WHERE name = ?1", [room], |_| Ok(()))
+    .optional()?
+    .is_some();
+  if existed {
+    Ok(())
+  } else {
+    Err(Error::RoomNotExisted(room.to_string()))
+  }
+}
+
+/// sender, timestamp and content of an `offline_messages` row, skipping the recipient
+fn read_offline_message(row: &Row) -> rusqlite::Result<(String, String, String)> {
+  Ok((row.get(1)?, row.get(2)?, row.get(3)?))
+}
+
+fn encode_ban_target(target: &BanTarget) -> (&'static str, String) {
+  match target {
+    BanTarget::User(name) => ("user", name.clone()),
+    BanTarget::Ip(ip) => ("ip", ip.to_string()),
+  }
+}
+
+fn decode_ban_target(kind: &str, target: &str) -> Result<BanTarget, Error> {
+  match kind {
+    "user" => Ok(BanTarget::User(target.to_string())),
+    "ip" => target
+      .parse()
+      .map(BanTarget::Ip)
+      .map_err(|_| Error::Corrupted(format!("invalid banned ip \"{}\"", target))),
+    _ => Err(Error::Corrupted(format!("unknown ban kind \"{}\"", kind))),
+  }
+}
+
+fn format_timestamp(timestamp: OffsetDateTime) -> Result<String, Error> {
+  timestamp
+    .format(&Rfc3339)
+    .map_err(|err| Error::Corrupted(err.to_string()))
+}
+
+fn parse_timestamp(timestamp: &str) -> Result<OffsetDateTime, Error> {
+  OffsetDateTime::parse(timestamp, &Rfc3339).map_err(|err| Error::Corrupted(err.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use std::{fs, path::PathBuf};
+
+  fn path(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("chatroom-storage-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join(name);
+    let _ = fs::remove_file(&path);
+    path
+  }
+
+  #[test]
+  fn rejects_newer_schemas() {
+    let path = path("newer.sqlite");
+    Connection::open(&path)
+      .unwrap()
+      .pragma_update(None, "user_version", SCHEMA_VERSION + 1)
+      .unwrap();
+    assert!(matches!(
+      SqliteStorage::open(&path),
+      Err(Error::UnknownSchema(version)) if version == SCHEMA_VERSION + 1
+    ));
+  }
+
+  #[test]
+  fn failed_writes_roll_back() {
+    let storage = SqliteStorage::open_in_memory().unwrap();
+    let result = storage.write(|tx| {
+      tx.execute(
+        "INSERT INTO users (name, password_hash) VALUES ('alice', 'hash-a')",
+        [],
+      )?;
+      Err::<(), _>(Error::Corrupted("halfway".to_string()))
+    });
+    assert!(matches!(result, Err(Error::Corrupted(_))));
+    assert!(storage.conn.lock().is_autocommit());
+    assert!(storage.load_users().unwrap().is_empty());
+
+    storage
+      .insert_user(
+        "alice",
+        &UserEssential {
+          password_hash: "hash-a".to_string(),
+        },
+      )
+      .unwrap();
+    assert_eq!(storage.load_users().unwrap().len(), 1);
+  }
+
+  #[test]
+  fn keeps_data_across_reopening() {
+    let path = path("reopen.sqlite");
+    SqliteStorage::open(&path)
+      .unwrap()
+      .insert_user(
+        "alice",
+        &UserEssential {
+          password_hash: "hash-a".to_string(),
+        },
+      )
+      .unwrap();
+    let users = SqliteStorage::open(&path).unwrap().load_users().unwrap();
+    assert_eq!(users["alice"].password_hash, "hash-a");
+  }
+}
@@ -0,0 +1,294 @@
+use thiserror::Error as ThisError;
+
+use std::{collections::HashMap, sync::Arc};
+
+use parking_lot::RwLock;
+
+use rand::Rng;
+
+use sha2::{Digest, Sha256};
+
+use time::OffsetDateTime;
+
+use chatroom_core::totp;
+
+use crate::storage::{self, SecondFactor, Storage};
+
+/// handed out on every enrolment
+const RECOVERY_CODES: usize = 10;
+/// characters of a recovery code, shown in two halves joined by a dash
+const RECOVERY_CODE_LEN: usize = 10;
+const RECOVERY_CODE_ALPHABET: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+
+/// how a second factor got proved
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verified {
+  OneTimeCode,
+  /// the code is used up now
+  RecoveryCode {
+    remaining: usize,
+  },
+}
+
+/// totp secrets of the users who enrolled, changes are written to storage before being applied
+#[derive(Debug)]
+pub struct TwoFactor {
+  storage: Arc<dyn Storage>,
+  enrolled: RwLock<HashMap<String, SecondFactor>>,
+  /// secrets handed out for enrolment, waiting for a first code made from them
+  pending: RwLock<HashMap<String, Vec<u8>>>,
+}
+
+impl TwoFactor {
+  pub fn new(storage: Arc<dyn Storage>) -> Result<Self, storage::Error> {
+    let enrolled = storage.load_second_factors()?;
+    Ok(Self {
+      storage,
+      enrolled: RwLock::new(enrolled),
+      pending: Default::default(),
+    })
+  }
+
+  pub fn is_enrolled(&self, name: &str) -> bool {
+    self.enrolled.read().contains_key(name)
+  }
+
+  /// a fresh secret for `name`, it replaces the current one only once confirmed
+  pub fn begin_enrollment(&self, name: &str) -> Vec<u8> {
+    let secret = totp::generate_secret();
+    self
+      .pending
+      .write()
+      .insert(name.to_string(), secret.clone());
+    secret
+  }
+
+  /// enroll `name` with the secret handed out last, returns the recovery codes in plain, they
+  /// are only kept hashed
+  pub fn confirm_enrollment(&self, name: &str, code: &str) -> Result<Vec<String>, Error> {
+    let mut pending = self.pending.write();
+    let secret = pending.get(name).ok_or(Error::NotPending)?;
+    let step = totp::verify(secret, code, OffsetDateTime::now_utc()).ok_or(Error::InvalidCode)?;
+
+    let codes = (0..RECOVERY_CODES)
+      .map(|_| generate_recovery_code())
+      .collect::<Vec<_>>();
+    let second_factor = SecondFactor {
+      secret: secret.clone(),
+      recovery_codes: codes.iter().map(|code| hash_recovery_code(code)).collect(),
+      last_step: step,
+    };
+    self.storage.set_second_factor(name, &second_factor)?;
+    pending.remove(name);
+    self
+      .enrolled
+      .write()
+      .insert(name.to_string(), second_factor);
+    Ok(codes)
+  }
+
+  /// check a one-time code or a recovery code of `name`
+  pub fn verify(&self, name: &str, code: &str) -> Result<Verified, Error> {
+    let mut enrolled = self.enrolled.write();
+    let second_factor = enrolled.get_mut(name).ok_or(Error::NotEnrolled)?;
+
+    if let Some(step) = totp::verify(&second_factor.secret, code, OffsetDateTime::now_utc()) {
+      if step <= second_factor.last_step {
+        return Err(Error::Replayed);
+      }
+      let updated = SecondFactor {
+        last_step: step,
+        ..second_factor.clone()
+      };
+      self.storage.set_second_factor(name, &updated)?;
+      *second_factor = updated;
+      return Ok(Verified::OneTimeCode);
+    }
+
+    let hash = hash_recovery_code(code);
+    let index = second_factor
+      .recovery_codes
+      .iter()
+      .position(|h| h == &hash)
+      .ok_or(Error::InvalidCode)?;
+    let mut updated = second_factor.clone();
+    updated.recovery_codes.remove(index);
+    self.storage.set_second_factor(name, &updated)?;
+    let remaining = updated.recovery_codes.len();
+    *second_factor = updated;
+    Ok(Verified::RecoveryCode { remaining })
+  }
+
+  /// returns whether `name` was enrolled
+  pub fn disable(&self, name: &str) -> Result<bool, Error> {
+    self.pending.write().remove(name);
+    let mut enrolled = self.enrolled.write();
+    let removed = self.storage.remove_second_factor(name)?;
+    enrolled.remove(name);
+    Ok(removed)
+  }
+
+  /// drop what is kept in memory for a deleted user, storage removes it along with the user
+  pub fn forget(&self, name: &str) {
+    self.enrolled.write().remove(name);
+    self.pending.write().remove(name);
+  }
+}
+
+fn generate_recovery_code() -> String {
+  let mut rng = rand::thread_rng();
+  let chars = (0..RECOVERY_CODE_LEN)
+    .map(|_| RECOVERY_CODE_ALPHABET[rng.gen_range(0..RECOVERY_CODE_ALPHABET.len())] as char)
+    .collect::<String>();
+  let (head, tail) = chars.split_at(RECOVERY_CODE_LEN / 2);
+  format!("{}-{}", head, tail)
+}
+
+/// dashes, spaces and case do not matter when typing a recovery code in
+fn hash_recovery_code(code: &str) -> String {
+  let normalized = code
+    .chars()
+    .filter(|c| c.is_ascii_alphanumeric())
+    .map(|c| c.to_ascii_uppercase())
+    .collect::<String>();
+  let digest = Sha256::digest(normalized.as_bytes());
+  digest.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[derive(ThisError, Debug)]
+pub enum Error {
+  #[error(transparent)]
+  Storage(#[from] storage::Error),
+  #[error("two-factor authentication is not enabled")]
+  NotEnrolled,
+  #[error("no enrolment is pending")]
+  NotPending,
+  #[error("one-time code is invalid")]
+  InvalidCode,
+  #[error("one-time code has been used already")]
+  Replayed,
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use time::Duration;
+
+  use chatroom_core::data::UserEssential;
+
+  use crate::storage::MemoryStorage;
+
+  /// alice with the secret and the recovery codes she got
+  fn enroll(storage: &Arc<dyn Storage>) -> (TwoFactor, Vec<u8>, Vec<String>) {
+    storage
+      .insert_user(
+        "alice",
+        &UserEssential {
+          password_hash: String::new(),
+        },
+      )
+      .unwrap();
+    let two_factor = TwoFactor::new(storage.clone()).unwrap();
+    let secret = two_factor.begin_enrollment("alice");
+    let code = totp::code_at(&secret, OffsetDateTime::now_utc());
+    let codes = two_factor.confirm_enrollment("alice", &code).unwrap();
+    (two_factor, secret, codes)
+  }
+
+  #[test]
+  fn rejects_replayed_codes_after_a_restart() {
+    let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::default());
+    let (two_factor, secret, _) = enroll(&storage);
+    let now = OffsetDateTime::now_utc();
+
+    // the code used for the enrolment is used up as well
+    let code = totp::code_at(&secret, now);
+    assert!(matches!(
+      two_factor.verify("alice", &code),
+      Err(Error::Replayed)
+    ));
+
+    let code = totp::code_at(&secret, now + Duration::seconds(totp::STEP as i64));
+    assert_eq!(
+      two_factor.verify("alice", &code).unwrap(),
+      Verified::OneTimeCode
+    );
+    assert!(matches!(
+      two_factor.verify("alice", &code),
+      Err(Error::Replayed)
+    ));
+
+    let restarted = TwoFactor::new(storage).unwrap();
+    assert!(matches!(
+      restarted.verify("alice", &code),
+      Err(Error::Replayed)
+    ));
+  }
+
+  #[test]
+  fn uses_up_recovery_codes() {
+    let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::default());
+    let (two_factor, _, codes) = enroll(&storage);
+
+    let typed = codes[0].to_lowercase().replace('-', " ");
+    assert_eq!(
+      two_factor.verify("alice", &typed).unwrap(),
+      Verified::RecoveryCode {
+        remaining: RECOVERY_CODES - 1
+      }
+    );
+    assert!(matches!(
+      two_factor.verify("alice", &codes[0]),
+      Err(Error::InvalidCode)
+    ));
+  }
+
+  #[test]
+  fn enrolls_only_with_a_code_of_the_new_secret() {
+    let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::default());
+    storage
+      .insert_user(
+        "alice",
+        &UserEssential {
+          password_hash: String::new(),
+        },
+      )
+      .unwrap();
+    let two_factor = TwoFactor::new(storage.clone()).unwrap();
+    assert!(matches!(
+      two_factor.confirm_enrollment("alice", "123456"),
+      Err(Error::NotPending)
+    ));
+
+    let secret = two_factor.begin_enrollment("alice");
+    let stale = totp::code_at(&secret, OffsetDateTime::now_utc() - Duration::hours(1));
+    assert!(matches!(
+      two_factor.confirm_enrollment("alice", &stale),
+      Err(Error::InvalidCode)
+    ));
+    assert!(!two_factor.is_enrolled("alice"));
+
+    let code = totp::code_at(&secret, OffsetDateTime::now_utc());
+    assert_eq!(
+      two_factor.confirm_enrollment("alice", &code).unwrap().len(),
+      RECOVERY_CODES
+    );
+    assert!(two_factor.is_enrolled("alice"));
+    assert!(storage.load_second_factors().unwrap().contains_key("alice"));
+  }
+
+  #[test]
+  fn forgets_disabled_second_factors() {
+    let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::default());
+    let (two_factor, _, codes) = enroll(&storage);
+    assert!(two_factor.disable("alice").unwrap());
+    assert!(!two_factor.disable("alice").unwrap());
+    assert!(!two_factor.is_enrolled("alice"));
+    assert!(storage.load_second_factors().unwrap().is_empty());
+    assert!(matches!(
+      two_factor.verify("alice", &codes[0]),
+      Err(Error::NotEnrolled)
+    ));
+  }
+}
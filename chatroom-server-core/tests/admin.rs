@@ -0,0 +1,132 @@
+mod common;
+
+use chatroom_core::{
+  data::{Command, ErrorCode, Notification, ResponseData},
+  srp::Verifier,
+};
+
+use chatroom_server_core::{
+  accounts::{Account, ConflictMode},
+  audit::AuditEvent,
+  storage::{Ban, BanTarget},
+};
+
+use common::{start_server, Client};
+
+fn is_not_kicked(notification: &Notification) -> bool {
+  !matches!(notification, Notification::Kicked { .. })
+}
+
+#[tokio::test]
+async fn tells_kicked_devices() {
+  let (server, addr) = start_server().await;
+  let mut laptop = Client::connect(addr).await;
+  let mut phone = Client::connect(addr).await;
+  laptop.register("alice", "secret").await;
+  laptop.login("alice", "secret").await.unwrap();
+  phone.login("alice", "secret").await.unwrap();
+
+  server.kick("alice").await.unwrap();
+  laptop.notification(is_not_kicked).await;
+  phone.notification(is_not_kicked).await;
+  assert!(!server.get_state().users.read()["alice"].is_online());
+}
+
+#[tokio::test]
+async fn revokes_the_sessions_of_banned_ips() {
+  let (server, addr) = start_server().await;
+  let laptop = Client::connect_from("127.0.0.1", addr, "laptop").await;
+  let mut phone = Client::connect_from("127.0.0.2", addr, "phone").await;
+  laptop.register("alice", "secret").await;
+  laptop.login("alice", "secret").await.unwrap();
+  let token = match phone.login("alice", "secret").await {
+    Ok(ResponseData::LoggedIn { token, .. }) => token,
+    response => panic!("unexpected response {:?}", response),
+  };
+
+  let ban = Ban {
+    target: BanTarget::Ip(phone.addr.ip()),
+    reason: None,
+    until: None,
+  };
+  assert_eq!(server.ban(ban).await.unwrap(), vec!["alice".to_string()]);
+  phone.notification(is_not_kicked).await;
+
+  let users = server.get_state().users.read().clone();
+  assert_eq!(users["alice"].devices.len(), 1);
+  assert_eq!(users["alice"].devices[0].ip_address, laptop.addr);
+
+  // not even from an address which is not banned
+  let elsewhere = Client::connect(addr).await;
+  let resume = Command::Resume {
+    token,
+    device: "phone".to_string(),
+  };
+  assert_eq!(
+    elsewhere.request(&resume).await,
+    Err(ErrorCode::SessionExpired)
+  );
+}
+
+#[tokio::test]
+async fn resets_passwords() {
+  let (server, addr) = start_server().await;
+  let client = Client::connect(addr).await;
+  client.register("alice", "secret").await;
+
+  server.reset_password("alice", "new secret").await.unwrap();
+  assert_eq!(
+    client.login("alice", "secret").await,
+    Err(ErrorCode::InvalidUserOrPass)
+  );
+  client.login("alice", "new secret").await.unwrap();
+  assert!(server.reset_password("bob", "secret").await.is_err());
+}
+
+#[tokio::test]
+async fn revokes_the_sessions_of_overwritten_accounts() {
+  let (server, addr) = start_server().await;
+  let client = Client::connect(addr).await;
+  client.register("alice", "secret").await;
+  client.register("bob", "secret").await;
+  let token = |response| match response {
+    Ok(ResponseData::LoggedIn { token, .. }) => token,
+    response => panic!("unexpected response {:?}", response),
+  };
+  let alice = token(client.login("alice", "secret").await);
+  let other = Client::connect(addr).await;
+  let bob = token(other.login("bob", "secret").await);
+
+  let accounts = [Account {
+    name: "alice".to_string(),
+    password_hash: Verifier::new("alice", "new secret").encode(),
+  }];
+  let summary = server
+    .import_accounts(&accounts, ConflictMode::Overwrite)
+    .await
+    .unwrap();
+  assert_eq!(summary.overwritten, 1);
+  let state = server.get_state();
+  state.audit.flush();
+  let records = state.storage.load_audit_records().unwrap();
+  assert_eq!(
+    records.last().unwrap().event,
+    AuditEvent::AccountsImported {
+      imported: vec![],
+      overwritten: vec!["alice".to_string()],
+    }
+  );
+
+  let resume = |token| Command::Resume {
+    token,
+    device: "tests".to_string(),
+  };
+  assert_eq!(
+    client.request(&resume(alice)).await,
+    Err(ErrorCode::SessionExpired)
+  );
+  assert!(matches!(
+    other.request(&resume(bob)).await,
+    Ok(ResponseData::ChatroomStatus { .. })
+  ));
+}
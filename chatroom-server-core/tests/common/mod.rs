@@ -0,0 +1,137 @@
+//! a server on loopback and clients speaking to it the way the apps do
+
+#![allow(dead_code)]
+
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use tokio::{net::UdpSocket, sync::mpsc::Receiver, time::timeout};
+
+use chatroom_core::{
+  buffer::Frame,
+  codec::Codec,
+  connection::Connection,
+  data::{default_coder, Command, DefaultCoder, ErrorCode, Notification, Response, ResponseData},
+  srp::{ClientHandshake, ClientSession, Verifier},
+};
+
+use chatroom_server_core::server::{Server, ServerState};
+
+pub async fn start_server() -> (Server<DefaultCoder>, SocketAddr) {
+  start_server_with(ServerState::new(Duration::from_secs(30))).await
+}
+
+pub async fn start_server_with(state: ServerState) -> (Server<DefaultCoder>, SocketAddr) {
+  let sock = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+  let addr = sock.local_addr().unwrap();
+  let server = Server::with_transport(default_coder(), state, Arc::new(()), sock).unwrap();
+  (server, addr)
+}
+
+pub struct Client {
+  pub connection: Connection<DefaultCoder>,
+  notifications: Receiver<(Frame, SocketAddr)>,
+  pub addr: SocketAddr,
+  pub server: SocketAddr,
+  /// the name its sessions are listed under
+  pub device: String,
+}
+
+impl Client {
+  pub async fn connect(server: SocketAddr) -> Self {
+    Self::connect_as(server, "tests").await
+  }
+
+  pub async fn connect_as(server: SocketAddr, device: &str) -> Self {
+    Self::connect_from("127.0.0.1", server, device).await
+  }
+
+  /// from another loopback address, e.g. `127.0.0.2`, to tell its devices apart by ip
+  pub async fn connect_from(ip: &str, server: SocketAddr, device: &str) -> Self {
+    let sock = UdpSocket::bind((ip, 0)).await.unwrap();
+    let addr = sock.local_addr().unwrap();
+    let (connection, notifications, _) = Connection::new(
+      sock,
+      default_coder(),
+      Default::default(),
+      Duration::from_secs(5),
+      3,
+    );
+    connection
+      .as_inner()
+      .exchange_key_with(server)
+      .await
+      .unwrap();
+    Self {
+      connection,
+      notifications,
+      addr,
+      server,
+      device: device.to_string(),
+    }
+  }
+
+  pub async fn request(&self, command: &Command) -> Response {
+    self.connection.request(command, self.server).await.unwrap()
+  }
+
+  pub async fn register(&self, name: &str, password: &str) {
+    let Verifier { salt, verifier } = Verifier::new(name, password);
+    let command = Command::Register {
+      username: name.to_string(),
+      salt,
+      verifier,
+    };
+    assert_eq!(self.request(&command).await, Ok(ResponseData::Success));
+  }
+
+  /// answer the challenge of the server, for a login or a password change
+  pub async fn authenticate(&self, name: &str, password: &str) -> Result<ClientSession, ErrorCode> {
+    let handshake = ClientHandshake::new(name, password);
+    let authenticate = Command::Authenticate {
+      username: name.to_string(),
+      public_key: handshake.public_key(),
+    };
+    match self.request(&authenticate).await? {
+      ResponseData::Challenge { salt, public_key } => {
+        Ok(handshake.respond(&salt, &public_key).unwrap())
+      }
+      response => panic!("unexpected response {:?}", response),
+    }
+  }
+
+  /// run the srp handshake, the server's proof is checked once it logged the client in
+  pub async fn login(&self, name: &str, password: &str) -> Response {
+    self.login_with_code(name, password, None).await
+  }
+
+  /// log in with a one-time code or a recovery code for accounts with two-factor authentication
+  pub async fn login_with_code(&self, name: &str, password: &str, code: Option<&str>) -> Response {
+    let session = self.authenticate(name, password).await?;
+    let login = Command::Login {
+      proof: session.proof().to_vec(),
+      device: self.device.clone(),
+      code: code.map(str::to_string),
+    };
+    let response = self.request(&login).await;
+    if let Ok(ResponseData::LoggedIn { proof, .. }) = &response {
+      session.verify_server(proof).unwrap();
+    }
+    response
+  }
+
+  /// the next notification from the server, skipping the kinds `skip` returns true for
+  pub async fn notification(&mut self, skip: impl Fn(&Notification) -> bool) -> Notification {
+    loop {
+      let (frame, _) = timeout(Duration::from_secs(5), self.notifications.recv())
+        .await
+        .expect("no notification arrived")
+        .unwrap();
+      let notification = default_coder()
+        .deserialize::<Notification>(&frame[..])
+        .unwrap();
+      if !skip(&notification) {
+        return notification;
+      }
+    }
+  }
+}
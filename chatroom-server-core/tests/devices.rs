@@ -0,0 +1,80 @@
+mod common;
+
+use chatroom_core::data::{Command, Notification, ResponseData};
+
+use common::{start_server, Client};
+
+fn is_online(notification: &Notification) -> bool {
+  matches!(notification, Notification::Online { .. })
+}
+
+#[tokio::test]
+async fn logs_in_from_several_devices() {
+  let (server, addr) = start_server().await;
+  let mut laptop = Client::connect(addr).await;
+  let phone = Client::connect(addr).await;
+  laptop.register("alice", "secret").await;
+
+  assert!(matches!(
+    laptop.login("alice", "secret").await,
+    Ok(ResponseData::LoggedIn { .. })
+  ));
+  let users = match phone.login("alice", "secret").await {
+    Ok(ResponseData::LoggedIn { users, .. }) => users,
+    response => panic!("unexpected response {:?}", response),
+  };
+
+  let alice = users.iter().find(|u| u.name == "alice").unwrap();
+  let mut devices: Vec<_> = alice.devices.iter().map(|d| d.ip_address).collect();
+  devices.sort();
+  let mut expected = vec![laptop.addr, phone.addr];
+  expected.sort();
+  assert_eq!(devices, expected);
+  assert_eq!(server.get_state().users.read()["alice"].devices.len(), 2);
+
+  match laptop.notification(|_| false).await {
+    Notification::Online { name, info, .. } => {
+      assert_eq!(name, "alice");
+      assert_eq!(info.ip_address, phone.addr);
+    }
+    notification => panic!("unexpected notification {:?}", notification),
+  }
+}
+
+#[tokio::test]
+async fn stays_online_until_the_last_device_logs_out() {
+  let (server, addr) = start_server().await;
+  let mut bob = Client::connect(addr).await;
+  let laptop = Client::connect(addr).await;
+  let phone = Client::connect(addr).await;
+  bob.register("bob", "hunter2").await;
+  bob.register("alice", "secret").await;
+  bob.login("bob", "hunter2").await.unwrap();
+  laptop.login("alice", "secret").await.unwrap();
+  phone.login("alice", "secret").await.unwrap();
+
+  assert_eq!(
+    laptop.request(&Command::Logout).await,
+    Ok(ResponseData::Success)
+  );
+  match bob.notification(is_online).await {
+    Notification::Offline {
+      name, ip_address, ..
+    } => {
+      assert_eq!(name, "alice");
+      assert_eq!(ip_address, laptop.addr);
+    }
+    notification => panic!("unexpected notification {:?}", notification),
+  }
+  assert_eq!(server.get_state().users.read()["alice"].devices.len(), 1);
+
+  assert_eq!(
+    phone.request(&Command::Logout).await,
+    Ok(ResponseData::Success)
+  );
+  match bob.notification(is_online).await {
+    Notification::Offline { ip_address, .. } => assert_eq!(ip_address, phone.addr),
+    notification => panic!("unexpected notification {:?}", notification),
+  }
+  assert!(server.get_state().users.read()["alice"].devices.is_empty());
+}
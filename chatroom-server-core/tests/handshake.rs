@@ -0,0 +1,121 @@
+mod common;
+
+use std::time::Duration;
+
+use chatroom_core::data::{
+  Capabilities, Command, ErrorCode, ResponseData, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+};
+
+use chatroom_server_core::server::ServerState;
+
+use common::{start_server, start_server_with, Client};
+
+fn hello(version: u16, capabilities: Capabilities) -> Command {
+  Command::Hello {
+    version,
+    capabilities,
+  }
+}
+
+#[tokio::test]
+async fn agrees_on_the_version_and_capabilities() {
+  let (_server, addr) = start_server().await;
+  let client = Client::connect(addr).await;
+
+  let capabilities = Capabilities::COMPRESSION | Capabilities::RELAY;
+  assert_eq!(
+    client.request(&hello(PROTOCOL_VERSION, capabilities)).await,
+    Ok(ResponseData::Hello {
+      version: PROTOCOL_VERSION,
+      capabilities: Capabilities::COMPRESSION,
+    })
+  );
+
+  // both ends compress from here on
+  client.connection.as_inner().enable_compression(addr);
+  client.register("alice", "secret").await;
+  assert!(matches!(
+    client.login("alice", "secret").await,
+    Ok(ResponseData::LoggedIn { .. })
+  ));
+}
+
+#[tokio::test]
+async fn keeps_compression_after_going_offline() {
+  let heartbeat_interval = Duration::from_millis(300);
+  let (_server, addr) = start_server_with(ServerState::new(heartbeat_interval)).await;
+  let client = Client::connect(addr).await;
+  let hello = hello(PROTOCOL_VERSION, Capabilities::COMPRESSION);
+  assert!(matches!(
+    client.request(&hello).await,
+    Ok(ResponseData::Hello { .. })
+  ));
+  client.connection.as_inner().enable_compression(addr);
+  client.register("alice", "secret").await;
+
+  // the client has no way to tell that the server stopped compressing, nor when
+  client.login("alice", "secret").await.unwrap();
+  assert_eq!(
+    client.request(&Command::Logout).await,
+    Ok(ResponseData::Success)
+  );
+  let token = match client.login("alice", "secret").await {
+    Ok(ResponseData::LoggedIn { token, .. }) => token,
+    response => panic!("unexpected response {:?}", response),
+  };
+
+  tokio::time::sleep(heartbeat_interval * 2).await;
+  assert_eq!(
+    client.request(&Command::GetChatroomStatus).await,
+    Err(ErrorCode::LoginRequired)
+  );
+  let resume = Command::Resume {
+    token,
+    device: client.device.clone(),
+  };
+  assert!(matches!(
+    client.request(&resume).await,
+    Ok(ResponseData::ChatroomStatus { .. })
+  ));
+  assert!(matches!(
+    client.request(&Command::GetChatroomStatus).await,
+    Ok(ResponseData::ChatroomStatus { .. })
+  ));
+}
+
+#[tokio::test]
+async fn speaks_its_own_version_to_newer_clients() {
+  let (_server, addr) = start_server().await;
+  let client = Client::connect(addr).await;
+
+  assert_eq!(
+    client
+      .request(&hello(PROTOCOL_VERSION + 1, Capabilities::empty()))
+      .await,
+    Ok(ResponseData::Hello {
+      version: PROTOCOL_VERSION,
+      capabilities: Capabilities::empty(),
+    })
+  );
+}
+
+#[tokio::test]
+async fn rejects_older_versions() {
+  let (_server, addr) = start_server().await;
+  let client = Client::connect(addr).await;
+
+  let version = MIN_PROTOCOL_VERSION - 1;
+  assert_eq!(
+    client
+      .request(&hello(version, Capabilities::COMPRESSION))
+      .await,
+    Err(ErrorCode::IncompatibleVersion {
+      version,
+      min: MIN_PROTOCOL_VERSION,
+      max: PROTOCOL_VERSION,
+    })
+  );
+
+  // nothing got negotiated, the client goes on without compression
+  client.register("alice", "secret").await;
+}
@@ -0,0 +1,110 @@
+mod common;
+
+use std::{iter, time::Duration};
+
+use chatroom_core::{
+  data::{Command, ErrorCode, ResponseData, UserEssential},
+  srp::{self, Verifier},
+};
+
+use chatroom_server_core::{config::LoginThrottle, server::ServerState};
+
+use common::{start_server, start_server_with, Client};
+
+/// as written by rust-argon2 before srp, for "correct horse" and a salt of sevens
+const LEGACY_HASH: &str = "$argon2i$v=19$m=4096,t=3,p=1$BwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwc$gau1B3okNp6RKH1cx9OZ7fCw67KcV7Y9VC0e9y8WwqI";
+
+#[tokio::test]
+async fn migrates_passwords_from_before_srp() {
+  let alice = UserEssential {
+    password_hash: LEGACY_HASH.to_string(),
+  };
+  let state = ServerState::from_user_essentials(
+    Duration::from_secs(30),
+    iter::once(("alice".to_string(), alice)),
+  );
+  let (_server, addr) = start_server_with(state).await;
+  let client = Client::connect(addr).await;
+
+  // answered like a wrong password, without counting it
+  for _ in 0..LoginThrottle::default().free_attempts + 1 {
+    assert_eq!(
+      client.login("alice", "correct horse").await,
+      Err(ErrorCode::InvalidUserOrPass)
+    );
+  }
+
+  let Verifier { salt, verifier } = Verifier::new("alice", "correct horse");
+  let migrate = Command::MigratePassword {
+    username: "alice".to_string(),
+    digest: srp::legacy_digest("correct horse"),
+    salt,
+    verifier,
+  };
+  assert_eq!(client.request(&migrate).await, Ok(ResponseData::Success));
+  assert!(matches!(
+    client.login("alice", "correct horse").await,
+    Ok(ResponseData::LoggedIn { .. })
+  ));
+}
+
+#[tokio::test]
+async fn counts_wrong_passwords_of_changes_as_failed_logins() {
+  let (server, addr) = start_server().await;
+  server.get_state().config.write().login_throttle = LoginThrottle {
+    free_attempts: 0,
+    base_delay: Duration::from_secs(60),
+    ..Default::default()
+  };
+  let client = Client::connect(addr).await;
+  client.register("alice", "secret").await;
+  client.login("alice", "secret").await.unwrap();
+
+  let session = client.authenticate("alice", "guess").await.unwrap();
+  let Verifier { salt, verifier } = Verifier::new("alice", "new secret");
+  let change = Command::ChangePassword {
+    proof: session.proof().to_vec(),
+    salt,
+    verifier,
+  };
+  assert_eq!(
+    client.request(&change).await,
+    Err(ErrorCode::InvalidUserOrPass)
+  );
+  assert!(matches!(
+    client.authenticate("alice", "secret").await,
+    Err(ErrorCode::Throttled { .. })
+  ));
+}
+
+#[tokio::test]
+async fn throttles_proofs_of_handshakes_started_before_a_failure() {
+  let (server, addr) = start_server().await;
+  server.get_state().config.write().login_throttle = LoginThrottle {
+    free_attempts: 0,
+    base_delay: Duration::from_secs(60),
+    ..Default::default()
+  };
+  let first = Client::connect(addr).await;
+  let second = Client::connect(addr).await;
+  first.register("alice", "secret").await;
+
+  // both challenges are handed out before either proof is checked
+  let sessions = [
+    first.authenticate("alice", "guess").await.unwrap(),
+    second.authenticate("alice", "secret").await.unwrap(),
+  ];
+  let login = |session: &srp::ClientSession| Command::Login {
+    proof: session.proof().to_vec(),
+    device: "tests".to_string(),
+    code: None,
+  };
+  assert_eq!(
+    first.request(&login(&sessions[0])).await,
+    Err(ErrorCode::InvalidUserOrPass)
+  );
+  assert!(matches!(
+    second.request(&login(&sessions[1])).await,
+    Err(ErrorCode::Throttled { .. })
+  ));
+}
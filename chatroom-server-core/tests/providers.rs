@@ -0,0 +1,76 @@
+mod common;
+
+use std::sync::Arc;
+
+use chatroom_core::data::{Command, ErrorCode, ResponseData};
+
+use chatroom_server_core::auth::{self, AuthProvider, EXTERNAL_PASSWORD_HASH};
+
+use common::{start_server, Client};
+
+/// a directory knowing everyone by the same password
+#[derive(Debug)]
+struct Directory;
+
+impl AuthProvider for Directory {
+  fn name(&self) -> &'static str {
+    "directory"
+  }
+
+  fn check_password(&self, _username: &str, password: &str) -> Result<bool, auth::Error> {
+    Ok(password == "directory password")
+  }
+}
+
+fn password_login(name: &str, password: &str) -> Command {
+  Command::PasswordLogin {
+    username: name.to_string(),
+    password: password.to_string(),
+    device: "tests".to_string(),
+    code: None,
+  }
+}
+
+#[tokio::test]
+async fn adds_external_users_to_the_roster() {
+  let (server, addr) = start_server().await;
+  server.get_state().set_auth_provider(Arc::new(Directory));
+  let client = Client::connect(addr).await;
+
+  assert_eq!(
+    client.request(&password_login("bob", "guess")).await,
+    Err(ErrorCode::InvalidUserOrPass)
+  );
+  assert!(matches!(
+    client
+      .request(&password_login("bob", "directory password"))
+      .await,
+    Ok(ResponseData::LoggedIn { .. })
+  ));
+  assert_eq!(
+    server.get_state().users.read()["bob"].password_hash,
+    EXTERNAL_PASSWORD_HASH
+  );
+}
+
+#[tokio::test]
+async fn never_hands_local_accounts_to_external_users() {
+  let (server, addr) = start_server().await;
+  let client = Client::connect(addr).await;
+  client.register("alice", "secret").await;
+  let password_hash = server.get_state().users.read()["alice"]
+    .password_hash
+    .clone();
+
+  server.get_state().set_auth_provider(Arc::new(Directory));
+  assert_eq!(
+    client
+      .request(&password_login("alice", "directory password"))
+      .await,
+    Err(ErrorCode::InvalidUserOrPass)
+  );
+  let state = server.get_state();
+  let users = state.users.read();
+  assert_eq!(users["alice"].password_hash, password_hash);
+  assert!(!users["alice"].is_online());
+}
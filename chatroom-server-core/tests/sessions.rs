@@ -0,0 +1,106 @@
+mod common;
+
+use chatroom_core::{
+  data::{Command, ErrorCode, Notification, ResponseData, SessionInfo},
+  srp::Verifier,
+};
+
+use common::{start_server, Client};
+
+async fn list_sessions(client: &Client) -> Vec<SessionInfo> {
+  match client.request(&Command::ListSessions).await {
+    Ok(ResponseData::Sessions { mut sessions }) => {
+      sessions.sort_by(|a, b| a.device.cmp(&b.device));
+      sessions
+    }
+    response => panic!("unexpected response {:?}", response),
+  }
+}
+
+#[tokio::test]
+async fn lists_the_sessions_of_every_device() {
+  let (_server, addr) = start_server().await;
+  let laptop = Client::connect_as(addr, "laptop").await;
+  let phone = Client::connect_as(addr, "phone").await;
+  laptop.register("alice", "secret").await;
+  laptop.login("alice", "secret").await.unwrap();
+  phone.login("alice", "secret").await.unwrap();
+
+  let sessions = list_sessions(&laptop).await;
+  assert_eq!(sessions.len(), 2);
+  assert_eq!(sessions[0].device, "laptop");
+  assert_eq!(sessions[0].ip_address, laptop.addr);
+  assert!(sessions[0].current);
+  assert_eq!(sessions[1].device, "phone");
+  assert_eq!(sessions[1].ip_address, phone.addr);
+  assert!(!sessions[1].current);
+  assert!(sessions.iter().all(|s| s.online));
+
+  let stranger = Client::connect(addr).await;
+  assert_eq!(
+    stranger.request(&Command::ListSessions).await,
+    Err(ErrorCode::LoginRequired)
+  );
+}
+
+#[tokio::test]
+async fn revokes_sessions_from_another_device() {
+  let (server, addr) = start_server().await;
+  let laptop = Client::connect_as(addr, "laptop").await;
+  let mut phone = Client::connect_as(addr, "phone").await;
+  laptop.register("alice", "secret").await;
+  laptop.login("alice", "secret").await.unwrap();
+  let token = match phone.login("alice", "secret").await {
+    Ok(ResponseData::LoggedIn { token, .. }) => token,
+    response => panic!("unexpected response {:?}", response),
+  };
+
+  let id = list_sessions(&laptop).await[1].id;
+  let revoke = Command::RevokeSession { id };
+  assert_eq!(laptop.request(&revoke).await, Ok(ResponseData::Success));
+
+  let notification = phone
+    .notification(|n| !matches!(n, Notification::SessionRevoked { .. }))
+    .await;
+  assert!(matches!(notification, Notification::SessionRevoked { .. }));
+  assert_eq!(server.get_state().users.read()["alice"].devices.len(), 1);
+  assert_eq!(list_sessions(&laptop).await.len(), 1);
+
+  assert_eq!(
+    laptop.request(&revoke).await,
+    Err(ErrorCode::SessionNotExisted)
+  );
+  let resume = Command::Resume {
+    token,
+    device: phone.device.clone(),
+  };
+  assert_eq!(phone.request(&resume).await, Err(ErrorCode::SessionExpired));
+}
+
+#[tokio::test]
+async fn changing_the_password_logs_the_other_devices_out() {
+  let (server, addr) = start_server().await;
+  let laptop = Client::connect_as(addr, "laptop").await;
+  let mut phone = Client::connect_as(addr, "phone").await;
+  laptop.register("alice", "secret").await;
+  laptop.login("alice", "secret").await.unwrap();
+  phone.login("alice", "secret").await.unwrap();
+
+  let session = laptop.authenticate("alice", "secret").await.unwrap();
+  let Verifier { salt, verifier } = Verifier::new("alice", "new secret");
+  let change = Command::ChangePassword {
+    proof: session.proof().to_vec(),
+    salt,
+    verifier,
+  };
+  assert_eq!(laptop.request(&change).await, Ok(ResponseData::Success));
+
+  let notification = phone
+    .notification(|n| !matches!(n, Notification::SessionRevoked { .. }))
+    .await;
+  assert!(matches!(notification, Notification::SessionRevoked { .. }));
+  let users = server.get_state().users.read().clone();
+  assert_eq!(users["alice"].devices.len(), 1);
+  assert_eq!(users["alice"].devices[0].ip_address, laptop.addr);
+  assert_eq!(list_sessions(&laptop).await.len(), 1);
+}
@@ -0,0 +1,120 @@
+mod common;
+
+use std::time::Duration;
+
+use time::OffsetDateTime;
+
+use chatroom_core::{
+  data::{Command, ErrorCode, ResponseData},
+  totp,
+};
+
+use chatroom_server_core::config::LoginThrottle;
+
+use common::{start_server, Client};
+
+/// enroll the user logged in on `client`, returns the secret and the recovery codes
+async fn enroll(client: &Client) -> (Vec<u8>, Vec<String>) {
+  let secret = match client.request(&Command::EnrollTotp).await {
+    Ok(ResponseData::TotpProvisioning { secret, .. }) => totp::decode_base32(&secret).unwrap(),
+    response => panic!("unexpected response {:?}", response),
+  };
+  // the step before, so that the current one is still left for logging in
+  let code = totp::code_at(&secret, previous_step());
+  match client.request(&Command::ConfirmTotp { code }).await {
+    Ok(ResponseData::RecoveryCodes { codes }) => (secret, codes),
+    response => panic!("unexpected response {:?}", response),
+  }
+}
+
+fn previous_step() -> OffsetDateTime {
+  OffsetDateTime::now_utc() - time::Duration::seconds(totp::STEP as i64)
+}
+
+/// a code of the right shape which is not the current one
+fn wrong_code(secret: &[u8]) -> String {
+  let code = totp::code_at(secret, OffsetDateTime::now_utc());
+  let first = (code.as_bytes()[0] - b'0' + 1) % 10;
+  format!("{}{}", first, &code[1..])
+}
+
+#[tokio::test]
+async fn asks_for_a_one_time_code() {
+  let (_server, addr) = start_server().await;
+  let laptop = Client::connect(addr).await;
+  laptop.register("alice", "secret").await;
+  laptop.login("alice", "secret").await.unwrap();
+  let (secret, _) = enroll(&laptop).await;
+
+  let phone = Client::connect(addr).await;
+  assert_eq!(
+    phone.login("alice", "secret").await,
+    Err(ErrorCode::SecondFactorRequired)
+  );
+  let code = totp::code_at(&secret, OffsetDateTime::now_utc());
+  assert!(matches!(
+    phone.login_with_code("alice", "secret", Some(&code)).await,
+    Ok(ResponseData::LoggedIn { .. })
+  ));
+
+  // a code is only good for one login, even from another device
+  let tablet = Client::connect(addr).await;
+  assert_eq!(
+    tablet.login_with_code("alice", "secret", Some(&code)).await,
+    Err(ErrorCode::InvalidOneTimeCode)
+  );
+}
+
+#[tokio::test]
+async fn counts_wrong_codes_as_failed_logins() {
+  let (server, addr) = start_server().await;
+  let client = Client::connect(addr).await;
+  client.register("alice", "secret").await;
+  client.login("alice", "secret").await.unwrap();
+  let (secret, _) = enroll(&client).await;
+  server.get_state().config.write().login_throttle = LoginThrottle {
+    free_attempts: 0,
+    base_delay: Duration::from_secs(60),
+    ..Default::default()
+  };
+
+  let other = Client::connect(addr).await;
+  let code = wrong_code(&secret);
+  assert_eq!(
+    other.login_with_code("alice", "secret", Some(&code)).await,
+    Err(ErrorCode::InvalidOneTimeCode)
+  );
+  assert!(matches!(
+    other.authenticate("alice", "secret").await,
+    Err(ErrorCode::Throttled { .. })
+  ));
+}
+
+#[tokio::test]
+async fn uses_recovery_codes_up() {
+  let (_server, addr) = start_server().await;
+  let laptop = Client::connect(addr).await;
+  laptop.register("alice", "secret").await;
+  laptop.login("alice", "secret").await.unwrap();
+  let (_, codes) = enroll(&laptop).await;
+
+  let phone = Client::connect(addr).await;
+  // typed in without the dash and in lower case
+  let typed = codes[0].replace('-', "").to_lowercase();
+  assert!(matches!(
+    phone.login_with_code("alice", "secret", Some(&typed)).await,
+    Ok(ResponseData::LoggedIn { .. })
+  ));
+  assert_eq!(
+    phone
+      .login_with_code("alice", "secret", Some(&codes[0]))
+      .await,
+    Err(ErrorCode::InvalidOneTimeCode)
+  );
+  assert!(matches!(
+    phone
+      .login_with_code("alice", "secret", Some(&codes[1]))
+      .await,
+    Ok(ResponseData::LoggedIn { .. })
+  ));
+}
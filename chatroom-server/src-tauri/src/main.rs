@@ -3,20 +3,33 @@
   windows_subsystem = "windows"
 )]
 
-use tauri::AppHandle;
-use tracing_subscriber::{fmt, prelude::*};
+use tauri::{api::path::app_dir, AppHandle, Manager};
+use tracing_subscriber::{filter::LevelFilter, fmt, prelude::*, Registry};
 
-use tracing::{info, instrument};
+use tracing::{info, instrument, warn};
 
-mod server;
 mod utils;
 
-use std::{iter, sync::Arc};
+use std::{
+  fs::File,
+  net::{IpAddr, SocketAddr},
+  path::PathBuf,
+  sync::Arc,
+};
 
-use server::Server;
+use chatroom_server_core::{
+  accounts::{self, AccountFormat, ConflictMode, ImportSummary},
+  audit::{self, AuditRecord, AuditTrail},
+  config::{LoginThrottle, RateLimit, RegistrationPolicy, ServerConfig},
+  lockout::Lockout,
+  logs::{self, FilterHandle, LogConfig, LogEntry, LogQuery},
+  server::{Server, ServerState},
+  storage::{Ban, BanTarget, SqliteStorage, Storage},
+};
 
 use chatroom_core::{
   data::{default_coder, DefaultCoder, User},
+  settings,
   utils::{Error, ErrorMsg},
 };
 
@@ -24,12 +37,20 @@ use parking_lot::RwLock;
 
 use std::time::Duration as StdDuration;
 
+use time::OffsetDateTime;
+
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
 struct Settings {
   heartbeat_interval: StdDuration,
   server_addr: String,
+  registration: RegistrationPolicy,
+  rate_limit: Option<RateLimit>,
+  login_throttle: LoginThrottle,
+  session_lifetime: StdDuration,
+  log: LogConfig,
 }
 
 impl Default for Settings {
@@ -37,37 +58,89 @@ impl Default for Settings {
     Self {
       heartbeat_interval: StdDuration::from_secs(60),
       server_addr: "0.0.0.0:0".into(),
+      registration: Default::default(),
+      rate_limit: None,
+      login_throttle: Default::default(),
+      session_lifetime: ServerConfig::default().session_lifetime,
+      log: Default::default(),
+    }
+  }
+}
+
+impl Settings {
+  fn server_config(&self) -> ServerConfig {
+    ServerConfig {
+      heartbeat_interval: self.heartbeat_interval,
+      registration: self.registration,
+      rate_limit: self.rate_limit,
+      login_throttle: self.login_throttle,
+      session_lifetime: self.session_lifetime,
+    }
+  }
+}
+
+impl settings::Settings for Settings {
+  const VERSION: u32 = 1;
+
+  fn validate(&self) -> Result<(), String> {
+    self.server_config().validate()?;
+    self.log.validate()?;
+    if let Err(err) = self.server_addr.parse::<SocketAddr>() {
+      return Err(format!("server address \"{}\": {}", self.server_addr, err));
     }
+    Ok(())
   }
 }
 
 #[derive(Default)]
 struct State {
   settings: RwLock<Settings>,
-  server: RwLock<Option<Server<DefaultCoder>>>,
+  /// shared so that commands can keep using it across awaits
+  server: RwLock<Option<Arc<Server<DefaultCoder>>>>,
+  /// absent if log files could not be opened
+  log_filter: RwLock<Option<FilterHandle<Registry>>>,
 }
 
 type MyState = Arc<State>;
 
+fn running_server(state: &MyState) -> Result<Arc<Server<DefaultCoder>>, ErrorMsg> {
+  Ok(state.server.read().clone().ok_or("server is not running")?)
+}
+
+fn settings_path(app: &AppHandle) -> Result<PathBuf, &'static str> {
+  let config_dir = app_dir(&app.config()).ok_or("failed to locate app data directory")?;
+  Ok(config_dir.join("settings.json"))
+}
+
+fn log_dir(app: &AppHandle) -> Result<PathBuf, &'static str> {
+  let data_dir = app_dir(&app.config()).ok_or("failed to locate app data directory")?;
+  Ok(data_dir.join("logs"))
+}
+
+fn open_storage(app: &AppHandle) -> Result<SqliteStorage, ErrorMsg> {
+  let data_dir = app_dir(&app.config()).ok_or("failed to locate app data directory")?;
+  std::fs::create_dir_all(&data_dir)?;
+  Ok(SqliteStorage::open(data_dir.join("users.db"))?)
+}
+
 #[tauri::command]
 #[instrument(skip(app, state))]
 async fn start_server(app: AppHandle, state: tauri::State<'_, MyState>) -> Result<(), ErrorMsg> {
-  let Settings {
-    heartbeat_interval,
-    server_addr,
-  } = state.settings.read().clone();
+  let settings = state.settings.read().clone();
+  let server_addr = settings.server_addr.clone();
   stop_server(state.clone()).await?;
+  let storage = Arc::new(open_storage(&app)?);
+  let server_state = ServerState::with_storage(settings.server_config(), storage)?;
   let server = Server::new(
     default_coder(),
-    iter::empty(),
-    app.clone(),
-    heartbeat_interval,
+    server_state,
+    Arc::new(utils::UserInfoNotifier::new(app.clone())),
     &server_addr,
   )
   .await;
   match server {
     Ok(server) => {
-      *state.server.write() = Some(server);
+      *state.server.write() = Some(Arc::new(server));
       Ok(())
     }
     Err(ref err @ Error::Network(ref inner)) => {
@@ -108,6 +181,203 @@ async fn get_users(state: tauri::State<'_, MyState>) -> Result<Vec<User>, ErrorM
   }
 }
 
+#[tauri::command]
+#[instrument(skip(app, state))]
+async fn export_users(
+  app: AppHandle,
+  state: tauri::State<'_, MyState>,
+  path: String,
+  format: Option<AccountFormat>,
+) -> Result<usize, ErrorMsg> {
+  let path = PathBuf::from(path);
+  let format = format
+    .or_else(|| AccountFormat::from_path(&path))
+    .ok_or("account format is unknown, please specify it")?;
+  let users = match state.server.read().as_ref() {
+    Some(server) => server.get_state().get_user_essentials(),
+    None => open_storage(&app)?.load_users()?,
+  };
+  let count = accounts::export(&users, format, File::create(&path)?)?;
+  info!(
+    source = "server",
+    "exported {} users to \"{}\".",
+    count,
+    path.display()
+  );
+  Ok(count)
+}
+
+#[tauri::command]
+#[instrument(skip(app, state))]
+async fn import_users(
+  app: AppHandle,
+  state: tauri::State<'_, MyState>,
+  path: String,
+  format: Option<AccountFormat>,
+  mode: ConflictMode,
+) -> Result<ImportSummary, ErrorMsg> {
+  let path = PathBuf::from(path);
+  let format = format
+    .or_else(|| AccountFormat::from_path(&path))
+    .ok_or("account format is unknown, please specify it")?;
+  let records = accounts::read(format, File::open(&path)?)?;
+  let server = state.server.read().clone();
+  let summary = match server {
+    Some(server) => server.get_state().import_accounts(&records, mode).await?,
+    None => {
+      let storage = Arc::new(open_storage(&app)?);
+      let trail = AuditTrail::new(storage.clone())?;
+      let summary = accounts::import(&*storage, &trail, &records, mode)?;
+      trail.flush();
+      summary
+    }
+  };
+  let _ = app.emit_all("user-info-updated", ());
+  info!(
+    source = "server",
+    "imported {} users from \"{}\", overwrote {} and skipped {}.",
+    summary.imported,
+    path.display(),
+    summary.overwritten,
+    summary.skipped
+  );
+  Ok(summary)
+}
+
+fn load_audit_records(app: &AppHandle, state: &MyState) -> Result<Vec<AuditRecord>, ErrorMsg> {
+  Ok(match state.server.read().as_ref() {
+    Some(server) => server.get_state().storage.load_audit_records()?,
+    None => open_storage(app)?.load_audit_records()?,
+  })
+}
+
+/// write the audit trail as json lines, returns how many records there were
+#[tauri::command]
+#[instrument(skip(app, state))]
+async fn export_audit(
+  app: AppHandle,
+  state: tauri::State<'_, MyState>,
+  path: String,
+) -> Result<usize, ErrorMsg> {
+  let records = load_audit_records(&app, &state)?;
+  let count = audit::export(&records, File::create(&path)?)?;
+  info!(
+    source = "server",
+    "exported {} audit records to \"{}\".", count, path
+  );
+  Ok(count)
+}
+
+/// fails if the audit trail is tampered with, returns how many records there are
+#[tauri::command]
+#[instrument(skip(app, state))]
+async fn verify_audit(app: AppHandle, state: tauri::State<'_, MyState>) -> Result<usize, ErrorMsg> {
+  let records = load_audit_records(&app, &state)?;
+  audit::verify(&records, None)?;
+  Ok(records.len())
+}
+
+#[tauri::command]
+#[instrument(skip(state))]
+async fn kick_user(state: tauri::State<'_, MyState>, name: String) -> Result<(), ErrorMsg> {
+  Ok(running_server(&state)?.kick(&name).await?)
+}
+
+fn ban_target(user: Option<String>, ip: Option<String>) -> Result<BanTarget, ErrorMsg> {
+  match (user, ip) {
+    (Some(user), None) => Ok(BanTarget::User(user)),
+    (None, Some(ip)) => Ok(BanTarget::Ip(ip.parse::<IpAddr>()?)),
+    _ => Err("either a user or an address is expected".into()),
+  }
+}
+
+/// ban `user` or `ip`, for `duration` seconds or forever, returns the users who got kicked
+#[tauri::command]
+#[instrument(skip(state))]
+async fn ban(
+  state: tauri::State<'_, MyState>,
+  user: Option<String>,
+  ip: Option<String>,
+  reason: Option<String>,
+  duration: Option<u64>,
+) -> Result<Vec<String>, ErrorMsg> {
+  let target = ban_target(user, ip)?;
+  let until = match duration {
+    Some(secs) => match i64::try_from(secs)
+      .ok()
+      .and_then(|secs| OffsetDateTime::now_utc().checked_add(time::Duration::seconds(secs)))
+    {
+      Some(until) => Some(until),
+      None => return Err(format!("ban duration of {} seconds is too long", secs).into()),
+    },
+    None => None,
+  };
+  let ban = Ban {
+    target,
+    reason,
+    until,
+  };
+  Ok(running_server(&state)?.ban(ban).await?)
+}
+
+#[tauri::command]
+#[instrument(skip(state))]
+async fn unban(
+  state: tauri::State<'_, MyState>,
+  user: Option<String>,
+  ip: Option<String>,
+) -> Result<bool, ErrorMsg> {
+  let target = ban_target(user, ip)?;
+  Ok(running_server(&state)?.unban(&target).await?)
+}
+
+#[tauri::command]
+#[instrument(skip(state))]
+async fn get_bans(state: tauri::State<'_, MyState>) -> Result<Vec<Ban>, ErrorMsg> {
+  Ok(running_server(&state)?.get_bans())
+}
+
+/// let `user` or `ip` try logging in again right away, returns whether it had to wait
+#[tauri::command]
+#[instrument(skip(state))]
+async fn unlock(
+  state: tauri::State<'_, MyState>,
+  user: Option<String>,
+  ip: Option<String>,
+) -> Result<bool, ErrorMsg> {
+  let target = ban_target(user, ip)?;
+  Ok(running_server(&state)?.unlock(&target))
+}
+
+#[tauri::command]
+#[instrument(skip(state))]
+async fn get_lockouts(state: tauri::State<'_, MyState>) -> Result<Vec<Lockout>, ErrorMsg> {
+  Ok(running_server(&state)?.get_lockouts())
+}
+
+#[tauri::command]
+#[instrument(skip(state))]
+async fn delete_user(state: tauri::State<'_, MyState>, name: String) -> Result<(), ErrorMsg> {
+  Ok(running_server(&state)?.delete_user(&name).await?)
+}
+
+#[tauri::command]
+#[instrument(skip(state, password))]
+async fn reset_password(
+  state: tauri::State<'_, MyState>,
+  name: String,
+  password: String,
+) -> Result<(), ErrorMsg> {
+  let server = running_server(&state)?;
+  Ok(server.reset_password(&name, &password).await?)
+}
+
+#[tauri::command]
+#[instrument(skip(state))]
+async fn disable_totp(state: tauri::State<'_, MyState>, name: String) -> Result<bool, ErrorMsg> {
+  Ok(running_server(&state)?.disable_totp(&name).await?)
+}
+
 #[tauri::command]
 #[instrument(skip(state))]
 async fn is_server_on(state: tauri::State<'_, MyState>) -> Result<bool, ErrorMsg> {
@@ -120,21 +390,87 @@ async fn get_settings(state: tauri::State<'_, MyState>) -> Result<Settings, Erro
   Ok(state.settings.read().clone())
 }
 
+/// save settings and hand them to the running server, only `server_addr` waits for a restart
+fn update_settings(
+  app: &AppHandle,
+  state: &MyState,
+  update: impl FnOnce(&mut Settings),
+) -> Result<(), ErrorMsg> {
+  let mut settings = state.settings.read().clone();
+  update(&mut settings);
+  settings::save(&settings_path(app)?, &settings)?;
+  if let Some(server) = state.server.read().as_ref() {
+    server.apply_config(settings.server_config());
+  }
+  *state.settings.write() = settings;
+  Ok(())
+}
+
 #[tauri::command]
-#[instrument(skip(state))]
+#[instrument(skip(app, state))]
 async fn set_settings(
+  app: AppHandle,
   state: tauri::State<'_, MyState>,
   heartbeat_interval: Option<u64>,
   server_addr: Option<String>,
+  registration: Option<RegistrationPolicy>,
 ) -> Result<(), ErrorMsg> {
-  let mut settings = state.settings.write();
-  if let Some(heartbeat_interval) = heartbeat_interval {
-    settings.heartbeat_interval = StdDuration::from_millis(heartbeat_interval);
-  };
-  if let Some(server_addr) = server_addr {
-    settings.server_addr = server_addr;
-  };
-  Ok(())
+  update_settings(&app, &state, |settings| {
+    if let Some(heartbeat_interval) = heartbeat_interval {
+      settings.heartbeat_interval = StdDuration::from_millis(heartbeat_interval);
+    };
+    if let Some(server_addr) = server_addr {
+      settings.server_addr = server_addr;
+    };
+    if let Some(registration) = registration {
+      settings.registration = registration;
+    };
+  })
+}
+
+/// `None` lifts the limit
+#[tauri::command]
+#[instrument(skip(app, state))]
+async fn set_rate_limit(
+  app: AppHandle,
+  state: tauri::State<'_, MyState>,
+  rate_limit: Option<RateLimit>,
+) -> Result<(), ErrorMsg> {
+  update_settings(&app, &state, |settings| settings.rate_limit = rate_limit)
+}
+
+#[tauri::command]
+#[instrument(skip(app, state))]
+async fn set_login_throttle(
+  app: AppHandle,
+  state: tauri::State<'_, MyState>,
+  login_throttle: LoginThrottle,
+) -> Result<(), ErrorMsg> {
+  login_throttle.validate()?;
+  update_settings(&app, &state, |settings| {
+    settings.login_throttle = login_throttle
+  })
+}
+
+/// the filter applies at once, format, rotation and max_files after a restart
+#[tauri::command]
+#[instrument(skip(app, state))]
+async fn set_log_config(
+  app: AppHandle,
+  state: tauri::State<'_, MyState>,
+  config: LogConfig,
+) -> Result<(), ErrorMsg> {
+  config.validate()?;
+  if let Some(handle) = state.log_filter.read().as_ref() {
+    handle.reload(logs::parse_filter(&config.filter)?)?;
+  }
+  update_settings(&app, &state, |settings| settings.log = config)
+}
+
+#[tauri::command]
+#[instrument(skip(app))]
+async fn query_logs(app: AppHandle, query: LogQuery) -> Result<Vec<LogEntry>, ErrorMsg> {
+  Ok(logs::query(&log_dir(&app)?, &query)?)
 }
 
 fn main() {
@@ -144,18 +480,64 @@ fn main() {
       start_server,
       stop_server,
       get_users,
+      export_users,
+      import_users,
+      export_audit,
+      verify_audit,
+      kick_user,
+      ban,
+      unban,
+      get_bans,
+      unlock,
+      get_lockouts,
+      delete_user,
+      reset_password,
+      disable_totp,
       get_settings,
       set_settings,
+      set_rate_limit,
+      set_login_throttle,
+      set_log_config,
+      query_logs,
       is_server_on
     ])
     .setup(|app| {
-      let subscriber = fmt()
-        .with_writer(utils::LogWriterMaker::new(app.handle()))
-        .with_ansi(false)
-        .with_target(false)
-        .with_timer(fmt::time::LocalTime::rfc_3339())
-        .finish();
+      let app = app.handle();
+      let loaded = settings_path(&app)
+        .map_err(|err| err.to_string())
+        .and_then(|path| settings::load::<Settings>(&path).map_err(|err| err.to_string()));
+      let log_config = match &loaded {
+        Ok(settings) => settings.log.clone(),
+        Err(_) => Default::default(),
+      };
+      let file_layer = log_dir(&app)
+        .map_err(|err| err.to_string())
+        .and_then(|dir| logs::file_layer(&dir, &log_config).map_err(|err| err.to_string()));
+      let (file_layer, file_error) = match file_layer {
+        Ok((layer, handle)) => {
+          *app.state::<MyState>().log_filter.write() = Some(handle);
+          (Some(layer), None)
+        }
+        Err(err) => (None, Some(err)),
+      };
+
+      let subscriber = tracing_subscriber::registry().with(file_layer).with(
+        fmt::layer()
+          .with_writer(utils::LogWriterMaker::new(app.clone()))
+          .with_ansi(false)
+          .with_target(false)
+          .with_timer(fmt::time::LocalTime::rfc_3339())
+          .with_filter(LevelFilter::INFO),
+      );
       tracing::subscriber::set_global_default(subscriber).expect("setting tracing default failed");
+
+      if let Some(err) = file_error {
+        warn!(source = "internal", "failed to open log files: {}", err);
+      }
+      match loaded {
+        Ok(settings) => *app.state::<MyState>().settings.write() = settings,
+        Err(err) => warn!(source = "internal", "failed to load settings: {}", err),
+      }
       Ok(())
     })
     .run(tauri::generate_context!())
@@ -2,6 +2,8 @@ use std::io::Write;
 use tauri::{AppHandle, Manager};
 use tracing_subscriber::fmt::MakeWriter;
 
+use chatroom_server_core::event::{EventListener, ServerEvent};
+
 pub struct LogWriter {
   app: AppHandle,
 }
@@ -36,3 +38,20 @@ impl<'a> MakeWriter<'a> for LogWriterMaker {
     }
   }
 }
+
+/// tell the frontend to refresh its user list whenever the server changes anything about users
+pub struct UserInfoNotifier {
+  app: AppHandle,
+}
+
+impl UserInfoNotifier {
+  pub fn new(app: AppHandle) -> Self {
+    Self { app }
+  }
+}
+
+impl EventListener for UserInfoNotifier {
+  fn on_event(&self, _event: ServerEvent) {
+    let _ = self.app.emit_all("user-info-updated", ());
+  }
+}
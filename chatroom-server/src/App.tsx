@@ -20,6 +20,30 @@ type ServerStatus = "running" | "ready" | "busy";
 
 type UserInfo = [string, string | null];
 
+type LogEntry = {
+  timestamp: string;
+  level: string;
+  spans: { name: string; fields: Record<string, string> }[];
+  message: string;
+  fields: Record<string, string>;
+};
+
+const format_log_entry = (entry: LogEntry): string => {
+  const spans = entry.spans
+    .map(
+      (span) =>
+        `${span.name}{${Object.entries(span.fields)
+          .map(([key, value]) => `${key}=${value}`)
+          .join(" ")}}:`
+    )
+    .join("");
+  const time = new Date(entry.timestamp).toLocaleString();
+  return `${time} ${entry.level} ${spans} ${entry.message}`;
+};
+
+const to_rfc3339 = (local: string): string | null =>
+  local === "" ? null : new Date(local).toISOString();
+
 const user_info_comparer = (a: UserInfo, b: UserInfo): number => {
   if (a[1] == null) {
     if (b[1] != null) {
@@ -43,6 +67,14 @@ const App: FC = () => {
   const [server_status, set_server_status] = useState<ServerStatus>("ready");
   const [logs, set_logs] = useState<string[]>([]);
   const [user_info, set_user_info] = useState<UserInfo[]>([]);
+  const [search_user, set_search_user] = useState("");
+  const [search_span, set_search_span] = useState("");
+  const [search_since, set_search_since] = useState("");
+  const [search_until, set_search_until] = useState("");
+  // past entries replace the live feed while present
+  const [search_results, set_search_results] = useState<LogEntry[] | null>(
+    null
+  );
 
   useEffect(() => {
     const unsubscribe = listen("log", (event) => {
@@ -57,16 +89,30 @@ const App: FC = () => {
     };
   }, []);
 
+  useEffect(() => {
+    (async () => {
+      const settings: {
+        heartbeat_interval: { secs: number; nanos: number };
+        server_addr: string;
+      } = await invoke("get_settings");
+      const { secs, nanos } = settings.heartbeat_interval;
+      set_ip_addr(settings.server_addr);
+      set_heartbeat_time(`${secs * 1000 + Math.floor(nanos / 1000000)}`);
+    })();
+  }, []);
+
   useEffect(() => {
     const unsubscribe = listen("user-info-updated", (log) => {
       (async () => {
         let data: {
           name: string;
-          online_info: { ip_address: string } | null;
+          devices: { ip_address: string }[];
         }[] = await invoke("get_users");
         let new_user_info: UserInfo[] = data.map((u) => [
           u.name,
-          u.online_info == null ? null : u.online_info.ip_address,
+          u.devices.length == 0
+            ? null
+            : u.devices.map((d) => d.ip_address).join(", "),
         ]);
         new_user_info.sort(user_info_comparer);
         set_user_info(new_user_info);
@@ -175,8 +221,74 @@ const App: FC = () => {
               borderRight: "2px solid #a5a5a5",
             }}
           >
-            <Box sx={{ padding: "10px", borderBottom: "1px solid #c4c4c4" }}>
-              日志
+            <Box
+              sx={{
+                display: "flex",
+                flexDirection: "row",
+                alignItems: "center",
+                flexWrap: "wrap",
+                gap: "8px",
+                padding: "10px",
+                borderBottom: "1px solid #c4c4c4",
+              }}
+            >
+              <span>日志</span>
+              <TextField
+                size="small"
+                label="用户"
+                value={search_user}
+                onChange={(event) => set_search_user(event.target.value)}
+                sx={{ width: "100px" }}
+              />
+              <TextField
+                size="small"
+                label="操作"
+                placeholder="LOGIN"
+                value={search_span}
+                onChange={(event) => set_search_span(event.target.value)}
+                sx={{ width: "100px" }}
+              />
+              <TextField
+                size="small"
+                label="起始时间"
+                type="datetime-local"
+                InputLabelProps={{ shrink: true }}
+                value={search_since}
+                onChange={(event) => set_search_since(event.target.value)}
+              />
+              <TextField
+                size="small"
+                label="结束时间"
+                type="datetime-local"
+                InputLabelProps={{ shrink: true }}
+                value={search_until}
+                onChange={(event) => set_search_until(event.target.value)}
+              />
+              <Button
+                variant="outlined"
+                onClick={async () => {
+                  try {
+                    const entries: LogEntry[] = await invoke("query_logs", {
+                      query: {
+                        user: search_user === "" ? null : search_user,
+                        span: search_span === "" ? null : search_span,
+                        since: to_rfc3339(search_since),
+                        until: to_rfc3339(search_until),
+                      },
+                    });
+                    set_search_results(entries);
+                  } catch (err) {
+                    console.error(err);
+                  }
+                }}
+              >
+                搜索
+              </Button>
+              {search_results != null && (
+                <Button onClick={() => set_search_results(null)}>
+                  实时日志
+                </Button>
+              )}
             </Box>
             <Box
               sx={{
@@ -199,7 +311,10 @@ const App: FC = () => {
                 maxHeight: "calc(100vh - 127px)",
               }}
             >
-              {logs.map((log, id) => (
+              {(search_results == null
+                ? logs
+                : search_results.map(format_log_entry)
+              ).map((log, id) => (
                 <Box
                   key={id}
                   sx={{